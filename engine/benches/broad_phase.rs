@@ -0,0 +1,110 @@
+// Compares pair finding in the spatial hash and the dynamic AABB tree on a
+// scene mixing many small bodies with a few large ones, which is the case a
+// fixed cell size handles worst.
+//
+// Run with `cargo bench --bench broad_phase`.
+
+use ashengine::physics::{BroadPhase, BroadPhaseKind};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use glam::Vec3;
+
+const WORLD_SIZE: f32 = 200.0;
+const SCENE_SIZES: [usize; 2] = [500, 2_000];
+
+// Deterministic scene: 95% small boxes, 5% boxes spanning many hash cells
+fn mixed_scene(count: usize) -> Vec<(Vec3, Vec3)> {
+    let mut seed = 0x9e37_79b9_u32;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed as f32 / u32::MAX as f32
+    };
+
+    (0..count)
+        .map(|i| {
+            let center = Vec3::new(next(), next(), next()) * WORLD_SIZE;
+            let half = if i % 20 == 0 {
+                10.0 + next() * 20.0
+            } else {
+                0.25 + next() * 0.75
+            };
+            (center - Vec3::splat(half), center + Vec3::splat(half))
+        })
+        .collect()
+}
+
+// Small per-frame motion, most bodies stay within the tree's fat AABBs
+fn jitter(scene: &[(Vec3, Vec3)], frame: usize) -> Vec<(Vec3, Vec3)> {
+    scene
+        .iter()
+        .enumerate()
+        .map(|(i, &(min, max))| {
+            let phase = (i + frame) as f32 * 0.37;
+            let offset = Vec3::new(phase.sin(), phase.cos(), (phase * 0.5).sin()) * 0.05;
+            (min + offset, max + offset)
+        })
+        .collect()
+}
+
+fn count_overlapping(broad_phase: &dyn BroadPhase, scene: &[(Vec3, Vec3)]) -> usize {
+    broad_phase
+        .potential_pairs()
+        .into_iter()
+        .filter(|&(i, j)| {
+            let (min_a, max_a) = scene[i];
+            let (min_b, max_b) = scene[j];
+            min_a.cmple(max_b).all() && min_b.cmple(max_a).all()
+        })
+        .count()
+}
+
+const KINDS: [(BroadPhaseKind, &str); 2] = [
+    (BroadPhaseKind::SpatialHash, "spatial_hash"),
+    (BroadPhaseKind::DynamicTree, "dynamic_tree"),
+];
+
+fn bench_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broad_phase_build");
+    // The spatial hash rebuilds its grid on every resize, keep runs short
+    group.sample_size(20);
+    for count in SCENE_SIZES {
+        let scene = mixed_scene(count);
+        for (kind, name) in KINDS {
+            group.bench_with_input(BenchmarkId::new(name, count), &scene, |b, scene| {
+                b.iter(|| {
+                    let mut broad_phase = kind.create();
+                    broad_phase.update(scene);
+                    black_box(count_overlapping(broad_phase.as_ref(), scene))
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broad_phase_step");
+    group.sample_size(20);
+    for count in SCENE_SIZES {
+        let scene = mixed_scene(count);
+        let frames: Vec<_> = (0..8).map(|frame| jitter(&scene, frame)).collect();
+        for (kind, name) in KINDS {
+            let mut broad_phase = kind.create();
+            broad_phase.update(&scene);
+            let mut frame = 0;
+            group.bench_function(BenchmarkId::new(name, count), |b| {
+                b.iter(|| {
+                    let bounds = &frames[frame % frames.len()];
+                    frame += 1;
+                    broad_phase.update(bounds);
+                    black_box(count_overlapping(broad_phase.as_ref(), bounds))
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_build, bench_step);
+criterion_main!(benches);
@@ -1,10 +1,24 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+const SHADER_DIR: &str = "src/physics/shaders";
 
 fn main() {
-    println!("cargo:rerun-if-changed=src/physics/shaders/particle_update.comp");
+    println!("cargo:rerun-if-changed={}", SHADER_DIR);
+
+    let compiler = shaderc::Compiler::new().unwrap();
+    let mut options = shaderc::CompileOptions::new().unwrap();
+    // `#include "particle_common.glsl"` and friends resolve next to the shaders
+    options.set_include_callback(|requested, _include_type, _requesting, _depth| {
+        let path = Path::new(SHADER_DIR).join(requested);
+        std::fs::read_to_string(&path)
+            .map(|content| shaderc::ResolvedInclude {
+                resolved_name: path.display().to_string(),
+                content,
+            })
+            .map_err(|e| format!("Failed to include `{}`: {}", requested, e))
+    });
 
-    let mut compiler = shaderc::Compiler::new().unwrap();
-    let shader_source = PathBuf::from("src/physics/shaders/particle_update.comp");
+    let shader_source = Path::new(SHADER_DIR).join("particle_update.comp");
 
     let artifact = compiler
         .compile_into_spirv(
@@ -12,7 +26,7 @@ fn main() {
             shaderc::ShaderKind::Compute,
             "particle_update.comp",
             "main",
-            None,
+            Some(&options),
         )
         .unwrap();
 
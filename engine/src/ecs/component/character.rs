@@ -0,0 +1,86 @@
+//! Character controller component
+//!
+//! Drives an entity as a kinematic capsule that walks over the CPU physics
+//! world, instead of simulating it as a rigid body.
+
+use super::Component;
+use crate::physics::{CharacterController, CharacterMove};
+use glam::Vec3;
+
+/// Component for kinematic, player-driven characters
+#[derive(Debug, Clone)]
+pub struct CharacterControllerComponent {
+    /// Capsule shape and movement settings
+    pub controller: CharacterController,
+    /// Desired velocity perpendicular to `controller.up`, set by gameplay code
+    pub move_velocity: Vec3,
+    /// Current speed along `controller.up`, accumulated from gravity and jumps
+    pub vertical_speed: f32,
+    /// Whether the character is standing on walkable ground
+    pub grounded: bool,
+    /// Normal of the ground below the character
+    pub ground_normal: Vec3,
+    /// Physics body the character is standing on
+    pub ground_body: Option<usize>,
+    /// Whether the controller moves the entity
+    pub enabled: bool,
+}
+
+impl Default for CharacterControllerComponent {
+    fn default() -> Self {
+        Self::new(CharacterController::default())
+    }
+}
+
+impl CharacterControllerComponent {
+    /// Create a new character controller component
+    pub fn new(controller: CharacterController) -> Self {
+        Self {
+            ground_normal: controller.up,
+            controller,
+            move_velocity: Vec3::ZERO,
+            vertical_speed: 0.0,
+            grounded: false,
+            ground_body: None,
+            enabled: true,
+        }
+    }
+
+    /// Set the desired movement velocity
+    pub fn set_move_velocity(&mut self, velocity: Vec3) {
+        let up = self.controller.up;
+        self.move_velocity = velocity - up * velocity.dot(up);
+    }
+
+    /// Jump with the given upward speed, only while grounded
+    pub fn jump(&mut self, speed: f32) -> bool {
+        if !self.grounded {
+            return false;
+        }
+        self.vertical_speed = speed;
+        self.grounded = false;
+        true
+    }
+
+    /// Motion for one step of `delta_time`, after applying gravity
+    pub(crate) fn step_motion(&mut self, gravity: Vec3, delta_time: f32) -> Vec3 {
+        let up = self.controller.up;
+        if self.grounded && self.vertical_speed < 0.0 {
+            self.vertical_speed = 0.0;
+        }
+        self.vertical_speed += gravity.dot(up) * delta_time;
+        (self.move_velocity + up * self.vertical_speed) * delta_time
+    }
+
+    /// Record the outcome of a move
+    pub(crate) fn apply_move(&mut self, result: &CharacterMove) {
+        self.grounded = result.grounded;
+        self.ground_normal = result.ground_normal;
+        self.ground_body = result.ground_body;
+        if result.grounded || (result.hit_ceiling && self.vertical_speed > 0.0) {
+            self.vertical_speed = 0.0;
+        }
+    }
+}
+
+impl Component for CharacterControllerComponent {}
@@ -36,12 +36,14 @@ pub trait ComponentStorage {
 }
 
 // Re-export common components
+mod character;
 mod physics;
-mod render;
+mod renderer;
 mod transform;
 
+pub use character::CharacterControllerComponent;
 pub use physics::PhysicsComponent;
-pub use render::RenderComponent;
+pub use renderer::RenderComponent;
 pub use transform::TransformComponent;
 
 use super::Entity;
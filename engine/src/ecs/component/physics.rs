@@ -4,8 +4,8 @@
 //! between the ECS and the GPU physics system.
 
 use super::Component;
-use crate::physics::{Particle, PhysicsError};
-use glam::{Vec3, Vec4};
+use crate::physics::{cuboid_inertia, Particle, PhysicsError, PhysicsObject};
+use glam::{Mat3, Quat, Vec3, Vec4};
 
 /// Component for entity physics properties
 #[derive(Debug)]
@@ -16,6 +16,8 @@ pub struct PhysicsComponent {
     pub velocity: Vec3,
     /// Current acceleration
     pub acceleration: Vec3,
+    /// Current angular velocity
+    pub angular_velocity: Vec3,
     /// Mass of the entity
     pub mass: f32,
     /// Bounding box for collision (min_x, min_y, min_z, radius)
@@ -24,6 +26,9 @@ pub struct PhysicsComponent {
     pub enabled: bool,
     /// Whether the entity is static (immovable)
     pub is_static: bool,
+    /// Whether the entity is moved by its transform and pushes dynamic
+    /// bodies without being pushed back
+    pub is_kinematic: bool,
     /// GPU particle data for physics system
     particle_data: Option<Particle>,
 }
@@ -34,10 +39,12 @@ impl Default for PhysicsComponent {
             position: Vec3::ZERO,
             velocity: Vec3::ZERO,
             acceleration: Vec3::ZERO,
+            angular_velocity: Vec3::ZERO,
             mass: 1.0,
             bounding_box: Vec4::new(0.0, 0.0, 0.0, 1.0),
             enabled: true,
             is_static: false,
+            is_kinematic: false,
             particle_data: None,
         }
     }
@@ -66,15 +73,21 @@ impl PhysicsComponent {
         self
     }
 
+    /// Set whether the entity is kinematic
+    pub fn with_kinematic(mut self, is_kinematic: bool) -> Self {
+        self.is_kinematic = is_kinematic;
+        self
+    }
+
+    /// Whether the physics simulation moves the entity
+    pub fn is_dynamic(&self) -> bool {
+        !self.is_static && !self.is_kinematic
+    }
+
     /// Update the internal particle data for GPU physics
     pub fn update_particle_data(&mut self) -> Result<(), PhysicsError> {
-        let particle = Particle {
-            position: self.position,
-            velocity: self.velocity,
-            acceleration: self.acceleration,
-            mass: self.mass,
-            bounding_box: self.bounding_box,
-        };
+        let particle = Particle::new(self.position.to_array(), self.velocity.to_array())
+            .with_mass(self.body_mass());
         self.particle_data = Some(particle);
         Ok(())
     }
@@ -86,14 +99,14 @@ impl PhysicsComponent {
 
     /// Apply an impulse force
     pub fn apply_impulse(&mut self, impulse: Vec3) {
-        if !self.is_static && self.enabled {
+        if self.is_dynamic() && self.enabled {
             self.velocity += impulse / self.mass;
         }
     }
 
     /// Apply a continuous force
     pub fn apply_force(&mut self, force: Vec3) {
-        if !self.is_static && self.enabled {
+        if self.is_dynamic() && self.enabled {
             self.acceleration += force / self.mass;
         }
     }
@@ -103,8 +116,32 @@ impl Component for PhysicsComponent {}
 
 // Bridge implementation for physics system integration
 impl PhysicsComponent {
-    /// Convert to physics system format
-    pub(crate) fn to_physics_data(&self) -> Option<Particle> {
-        self.particle_data.clone()
+    /// Mass seen by the CPU physics world, zero for static and kinematic bodies
+    pub(crate) fn body_mass(&self) -> f32 {
+        if self.is_dynamic() {
+            self.mass
+        } else {
+            0.0
+        }
+    }
+
+    /// Inertia of the cube described by `bounding_box`
+    pub(crate) fn body_inertia(&self) -> Mat3 {
+        cuboid_inertia(self.body_mass(), Vec3::splat(self.bounding_box.w))
+    }
+
+    /// Convert to a CPU physics world rigid body placed at `position`
+    pub(crate) fn to_rigid_body(&self, position: Vec3, orientation: Quat) -> PhysicsObject {
+        PhysicsObject::RigidBody {
+            position,
+            velocity: self.velocity,
+            acceleration: self.acceleration,
+            orientation,
+            angular_velocity: self.angular_velocity,
+            angular_acceleration: Vec3::ZERO,
+            mass: self.body_mass(),
+            inertia_tensor: self.body_inertia(),
+            bounding_box: self.bounding_box,
+        }
     }
 }
@@ -2,6 +2,7 @@
 //!
 //! Provides a unified renderer component that works with the graphics system.
 
+use super::Component;
 use crate::graphics::{render::PassType, resource::ResourceHandle};
 
 /// Base renderer component shared by all renderer types
@@ -110,3 +111,5 @@ impl RenderComponent {
         self.color
     }
 }
+
+impl Component for RenderComponent {}
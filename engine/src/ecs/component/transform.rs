@@ -52,6 +52,11 @@ impl TransformComponent {
         self.cached_matrix
     }
 
+    /// Whether the transform changed since the matrix was last computed
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
     /// Set the position
     pub fn set_position(&mut self, position: Vec3) {
         self.position = position;
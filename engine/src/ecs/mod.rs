@@ -5,20 +5,25 @@
 
 mod component;
 mod query;
-mod resource;
 mod system;
 mod world;
 
-pub use component::{Component, ComponentId, ComponentStorage};
-pub use query::{Query, QueryBuilder, QueryFilter};
-pub use resource::{Resource, ResourceId, Resources};
-pub use system::{System, SystemId};
+pub use component::{
+    CharacterControllerComponent, Component, ComponentId, ComponentStorage, PhysicsComponent,
+    RenderComponent, TransformComponent,
+};
+pub use query::{Query, QueryBuilder, QueryFilter, QueryIter, QueryMut, QueryMutIter};
+pub use system::{
+    CharacterControllerSystem, PhysicsBridgeSystem, PhysicsWorldBridgeSystem, RenderSystem, System,
+    SystemConfig, SystemId, SystemStage,
+};
 pub use world::{Entity, EntityBuilder, World};
 
 pub mod prelude {
     //! Commonly used types and traits
 
     pub use super::{
-        Component, ComponentId, Entity, EntityBuilder, Query, QueryBuilder, Resource, System, World,
+        Component, ComponentId, Entity, EntityBuilder, Query, QueryBuilder, QueryMut, System,
+        SystemConfig, SystemStage, World,
     };
 }
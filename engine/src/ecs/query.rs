@@ -2,7 +2,7 @@
 //!
 //! Provides efficient iteration and filtering over components
 
-use super::{Entity, World};
+use super::{Component, Entity, World};
 use std::marker::PhantomData;
 
 /// Filter for component queries
@@ -74,7 +74,7 @@ where
     }
 }
 
-/// Trait for component queries
+/// Trait for read-only component queries
 pub trait Query<'a>: Sized {
     /// Type of the query result
     type Item;
@@ -86,7 +86,7 @@ pub trait Query<'a>: Sized {
 // Implement Query for common tuple sizes
 impl<'a, A> Query<'a> for &'a A
 where
-    A: 'static,
+    A: Component,
 {
     type Item = &'a A;
 
@@ -95,27 +95,11 @@ where
     }
 }
 
-impl<'a, A> Query<'a> for &'a mut A
-where
-    A: 'static,
-{
-    type Item = &'a mut A;
-
-    fn fetch(world: &'a World, entity: Entity) -> Option<Self::Item> {
-        // Safety: We know this is safe because the borrow checker ensures
-        // we don't have multiple mutable references
-        unsafe {
-            let world_ptr = world as *const World as *mut World;
-            (*world_ptr).get_component_mut::<A>(entity)
-        }
-    }
-}
-
 // Implement for tuples
 impl<'a, A, B> Query<'a> for (&'a A, &'a B)
 where
-    A: 'static,
-    B: 'static,
+    A: Component,
+    B: Component,
 {
     type Item = (&'a A, &'a B);
 
@@ -127,21 +111,129 @@ where
     }
 }
 
-impl<'a, A, B> Query<'a> for (&'a mut A, &'a B)
+/// Iterator for queries borrowing components mutably
+pub struct QueryMutIter<'a, Q> {
+    world: *mut World,
+    current: usize,
+    _phantom: PhantomData<(&'a mut World, Q)>,
+}
+
+impl<'a, Q: QueryMut<'a>> QueryMutIter<'a, Q> {
+    pub(crate) fn new(world: &'a mut World) -> Self {
+        Q::check_access();
+        Self {
+            world,
+            current: 0,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a, Q> Iterator for QueryMutIter<'a, Q>
 where
-    A: 'static,
-    B: 'static,
+    Q: QueryMut<'a>,
 {
-    type Item = (&'a mut A, &'a B);
+    type Item = (Entity, Q::Item);
 
-    fn fetch(world: &'a World, entity: Entity) -> Option<Self::Item> {
-        unsafe {
-            let world_ptr = world as *const World as *mut World;
-            Some((
-                (*world_ptr).get_component_mut::<A>(entity)?,
-                world.get_component::<B>(entity)?,
-            ))
+    fn next(&mut self) -> Option<Self::Item> {
+        // Safety: the iterator holds the world's exclusive borrow for `'a`,
+        // and every entity is visited once so components are never aliased
+        let entities = unsafe { (*self.world).entities().len() };
+        while self.current < entities {
+            let entity = unsafe { (*self.world).entities()[self.current] };
+            self.current += 1;
+
+            if let Some(entity) = entity {
+                if let Some(components) = unsafe { Q::fetch_mut(self.world, entity) } {
+                    return Some((entity, components));
+                }
+            }
         }
+        None
+    }
+}
+
+/// Trait for component queries that may borrow components mutably
+pub trait QueryMut<'a>: Sized {
+    /// Type of the query result
+    type Item;
+
+    /// Panic if the query would borrow one component type twice mutably
+    fn check_access() {}
+
+    /// Fetch components for an entity
+    ///
+    /// # Safety
+    ///
+    /// `world` must be exclusively borrowed for `'a`, and no other results
+    /// for `entity` may be alive.
+    unsafe fn fetch_mut(world: *mut World, entity: Entity) -> Option<Self::Item>;
+}
+
+impl<'a, A> QueryMut<'a> for &'a A
+where
+    A: Component,
+{
+    type Item = &'a A;
+
+    unsafe fn fetch_mut(world: *mut World, entity: Entity) -> Option<Self::Item> {
+        (*world).get_component::<A>(entity)
+    }
+}
+
+impl<'a, A> QueryMut<'a> for &'a mut A
+where
+    A: Component,
+{
+    type Item = &'a mut A;
+
+    unsafe fn fetch_mut(world: *mut World, entity: Entity) -> Option<Self::Item> {
+        (*world).get_component_mut::<A>(entity)
+    }
+}
+
+impl<'a, A, B> QueryMut<'a> for (&'a mut A, &'a B)
+where
+    A: Component,
+    B: Component,
+{
+    type Item = (&'a mut A, &'a B);
+
+    fn check_access() {
+        assert_ne!(
+            A::component_id(),
+            B::component_id(),
+            "query borrows a component mutably and immutably"
+        );
+    }
+
+    unsafe fn fetch_mut(world: *mut World, entity: Entity) -> Option<Self::Item> {
+        // Both are looked up before handing out the mutable borrow
+        let b = (*world).get_component::<B>(entity)? as *const B;
+        let a = (*world).get_component_mut::<A>(entity)?;
+        Some((a, &*b))
+    }
+}
+
+impl<'a, A, B> QueryMut<'a> for (&'a mut A, &'a mut B)
+where
+    A: Component,
+    B: Component,
+{
+    type Item = (&'a mut A, &'a mut B);
+
+    fn check_access() {
+        assert_ne!(
+            A::component_id(),
+            B::component_id(),
+            "query borrows a component mutably twice"
+        );
+    }
+
+    unsafe fn fetch_mut(world: *mut World, entity: Entity) -> Option<Self::Item> {
+        let b = (*world).get_component_mut::<B>(entity)? as *mut B;
+        let a = (*world).get_component_mut::<A>(entity)?;
+        Some((a, &mut *b))
     }
 }
 
@@ -150,7 +242,6 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ecs::component::Component;
 
     #[derive(Debug, Default)]
     struct Position(f32, f32, f32);
@@ -171,5 +262,8 @@ mod tests {
 
         let results: Vec<_> = query.collect();
         assert_eq!(results.len(), 1);
+        let (_, (position, velocity)) = &results[0];
+        assert_eq!((position.0, position.1, position.2), (0.0, 0.0, 0.0));
+        assert_eq!((velocity.0, velocity.1, velocity.2), (1.0, 1.0, 1.0));
     }
 }
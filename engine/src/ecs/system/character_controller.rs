@@ -0,0 +1,144 @@
+//! Character controller system
+//!
+//! Moves entities with a `CharacterControllerComponent` through the CPU
+//! physics world using capsule move-and-slide queries.
+
+use std::sync::{Arc, Mutex};
+
+use super::{System, SystemStage};
+use crate::ecs::component::{CharacterControllerComponent, TransformComponent};
+use crate::ecs::World;
+use crate::physics::{PhysicsWorld, QueryFilter};
+
+/// System that moves kinematic characters against a `PhysicsWorld`
+pub struct CharacterControllerSystem {
+    physics_world: Arc<Mutex<PhysicsWorld>>,
+    delta_time: f32,
+}
+
+impl CharacterControllerSystem {
+    /// Create a new character controller system
+    pub fn new(physics_world: Arc<Mutex<PhysicsWorld>>) -> Self {
+        Self {
+            physics_world,
+            delta_time: Self::config().fixed_timestep.unwrap_or(1.0 / 60.0),
+        }
+    }
+
+    /// Configure the system
+    pub fn config() -> super::SystemConfig {
+        super::SystemConfig {
+            stage: SystemStage::Early, // Move characters alongside physics
+            enabled: true,
+            fixed_timestep: Some(1.0 / 60.0),
+        }
+    }
+}
+
+impl System for CharacterControllerSystem {
+    fn update(&mut self, world: &mut World) {
+        let physics = match self.physics_world.lock() {
+            Ok(physics) => physics,
+            Err(e) => {
+                log::error!("Physics world lock poisoned: {:?}", e);
+                return;
+            }
+        };
+
+        for (_entity, (transform, character)) in
+            world.query_mut::<(&mut TransformComponent, &mut CharacterControllerComponent)>()
+        {
+            if !character.enabled {
+                continue;
+            }
+
+            let motion = character.step_motion(physics.gravity, self.delta_time);
+            let result = physics.move_character(
+                &character.controller,
+                transform.position,
+                motion,
+                &QueryFilter::default(),
+            );
+
+            transform.set_position(result.position);
+            character.apply_move(&result);
+        }
+    }
+
+    fn initialize(&mut self, _world: &mut World) {
+        log::info!("Initializing character controller system");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::PhysicsObject;
+    use glam::{Mat3, Quat, Vec3, Vec4};
+
+    #[test]
+    fn test_character_controller_config() {
+        let config = CharacterControllerSystem::config();
+        assert_eq!(config.stage, SystemStage::Early);
+        assert_eq!(config.fixed_timestep, Some(1.0 / 60.0));
+    }
+
+    #[test]
+    fn test_characters_land_and_walk() {
+        // Floor with its top face at y = 0
+        let mut physics_world = PhysicsWorld::new(Vec3::new(0.0, -9.81, 0.0));
+        physics_world.add_object(PhysicsObject::RigidBody {
+            position: Vec3::new(0.0, -50.0, 0.0),
+            velocity: Vec3::ZERO,
+            acceleration: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            angular_velocity: Vec3::ZERO,
+            angular_acceleration: Vec3::ZERO,
+            mass: 0.0,
+            inertia_tensor: Mat3::ZERO,
+            bounding_box: Vec4::new(0.0, 0.0, 0.0, 50.0),
+        });
+        physics_world.sync_broad_phase();
+
+        let mut system = CharacterControllerSystem::new(Arc::new(Mutex::new(physics_world)));
+        let mut world = World::new();
+        let character = world
+            .build_entity()
+            .with(TransformComponent::new(
+                Vec3::new(0.0, 1.0, 0.0),
+                Quat::IDENTITY,
+                Vec3::ONE,
+            ))
+            .with(CharacterControllerComponent::default())
+            .build();
+
+        for _ in 0..60 {
+            system.update(&mut world);
+        }
+
+        let component = world
+            .get_component::<CharacterControllerComponent>(character)
+            .unwrap();
+        let standing_height = component.controller.foot_offset() + component.controller.skin_width;
+        assert!(component.grounded);
+        assert_eq!(component.ground_body, Some(0));
+        let transform = world
+            .get_component::<TransformComponent>(character)
+            .unwrap();
+        assert!((transform.position.y - standing_height).abs() < 1e-2);
+
+        world
+            .get_component_mut::<CharacterControllerComponent>(character)
+            .unwrap()
+            .set_move_velocity(Vec3::new(1.0, 0.0, 0.0));
+        for _ in 0..60 {
+            system.update(&mut world);
+        }
+
+        let transform = world
+            .get_component::<TransformComponent>(character)
+            .unwrap();
+        assert!((transform.position.x - 1.0).abs() < 1e-2, "{:?}", transform);
+        assert!((transform.position.y - standing_height).abs() < 1e-2);
+    }
+}
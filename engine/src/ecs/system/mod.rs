@@ -14,7 +14,10 @@ use std::any::TypeId;
 pub struct SystemId(TypeId);
 
 /// Trait for implementing systems
-pub trait System: 'static + Send + Sync {
+///
+/// Systems aren't required to be `Send` or `Sync`, since the render system
+/// holds a renderer whose Vulkan structs are neither.
+pub trait System: 'static {
     /// Get the system's unique identifier
     fn system_id(&self) -> SystemId {
         SystemId(TypeId::of::<Self>())
@@ -31,10 +34,14 @@ pub trait System: 'static + Send + Sync {
 }
 
 // System implementations
+mod character_controller;
 mod physics_bridge;
+mod physics_world_bridge;
 mod render_system;
 
+pub use character_controller::CharacterControllerSystem;
 pub use physics_bridge::PhysicsBridgeSystem;
+pub use physics_world_bridge::PhysicsWorldBridgeSystem;
 pub use render_system::RenderSystem;
 
 /// System execution stage
@@ -69,9 +76,22 @@ impl Default for SystemConfig {
     }
 }
 
+/// Most fixed-timestep steps a system runs in one update
+///
+/// Time beyond this is dropped so a long stall doesn't leave the scheduler
+/// catching up over many frames.
+pub const MAX_FIXED_STEPS_PER_UPDATE: u32 = 8;
+
 /// System scheduler for managing system execution
 pub struct SystemScheduler {
-    systems: Vec<(Box<dyn System>, SystemConfig)>,
+    systems: Vec<ScheduledSystem>,
+}
+
+/// A system with its configuration and unsimulated fixed-timestep time
+struct ScheduledSystem {
+    system: Box<dyn System>,
+    config: SystemConfig,
+    accumulator: f32,
 }
 
 impl SystemScheduler {
@@ -84,22 +104,37 @@ impl SystemScheduler {
 
     /// Add a system with configuration
     pub fn add_system<S: System + 'static>(&mut self, system: S, config: SystemConfig) {
-        self.systems.push((Box::new(system), config));
+        self.systems.push(ScheduledSystem {
+            system: Box::new(system),
+            config,
+            accumulator: 0.0,
+        });
         // Sort systems by stage to ensure correct execution order
-        self.systems.sort_by_key(|(_, config)| config.stage);
+        self.systems.sort_by_key(|scheduled| scheduled.config.stage);
     }
 
     /// Update all systems
     pub fn update(&mut self, world: &mut World, delta_time: f32) {
-        for (system, config) in self.systems.iter_mut() {
-            if config.enabled {
-                match config.fixed_timestep {
-                    Some(step) => {
-                        // TODO: Implement fixed timestep logic
-                        system.update(world);
+        for scheduled in self.systems.iter_mut() {
+            if !scheduled.config.enabled {
+                continue;
+            }
+            match scheduled.config.fixed_timestep {
+                // Run as many whole steps as fit into the elapsed time, up
+                // to the catch-up cap
+                Some(step) => {
+                    scheduled.accumulator += delta_time;
+                    let mut steps = 0;
+                    while scheduled.accumulator >= step && steps < MAX_FIXED_STEPS_PER_UPDATE {
+                        scheduled.system.update(world);
+                        scheduled.accumulator -= step;
+                        steps += 1;
+                    }
+                    if steps == MAX_FIXED_STEPS_PER_UPDATE {
+                        scheduled.accumulator %= step;
                     }
-                    None => system.update(world),
                 }
+                None => scheduled.system.update(world),
             }
         }
     }
@@ -110,3 +145,56 @@ impl Default for SystemScheduler {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct CountingSystem(Rc<Cell<u32>>);
+
+    impl System for CountingSystem {
+        fn update(&mut self, _world: &mut World) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    fn fixed(step: f32) -> SystemConfig {
+        SystemConfig {
+            fixed_timestep: Some(step),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fixed_timestep_accumulates() {
+        let runs = Rc::new(Cell::new(0));
+        let mut scheduler = SystemScheduler::new();
+        scheduler.add_system(CountingSystem(runs.clone()), fixed(0.25));
+        let mut world = World::new();
+
+        scheduler.update(&mut world, 0.1);
+        assert_eq!(runs.get(), 0);
+        scheduler.update(&mut world, 0.6);
+        assert_eq!(runs.get(), 2);
+        scheduler.update(&mut world, 0.1);
+        assert_eq!(runs.get(), 3);
+    }
+
+    #[test]
+    fn test_fixed_timestep_caps_catch_up() {
+        let runs = Rc::new(Cell::new(0));
+        let mut scheduler = SystemScheduler::new();
+        scheduler.add_system(CountingSystem(runs.clone()), fixed(0.25));
+        let mut world = World::new();
+
+        // A ten second stall runs the capped number of steps only
+        scheduler.update(&mut world, 10.0);
+        assert_eq!(runs.get(), MAX_FIXED_STEPS_PER_UPDATE);
+
+        // The dropped time isn't made up on the next frame
+        scheduler.update(&mut world, 0.25);
+        assert_eq!(runs.get(), MAX_FIXED_STEPS_PER_UPDATE + 1);
+    }
+}
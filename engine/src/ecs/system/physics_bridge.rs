@@ -1,30 +1,43 @@
-//! Bridge system between ECS and GPU physics
+//! Bridge system between ECS and particle physics
 //!
-//! This system synchronizes physics components with the GPU physics system,
-//! handling data transfer and state updates.
+//! This system synchronizes physics components with a particle backend,
+//! either the GPU physics system or its CPU reference, handling data transfer
+//! and state updates.
+//!
+//! The bridge uploads the whole particle buffer every frame, so emitters
+//! should run on their own `GpuPhysicsSystem` rather than the bridged one.
 
 use glam::Vec3;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use super::{System, SystemStage};
 use crate::ecs::component::{PhysicsComponent, TransformComponent};
-use crate::ecs::World;
-use crate::physics::{GpuPhysicsSystem, Particle, PhysicsError};
+use crate::ecs::{Entity, World};
+use crate::physics::{Particle, ParticleBackend, PhysicsError};
 
-/// System that bridges ECS physics components with the GPU physics system
+/// System that bridges ECS physics components with a particle backend
 pub struct PhysicsBridgeSystem {
-    physics_system: Arc<GpuPhysicsSystem>,
+    physics_system: Arc<Mutex<dyn ParticleBackend>>,
     particles: Vec<Particle>,
+    /// Entity owning each uploaded particle, in upload order
+    entities: Vec<Entity>,
     frame_count: u32,
+    delta_time: f32,
 }
 
 impl PhysicsBridgeSystem {
     /// Create a new physics bridge system
-    pub fn new(physics_system: Arc<GpuPhysicsSystem>) -> Self {
+    ///
+    /// Takes a `GpuPhysicsSystem` or a `CpuParticleBackend` behind a mutex,
+    /// e.g. `Arc::new(Mutex::new(CpuParticleBackend::new(1024)))`.
+    pub fn new(physics_system: Arc<Mutex<dyn ParticleBackend>>) -> Self {
         Self {
             physics_system,
             particles: Vec::new(),
+            entities: Vec::new(),
             frame_count: 0,
+            delta_time: Self::config().fixed_timestep.unwrap_or(1.0 / 60.0),
         }
     }
 
@@ -37,15 +50,19 @@ impl PhysicsBridgeSystem {
         }
     }
 
-    /// Synchronize physics data to GPU
-    fn sync_to_gpu(&mut self) -> Result<(), PhysicsError> {
-        self.physics_system.update_particles(&self.particles)?;
-        Ok(())
-    }
-
-    /// Synchronize physics data from GPU
-    fn sync_from_gpu(&mut self) -> Result<(), PhysicsError> {
-        self.particles = self.physics_system.get_particle_data()?;
+    /// Upload the particles, step the simulation and read the results back
+    fn simulate(&mut self) -> Result<(), PhysicsError> {
+        let mut physics_system =
+            self.physics_system
+                .lock()
+                .map_err(|e| PhysicsError::SynchronizationError {
+                    message: format!("Physics system lock poisoned: {}", e),
+                    source: None,
+                })?;
+
+        physics_system.update_particles(&self.particles)?;
+        physics_system.step(self.delta_time)?;
+        self.particles = physics_system.get_particle_data()?;
         Ok(())
     }
 }
@@ -54,37 +71,33 @@ impl System for PhysicsBridgeSystem {
     fn update(&mut self, world: &mut World) {
         // Clear previous frame's data
         self.particles.clear();
+        self.entities.clear();
 
         // Collect physics data from components
-        for (entity, (transform, physics)) in
+        for (entity, (_transform, physics)) in
             world.query_mut::<(&mut TransformComponent, &mut PhysicsComponent)>()
         {
             if physics.enabled {
-                // Update physics component with new data
+                // Refresh particle data from the last written-back state
+                physics.update_particle_data().ok();
                 if let Some(particle_data) = physics.particle_data() {
-                    self.particles.push(particle_data.clone());
-                } else {
-                    // Initialize particle data if not present
-                    physics.update_particle_data().ok();
-                    if let Some(particle_data) = physics.particle_data() {
-                        self.particles.push(particle_data.clone());
-                    }
+                    self.particles.push(*particle_data);
+                    self.entities.push(entity);
                 }
             }
         }
 
-        // Sync with GPU physics system
-        if let Err(e) = self.sync_to_gpu() {
-            log::error!("Failed to sync physics data to GPU: {:?}", e);
-            return;
-        }
-
-        // Run physics simulation
-        self.physics_system.step();
-
-        // Get updated physics data
-        if let Err(e) = self.sync_from_gpu() {
-            log::error!("Failed to sync physics data from GPU: {:?}", e);
+        // Particles are packed, so map each entity to its slot in the upload
+        let slots: HashMap<Entity, usize> = self
+            .entities
+            .iter()
+            .enumerate()
+            .map(|(slot, &entity)| (entity, slot))
+            .collect();
+
+        // Run the simulation on the backend
+        if let Err(e) = self.simulate() {
+            log::error!("Failed to simulate physics: {:?}", e);
             return;
         }
 
@@ -93,14 +106,18 @@ impl System for PhysicsBridgeSystem {
             world.query_mut::<(&mut TransformComponent, &mut PhysicsComponent)>()
         {
             if physics.enabled {
-                if let Some(particle) = self.particles.get(entity.index()) {
+                let particle = slots
+                    .get(&entity)
+                    .and_then(|&slot| self.particles.get(slot));
+                if let Some(particle) = particle {
+                    let position = Vec3::from(particle.position);
+
                     // Update transform
-                    transform.set_position(particle.position);
+                    transform.set_position(position);
 
                     // Update physics component
-                    physics.position = particle.position;
-                    physics.velocity = particle.velocity;
-                    physics.acceleration = particle.acceleration;
+                    physics.position = position;
+                    physics.velocity = Vec3::from(particle.velocity);
                 }
             }
         }
@@ -109,13 +126,18 @@ impl System for PhysicsBridgeSystem {
     }
 
     fn initialize(&mut self, _world: &mut World) {
-        // Initialize GPU physics resources
-        log::info!("Initializing physics bridge system");
+        if let Ok(physics_system) = self.physics_system.lock() {
+            log::info!(
+                "Initializing physics bridge system on the {} backend",
+                physics_system.name()
+            );
+        }
     }
 
     fn cleanup(&mut self, _world: &mut World) {
         // Clean up physics resources
         self.particles.clear();
+        self.entities.clear();
     }
 }
 
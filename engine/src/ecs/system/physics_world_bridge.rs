@@ -0,0 +1,319 @@
+//! Bridge system between ECS and the CPU physics world
+//!
+//! Keeps one `PhysicsWorld` rigid body per entity with a `PhysicsComponent`
+//! and a `TransformComponent`. Bodies are created and removed as components
+//! come and go, and state flows both ways:
+//!
+//! - Static entities are teleported to their transform every frame.
+//! - Kinematic entities follow their transform with a velocity derived from
+//!   the movement, so dynamic bodies they touch get pushed correctly.
+//! - Dynamic entities are simulated and write position, rotation and
+//!   velocities back to their components.
+
+use glam::{Quat, Vec3};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use super::{System, SystemStage};
+use crate::ecs::component::{PhysicsComponent, TransformComponent};
+use crate::ecs::{Entity, World};
+use crate::physics::{PhysicsObject, PhysicsWorld};
+
+/// System that bridges ECS physics components with a CPU `PhysicsWorld`
+pub struct PhysicsWorldBridgeSystem {
+    physics_world: Arc<Mutex<PhysicsWorld>>,
+    bodies: HashMap<Entity, usize>,
+    delta_time: f32,
+}
+
+impl PhysicsWorldBridgeSystem {
+    /// Create a new physics world bridge system
+    pub fn new(physics_world: Arc<Mutex<PhysicsWorld>>) -> Self {
+        Self {
+            physics_world,
+            bodies: HashMap::new(),
+            delta_time: Self::config().fixed_timestep.unwrap_or(1.0 / 60.0),
+        }
+    }
+
+    /// Configure the system
+    pub fn config() -> super::SystemConfig {
+        super::SystemConfig {
+            stage: SystemStage::Early, // Run before other systems
+            enabled: true,
+            fixed_timestep: Some(1.0 / 60.0), // Physics runs at fixed timestep
+        }
+    }
+
+    /// Physics world body backing `entity`, stable for the entity's lifetime
+    pub fn body(&self, entity: Entity) -> Option<usize> {
+        self.bodies.get(&entity).copied()
+    }
+
+    /// Write the component state into its body before the step
+    fn push_state(
+        body: &mut PhysicsObject,
+        transform: &TransformComponent,
+        physics: &mut PhysicsComponent,
+        delta_time: f32,
+    ) {
+        let PhysicsObject::RigidBody {
+            position,
+            velocity,
+            acceleration,
+            orientation,
+            angular_velocity,
+            mass,
+            inertia_tensor,
+            bounding_box,
+            ..
+        } = body
+        else {
+            return;
+        };
+
+        *mass = physics.body_mass();
+        *inertia_tensor = physics.body_inertia();
+        *bounding_box = physics.bounding_box;
+
+        if physics.is_static {
+            *position = transform.position;
+            *orientation = transform.rotation;
+            *velocity = Vec3::ZERO;
+            *angular_velocity = Vec3::ZERO;
+        } else if physics.is_kinematic {
+            // Reach the transform by the end of the step
+            *velocity = (transform.position - *position) / delta_time;
+            *angular_velocity = rotation_velocity(*orientation, transform.rotation, delta_time);
+        } else {
+            *position = transform.position;
+            *orientation = transform.rotation;
+            *velocity = physics.velocity;
+            *angular_velocity = physics.angular_velocity;
+            *acceleration += physics.acceleration;
+            physics.acceleration = Vec3::ZERO;
+        }
+    }
+
+    /// Read the simulated state back after the step
+    fn pull_state(
+        body: &PhysicsObject,
+        transform: &mut TransformComponent,
+        physics: &mut PhysicsComponent,
+    ) {
+        let PhysicsObject::RigidBody {
+            position,
+            velocity,
+            orientation,
+            angular_velocity,
+            ..
+        } = body
+        else {
+            return;
+        };
+
+        if physics.is_dynamic() {
+            transform.set_position(*position);
+            transform.set_rotation(*orientation);
+        }
+        physics.position = *position;
+        physics.velocity = *velocity;
+        physics.angular_velocity = *angular_velocity;
+    }
+}
+
+/// Angular velocity that turns `from` into `to` over `delta_time`
+fn rotation_velocity(from: Quat, to: Quat, delta_time: f32) -> Vec3 {
+    let mut delta = to * from.conjugate();
+    // Take the short way around
+    if delta.w < 0.0 {
+        delta = -delta;
+    }
+    let (axis, angle) = delta.to_axis_angle();
+    if angle <= f32::EPSILON {
+        return Vec3::ZERO;
+    }
+    axis * (angle / delta_time)
+}
+
+impl System for PhysicsWorldBridgeSystem {
+    fn update(&mut self, world: &mut World) {
+        let mut physics_world = match self.physics_world.lock() {
+            Ok(physics_world) => physics_world,
+            Err(e) => {
+                log::error!("Physics world lock poisoned: {:?}", e);
+                return;
+            }
+        };
+
+        // Create new bodies and push component state into existing ones
+        let mut seen = HashSet::new();
+        for (entity, (transform, physics)) in
+            world.query_mut::<(&mut TransformComponent, &mut PhysicsComponent)>()
+        {
+            if !physics.enabled {
+                continue;
+            }
+            seen.insert(entity);
+
+            match self.bodies.get(&entity) {
+                Some(&index) => Self::push_state(
+                    &mut physics_world.objects[index].borrow_mut(),
+                    transform,
+                    physics,
+                    self.delta_time,
+                ),
+                None => {
+                    let body = physics.to_rigid_body(transform.position, transform.rotation);
+                    physics.acceleration = Vec3::ZERO;
+                    let index = physics_world.add_object(body);
+                    self.bodies.insert(entity, index);
+                }
+            }
+        }
+
+        // Remove bodies whose entity, component or transform went away
+        self.bodies.retain(|entity, index| {
+            let keep = seen.contains(entity);
+            if !keep {
+                physics_world.remove_object(*index);
+            }
+            keep
+        });
+
+        physics_world.update(self.delta_time);
+
+        for (entity, (transform, physics)) in
+            world.query_mut::<(&mut TransformComponent, &mut PhysicsComponent)>()
+        {
+            if let Some(&index) = self.bodies.get(&entity) {
+                Self::pull_state(&physics_world.objects[index].borrow(), transform, physics);
+            }
+        }
+    }
+
+    fn initialize(&mut self, _world: &mut World) {
+        log::info!("Initializing physics world bridge system");
+    }
+
+    fn cleanup(&mut self, _world: &mut World) {
+        if let Ok(mut physics_world) = self.physics_world.lock() {
+            for (_, index) in self.bodies.drain() {
+                physics_world.remove_object(index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::{Vec2, Vec3Swizzles};
+
+    #[test]
+    fn test_physics_world_bridge_config() {
+        let config = PhysicsWorldBridgeSystem::config();
+        assert_eq!(config.stage, SystemStage::Early);
+        assert_eq!(config.fixed_timestep, Some(1.0 / 60.0));
+    }
+
+    #[test]
+    fn test_rotation_velocity() {
+        let from = Quat::IDENTITY;
+        let to = Quat::from_rotation_y(0.1);
+        let omega = rotation_velocity(from, to, 0.5);
+        assert!((omega - Vec3::new(0.0, 0.2, 0.0)).length() < 1e-4);
+        assert_eq!(rotation_velocity(to, to, 0.5), Vec3::ZERO);
+    }
+
+    fn transform(position: Vec3) -> TransformComponent {
+        TransformComponent::new(position, Quat::IDENTITY, Vec3::ONE)
+    }
+
+    #[test]
+    fn test_bodies_follow_component_lifecycle() {
+        let physics_world = Arc::new(Mutex::new(PhysicsWorld::new(Vec3::ZERO)));
+        let mut bridge = PhysicsWorldBridgeSystem::new(physics_world.clone());
+        let mut world = World::new();
+
+        let fixed = world
+            .build_entity()
+            .with(transform(Vec3::new(0.0, -5.0, 0.0)))
+            .with(PhysicsComponent::new(Vec3::ZERO, 1.0, 0.5).with_static(true))
+            .build();
+        let mut spinning = PhysicsComponent::new(Vec3::ZERO, 1.0, 0.5).with_velocity(Vec3::X);
+        spinning.angular_velocity = Vec3::Y;
+        let dynamic = world
+            .build_entity()
+            .with(transform(Vec3::ZERO))
+            .with(spinning)
+            .build();
+        let kinematic = world
+            .build_entity()
+            .with(transform(Vec3::new(10.0, 0.0, 0.0)))
+            .with(PhysicsComponent::new(Vec3::ZERO, 1.0, 0.5).with_kinematic(true))
+            .build();
+
+        for _ in 0..60 {
+            // Gameplay code moves the kinematic body through its transform
+            let transform = world
+                .get_component_mut::<TransformComponent>(kinematic)
+                .unwrap();
+            transform.set_position(transform.position + Vec3::Z * 0.01);
+            bridge.update(&mut world);
+        }
+
+        let body_state = |entity| match &*physics_world.lock().unwrap().objects
+            [bridge.body(entity).unwrap()]
+        .borrow()
+        {
+            PhysicsObject::RigidBody {
+                position,
+                orientation,
+                velocity,
+                angular_velocity,
+                ..
+            } => (*position, *orientation, *velocity, *angular_velocity),
+            _ => unreachable!(),
+        };
+
+        // Dynamic state is simulated and written back, rotation included
+        let (position, orientation, velocity, angular_velocity) = body_state(dynamic);
+        assert!(
+            position.x > 0.5 && position.yz() == Vec2::ZERO,
+            "{:?}",
+            position
+        );
+        assert!(orientation.angle_between(Quat::IDENTITY) > 0.1);
+        let moved = world.get_component::<TransformComponent>(dynamic).unwrap();
+        assert_eq!((moved.position, moved.rotation), (position, orientation));
+        let physics = world.get_component::<PhysicsComponent>(dynamic).unwrap();
+        assert_eq!(physics.position, position);
+        assert_eq!(
+            (physics.velocity, physics.angular_velocity),
+            (velocity, angular_velocity)
+        );
+
+        // Static and kinematic bodies follow their transforms
+        assert_eq!(body_state(fixed).0, Vec3::new(0.0, -5.0, 0.0));
+        let target = world
+            .get_component::<TransformComponent>(kinematic)
+            .unwrap()
+            .position;
+        assert!((target - Vec3::new(10.0, 0.0, 0.6)).length() < 1e-4);
+        assert!((body_state(kinematic).0 - target).length() < 1e-4);
+
+        // Dropping the component or the entity removes the body
+        let bodies = [fixed, dynamic].map(|entity| bridge.body(entity).unwrap());
+        world.remove_component::<PhysicsComponent>(dynamic);
+        world.delete_entity(fixed);
+        bridge.update(&mut world);
+        assert_eq!(bridge.body(dynamic), None);
+        assert_eq!(bridge.body(fixed), None);
+        assert!(bridge.body(kinematic).is_some());
+        let physics_world = physics_world.lock().unwrap();
+        assert!(bodies
+            .iter()
+            .all(|&body| !physics_world.contains_object(body)));
+    }
+}
@@ -3,7 +3,9 @@
 //! Handles collection of renderable entities from ECS and interfaces directly
 //! with the graphics system for efficient rendering.
 
-use std::collections::{HashMap, HashSet};
+use glam::{Mat4, Vec3};
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use crate::{
@@ -11,48 +13,47 @@ use crate::{
         component::{RenderComponent, TransformComponent},
         System, SystemStage, World,
     },
-    error::Result,
     graphics::{
-        render::{PassType, RenderGraph},
+        command::{CommandBatch, RenderCommand, RenderOperation},
+        render::PassType,
         resource::{ResourceHandle, ResourceManager},
         Renderer,
     },
 };
 
+/// Material and the meshes drawn with it, with their render layers
+type MaterialBatch = (Option<ResourceHandle>, Vec<(ResourceHandle, i32)>);
+
 /// Batched render data for each pass type
 #[derive(Default)]
 struct PassBatches {
-    /// Entities by material for optimal state changes
-    render_batches: Vec<(
-        ResourceHandle, // Material
-        Vec<(TransformComponent, RenderComponent)>,
-    )>,
-    /// Dirty resources that need updating
-    dirty_resources: HashSet<ResourceHandle>,
+    /// Meshes by material for optimal state changes
+    render_batches: Vec<MaterialBatch>,
 }
 
 /// Unified render system that handles both ECS integration and graphics rendering
 pub struct RenderSystem {
-    renderer: Arc<Renderer>,
+    renderer: Arc<Mutex<Renderer>>,
     resource_manager: Arc<ResourceManager>,
-    render_graph: Arc<RenderGraph>,
     batches: HashMap<PassType, PassBatches>,
+    commands: CommandBatch,
     frustum_culling: bool,
+    view_projection: Option<Mat4>,
 }
 
 impl RenderSystem {
     /// Create a new render system
-    pub fn new(
-        renderer: Arc<Renderer>,
-        resource_manager: Arc<ResourceManager>,
-        render_graph: Arc<RenderGraph>,
-    ) -> Self {
+    ///
+    /// The renderer owns the render graph its commands are recorded into, so
+    /// no separate graph is passed in.
+    pub fn new(renderer: Arc<Mutex<Renderer>>, resource_manager: Arc<ResourceManager>) -> Self {
         Self {
             renderer,
             resource_manager,
-            render_graph,
             batches: HashMap::new(),
+            commands: CommandBatch::new(),
             frustum_culling: true,
+            view_projection: None,
         }
     }
 
@@ -70,133 +71,157 @@ impl RenderSystem {
         self.frustum_culling = enable;
     }
 
+    /// Set the camera view-projection matrix used for frustum culling
+    ///
+    /// Nothing is culled until a matrix has been set.
+    pub fn set_view_projection(&mut self, view_projection: Mat4) {
+        self.view_projection = Some(view_projection);
+    }
+
+    /// Whether an entity at `position` falls outside the camera frustum
+    fn is_culled(&self, position: Vec3) -> bool {
+        match self.view_projection {
+            Some(view_projection) if self.frustum_culling => {
+                !in_frustum(&view_projection, position)
+            }
+            _ => false,
+        }
+    }
+
     /// Clear all batches
     fn clear_batches(&mut self) {
         self.batches.clear();
+        self.commands.clear();
     }
 
     /// Add an entity to the appropriate render batch
     fn add_to_batch(
-        batches: &mut Vec<(ResourceHandle, Vec<(TransformComponent, RenderComponent)>)>,
+        batches: &mut Vec<MaterialBatch>,
         material: Option<ResourceHandle>,
-        transform: TransformComponent,
-        renderer: RenderComponent,
+        mesh: ResourceHandle,
+        layer: i32,
     ) {
-        let material_handle = material.unwrap_or_else(ResourceHandle::default);
-
         // Find existing batch or create new one
-        if let Some(batch) = batches.iter_mut().find(|(mat, _)| *mat == material_handle) {
-            batch.1.push((transform, renderer));
+        if let Some(batch) = batches.iter_mut().find(|(mat, _)| *mat == material) {
+            batch.1.push((mesh, layer));
         } else {
-            batches.push((material_handle, vec![(transform, renderer)]));
+            batches.push((material, vec![(mesh, layer)]));
         }
     }
 
-    /// Sort batches by material and render order
-    fn sort_batches(&mut self) {
-        for batches in self.batches.values_mut() {
-            batches.render_batches.sort_by_key(|(material, entities)| {
-                (
-                    *material, // First by material for minimal state changes
-                    entities.first().map(|(_, r)| r.sort_key()).unwrap_or(0), // Then by render order
-                )
-            });
-        }
-    }
-
-    /// Update transform resources and record draw commands for a render pass
-    fn process_pass_batches(&self, pass_type: PassType) -> Result<()> {
-        if let Some(pass_batches) = self.batches.get(&pass_type) {
-            // Begin the render pass
-            self.render_graph.begin_pass(pass_type)?;
-
-            // Process each material batch
-            for (material, entities) in &pass_batches.render_batches {
-                // Bind material resources
-                if *material != ResourceHandle::default() {
-                    self.renderer.bind_material(*material)?;
-                }
-
-                // Process each entity
-                for (transform, renderer) in entities {
-                    // Update transform if needed
-                    if pass_batches
-                        .dirty_resources
-                        .contains(&renderer.transform_buffer())
-                    {
-                        if let Some(matrix) = transform.matrix() {
-                            self.renderer.update_buffer(
-                                renderer.transform_buffer(),
-                                &matrix.to_cols_array(),
-                                0,
-                            )?;
-                        }
-                    }
-
-                    // Draw the mesh
-                    self.renderer.draw_mesh(renderer.mesh(), 1)?;
-                }
+    /// Collect and batch renderable entities, updating dirty transform buffers
+    fn collect_renderables(&mut self, world: &mut World) {
+        for (_, (transform, renderer)) in
+            world.query_mut::<(&mut TransformComponent, &RenderComponent)>()
+        {
+            if !renderer.should_render()
+                || (renderer.culling_enabled() && self.is_culled(transform.position))
+            {
+                continue;
             }
 
-            // End the render pass
-            self.render_graph.end_pass()?;
-        }
-
-        Ok(())
-    }
-
-    /// Collect and batch renderable entities
-    fn collect_renderables(&mut self, world: &World) {
-        for (_, (transform, renderer)) in world.query::<(&TransformComponent, &RenderComponent)>() {
-            if !renderer.should_render() || (self.frustum_culling && !renderer.culling_enabled()) {
+            // Skip meshes that were never loaded or have been destroyed
+            if self.resource_manager.get_mesh(renderer.mesh()).is_none() {
                 continue;
             }
 
-            // Get or create pass batches
-            let pass_batches = self
-                .batches
-                .entry(renderer.pass_type())
-                .or_insert_with(PassBatches::default);
+            if transform.is_dirty() {
+                let matrix = transform.matrix();
+                self.commands.add(RenderCommand {
+                    pass_type: renderer.pass_type(),
+                    operation: RenderOperation::UpdateBuffer {
+                        buffer: renderer.transform_buffer(),
+                        data: bytemuck::cast_slice(&matrix.to_cols_array()).to_vec(),
+                        offset: 0,
+                    },
+                    sort_key: renderer.sort_key(),
+                });
+            }
 
-            // Add to appropriate batch
+            let pass_batches = self.batches.entry(renderer.pass_type()).or_default();
             Self::add_to_batch(
                 &mut pass_batches.render_batches,
                 renderer.material(),
-                transform.clone(),
-                renderer.clone(),
+                renderer.mesh(),
+                renderer.sort_key(),
             );
+        }
+    }
 
-            // Mark transform buffer as dirty if needed
-            if transform.is_dirty() {
-                pass_batches
-                    .dirty_resources
-                    .insert(renderer.transform_buffer());
+    /// Record draw commands for every batch
+    fn record_batches(&mut self) {
+        for (&pass_type, pass_batches) in &self.batches {
+            for (material, meshes) in &pass_batches.render_batches {
+                for &(mesh, layer) in meshes {
+                    self.commands.add(RenderCommand {
+                        pass_type,
+                        operation: RenderOperation::Draw {
+                            mesh,
+                            material: *material,
+                            instance_count: 1,
+                        },
+                        sort_key: layer,
+                    });
+                }
             }
         }
+
+        // Passes in execution order, then render layers. The sort is stable,
+        // so draws sharing a material stay together within a layer.
+        self.commands.sort();
     }
 }
 
+/// Check whether a world-space point lies inside the clip volume of a
+/// view-projection matrix
+fn in_frustum(view_projection: &Mat4, point: Vec3) -> bool {
+    let clip = *view_projection * point.extend(1.0);
+    clip.w > 0.0
+        && clip.x.abs() <= clip.w
+        && clip.y.abs() <= clip.w
+        && (0.0..=clip.w).contains(&clip.z)
+}
+
 impl System for RenderSystem {
-    fn update(&mut self, world: &mut World) -> Result<()> {
+    fn update(&mut self, world: &mut World) {
         // Clear previous frame's batches
         self.clear_batches();
 
         // Collect renderable entities
         self.collect_renderables(world);
 
-        // Sort batches for optimal rendering
-        self.sort_batches();
-
-        // Process each pass type in order
-        for pass_type in &[
-            PassType::Geometry,
-            PassType::Lighting,
-            PassType::PostProcess,
-            PassType::UI,
-        ] {
-            self.process_pass_batches(*pass_type)?;
+        // Batch draws per pass and material
+        self.record_batches();
+
+        if let Err(e) = self.renderer.lock().submit_commands(&self.commands) {
+            log::error!("Failed to submit render commands: {:?}", e);
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_system_config() {
+        let config = RenderSystem::config();
+        assert_eq!(config.stage, SystemStage::Late);
+        assert_eq!(config.fixed_timestep, None);
+    }
 
-        Ok(())
+    #[test]
+    fn test_in_frustum() {
+        let view = Mat4::look_at_rh(Vec3::new(0.0, 0.0, 5.0), Vec3::ZERO, Vec3::Y);
+        let projection = Mat4::perspective_rh(60f32.to_radians(), 1.0, 0.1, 100.0);
+        let view_projection = projection * view;
+
+        assert!(in_frustum(&view_projection, Vec3::ZERO));
+        // Behind the camera
+        assert!(!in_frustum(&view_projection, Vec3::new(0.0, 0.0, 10.0)));
+        // Beyond the far plane
+        assert!(!in_frustum(&view_projection, Vec3::new(0.0, 0.0, -200.0)));
+        // Off to the side
+        assert!(!in_frustum(&view_projection, Vec3::new(50.0, 0.0, 0.0)));
     }
 }
@@ -3,11 +3,12 @@
 //! The World struct is the main container for the ECS, managing entities,
 //! components, and providing query functionality.
 
-use std::any::{Any, TypeId};
+use std::any::Any;
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 use super::component::{Component, ComponentId};
+use super::query::{Query, QueryBuilder, QueryIter, QueryMut, QueryMutIter};
 use super::system::{System, SystemScheduler};
 
 /// Entity identifier
@@ -25,16 +26,33 @@ impl Entity {
     }
 }
 
-/// Storage for a single component type
-struct ComponentStorage {
-    data: Box<dyn Any>,
-    removed: Vec<usize>,
+/// Type-erased storage for a single component type, indexed by entity index
+trait ErasedStorage: Any {
+    fn remove_index(&mut self, index: usize);
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Component> ErasedStorage for Vec<Option<T>> {
+    fn remove_index(&mut self, index: usize) {
+        if let Some(slot) = self.get_mut(index) {
+            *slot = None;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
 }
 
 /// World containing all entities and components
 pub struct World {
     entities: Vec<Option<Entity>>,
-    components: HashMap<ComponentId, ComponentStorage>,
+    components: HashMap<ComponentId, Box<dyn ErasedStorage>>,
     next_entity_id: AtomicUsize,
     scheduler: SystemScheduler,
 }
@@ -80,64 +98,89 @@ impl World {
         entity
     }
 
-    /// Add a component to an entity
+    /// Create a new entity and add components to it with a builder
+    pub fn build_entity(&mut self) -> EntityBuilder<'_> {
+        let entity = self.create_entity();
+        EntityBuilder {
+            world: self,
+            entity,
+        }
+    }
+
+    /// Check whether an entity has not been deleted
+    pub fn is_alive(&self, entity: Entity) -> bool {
+        self.entities.get(entity.index).copied().flatten() == Some(entity)
+    }
+
+    /// Entity slots, `None` for deleted entities
+    pub fn entities(&self) -> &[Option<Entity>] {
+        &self.entities
+    }
+
+    /// Add a component to an entity, replacing any existing one
     pub fn add_component<T: Component>(&mut self, entity: Entity, component: T) {
-        let component_id = T::component_id();
+        if !self.is_alive(entity) {
+            return;
+        }
 
-        let storage = self
+        let components = self
             .components
-            .entry(component_id)
-            .or_insert_with(|| ComponentStorage {
-                data: Box::new(Vec::<T>::new()),
-                removed: Vec::new(),
-            });
-
-        let components = storage.data.downcast_mut::<Vec<T>>().unwrap();
+            .entry(T::component_id())
+            .or_insert_with(|| Box::new(Vec::<Option<T>>::new()))
+            .as_any_mut()
+            .downcast_mut::<Vec<Option<T>>>()
+            .unwrap();
 
         if entity.index >= components.len() {
-            components.resize_with(entity.index + 1, Default::default);
+            components.resize_with(entity.index + 1, || None);
         }
-        components[entity.index] = component;
+        components[entity.index] = Some(component);
     }
 
     /// Get a reference to a component
     pub fn get_component<T: Component>(&self, entity: Entity) -> Option<&T> {
+        if !self.is_alive(entity) {
+            return None;
+        }
         let storage = self.components.get(&T::component_id())?;
-        let components = storage.data.downcast_ref::<Vec<T>>().unwrap();
-        components.get(entity.index)
+        let components = storage.as_any().downcast_ref::<Vec<Option<T>>>().unwrap();
+        components.get(entity.index)?.as_ref()
     }
 
     /// Get a mutable reference to a component
     pub fn get_component_mut<T: Component>(&mut self, entity: Entity) -> Option<&mut T> {
+        if !self.is_alive(entity) {
+            return None;
+        }
         let storage = self.components.get_mut(&T::component_id())?;
-        let components = storage.data.downcast_mut::<Vec<T>>().unwrap();
-        components.get_mut(entity.index)
+        let components = storage
+            .as_any_mut()
+            .downcast_mut::<Vec<Option<T>>>()
+            .unwrap();
+        components.get_mut(entity.index)?.as_mut()
     }
 
     /// Remove a component from an entity
     pub fn remove_component<T: Component>(&mut self, entity: Entity) -> Option<T> {
-        let storage = self.components.get_mut(&T::component_id())?;
-        let components = storage.data.downcast_mut::<Vec<T>>().unwrap();
-        if entity.index < components.len() {
-            storage.removed.push(entity.index);
-            Some(std::mem::take(&mut components[entity.index]))
-        } else {
-            None
+        if !self.is_alive(entity) {
+            return None;
         }
+        let storage = self.components.get_mut(&T::component_id())?;
+        let components = storage
+            .as_any_mut()
+            .downcast_mut::<Vec<Option<T>>>()
+            .unwrap();
+        components.get_mut(entity.index)?.take()
     }
 
     /// Delete an entity and all its components
     pub fn delete_entity(&mut self, entity: Entity) {
-        if let Some(stored_entity) = self.entities.get_mut(entity.index) {
-            if let Some(e) = stored_entity {
-                if e.id == entity.id {
-                    *stored_entity = None;
-                    // Remove components
-                    for storage in self.components.values_mut() {
-                        storage.removed.push(entity.index);
-                    }
-                }
-            }
+        if !self.is_alive(entity) {
+            return;
+        }
+        self.entities[entity.index] = None;
+        for storage in self.components.values_mut() {
+            storage.remove_index(entity.index);
         }
     }
 
@@ -152,12 +195,20 @@ impl World {
 
     /// Update all systems
     pub fn update(&mut self, delta_time: f32) {
-        self.scheduler.update(self, delta_time);
+        // Systems get the world mutably, so the scheduler steps out of it
+        let mut scheduler = std::mem::take(&mut self.scheduler);
+        scheduler.update(self, delta_time);
+        self.scheduler = scheduler;
+    }
+
+    /// Query for components of every entity that has all of them
+    pub fn query<'a, Q: Query<'a>>(&'a self) -> QueryIter<'a, Q> {
+        QueryBuilder::new(self).build()
     }
 
-    /// Query for components
-    pub fn query<'a, Q: Query<'a>>(&'a mut self) -> QueryIter<'a, Q> {
-        Q::create_query(self)
+    /// Query for components, some of them mutably
+    pub fn query_mut<'a, Q: QueryMut<'a>>(&'a mut self) -> QueryMutIter<'a, Q> {
+        QueryMutIter::new(self)
     }
 
     // Helper methods
@@ -166,36 +217,22 @@ impl World {
     }
 }
 
-/// Trait for component queries
-pub trait Query<'a> {
-    type Item;
-
-    fn create_query(world: &'a mut World) -> QueryIter<'a, Self>
-    where
-        Self: Sized;
-}
-
-/// Iterator for query results
-pub struct QueryIter<'a, Q: Query<'a>> {
+/// Builder for creating an entity together with its components
+pub struct EntityBuilder<'a> {
     world: &'a mut World,
-    current: usize,
-    _phantom: std::marker::PhantomData<Q>,
+    entity: Entity,
 }
 
-impl<'a, Q: Query<'a>> Iterator for QueryIter<'a, Q> {
-    type Item = Q::Item;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        while self.current < self.world.entities.len() {
-            let index = self.current;
-            self.current += 1;
+impl EntityBuilder<'_> {
+    /// Add a component to the entity
+    pub fn with<T: Component>(self, component: T) -> Self {
+        self.world.add_component(self.entity, component);
+        self
+    }
 
-            if let Some(entity) = self.world.entities[index] {
-                // Query implementation would check for components here
-                // and return the requested tuple
-            }
-        }
-        None
+    /// Finish building and return the entity
+    pub fn build(self) -> Entity {
+        self.entity
     }
 }
 
@@ -211,4 +248,42 @@ mod tests {
         assert_eq!(entity.generation, 0);
         assert_eq!(entity.index, 0);
     }
+
+    #[derive(Debug, PartialEq)]
+    struct Health(u32);
+    impl Component for Health {}
+
+    #[derive(Debug, PartialEq)]
+    struct Armor(u32);
+    impl Component for Armor {}
+
+    #[test]
+    fn test_deleted_entity_slot_starts_empty() {
+        let mut world = World::new();
+        let entity = world.build_entity().with(Health(10)).build();
+        world.delete_entity(entity);
+        assert!(!world.is_alive(entity));
+        assert_eq!(world.get_component::<Health>(entity), None);
+
+        // The slot is reused without the old components or handle
+        let reused = world.create_entity();
+        assert_eq!(reused.index(), entity.index());
+        assert_eq!(world.get_component::<Health>(reused), None);
+        world.add_component(entity, Health(5));
+        assert_eq!(world.get_component::<Health>(reused), None);
+    }
+
+    #[test]
+    fn test_query_mut() {
+        let mut world = World::new();
+        let a = world.build_entity().with(Health(10)).with(Armor(2)).build();
+        let b = world.build_entity().with(Health(10)).build();
+
+        for (_, (health, armor)) in world.query_mut::<(&mut Health, &Armor)>() {
+            health.0 -= armor.0;
+        }
+        assert_eq!(world.get_component::<Health>(a), Some(&Health(8)));
+        assert_eq!(world.get_component::<Health>(b), Some(&Health(10)));
+        assert_eq!(world.query::<&Health>().count(), 2);
+    }
 }
@@ -1,4 +1,4 @@
-use ash::LoadingError;
+use ash::{vk, LoadingError};
 use std::ffi::NulError;
 use thiserror::Error;
 
@@ -124,6 +124,12 @@ impl From<LoadingError> for VulkanError {
     }
 }
 
+impl From<vk::Result> for VulkanError {
+    fn from(e: vk::Result) -> Self {
+        VulkanError::General(e.to_string())
+    }
+}
+
 impl From<winit::error::OsError> for VulkanError {
     fn from(e: winit::error::OsError) -> Self {
         VulkanError::WindowError(e.to_string())
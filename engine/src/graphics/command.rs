@@ -28,6 +28,11 @@ pub enum RenderOperation {
         data: Vec<u8>,
         offset: u64,
     },
+    /// Non-indexed draw straight from a vertex buffer
+    DrawVertices {
+        buffer: ResourceHandle,
+        vertex_count: u32,
+    },
     SetPipeline(ResourceHandle),
     BindMaterial(ResourceHandle),
 }
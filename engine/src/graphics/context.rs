@@ -17,6 +17,7 @@ pub struct Context {
     swapchain_loader: Arc<ash::extensions::khr::Swapchain>,
     queue_family_index: u32,
     graphics_queue: vk::Queue,
+    transfer_queue: Option<(u32, vk::Queue)>,
 }
 
 impl Context {
@@ -36,7 +37,7 @@ impl Context {
 
         let mut instance_extensions = vec![ash::extensions::khr::Surface::name().as_ptr()];
 
-        if let Some(window) = window {
+        if window.is_some() {
             #[cfg(target_os = "windows")]
             {
                 instance_extensions.push(ash::extensions::khr::Win32Surface::name().as_ptr());
@@ -108,30 +109,44 @@ impl Context {
             .map(|(i, _)| i as u32)
             .ok_or(VulkanError::NoSuitableGpu)?;
 
+        // A transfer-only family usually maps to the DMA engines, uploads
+        // on it run alongside rendering
+        let transfer_family_index = queue_families
+            .iter()
+            .enumerate()
+            .find(|(_, props)| {
+                props.queue_flags.contains(vk::QueueFlags::TRANSFER)
+                    && !props
+                        .queue_flags
+                        .intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+            })
+            .map(|(i, _)| i as u32);
+
         // Create logical device
-        let queue_create_info = vk::DeviceQueueCreateInfo::builder()
+        let queue_priorities = [1.0];
+        let mut queue_create_infos = vec![vk::DeviceQueueCreateInfo::builder()
             .queue_family_index(queue_family_index)
-            .queue_priorities(&[1.0]);
+            .queue_priorities(&queue_priorities)
+            .build()];
+        if let Some(index) = transfer_family_index {
+            queue_create_infos.push(
+                vk::DeviceQueueCreateInfo::builder()
+                    .queue_family_index(index)
+                    .queue_priorities(&queue_priorities)
+                    .build(),
+            );
+        }
 
         let mut device_extensions = vec![ash::extensions::khr::Swapchain::name().as_ptr()];
-        device_extensions.push(ash::extensions::khr::ShaderNonSemanticInfo::name().as_ptr());
+        // Lets shaders use debugPrintfEXT, the extension has no features
+        device_extensions.push(vk::KhrShaderNonSemanticInfoFn::name().as_ptr());
 
-        // Enable the DebugPrintf feature
-        let mut shader_non_semantic_info_features =
-            vk::PhysicalDeviceShaderNonSemanticInfoFeaturesKHR::builder()
-                .shader_debug_printf(true)
-                .build();
-
-        let mut device_features = vk::PhysicalDeviceFeatures2::builder()
-            .features(vk::PhysicalDeviceFeatures::default())
-            .push_next(&mut shader_non_semantic_info_features) // Chain the feature struct
-            .build();
+        let device_features = vk::PhysicalDeviceFeatures::default();
 
         let device_create_info = vk::DeviceCreateInfo::builder()
-            .queue_create_infos(std::slice::from_ref(&queue_create_info))
-            .enabled_features(&device_features.features) // Pass features, not the struct itself
+            .queue_create_infos(&queue_create_infos)
+            .enabled_features(&device_features)
             .enabled_extension_names(&device_extensions)
-            .push_next(&mut device_features) // Chain the features struct for 1.1+ compatibility
             .build();
 
         let device = unsafe {
@@ -143,6 +158,8 @@ impl Context {
         };
 
         let graphics_queue = unsafe { device.get_device_queue(queue_family_index, 0) };
+        let transfer_queue = transfer_family_index
+            .map(|index| (index, unsafe { device.get_device_queue(index, 0) }));
         let swapchain_loader = Arc::new(ash::extensions::khr::Swapchain::new(&instance, &device));
 
         Ok(Self {
@@ -155,6 +172,7 @@ impl Context {
             swapchain_loader,
             queue_family_index,
             graphics_queue,
+            transfer_queue,
         })
     }
 
@@ -178,6 +196,12 @@ impl Context {
         self.graphics_queue
     }
 
+    /// Family index and queue of a dedicated transfer queue, if the device
+    /// has one
+    pub fn transfer_queue(&self) -> Option<(u32, vk::Queue)> {
+        self.transfer_queue
+    }
+
     pub fn instance(&self) -> Arc<Instance> {
         self.instance.clone()
     }
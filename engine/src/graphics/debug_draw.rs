@@ -0,0 +1,412 @@
+//! Immediate-mode debug drawing
+//!
+//! Shapes are pushed every frame as colored line segments in world space and
+//! drawn in `PassType::Debug` with a line list pipeline. All vertices are
+//! generated on the CPU, so what gets drawn can be checked without a device.
+//! Text labels stay anchored in world space until they are projected for the
+//! text renderer.
+
+use glam::{Mat4, Quat, Vec3};
+use memoffset::offset_of;
+
+use super::command::{CommandBatch, RenderCommand, RenderOperation};
+use super::render::{
+    pipeline::{VertexAttributeDesc, VertexBindingDesc, VertexInputRate},
+    PassType,
+};
+use super::resource::ResourceHandle;
+use crate::text::{ndc_to_pixel, TextElement};
+use ash::vk;
+
+/// Segments per circle of a wireframe sphere
+const DEFAULT_SPHERE_SEGMENTS: u32 = 16;
+/// Vertices per frame, lines past it are dropped
+const DEFAULT_MAX_VERTICES: usize = 128 * 1024;
+
+/// Corner pairs of the 12 edges of a box, corners indexed by their sign bits
+const BOX_EDGES: [(usize, usize); 12] = [
+    (0, 1),
+    (2, 3),
+    (4, 5),
+    (6, 7),
+    (0, 2),
+    (1, 3),
+    (4, 6),
+    (5, 7),
+    (0, 4),
+    (1, 5),
+    (2, 6),
+    (3, 7),
+];
+
+/// Vertex of a debug line
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DebugVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 4],
+}
+
+impl DebugVertex {
+    pub fn vertex_binding() -> VertexBindingDesc {
+        VertexBindingDesc {
+            binding: 0,
+            stride: std::mem::size_of::<DebugVertex>() as u32,
+            input_rate: VertexInputRate::Vertex,
+        }
+    }
+
+    pub fn vertex_attributes() -> [VertexAttributeDesc; 2] {
+        [
+            VertexAttributeDesc {
+                location: 0,
+                binding: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: offset_of!(DebugVertex, position) as u32,
+            },
+            VertexAttributeDesc {
+                location: 1,
+                binding: 0,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: offset_of!(DebugVertex, color) as u32,
+            },
+        ]
+    }
+}
+
+/// Text label anchored at a world-space position
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugText {
+    pub position: Vec3,
+    pub text: String,
+    pub color: [f32; 4],
+}
+
+/// Collects debug shapes for one frame
+#[derive(Debug)]
+pub struct DebugDraw {
+    vertices: Vec<DebugVertex>,
+    texts: Vec<DebugText>,
+    max_vertices: usize,
+    sphere_segments: u32,
+    dropped_lines: usize,
+    enabled: bool,
+}
+
+impl Default for DebugDraw {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DebugDraw {
+    /// Create an enabled debug drawer with the default vertex budget
+    pub fn new() -> Self {
+        Self {
+            vertices: Vec::new(),
+            texts: Vec::new(),
+            max_vertices: DEFAULT_MAX_VERTICES,
+            sphere_segments: DEFAULT_SPHERE_SEGMENTS,
+            dropped_lines: 0,
+            enabled: true,
+        }
+    }
+
+    /// Limit the vertices per frame, typically to the vertex buffer's size
+    pub fn with_max_vertices(mut self, max_vertices: usize) -> Self {
+        self.max_vertices = max_vertices;
+        self
+    }
+
+    /// Set how round spheres are drawn
+    pub fn with_sphere_segments(mut self, segments: u32) -> Self {
+        self.sphere_segments = segments.max(3);
+        self
+    }
+
+    /// Disabled drawers ignore every shape pushed to them
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Line segment from `start` to `end`
+    pub fn line(&mut self, start: Vec3, end: Vec3, color: [f32; 4]) {
+        if !self.enabled {
+            return;
+        }
+        if self.vertices.len() + 2 > self.max_vertices {
+            self.dropped_lines += 1;
+            return;
+        }
+        self.vertices.extend([
+            DebugVertex {
+                position: start.into(),
+                color,
+            },
+            DebugVertex {
+                position: end.into(),
+                color,
+            },
+        ]);
+    }
+
+    /// Axis-aligned box between two corners
+    pub fn aabb(&mut self, min: Vec3, max: Vec3, color: [f32; 4]) {
+        let center = (min + max) * 0.5;
+        self.oriented_box(center, (max - min) * 0.5, Quat::IDENTITY, color);
+    }
+
+    /// Box rotated about its center
+    pub fn oriented_box(
+        &mut self,
+        center: Vec3,
+        half_extents: Vec3,
+        orientation: Quat,
+        color: [f32; 4],
+    ) {
+        let corners: [Vec3; 8] = std::array::from_fn(|i| {
+            let sign = Vec3::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { -1.0 } else { 1.0 },
+            );
+            center + orientation * (half_extents * sign)
+        });
+        for (a, b) in BOX_EDGES {
+            self.line(corners[a], corners[b], color);
+        }
+    }
+
+    /// Wireframe sphere made of one circle around each axis
+    pub fn sphere(&mut self, center: Vec3, radius: f32, color: [f32; 4]) {
+        for (u, v) in [(Vec3::X, Vec3::Y), (Vec3::Y, Vec3::Z), (Vec3::Z, Vec3::X)] {
+            self.circle(center, u * radius, v * radius, color);
+        }
+    }
+
+    /// Line from `start` to `end` with a four-pronged head at `end`
+    pub fn arrow(&mut self, start: Vec3, end: Vec3, color: [f32; 4]) {
+        self.line(start, end, color);
+
+        let shaft = end - start;
+        let length = shaft.length();
+        if length <= f32::EPSILON {
+            return;
+        }
+        let direction = shaft / length;
+        let head = length * 0.2;
+        let (side, up) = direction.any_orthonormal_pair();
+        for prong in [side, -side, up, -up] {
+            self.line(end, end - direction * head + prong * head * 0.5, color);
+        }
+    }
+
+    /// Small three-axis cross marking a point
+    pub fn point(&mut self, position: Vec3, size: f32, color: [f32; 4]) {
+        for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            let offset = axis * size * 0.5;
+            self.line(position - offset, position + offset, color);
+        }
+    }
+
+    /// Text label at a world-space position
+    pub fn text_3d(&mut self, position: Vec3, text: impl Into<String>, color: [f32; 4]) {
+        if !self.enabled {
+            return;
+        }
+        self.texts.push(DebugText {
+            position,
+            text: text.into(),
+            color,
+        });
+    }
+
+    fn circle(&mut self, center: Vec3, u: Vec3, v: Vec3, color: [f32; 4]) {
+        let step = std::f32::consts::TAU / self.sphere_segments as f32;
+        let point = |i: u32| {
+            let angle = i as f32 * step;
+            center + u * angle.cos() + v * angle.sin()
+        };
+        for i in 0..self.sphere_segments {
+            self.line(point(i), point(i + 1), color);
+        }
+    }
+
+    /// Line list vertices pushed this frame
+    pub fn vertices(&self) -> &[DebugVertex] {
+        &self.vertices
+    }
+
+    pub fn texts(&self) -> &[DebugText] {
+        &self.texts
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.vertices.len() / 2
+    }
+
+    /// Lines dropped this frame for exceeding the vertex budget
+    pub fn dropped_lines(&self) -> usize {
+        self.dropped_lines
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vertices.is_empty() && self.texts.is_empty()
+    }
+
+    /// Forget this frame's shapes, call once they were recorded
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+        self.texts.clear();
+        self.dropped_lines = 0;
+    }
+
+    /// Queue the frame's lines for `PassType::Debug`, uploading them to
+    /// `vertex_buffer` first. The buffer must hold `max_vertices` vertices.
+    pub fn record(&self, batch: &mut CommandBatch, vertex_buffer: ResourceHandle) {
+        if self.vertices.is_empty() {
+            return;
+        }
+        batch.add(RenderCommand {
+            pass_type: PassType::Debug,
+            operation: RenderOperation::UpdateBuffer {
+                buffer: vertex_buffer,
+                data: bytemuck::cast_slice(&self.vertices).to_vec(),
+                offset: 0,
+            },
+            sort_key: 0,
+        });
+        batch.add(RenderCommand {
+            pass_type: PassType::Debug,
+            operation: RenderOperation::DrawVertices {
+                buffer: vertex_buffer,
+                vertex_count: self.vertices.len() as u32,
+            },
+            sort_key: 1,
+        });
+    }
+
+    /// Project the labels to pixel positions for the text renderer. Labels
+    /// behind the camera or off screen are left out.
+    pub fn text_elements(&self, view_projection: Mat4, viewport: [f32; 2]) -> Vec<TextElement> {
+        self.texts
+            .iter()
+            .enumerate()
+            .filter_map(|(index, label)| {
+                let clip = view_projection * label.position.extend(1.0);
+                if clip.w <= 0.0 {
+                    return None;
+                }
+                let ndc = clip.truncate() / clip.w;
+                if ndc.x.abs() > 1.0 || ndc.y.abs() > 1.0 {
+                    return None;
+                }
+                Some(TextElement {
+                    text: label.text.clone(),
+                    position: ndc_to_pixel(ndc.x, ndc.y, viewport[0], viewport[1]),
+                    color: label.color,
+                    scale: 1.0,
+                    element_id: index as u32,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [f32; 4] = [1.0; 4];
+
+    fn positions(draw: &DebugDraw) -> Vec<Vec3> {
+        draw.vertices()
+            .iter()
+            .map(|v| Vec3::from(v.position))
+            .collect()
+    }
+
+    #[test]
+    fn test_shape_vertices() {
+        let mut draw = DebugDraw::new().with_sphere_segments(8);
+        draw.aabb(Vec3::new(-1.0, 0.0, 2.0), Vec3::new(1.0, 4.0, 3.0), WHITE);
+        assert_eq!(draw.line_count(), 12);
+        // Every corner is shared by three edges
+        for corner in [Vec3::new(-1.0, 0.0, 2.0), Vec3::new(1.0, 4.0, 3.0)] {
+            let uses = positions(&draw)
+                .iter()
+                .filter(|p| p.distance(corner) < 1e-6)
+                .count();
+            assert_eq!(uses, 3);
+        }
+
+        draw.clear();
+        draw.sphere(Vec3::new(0.0, 1.0, 0.0), 2.0, WHITE);
+        assert_eq!(draw.line_count(), 24);
+        assert!(positions(&draw)
+            .iter()
+            .all(|p| (p.distance(Vec3::new(0.0, 1.0, 0.0)) - 2.0).abs() < 1e-5));
+
+        draw.clear();
+        draw.arrow(Vec3::ZERO, Vec3::new(0.0, 0.0, 5.0), WHITE);
+        assert_eq!(draw.line_count(), 5);
+        // The head prongs point back along the shaft
+        for prong in positions(&draw)[2..].chunks(2) {
+            assert_eq!(prong[0], Vec3::new(0.0, 0.0, 5.0));
+            assert!((prong[1].z - 4.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_budget_and_recording() {
+        let mut draw = DebugDraw::new().with_max_vertices(20);
+        draw.aabb(Vec3::ZERO, Vec3::ONE, WHITE);
+        assert_eq!(draw.line_count(), 10);
+        assert_eq!(draw.dropped_lines(), 2);
+
+        let mut batch = CommandBatch::new();
+        draw.record(&mut batch, ResourceHandle::new());
+        assert_eq!(batch.commands.len(), 2);
+        assert!(batch
+            .commands
+            .iter()
+            .all(|command| command.pass_type == PassType::Debug));
+        match &batch.commands[0].operation {
+            RenderOperation::UpdateBuffer { data, .. } => {
+                assert_eq!(data.len(), 20 * std::mem::size_of::<DebugVertex>())
+            }
+            other => panic!("expected a buffer update, got {:?}", other),
+        }
+        assert!(matches!(
+            batch.commands[1].operation,
+            RenderOperation::DrawVertices {
+                vertex_count: 20,
+                ..
+            }
+        ));
+
+        draw.set_enabled(false);
+        draw.clear();
+        draw.line(Vec3::ZERO, Vec3::ONE, WHITE);
+        assert!(draw.is_empty());
+    }
+
+    #[test]
+    fn test_text_projection() {
+        let mut draw = DebugDraw::new();
+        draw.text_3d(Vec3::new(0.0, 0.0, -5.0), "center", WHITE);
+        draw.text_3d(Vec3::new(0.0, 0.0, 5.0), "behind", WHITE);
+        draw.text_3d(Vec3::new(100.0, 0.0, -5.0), "off screen", WHITE);
+
+        let view_projection = Mat4::perspective_rh(1.0, 1.0, 0.1, 100.0);
+        let elements = draw.text_elements(view_projection, [800.0, 600.0]);
+        assert_eq!(elements.len(), 1);
+        assert_eq!(elements[0].text, "center");
+        assert_eq!(elements[0].position, [400.0, 300.0]);
+        assert_eq!(draw.line_count(), 0);
+    }
+}
@@ -1,29 +1,41 @@
 //! Graphics module containing all Vulkan-related implementation
 
-pub mod commands;
+pub mod command;
 pub mod context;
+pub mod debug_draw;
+// The pipeline manager in `pipeline/` is not wired up yet
+#[path = "pipeline.rs"]
 pub mod pipeline;
+pub mod render;
 pub mod render_pass;
 pub mod renderer;
+pub mod resource;
 pub mod shader;
 pub mod swapchain;
 
 // Re-exports for convenience
+pub use debug_draw::DebugDraw;
 pub use pipeline::Pipeline;
 pub use render_pass::RenderPass;
 pub use renderer::Renderer;
 pub use swapchain::Swapchain;
 
 // Helper functions module
+#[allow(dead_code)]
 pub(crate) mod utils {
+    use crate::memory::{MemoryAllocator, MemoryBlock, MemoryTag};
     use ash::vk;
 
+    // Buffer with memory from the shared allocator. Free the block through
+    // the allocator after destroying the buffer.
+    #[track_caller]
     pub fn create_buffer(
         device: &ash::Device,
+        allocator: &MemoryAllocator,
         size: vk::DeviceSize,
         usage: vk::BufferUsageFlags,
-        #[allow(unused_variables)] memory_properties: vk::MemoryPropertyFlags,
-    ) -> crate::Result<(vk::Buffer, vk::DeviceMemory)> {
+        memory_properties: vk::MemoryPropertyFlags,
+    ) -> crate::error::Result<(vk::Buffer, MemoryBlock)> {
         let buffer_info = vk::BufferCreateInfo::builder()
             .size(size)
             .usage(usage)
@@ -32,38 +44,40 @@ pub(crate) mod utils {
         let buffer = unsafe {
             device
                 .create_buffer(&buffer_info, None)
-                .map_err(|e| crate::VulkanError::BufferCreation(e.to_string()))?
+                .map_err(|e| crate::error::VulkanError::BufferCreation(e.to_string()))?
         };
 
         let mem_requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
 
-        let alloc_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(mem_requirements.size)
-            .memory_type_index(0);
-
-        let memory = unsafe {
-            device
-                .allocate_memory(&alloc_info, None)
-                .map_err(|e| crate::VulkanError::MemoryAllocation(e.to_string()))?
-        };
+        let block = allocator
+            .allocate(mem_requirements, memory_properties, MemoryTag::Graphics)
+            .map_err(|e| {
+                unsafe { device.destroy_buffer(buffer, None) };
+                crate::error::VulkanError::MemoryAllocation(e.to_string())
+            })?;
 
         unsafe {
-            device
-                .bind_buffer_memory(buffer, memory, 0)
-                .map_err(|e| crate::VulkanError::MemoryBinding(e.to_string()))?;
+            if let Err(e) = device.bind_buffer_memory(buffer, block.memory, block.offset) {
+                device.destroy_buffer(buffer, None);
+                let _ = allocator.free(block);
+                return Err(crate::error::VulkanError::MemoryBinding(e.to_string()));
+            }
         }
 
-        Ok((buffer, memory))
+        Ok((buffer, block))
     }
 
+    // Image with memory from the shared allocator, freed like `create_buffer`
+    #[track_caller]
     pub fn create_image(
         device: &ash::Device,
+        allocator: &MemoryAllocator,
         width: u32,
         height: u32,
         format: vk::Format,
         usage: vk::ImageUsageFlags,
-        #[allow(unused_variables)] memory_properties: vk::MemoryPropertyFlags,
-    ) -> crate::Result<(vk::Image, vk::DeviceMemory)> {
+        memory_properties: vk::MemoryPropertyFlags,
+    ) -> crate::error::Result<(vk::Image, MemoryBlock)> {
         let image_info = vk::ImageCreateInfo::builder()
             .image_type(vk::ImageType::TYPE_2D)
             .extent(vk::Extent3D {
@@ -83,36 +97,35 @@ pub(crate) mod utils {
         let image = unsafe {
             device
                 .create_image(&image_info, None)
-                .map_err(|e| crate::VulkanError::ImageCreation(e.to_string()))?
+                .map_err(|e| crate::error::VulkanError::ImageCreation(e.to_string()))?
         };
 
         let mem_requirements = unsafe { device.get_image_memory_requirements(image) };
 
-        let alloc_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(mem_requirements.size)
-            .memory_type_index(0);
-
-        let memory = unsafe {
-            device
-                .allocate_memory(&alloc_info, None)
-                .map_err(|e| crate::VulkanError::MemoryAllocation(e.to_string()))?
-        };
+        let block = allocator
+            .allocate(mem_requirements, memory_properties, MemoryTag::Graphics)
+            .map_err(|e| {
+                unsafe { device.destroy_image(image, None) };
+                crate::error::VulkanError::MemoryAllocation(e.to_string())
+            })?;
 
         unsafe {
-            device
-                .bind_image_memory(image, memory, 0)
-                .map_err(|e| crate::VulkanError::MemoryBinding(e.to_string()))?;
+            if let Err(e) = device.bind_image_memory(image, block.memory, block.offset) {
+                device.destroy_image(image, None);
+                let _ = allocator.free(block);
+                return Err(crate::error::VulkanError::MemoryBinding(e.to_string()));
+            }
         }
 
-        Ok((image, memory))
+        Ok((image, block))
     }
 
     pub fn create_shader_module(
         device: &ash::Device,
         code: &[u8],
-    ) -> crate::Result<vk::ShaderModule> {
-        if code.len() % 4 != 0 {
-            return Err(crate::VulkanError::ShaderCreation(
+    ) -> crate::error::Result<vk::ShaderModule> {
+        if !code.len().is_multiple_of(4) {
+            return Err(crate::error::VulkanError::ShaderCreation(
                 "Shader code length must be a multiple of 4".to_string(),
             ));
         }
@@ -129,7 +142,7 @@ pub(crate) mod utils {
         unsafe {
             device
                 .create_shader_module(&create_info, None)
-                .map_err(|e| crate::VulkanError::ShaderCreation(e.to_string()))
+                .map_err(|e| crate::error::VulkanError::ShaderCreation(e.to_string()))
         }
     }
 }
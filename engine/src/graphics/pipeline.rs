@@ -106,7 +106,7 @@ impl Pipeline {
 
         log::debug!("Creating graphics pipeline");
         let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
-            .stages(&shader_stages)
+            .stages(shader_stages)
             .vertex_input_state(&vertex_input_info)
             .input_assembly_state(&input_assembly_info)
             .viewport_state(&viewport_state)
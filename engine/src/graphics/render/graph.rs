@@ -3,13 +3,16 @@
 //! Provides a flexible system for defining render passes and their dependencies,
 //! with support for command-based execution and resource transitions.
 
+use super::pass::PassType;
 use crate::{
-    error::Result,
+    error::{Result, VulkanError},
     graphics::resource::{
-        Material, Mesh, Pipeline, ResourceHandle, ResourceManager, TextureFormat,
+        Material, Mesh, ResourceHandle, ResourceManager, TextureFormat, UploadToken, UploadUse,
     },
+    memory::TypedBuffer,
 };
 use ash::vk;
+use bytemuck::Pod;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
@@ -17,6 +20,38 @@ use std::sync::Arc;
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct PassId(usize);
 
+/// Kind and format of a pass attachment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentType {
+    Color { format: TextureFormat, clear: bool },
+    Depth { format: TextureFormat, clear: bool },
+}
+
+/// Description of a single attachment written or read by a pass
+#[derive(Debug, Clone)]
+pub struct AttachmentDesc {
+    pub ty: AttachmentType,
+    pub samples: vk::SampleCountFlags,
+    pub load_op: vk::AttachmentLoadOp,
+    pub store_op: vk::AttachmentStoreOp,
+    pub initial_layout: vk::ImageLayout,
+    pub final_layout: vk::ImageLayout,
+}
+
+/// Description of a pass added to the graph
+#[derive(Debug, Clone)]
+pub struct PassDesc {
+    pub name: String,
+    pub attachments: Vec<AttachmentDesc>,
+    /// Indices into `attachments`
+    pub color_attachments: Vec<usize>,
+    pub depth_attachment: Option<usize>,
+    /// Attachments of the passes this one depends on, read as inputs
+    pub input_attachments: Vec<usize>,
+    /// Passes that have to run before this one
+    pub dependencies: HashSet<PassId>,
+}
+
 /// Resource state tracking for synchronization
 #[derive(Debug, Clone, Copy)]
 pub struct ResourceState {
@@ -43,6 +78,7 @@ struct ResourceUsage {
 
 /// Resource dependency information
 #[derive(Debug)]
+#[allow(dead_code)] // Read once barriers are built from the dependencies
 struct ResourceDependency {
     source_pass: PassId,
     destination_pass: PassId,
@@ -61,6 +97,7 @@ struct PassDependency {
 pub struct RenderGraph {
     device: Arc<ash::Device>,
     resource_manager: Arc<ResourceManager>,
+    passes: Vec<PassDesc>,
     current_pass: Option<PassType>,
     pass_state: PassState,
     resource_states: HashMap<ResourceHandle, ResourceState>,
@@ -74,33 +111,21 @@ pub struct RenderGraph {
 
     // Resource tracking
     pass_dependencies: HashMap<PassId, PassDependency>,
+    #[allow(dead_code)]
     resource_lifetimes: HashMap<ResourceHandle, (PassId, PassId)>, // (first_use, last_use)
     current_pass_id: usize,
 }
 
 impl RenderGraph {
-    /// Create a new render graph
-    pub fn new(device: Arc<ash::Device>, resource_manager: Arc<ResourceManager>) -> Result<Self> {
+    /// Create a new render graph submitting to the given graphics queue
+    pub fn new(
+        device: Arc<ash::Device>,
+        resource_manager: Arc<ResourceManager>,
+        graphics_queue_family: u32,
+        graphics_queue: vk::Queue,
+    ) -> Result<Self> {
         const MAX_FRAMES_IN_FLIGHT: usize = 2;
 
-        // Find graphics queue family
-        let queue_family_properties = unsafe {
-            device.get_physical_device_queue_family_properties(
-                // TODO: Store physical device in RenderGraph or pass as parameter
-                vk::PhysicalDevice::null(),
-            )
-        };
-
-        let graphics_queue_family = queue_family_properties
-            .iter()
-            .enumerate()
-            .find(|(_, properties)| properties.queue_flags.contains(vk::QueueFlags::GRAPHICS))
-            .map(|(index, _)| index as u32)
-            .ok_or_else(|| vk::Result::ERROR_INITIALIZATION_FAILED)?;
-
-        // Get graphics queue
-        let graphics_queue = unsafe { device.get_device_queue(graphics_queue_family, 0) };
-
         // Create command pool
         let pool_info = vk::CommandPoolCreateInfo::builder()
             .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
@@ -143,6 +168,7 @@ impl RenderGraph {
         Ok(Self {
             device,
             resource_manager,
+            passes: Vec::new(),
             current_pass: None,
             pass_state: PassState {
                 current_pipeline: None,
@@ -163,6 +189,28 @@ impl RenderGraph {
         })
     }
 
+    /// Add a pass to the graph. Its dependencies have to be added first.
+    pub fn add_pass(&mut self, desc: PassDesc) -> Result<PassId> {
+        if let Some(missing) = desc
+            .dependencies
+            .iter()
+            .find(|dependency| dependency.0 >= self.passes.len())
+        {
+            return Err(VulkanError::ValidationError(format!(
+                "Pass {} depends on unknown pass {:?}",
+                desc.name, missing
+            )));
+        }
+
+        self.passes.push(desc);
+        Ok(PassId(self.passes.len() - 1))
+    }
+
+    /// Description of a pass added with `add_pass`
+    pub fn pass(&self, id: PassId) -> Option<&PassDesc> {
+        self.passes.get(id.0)
+    }
+
     /// Begin a render pass
     pub fn begin_pass(&mut self, pass_type: PassType) -> Result<()> {
         // End current pass if one is active
@@ -256,8 +304,13 @@ impl RenderGraph {
         Ok(())
     }
 
-    /// Bind a pipeline
-    pub fn bind_pipeline(&mut self, pipeline: vk::Pipeline) -> Result<()> {
+    /// Bind a pipeline, its layout is used for the descriptor sets bound
+    /// after it
+    pub fn bind_pipeline(
+        &mut self,
+        pipeline: vk::Pipeline,
+        layout: vk::PipelineLayout,
+    ) -> Result<()> {
         if let Some(command_buffer) = self.pass_state.command_buffer {
             if Some(pipeline) != self.pass_state.current_pipeline {
                 unsafe {
@@ -269,155 +322,114 @@ impl RenderGraph {
                 }
                 self.pass_state.current_pipeline = Some(pipeline);
             }
+            self.pass_state.current_layout = Some(layout);
         }
         Ok(())
     }
 
-    /// Bind a material's resources
+    /// Bind a material's resources with the layout of the bound pipeline
     pub fn bind_material(&mut self, material: &Material) -> Result<()> {
-        if let Some(command_buffer) = self.pass_state.command_buffer {
-            // Get descriptor sets from material
-            let descriptor_sets = material.descriptor_sets();
-            let pipeline_layout = material.pipeline_layout();
-
-            if descriptor_sets.is_empty() {
-                return Ok(());
-            }
-
-            // Bind descriptor sets
+        if let (Some(command_buffer), Some(pipeline_layout)) = (
+            self.pass_state.command_buffer,
+            self.pass_state.current_layout,
+        ) {
             unsafe {
                 self.device.cmd_bind_descriptor_sets(
                     command_buffer,
                     vk::PipelineBindPoint::GRAPHICS,
                     pipeline_layout,
                     0, // First set
-                    descriptor_sets,
+                    &[material.descriptor_set()],
                     &[], // No dynamic offsets
                 );
             }
-
-            // Update pipeline layout state
-            self.pass_state.current_layout = Some(pipeline_layout);
         }
         Ok(())
     }
 
     /// Draw a mesh
     pub fn draw_mesh(&mut self, mesh: &Mesh, instance_count: u32) -> Result<()> {
-        if let Some(command_buffer) = self.pass_state.command_buffer {
-            // Bind vertex buffers
-            let vertex_buffers = mesh.vertex_buffers();
-            let vertex_offsets = vec![0; vertex_buffers.len()];
-
-            unsafe {
-                self.device.cmd_bind_vertex_buffers(
-                    command_buffer,
-                    0, // First binding
-                    vertex_buffers,
-                    &vertex_offsets,
-                );
-            }
-
-            // Bind index buffer if mesh is indexed
-            if let Some(index_buffer) = mesh.index_buffer() {
-                unsafe {
-                    self.device.cmd_bind_index_buffer(
-                        command_buffer,
-                        index_buffer,
-                        0, // Offset
-                        mesh.index_type(),
-                    );
+        let Some(command_buffer) = self.pass_state.command_buffer else {
+            return Ok(());
+        };
+        let (Some(vertex_buffer), Some(index_buffer)) = (
+            self.resource_manager.get_buffer(mesh.vertex_buffer),
+            self.resource_manager.get_buffer(mesh.index_buffer),
+        ) else {
+            return Err(VulkanError::ValidationError(
+                "Mesh buffers have been destroyed".to_string(),
+            ));
+        };
 
-                    // Draw indexed
-                    self.device.cmd_draw_indexed(
-                        command_buffer,
-                        mesh.index_count(),
-                        instance_count,
-                        0, // First index
-                        0, // Vertex offset
-                        0, // First instance
-                    );
-                }
-            } else {
-                // Draw non-indexed
-                unsafe {
-                    self.device.cmd_draw(
-                        command_buffer,
-                        mesh.vertex_count(),
-                        instance_count,
-                        0, // First vertex
-                        0, // First instance
-                    );
-                }
-            }
+        unsafe {
+            self.device
+                .cmd_bind_vertex_buffers(command_buffer, 0, &[vertex_buffer], &[0]);
+            self.device.cmd_bind_index_buffer(
+                command_buffer,
+                index_buffer,
+                0,
+                vk::IndexType::UINT32,
+            );
+            self.device.cmd_draw_indexed(
+                command_buffer,
+                mesh.indices.len() as u32,
+                instance_count,
+                0, // First index
+                0, // Vertex offset
+                0, // First instance
+            );
         }
         Ok(())
     }
 
-    /// Update a buffer's contents
-    pub fn update_buffer(&mut self, buffer: vk::Buffer, data: &[u8], offset: u64) -> Result<()> {
+    /// Draw vertices straight from a vertex buffer
+    pub fn draw_vertices(&mut self, buffer: vk::Buffer, vertex_count: u32) -> Result<()> {
         if let Some(command_buffer) = self.pass_state.command_buffer {
-            let size = data.len() as u64;
-
-            // Create staging buffer
-            let staging_buffer_info = vk::BufferCreateInfo::builder()
-                .size(size)
-                .usage(vk::BufferUsageFlags::TRANSFER_SRC)
-                .sharing_mode(vk::SharingMode::EXCLUSIVE)
-                .build();
-
-            let staging_buffer = unsafe { self.device.create_buffer(&staging_buffer_info, None)? };
-
-            // Allocate and map staging memory
-            let memory_reqs = unsafe { self.device.get_buffer_memory_requirements(staging_buffer) };
-
-            let memory_info = vk::MemoryAllocateInfo::builder()
-                .allocation_size(memory_reqs.size)
-                .memory_type_index(0) // TODO: Find proper memory type
-                .build();
-
-            let staging_memory = unsafe { self.device.allocate_memory(&memory_info, None)? };
-
-            // Bind staging memory
             unsafe {
                 self.device
-                    .bind_buffer_memory(staging_buffer, staging_memory, 0)?;
-            }
-
-            // Copy data to staging buffer
-            unsafe {
-                let ptr =
-                    self.device
-                        .map_memory(staging_memory, 0, size, vk::MemoryMapFlags::empty())?
-                        as *mut u8;
-
-                std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
-
-                self.device.unmap_memory(staging_memory);
-            }
-
-            // Copy from staging to destination buffer
-            let copy_region = vk::BufferCopy::builder()
-                .src_offset(0)
-                .dst_offset(offset)
-                .size(size)
-                .build();
-
-            unsafe {
-                self.device
-                    .cmd_copy_buffer(command_buffer, staging_buffer, buffer, &[copy_region]);
-            }
-
-            // Clean up staging resources
-            unsafe {
-                self.device.destroy_buffer(staging_buffer, None);
-                self.device.free_memory(staging_memory, None);
+                    .cmd_bind_vertex_buffers(command_buffer, 0, &[buffer], &[0]);
+                self.device.cmd_draw(
+                    command_buffer,
+                    vertex_count,
+                    1, // Instance count
+                    0, // First vertex
+                    0, // First instance
+                );
             }
         }
         Ok(())
     }
 
+    /// Update a buffer's contents. The data is queued on the upload
+    /// manager and reaches the buffer once it is flushed, the buffer needs
+    /// `TRANSFER_DST` usage.
+    pub fn update_buffer(
+        &mut self,
+        buffer: vk::Buffer,
+        data: &[u8],
+        offset: u64,
+    ) -> Result<UploadToken> {
+        self.resource_manager
+            .uploads()
+            .upload_buffer(buffer, offset, data, UploadUse::ANY)
+    }
+
+    /// Update elements of a typed buffer, starting at `first`. Like
+    /// `update_buffer` the data reaches the buffer once the upload manager
+    /// is flushed.
+    pub fn update_typed_buffer<T: Pod>(
+        &mut self,
+        buffer: &TypedBuffer<T>,
+        first: usize,
+        data: &[T],
+    ) -> Result<UploadToken> {
+        self.resource_manager
+            .uploads()
+            .upload_typed(buffer, first, data)
+    }
+
     /// Register resource usage in the current pass
+    #[allow(dead_code)]
     fn register_resource_usage(
         &mut self,
         resource: ResourceHandle,
@@ -461,6 +473,7 @@ impl RenderGraph {
     }
 
     /// Track resource state changes and insert barriers
+    #[allow(dead_code)]
     fn transition_resource(
         &mut self,
         resource: ResourceHandle,
@@ -579,57 +592,6 @@ impl RenderGraph {
 
         Ok(())
     }
-
-    /// Track resource state changes and insert barriers
-    fn transition_resource(
-        &mut self,
-        resource: ResourceHandle,
-        new_state: ResourceState,
-    ) -> Result<()> {
-        if let Some(command_buffer) = self.pass_state.command_buffer {
-            if let Some(old_state) = self.resource_states.get(&resource) {
-                if old_state.layout != new_state.layout
-                    || old_state.access_mask != new_state.access_mask
-                    || old_state.stage_mask != new_state.stage_mask
-                {
-                    // Create image memory barrier
-                    let barrier = vk::ImageMemoryBarrier::builder()
-                        .old_layout(old_state.layout)
-                        .new_layout(new_state.layout)
-                        .src_access_mask(old_state.access_mask)
-                        .dst_access_mask(new_state.access_mask)
-                        .src_queue_family_index(self.graphics_queue_family)
-                        .dst_queue_family_index(self.graphics_queue_family)
-                        // TODO: Get actual image from resource manager
-                        .image(vk::Image::null())
-                        .subresource_range(
-                            vk::ImageSubresourceRange::builder()
-                                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                                .base_mip_level(0)
-                                .level_count(1)
-                                .base_array_layer(0)
-                                .layer_count(1)
-                                .build(),
-                        )
-                        .build();
-
-                    unsafe {
-                        self.device.cmd_pipeline_barrier(
-                            command_buffer,
-                            old_state.stage_mask,
-                            new_state.stage_mask,
-                            vk::DependencyFlags::empty(),
-                            &[],        // No memory barriers
-                            &[],        // No buffer memory barriers
-                            &[barrier], // Image memory barriers
-                        );
-                    }
-                }
-            }
-            self.resource_states.insert(resource, new_state);
-        }
-        Ok(())
-    }
 }
 
 impl Drop for RenderGraph {
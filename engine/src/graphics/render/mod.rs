@@ -11,10 +11,9 @@ pub mod pipeline;
 
 use crate::{
     error::Result,
-    graphics::resource::{ResourceHandle, ResourceManager, TextureFormat},
+    graphics::resource::{ResourceManager, TextureFormat},
 };
 use ash::vk;
-use std::collections::HashSet;
 use std::sync::Arc;
 
 pub use self::{
@@ -29,17 +28,6 @@ impl TextureFormat {
     pub const R16G16B16A16_SFLOAT: Self = Self::Custom(vk::Format::R16G16B16A16_SFLOAT);
     pub const R8G8B8A8_UNORM: Self = Self::Custom(vk::Format::R8G8B8A8_UNORM);
     pub const D32_SFLOAT: Self = Self::Custom(vk::Format::D32_SFLOAT);
-
-    pub(crate) fn to_vk_format(&self) -> vk::Format {
-        match self {
-            TextureFormat::R8G8B8A8Unorm => vk::Format::R8G8B8A8_UNORM,
-            TextureFormat::B8G8R8A8Unorm => vk::Format::B8G8R8A8_UNORM,
-            TextureFormat::R8G8B8Unorm => vk::Format::R8G8B8_UNORM,
-            TextureFormat::R8Unorm => vk::Format::R8_UNORM,
-            TextureFormat::Depth32Float => vk::Format::D32_SFLOAT,
-            TextureFormat::Custom(format) => *format,
-        }
-    }
 }
 
 /// Configuration for deferred rendering
@@ -52,21 +40,33 @@ pub struct DeferredConfig {
 
 /// Main render system managing all rendering operations
 pub struct RenderSystem {
+    #[allow(dead_code)]
     device: Arc<ash::Device>,
+    #[allow(dead_code)]
     resource_manager: Arc<ResourceManager>,
     graph: RenderGraph,
     pass_manager: PassManager,
 }
 
 impl RenderSystem {
-    /// Create a new render system
-    pub fn new(device: Arc<ash::Device>, resource_manager: Arc<ResourceManager>) -> Self {
-        Self {
-            graph: RenderGraph::new(device.clone(), resource_manager.clone()),
+    /// Create a new render system submitting to the given graphics queue
+    pub fn new(
+        device: Arc<ash::Device>,
+        resource_manager: Arc<ResourceManager>,
+        graphics_queue_family: u32,
+        graphics_queue: vk::Queue,
+    ) -> Result<Self> {
+        Ok(Self {
+            graph: RenderGraph::new(
+                device.clone(),
+                resource_manager.clone(),
+                graphics_queue_family,
+                graphics_queue,
+            )?,
             pass_manager: PassManager::new(device.clone(), resource_manager.clone()),
             device,
             resource_manager,
-        }
+        })
     }
 
     /// Initialize deferred rendering pipeline
@@ -110,13 +110,3 @@ impl RenderSystem {
         &self.pass_manager
     }
 }
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum TextureFormat {
-    R8G8B8A8Unorm,
-    B8G8R8A8Unorm,
-    R8G8B8Unorm,
-    R8Unorm,
-    Depth32Float,
-    Custom(vk::Format),
-}
@@ -7,19 +7,21 @@ use super::{
     graph::{AttachmentDesc, AttachmentType, PassDesc},
     pipeline::{DepthConfig, PipelineBuilder, RasterizationConfig},
 };
-use crate::{
-    error::Result,
-    graphics::resource::{ResourceHandle, ResourceManager, TextureFormat},
+use crate::graphics::{
+    debug_draw::DebugVertex,
+    resource::{ResourceManager, TextureFormat},
 };
 use ash::vk;
 use std::sync::Arc;
 
-/// Types of render passes supported by the system
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Types of render passes supported by the system, in execution order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum PassType {
     Geometry,
     Lighting,
     PostProcess,
+    /// Debug lines over the final image, see `graphics::debug_draw`
+    Debug,
     UI,
 }
 
@@ -104,7 +106,7 @@ impl PassManager {
         let mut depth_attachment = None;
 
         // Add color attachments
-        for (i, (format, clear_color)) in config
+        for (i, (format, _)) in config
             .color_formats
             .iter()
             .zip(config.clear_colors.iter())
@@ -123,6 +125,7 @@ impl PassManager {
                     PassType::Geometry => vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
                     PassType::Lighting => vk::ImageLayout::PRESENT_SRC_KHR,
                     PassType::PostProcess => vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    PassType::Debug => vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
                     PassType::UI => vk::ImageLayout::PRESENT_SRC_KHR,
                 },
             });
@@ -201,6 +204,27 @@ impl PassManager {
                     })
                     .blend(true);
             }
+            PassType::Debug => {
+                // World-space lines, tested against the scene but never
+                // occluding each other
+                let [position, color] = DebugVertex::vertex_attributes();
+                builder = builder
+                    .add_vertex_binding(DebugVertex::vertex_binding())
+                    .add_vertex_attribute(position)
+                    .add_vertex_attribute(color)
+                    .add_push_constant_range(vk::ShaderStageFlags::VERTEX, 0..64)
+                    .topology(vk::PrimitiveTopology::LINE_LIST)
+                    .rasterization(RasterizationConfig {
+                        cull_mode: vk::CullModeFlags::NONE,
+                        ..Default::default()
+                    })
+                    .depth(DepthConfig {
+                        test_enable: true,
+                        write_enable: false,
+                        compare_op: vk::CompareOp::LESS_OR_EQUAL,
+                    })
+                    .blend(true);
+            }
             PassType::UI => {
                 builder = builder
                     .rasterization(RasterizationConfig {
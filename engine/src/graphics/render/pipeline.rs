@@ -3,7 +3,6 @@
 //! Provides a flexible builder pattern for creating graphics pipelines
 //! with support for different render configurations.
 
-use super::graph::PassId;
 use crate::{
     error::Result,
     graphics::resource::{ResourceHandle, ResourceManager, ShaderStage},
@@ -92,6 +91,7 @@ pub struct PipelineBuilder {
     rasterization: RasterizationConfig,
     depth: DepthConfig,
     blend_enable: bool,
+    topology: vk::PrimitiveTopology,
     descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
     push_constant_ranges: Vec<vk::PushConstantRange>,
     pipeline_cache: Option<vk::PipelineCache>,
@@ -109,6 +109,7 @@ impl PipelineBuilder {
             rasterization: RasterizationConfig::default(),
             depth: DepthConfig::default(),
             blend_enable: false,
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
             descriptor_set_layouts: Vec::new(),
             push_constant_ranges: Vec::new(),
             pipeline_cache: None,
@@ -179,6 +180,12 @@ impl PipelineBuilder {
         self
     }
 
+    /// Set the primitive topology, triangle lists by default
+    pub fn topology(mut self, topology: vk::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
     /// Build the pipeline for a specific render pass
     pub fn build(&self, render_pass: vk::RenderPass, subpass: u32) -> Result<vk::Pipeline> {
         // Vertex input state
@@ -213,14 +220,14 @@ impl PipelineBuilder {
 
         // Input assembly state
         let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+            .topology(self.topology)
             .primitive_restart_enable(false);
 
         // Shader stages
         let shader_stages: Vec<_> = self
             .shader_stages
             .iter()
-            .filter_map(|(stage, handle)| self.resource_manager.get_shader_stage_info(*handle))
+            .filter_map(|(_, handle)| self.resource_manager.get_shader_stage_info(*handle))
             .collect();
 
         // Rasterization state
@@ -299,7 +306,7 @@ impl PipelineBuilder {
         let pipeline = unsafe {
             self.device
                 .create_graphics_pipelines(vk::PipelineCache::null(), &[create_info.build()], None)
-                .map_err(|e| crate::error::VulkanError::PipelineCreation(e.to_string()))?[0]
+                .map_err(|(_, e)| crate::error::VulkanError::PipelineCreation(e.to_string()))?[0]
         };
 
         Ok(pipeline)
@@ -2,10 +2,11 @@
 //!
 //! Handles the execution of render commands and manages the graphics pipeline
 
+use ash::vk;
 use std::sync::Arc;
 
 use super::{
-    command::{CommandBatch, RenderCommand, RenderOperation},
+    command::{CommandBatch, RenderOperation},
     render::{PassType, RenderGraph},
     resource::{ResourceHandle, ResourceManager},
 };
@@ -19,22 +20,50 @@ pub struct Renderer {
 }
 
 impl Renderer {
-    /// Create a new renderer
-    pub fn new(device: Arc<ash::Device>, resource_manager: Arc<ResourceManager>) -> Result<Self> {
+    /// Create a new renderer submitting to the given graphics queue
+    pub fn new(
+        device: Arc<ash::Device>,
+        resource_manager: Arc<ResourceManager>,
+        graphics_queue_family: u32,
+        graphics_queue: vk::Queue,
+    ) -> Result<Self> {
         Ok(Self {
-            render_graph: RenderGraph::new(device, resource_manager.clone()),
+            render_graph: RenderGraph::new(
+                device,
+                resource_manager.clone(),
+                graphics_queue_family,
+                graphics_queue,
+            )?,
             resource_manager,
         })
     }
 
     /// Submit a batch of render commands for processing
-    pub fn submit_commands(&self, batch: &CommandBatch) -> Result<()> {
+    pub fn submit_commands(&mut self, batch: &CommandBatch) -> Result<()> {
+        // Buffer updates go out in one transfer ahead of the passes reading
+        // them
+        for command in &batch.commands {
+            if let RenderOperation::UpdateBuffer {
+                buffer,
+                data,
+                offset,
+            } = &command.operation
+            {
+                self.update_buffer(*buffer, data, *offset)?;
+            }
+        }
+        self.resource_manager.uploads().flush()?;
+
         let mut current_pass: Option<PassType> = None;
 
         for command in &batch.commands {
+            if let RenderOperation::UpdateBuffer { .. } = command.operation {
+                continue;
+            }
+
             // Start new render pass if needed
             if current_pass != Some(command.pass_type) {
-                if let Some(pass_type) = current_pass {
+                if current_pass.is_some() {
                     // End previous pass
                     self.render_graph.end_pass()?;
                 }
@@ -58,13 +87,13 @@ impl Renderer {
                     // Draw the mesh
                     self.draw_mesh(*mesh, *instance_count)?;
                 }
-                RenderOperation::UpdateBuffer {
+                RenderOperation::DrawVertices {
                     buffer,
-                    data,
-                    offset,
+                    vertex_count,
                 } => {
-                    self.update_buffer(*buffer, data, *offset)?;
+                    self.draw_vertices(*buffer, *vertex_count)?;
                 }
+                RenderOperation::UpdateBuffer { .. } => {}
                 RenderOperation::SetPipeline(pipeline) => {
                     self.bind_pipeline(*pipeline)?;
                 }
@@ -84,15 +113,15 @@ impl Renderer {
 
     // Private helper methods for command execution
 
-    fn bind_pipeline(&self, pipeline: ResourceHandle) -> Result<()> {
+    fn bind_pipeline(&mut self, pipeline: ResourceHandle) -> Result<()> {
         // Get pipeline from resource manager and bind it
-        if let Some(pipeline) = self.resource_manager.get_pipeline(pipeline) {
-            self.render_graph.bind_pipeline(pipeline)?;
+        if let Some((pipeline, layout)) = self.resource_manager.get_pipeline(pipeline) {
+            self.render_graph.bind_pipeline(pipeline, layout)?;
         }
         Ok(())
     }
 
-    fn bind_material(&self, material: ResourceHandle) -> Result<()> {
+    fn bind_material(&mut self, material: ResourceHandle) -> Result<()> {
         // Get material from resource manager and bind its resources
         if let Some(material) = self.resource_manager.get_material(material) {
             self.render_graph.bind_material(&material)?;
@@ -100,7 +129,7 @@ impl Renderer {
         Ok(())
     }
 
-    fn draw_mesh(&self, mesh: ResourceHandle, instance_count: u32) -> Result<()> {
+    fn draw_mesh(&mut self, mesh: ResourceHandle, instance_count: u32) -> Result<()> {
         // Get mesh from resource manager and draw it
         if let Some(mesh) = self.resource_manager.get_mesh(mesh) {
             self.render_graph.draw_mesh(&mesh, instance_count)?;
@@ -108,7 +137,14 @@ impl Renderer {
         Ok(())
     }
 
-    fn update_buffer(&self, buffer: ResourceHandle, data: &[u8], offset: u64) -> Result<()> {
+    fn draw_vertices(&mut self, buffer: ResourceHandle, vertex_count: u32) -> Result<()> {
+        if let Some(buffer) = self.resource_manager.get_buffer(buffer) {
+            self.render_graph.draw_vertices(buffer, vertex_count)?;
+        }
+        Ok(())
+    }
+
+    fn update_buffer(&mut self, buffer: ResourceHandle, data: &[u8], offset: u64) -> Result<()> {
         // Get buffer from resource manager and update its contents
         if let Some(buffer) = self.resource_manager.get_buffer(buffer) {
             self.render_graph.update_buffer(buffer, data, offset)?;
@@ -7,7 +7,8 @@ use ash::vk;
 use parking_lot::RwLock;
 use std::sync::Arc;
 
-use crate::error::Result;
+use crate::error::{Result, VulkanError};
+use crate::memory::{MemoryAllocator, MemoryBlock, MemoryTag};
 
 /// Types of buffers that can be managed
 #[derive(Debug, Clone, Copy)]
@@ -31,10 +32,11 @@ pub struct BufferConfig {
 /// A persistently mapped buffer for efficient updates
 pub struct MappedBuffer {
     buffer: vk::Buffer,
-    memory: vk::DeviceMemory,
+    memory: MemoryBlock,
     mapped_ptr: *mut u8,
     size: vk::DeviceSize,
     device: Arc<ash::Device>,
+    allocator: Arc<MemoryAllocator>,
     // Optional ring buffer tracking
     ring_offset: RwLock<vk::DeviceSize>,
     ring_size: vk::DeviceSize,
@@ -45,8 +47,10 @@ unsafe impl Sync for MappedBuffer {}
 
 impl MappedBuffer {
     /// Create a new mapped buffer
+    #[track_caller]
     pub fn new(
         device: Arc<ash::Device>,
+        allocator: Arc<MemoryAllocator>,
         size: vk::DeviceSize,
         usage: vk::BufferUsageFlags,
         ring_buffer: bool,
@@ -65,23 +69,34 @@ impl MappedBuffer {
         let memory_flags =
             vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
 
-        // TODO: Proper memory type selection
-        let memory_type_index = 0;
-
-        let alloc_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(mem_requirements.size)
-            .memory_type_index(memory_type_index)
-            .build();
-
-        let memory = unsafe { device.allocate_memory(&alloc_info, None)? };
-
-        unsafe {
-            device.bind_buffer_memory(buffer, memory, 0)?;
-        }
-
-        // Persistently map the memory
-        let mapped_ptr =
-            unsafe { device.map_memory(memory, 0, size, vk::MemoryMapFlags::empty())? as *mut u8 };
+        let memory = allocator
+            .allocate_named(
+                mem_requirements,
+                memory_flags,
+                MemoryTag::Graphics,
+                "mapped buffer",
+            )
+            .map_err(|e| {
+                unsafe { device.destroy_buffer(buffer, None) };
+                VulkanError::MemoryAllocation(e.to_string())
+            })?;
+
+        let bound = unsafe { device.bind_buffer_memory(buffer, memory.memory, memory.offset) }
+            .map_err(|e| VulkanError::MemoryBinding(e.to_string()));
+
+        // Persistently map the memory, shared with other buffers in the block
+        let mapped_ptr = match bound.and_then(|_| {
+            allocator
+                .map(&memory)
+                .map_err(|e| VulkanError::MemoryMapping(e.to_string()))
+        }) {
+            Ok(ptr) => ptr as *mut u8,
+            Err(e) => {
+                unsafe { device.destroy_buffer(buffer, None) };
+                let _ = allocator.free(memory);
+                return Err(e);
+            }
+        };
 
         Ok(Self {
             buffer,
@@ -89,6 +104,7 @@ impl MappedBuffer {
             mapped_ptr,
             size,
             device,
+            allocator,
             ring_offset: RwLock::new(0),
             ring_size: if ring_buffer { size } else { 0 },
         })
@@ -107,6 +123,9 @@ impl MappedBuffer {
     }
 
     /// Allocate space in the ring buffer, returning the offset
+    ///
+    /// Wrapping around is not fenced, so data the GPU still reads can be
+    /// overwritten. Per-frame data should use `memory::FrameAllocator`.
     pub fn ring_allocate(&self, size: vk::DeviceSize) -> Option<vk::DeviceSize> {
         if self.ring_size == 0 {
             return None;
@@ -133,10 +152,10 @@ impl MappedBuffer {
 
 impl Drop for MappedBuffer {
     fn drop(&mut self) {
+        self.allocator.unmap(&self.memory);
         unsafe {
-            self.device.unmap_memory(self.memory);
             self.device.destroy_buffer(self.buffer, None);
-            self.device.free_memory(self.memory, None);
         }
+        let _ = self.allocator.free(self.memory);
     }
 }
@@ -5,7 +5,6 @@
 use super::{ResourceHandle, ResourceManager};
 use ash::vk;
 use std::collections::HashMap;
-use std::sync::Arc;
 
 /// Material parameter types that can be passed to shaders
 #[derive(Debug, Clone)]
@@ -34,8 +33,10 @@ pub struct MaterialDescriptor {
 /// A material instance that can be used for rendering
 pub struct Material {
     descriptor: MaterialDescriptor,
+    #[allow(dead_code)]
     uniform_buffer: ResourceHandle,
     descriptor_set: vk::DescriptorSet,
+    #[allow(dead_code)]
     descriptor_pool: vk::DescriptorPool,
     descriptor_set_layout: vk::DescriptorSetLayout,
 }
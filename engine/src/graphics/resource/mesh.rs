@@ -1,6 +1,6 @@
 //! Mesh resource definition
 
-use crate::graphics::resource::ResourceHandle;
+use crate::graphics::resource::{ResourceHandle, UploadToken};
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
@@ -15,4 +15,6 @@ pub struct Mesh {
     pub indices: Vec<u32>, // Assuming u32 for indices
     pub vertex_buffer: ResourceHandle,
     pub index_buffer: ResourceHandle,
+    /// Completes once the vertex and index data reached the buffers
+    pub upload: UploadToken,
 }
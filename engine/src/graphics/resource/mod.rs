@@ -5,18 +5,21 @@
 //! - Textures
 //! - Materials
 //! - Shaders
+//! - Staging uploads
 
 mod buffer;
 mod material;
 mod mesh;
 mod shader;
 mod texture;
+mod upload;
 
-pub use buffer::{BufferType, MappedBuffer};
+pub use buffer::{BufferConfig, BufferType, MappedBuffer};
 pub use material::{Material, MaterialDescriptor, MaterialParam};
-pub use mesh::Mesh;
+pub use mesh::{Mesh, Vertex};
 pub use shader::{ShaderDescriptor, ShaderManager, ShaderModule, ShaderStage};
 pub use texture::{TextureDescriptor, TextureFormat, TextureManager};
+pub use upload::{UploadConfig, UploadManager, UploadQueue, UploadToken, UploadUse};
 
 use ash::vk;
 use parking_lot::RwLock;
@@ -24,13 +27,14 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use crate::error::Result;
+use crate::memory::{MemoryAllocator, MemoryBlock, MemoryTag};
 
 /// Unique identifier for a graphics resource
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ResourceHandle(u64);
 
 impl ResourceHandle {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         use std::sync::atomic::{AtomicU64, Ordering};
         static NEXT_ID: AtomicU64 = AtomicU64::new(1);
         Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
@@ -46,30 +50,12 @@ pub enum ResourceType {
     Material,
     Shader,
     Mesh,
-}
-
-/// Find suitable memory type for buffer allocation
-fn find_memory_type(
-    device: &ash::Device,
-    type_filter: u32,
-    properties: vk::MemoryPropertyFlags,
-) -> Option<u32> {
-    // TODO: Store physical device and memory properties in ResourceManager
-    let mem_properties =
-        unsafe { device.get_physical_device_memory_properties(vk::PhysicalDevice::null()) };
-
-    for i in 0..mem_properties.memory_type_count {
-        if (type_filter & (1 << i)) != 0
-            && (mem_properties.memory_types[i as usize].property_flags & properties) == properties
-        {
-            return Some(i);
-        }
-    }
-    None
+    Pipeline,
 }
 
 /// Manager for mesh resources
 pub struct MeshManager {
+    #[allow(dead_code)]
     device: Arc<ash::Device>,
     resource_manager: Arc<ResourceManager>,
 }
@@ -83,32 +69,51 @@ impl MeshManager {
         }
     }
 
-    /// Create a new mesh from vertex and index data.
+    /// Create a new mesh from vertex and index data. The data is queued on
+    /// the upload manager, the mesh can be drawn once `Mesh::upload` is
+    /// complete.
+    #[track_caller]
     pub fn create_mesh(&self, vertices: &[Vertex], indices: &[u32]) -> Result<Mesh> {
         let vertex_buffer = self.resource_manager.create_buffer(
-            (std::mem::size_of::<Vertex>() * vertices.len()) as vk::DeviceSize,
-            vk::BufferUsageFlags::VERTEX_BUFFER,
+            std::mem::size_of_val(vertices) as vk::DeviceSize,
+            vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
             BufferType::Vertex,
         )?;
 
         let index_buffer = self.resource_manager.create_buffer(
-            (std::mem::size_of::<u32>() * indices.len()) as vk::DeviceSize,
-            vk::BufferUsageFlags::INDEX_BUFFER,
+            std::mem::size_of_val(indices) as vk::DeviceSize,
+            vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
             BufferType::Index,
         )?;
 
-        // TODO: Actually upload data to buffers
+        // Tokens are ordered, the later one covers both uploads
+        let uploads = self.resource_manager.uploads();
+        let vertex_upload = uploads.upload_buffer(
+            self.resource_manager.get_buffer(vertex_buffer).unwrap(),
+            0,
+            bytemuck::cast_slice(vertices),
+            UploadUse::VERTEX,
+        )?;
+        let index_upload = uploads.upload_buffer(
+            self.resource_manager.get_buffer(index_buffer).unwrap(),
+            0,
+            bytemuck::cast_slice(indices),
+            UploadUse::INDEX,
+        )?;
 
         Ok(Mesh {
             vertices: vertices.to_vec(),
             indices: indices.to_vec(),
             vertex_buffer,
             index_buffer,
+            upload: vertex_upload.max(index_upload),
         })
     }
 
     /// Destroy a mesh and release its resources.
     pub fn destroy_mesh(&self, mesh: Mesh) -> Result<()> {
+        // The copy may still be writing the buffers
+        self.resource_manager.uploads().wait(mesh.upload)?;
         self.resource_manager.destroy_resource(mesh.vertex_buffer);
         self.resource_manager.destroy_resource(mesh.index_buffer);
         Ok(())
@@ -118,29 +123,50 @@ impl MeshManager {
 /// Central manager for all graphics resources
 pub struct ResourceManager {
     device: Arc<ash::Device>,
+    allocator: Arc<MemoryAllocator>,
+    uploads: Arc<UploadManager>,
     resources: RwLock<HashMap<ResourceHandle, ResourceType>>,
     buffers: RwLock<HashMap<ResourceHandle, vk::Buffer>>,
-    buffer_memories: RwLock<HashMap<ResourceHandle, vk::DeviceMemory>>,
+    buffer_memories: RwLock<HashMap<ResourceHandle, MemoryBlock>>,
+    pipelines: RwLock<HashMap<ResourceHandle, (vk::Pipeline, vk::PipelineLayout)>>,
+    materials: RwLock<HashMap<ResourceHandle, Arc<Material>>>,
+    meshes: RwLock<HashMap<ResourceHandle, Arc<Mesh>>>,
     texture_manager: TextureManager,
     shader_manager: ShaderManager,
     mesh_manager: Option<MeshManager>,
 }
 
 impl ResourceManager {
-    /// Create a new resource manager.
-    pub fn new(device: Arc<ash::Device>) -> Self {
+    /// Create a new resource manager allocating from the shared allocator
+    /// and uploading initial data through `uploads`.
+    pub fn new(
+        device: Arc<ash::Device>,
+        allocator: Arc<MemoryAllocator>,
+        uploads: Arc<UploadManager>,
+    ) -> Self {
         Self {
             device: device.clone(),
+            allocator: allocator.clone(),
+            uploads: uploads.clone(),
             resources: RwLock::new(HashMap::new()),
             buffers: RwLock::new(HashMap::new()),
             buffer_memories: RwLock::new(HashMap::new()),
-            texture_manager: TextureManager::new(device.clone()),
+            pipelines: RwLock::new(HashMap::new()),
+            materials: RwLock::new(HashMap::new()),
+            meshes: RwLock::new(HashMap::new()),
+            texture_manager: TextureManager::new(device.clone(), allocator, uploads),
             shader_manager: ShaderManager::new(device.clone()),
             mesh_manager: None,
         }
     }
 
+    /// The upload manager resources are filled through
+    pub fn uploads(&self) -> &Arc<UploadManager> {
+        &self.uploads
+    }
+
     /// Create a new mapped buffer for efficient updates
+    #[track_caller]
     pub fn create_mapped_buffer(
         &self,
         size: vk::DeviceSize,
@@ -159,43 +185,22 @@ impl ResourceManager {
                 .map_err(|e| crate::error::VulkanError::BufferCreation(e.to_string()))?
         };
 
-        let mem_requirements = unsafe { self.device.get_buffer_memory_requirements(buffer) };
-
         // Request host visible and coherent memory
         let memory_flags =
             vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
 
-        let memory_type_index = find_memory_type(
-            &self.device,
-            mem_requirements.memory_type_bits,
-            memory_flags,
-        )
-        .ok_or_else(|| crate::error::VulkanError::NoSuitableMemory)?;
-
-        let alloc_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(mem_requirements.size)
-            .memory_type_index(memory_type_index)
-            .build();
+        let memory = self.allocate_buffer_memory(buffer, memory_flags, "mapped buffer")?;
 
-        let memory = unsafe {
-            self.device
-                .allocate_memory(&alloc_info, None)
-                .map_err(|e| crate::error::VulkanError::MemoryAllocation(e.to_string()))?
+        // Map the memory, the mapping is shared with other buffers in the block
+        let mapped_ptr = match self.allocator.map(&memory) {
+            Ok(ptr) => ptr as *mut u8,
+            Err(e) => {
+                unsafe { self.device.destroy_buffer(buffer, None) };
+                let _ = self.allocator.free(memory);
+                return Err(crate::error::VulkanError::MemoryMapping(e.to_string()));
+            }
         };
 
-        unsafe {
-            self.device
-                .bind_buffer_memory(buffer, memory, 0)
-                .map_err(|e| crate::error::VulkanError::MemoryBinding(e.to_string()))?;
-        }
-
-        // Map the memory
-        let mapped_ptr = unsafe {
-            self.device
-                .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
-                .map_err(|e| crate::error::VulkanError::MemoryMapping(e.to_string()))?
-        } as *mut u8;
-
         let handle = ResourceHandle::new();
         self.resources
             .write()
@@ -207,6 +212,7 @@ impl ResourceManager {
     }
 
     /// Create a new texture
+    #[track_caller]
     pub fn create_texture(&self, descriptor: TextureDescriptor) -> Result<ResourceHandle> {
         self.texture_manager.create_texture(descriptor)
     }
@@ -217,6 +223,7 @@ impl ResourceManager {
     }
 
     /// Create a new buffer and return its handle
+    #[track_caller]
     pub fn create_buffer(
         &self,
         size: vk::DeviceSize,
@@ -235,34 +242,10 @@ impl ResourceManager {
                 .map_err(|e| crate::error::VulkanError::BufferCreation(e.to_string()))?
         };
 
-        let mem_requirements = unsafe { self.device.get_buffer_memory_requirements(buffer) };
-
         // Use device local memory for non-mapped buffers
         let memory_flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
 
-        let memory_type_index = find_memory_type(
-            &self.device,
-            mem_requirements.memory_type_bits,
-            memory_flags,
-        )
-        .ok_or_else(|| crate::error::VulkanError::NoSuitableMemory)?;
-
-        let alloc_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(mem_requirements.size)
-            .memory_type_index(memory_type_index)
-            .build();
-
-        let memory = unsafe {
-            self.device
-                .allocate_memory(&alloc_info, None)
-                .map_err(|e| crate::error::VulkanError::MemoryAllocation(e.to_string()))?
-        };
-
-        unsafe {
-            self.device
-                .bind_buffer_memory(buffer, memory, 0)
-                .map_err(|e| crate::error::VulkanError::MemoryBinding(e.to_string()))?;
-        }
+        let memory = self.allocate_buffer_memory(buffer, memory_flags, "buffer")?;
 
         let handle = ResourceHandle::new();
         self.resources
@@ -274,11 +257,100 @@ impl ResourceManager {
         Ok(handle)
     }
 
+    /// Allocate memory for `buffer` from the shared allocator and bind it.
+    /// The buffer is destroyed if that fails.
+    #[track_caller]
+    fn allocate_buffer_memory(
+        &self,
+        buffer: vk::Buffer,
+        memory_flags: vk::MemoryPropertyFlags,
+        name: &'static str,
+    ) -> Result<MemoryBlock> {
+        let mem_requirements = unsafe { self.device.get_buffer_memory_requirements(buffer) };
+
+        let memory = self
+            .allocator
+            .allocate_named(mem_requirements, memory_flags, MemoryTag::Graphics, name)
+            .map_err(|e| {
+                unsafe { self.device.destroy_buffer(buffer, None) };
+                crate::error::VulkanError::MemoryAllocation(e.to_string())
+            })?;
+
+        unsafe {
+            if let Err(e) = self
+                .device
+                .bind_buffer_memory(buffer, memory.memory, memory.offset)
+            {
+                self.device.destroy_buffer(buffer, None);
+                let _ = self.allocator.free(memory);
+                return Err(crate::error::VulkanError::MemoryBinding(e.to_string()));
+            }
+        }
+        Ok(memory)
+    }
+
     /// Create a new shader module.
     pub fn create_shader(&self, descriptor: ShaderDescriptor) -> Result<ResourceHandle> {
         self.shader_manager.create_shader(descriptor)
     }
 
+    /// Register a pipeline for draw commands. The pipeline stays owned by
+    /// the caller and has to outlive the handle.
+    pub fn register_pipeline(
+        &self,
+        pipeline: vk::Pipeline,
+        layout: vk::PipelineLayout,
+    ) -> ResourceHandle {
+        let handle = ResourceHandle::new();
+        self.resources
+            .write()
+            .insert(handle, ResourceType::Pipeline);
+        self.pipelines.write().insert(handle, (pipeline, layout));
+        handle
+    }
+
+    /// Take ownership of a material for draw commands
+    pub fn add_material(&self, material: Material) -> ResourceHandle {
+        let handle = ResourceHandle::new();
+        self.resources
+            .write()
+            .insert(handle, ResourceType::Material);
+        self.materials.write().insert(handle, Arc::new(material));
+        handle
+    }
+
+    /// Take ownership of a mesh for draw commands, its buffers are
+    /// destroyed along with it
+    pub fn add_mesh(&self, mesh: Mesh) -> ResourceHandle {
+        let handle = ResourceHandle::new();
+        self.resources.write().insert(handle, ResourceType::Mesh);
+        self.meshes.write().insert(handle, Arc::new(mesh));
+        handle
+    }
+
+    /// Get a registered pipeline and its layout
+    pub fn get_pipeline(
+        &self,
+        handle: ResourceHandle,
+    ) -> Option<(vk::Pipeline, vk::PipelineLayout)> {
+        self.pipelines.read().get(&handle).copied()
+    }
+
+    /// Get a material added with `add_material`
+    pub fn get_material(&self, handle: ResourceHandle) -> Option<Arc<Material>> {
+        self.materials.read().get(&handle).cloned()
+    }
+
+    /// Get a mesh added with `add_mesh`
+    pub fn get_mesh(&self, handle: ResourceHandle) -> Option<Arc<Mesh>> {
+        self.meshes.read().get(&handle).cloned()
+    }
+
+    /// Get the image of a texture
+    pub fn get_image(&self, handle: ResourceHandle) -> Option<vk::Image> {
+        self.texture_manager.get_image(handle)
+    }
+
     /// Get a buffer handle if it exists
     pub fn get_buffer(&self, handle: ResourceHandle) -> Option<vk::Buffer> {
         self.buffers.read().get(&handle).copied()
@@ -299,28 +371,42 @@ impl ResourceManager {
 
     /// Destroy a resource
     pub fn destroy_resource(&self, handle: ResourceHandle) {
-        if let Some(resource_type) = self.resources.write().remove(&handle) {
+        // Meshes destroy their buffers through here, release the lock first
+        let resource_type = self.resources.write().remove(&handle);
+        if let Some(resource_type) = resource_type {
             match resource_type {
                 ResourceType::Buffer(_) | ResourceType::MappedBuffer(_) => {
                     // Get buffer and memory handles
                     let buffer = self.buffers.write().remove(&handle);
                     let memory = self.buffer_memories.write().remove(&handle);
 
-                    unsafe {
-                        // For mapped buffers, unmap memory before cleanup
-                        if let ResourceType::MappedBuffer(_) = resource_type {
-                            if let Some(mem) = memory {
-                                self.device.unmap_memory(mem);
-                            }
-                        }
+                    // For mapped buffers, unmap memory before cleanup
+                    if let (ResourceType::MappedBuffer(_), Some(mem)) = (&resource_type, &memory) {
+                        self.allocator.unmap(mem);
+                    }
 
-                        // Cleanup buffer and memory
-                        if let Some(buf) = buffer {
+                    // Cleanup buffer and memory
+                    if let Some(buf) = buffer {
+                        unsafe {
                             self.device.destroy_buffer(buf, None);
                         }
-                        if let Some(mem) = memory {
-                            self.device.free_memory(mem, None);
-                        }
+                    }
+                    if let Some(mem) = memory {
+                        let _ = self.allocator.free(mem);
+                    }
+                }
+                ResourceType::Pipeline => {
+                    self.pipelines.write().remove(&handle);
+                }
+                ResourceType::Material => {
+                    self.materials.write().remove(&handle);
+                }
+                ResourceType::Mesh => {
+                    if let Some(mesh) = self.meshes.write().remove(&handle) {
+                        // The copy may still be writing the buffers
+                        let _ = self.uploads.wait(mesh.upload);
+                        self.destroy_resource(mesh.vertex_buffer);
+                        self.destroy_resource(mesh.index_buffer);
                     }
                 }
                 _ => {
@@ -6,6 +6,7 @@ use super::ResourceHandle;
 use ash::vk;
 use parking_lot::RwLock;
 use std::collections::HashMap;
+use std::ffi::CString;
 use std::sync::Arc;
 
 /// Type of shader module
@@ -20,7 +21,7 @@ pub enum ShaderStage {
 }
 
 impl ShaderStage {
-    fn to_vk_stage_flags(&self) -> vk::ShaderStageFlags {
+    fn to_vk_stage_flags(self) -> vk::ShaderStageFlags {
         match self {
             ShaderStage::Vertex => vk::ShaderStageFlags::VERTEX,
             ShaderStage::Fragment => vk::ShaderStageFlags::FRAGMENT,
@@ -47,7 +48,7 @@ pub struct ShaderDescriptor {
 pub struct ShaderModule {
     module: vk::ShaderModule,
     stage: ShaderStage,
-    entry_point: String,
+    entry_point: CString,
 }
 
 /// Manager for shader resources
@@ -70,18 +71,19 @@ impl ShaderManager {
         &self,
         descriptor: ShaderDescriptor,
     ) -> crate::error::Result<ResourceHandle> {
+        let entry_point = CString::new(descriptor.entry_point)?;
         let create_info = vk::ShaderModuleCreateInfo::builder().code(&descriptor.code);
 
         let module = unsafe {
             self.device
                 .create_shader_module(&create_info, None)
-                .map_err(|e| crate::error::VulkanError::ShaderModuleCreation(e.to_string()))?
+                .map_err(|e| crate::error::VulkanError::ShaderCreation(e.to_string()))?
         };
 
         let shader = ShaderModule {
             module,
             stage: descriptor.stage,
-            entry_point: descriptor.entry_point,
+            entry_point,
         };
 
         let handle = ResourceHandle::new();
@@ -99,7 +101,7 @@ impl ShaderManager {
             vk::PipelineShaderStageCreateInfo::builder()
                 .stage(shader.stage.to_vk_stage_flags())
                 .module(shader.module)
-                .name(shader.entry_point.as_bytes())
+                .name(&shader.entry_point)
                 .build()
         })
     }
@@ -123,12 +125,12 @@ impl Drop for ShaderManager {
 
 /// Utility functions for shader management
 pub mod util {
-    use super::*;
     use std::fs::File;
     use std::io::Read;
     use std::path::Path;
 
     /// Load SPIR-V shader from a file
+    #[allow(dead_code)]
     pub fn load_spirv<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u32>> {
         let mut file = File::open(path)?;
         let mut bytes = Vec::new();
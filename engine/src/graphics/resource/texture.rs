@@ -2,30 +2,33 @@
 //!
 //! Handles creation, storage, and lifecycle of texture resources
 
-use super::{ResourceHandle, ResourceManager};
+use super::{ResourceHandle, UploadManager, UploadToken};
+use crate::memory::{MemoryAllocator, MemoryBlock, MemoryTag};
 use ash::vk;
 use parking_lot::RwLock;
 use std::collections::HashMap;
 use std::sync::Arc;
 
 /// Format of the texture data
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TextureFormat {
     R8G8B8A8Unorm,
     B8G8R8A8Unorm,
     R8G8B8Unorm,
     R8Unorm,
     Depth32Float,
+    Custom(vk::Format),
 }
 
 impl TextureFormat {
-    fn to_vk_format(&self) -> vk::Format {
+    pub(crate) fn to_vk_format(self) -> vk::Format {
         match self {
             TextureFormat::R8G8B8A8Unorm => vk::Format::R8G8B8A8_UNORM,
             TextureFormat::B8G8R8A8Unorm => vk::Format::B8G8R8A8_UNORM,
             TextureFormat::R8G8B8Unorm => vk::Format::R8G8B8_UNORM,
             TextureFormat::R8Unorm => vk::Format::R8_UNORM,
             TextureFormat::Depth32Float => vk::Format::D32_SFLOAT,
+            TextureFormat::Custom(format) => format,
         }
     }
 }
@@ -39,7 +42,8 @@ pub struct TextureDescriptor {
     pub height: u32,
     /// Format of the texture data
     pub format: TextureFormat,
-    /// Initial data to upload to the texture
+    /// Initial data to upload to the texture, tightly packed texels of
+    /// the first mip level
     pub data: Option<Vec<u8>>,
     /// Usage flags for the texture
     pub usage: vk::ImageUsageFlags,
@@ -48,49 +52,75 @@ pub struct TextureDescriptor {
 /// Managed texture resource
 pub struct Texture {
     image: vk::Image,
-    memory: vk::DeviceMemory,
+    memory: MemoryBlock,
     view: vk::ImageView,
     sampler: vk::Sampler,
+    #[allow(dead_code)]
     format: vk::Format,
+    #[allow(dead_code)]
     extent: vk::Extent3D,
+    upload: UploadToken,
 }
 
 /// Manager for texture resources
 pub struct TextureManager {
     device: Arc<ash::Device>,
+    allocator: Arc<MemoryAllocator>,
+    uploads: Arc<UploadManager>,
     textures: RwLock<HashMap<ResourceHandle, Texture>>,
 }
 
 impl TextureManager {
-    /// Create a new texture manager
-    pub fn new(device: Arc<ash::Device>) -> Self {
+    /// Create a new texture manager allocating from the shared allocator
+    /// and uploading initial data through `uploads`
+    pub fn new(
+        device: Arc<ash::Device>,
+        allocator: Arc<MemoryAllocator>,
+        uploads: Arc<UploadManager>,
+    ) -> Self {
         Self {
             device,
+            allocator,
+            uploads,
             textures: RwLock::new(HashMap::new()),
         }
     }
 
-    /// Create a new texture from a descriptor
+    /// Create a new texture from a descriptor. Initial data is queued on the
+    /// upload manager, the texture can be sampled once `upload_token` is
+    /// complete.
+    #[track_caller]
     pub fn create_texture(
         &self,
         descriptor: TextureDescriptor,
     ) -> crate::error::Result<ResourceHandle> {
         let format = descriptor.format.to_vk_format();
+        let extent = vk::Extent3D {
+            width: descriptor.width,
+            height: descriptor.height,
+            depth: 1,
+        };
+        let aspect = if format == vk::Format::D32_SFLOAT {
+            vk::ImageAspectFlags::DEPTH
+        } else {
+            vk::ImageAspectFlags::COLOR
+        };
+
+        let mut usage = descriptor.usage | vk::ImageUsageFlags::SAMPLED;
+        if descriptor.data.is_some() {
+            usage |= vk::ImageUsageFlags::TRANSFER_DST;
+        }
 
         // Create image
         let image_info = vk::ImageCreateInfo::builder()
             .image_type(vk::ImageType::TYPE_2D)
             .format(format)
-            .extent(vk::Extent3D {
-                width: descriptor.width,
-                height: descriptor.height,
-                depth: 1,
-            })
+            .extent(extent)
             .mip_levels(1)
             .array_layers(1)
             .samples(vk::SampleCountFlags::TYPE_1)
             .tiling(vk::ImageTiling::OPTIMAL)
-            .usage(descriptor.usage | vk::ImageUsageFlags::SAMPLED)
+            .usage(usage)
             .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
         let image = unsafe {
@@ -102,23 +132,28 @@ impl TextureManager {
         // Allocate and bind memory
         let memory_requirements = unsafe { self.device.get_image_memory_requirements(image) };
 
-        // TODO: Implement proper memory type selection
-        let memory_type_index = 0;
-
-        let alloc_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(memory_requirements.size)
-            .memory_type_index(memory_type_index);
-
-        let memory = unsafe {
-            self.device
-                .allocate_memory(&alloc_info, None)
-                .map_err(|e| crate::error::VulkanError::MemoryAllocation(e.to_string()))?
-        };
+        let memory = self
+            .allocator
+            .allocate_named(
+                memory_requirements,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                MemoryTag::Graphics,
+                "texture",
+            )
+            .map_err(|e| {
+                unsafe { self.device.destroy_image(image, None) };
+                crate::error::VulkanError::MemoryAllocation(e.to_string())
+            })?;
 
         unsafe {
-            self.device
-                .bind_image_memory(image, memory, 0)
-                .map_err(|e| crate::error::VulkanError::MemoryBinding(e.to_string()))?;
+            if let Err(e) = self
+                .device
+                .bind_image_memory(image, memory.memory, memory.offset)
+            {
+                self.device.destroy_image(image, None);
+                let _ = self.allocator.free(memory);
+                return Err(crate::error::VulkanError::MemoryBinding(e.to_string()));
+            }
         }
 
         // Create image view
@@ -127,11 +162,7 @@ impl TextureManager {
             .view_type(vk::ImageViewType::TYPE_2D)
             .format(format)
             .subresource_range(vk::ImageSubresourceRange {
-                aspect_mask: if format == vk::Format::D32_SFLOAT {
-                    vk::ImageAspectFlags::DEPTH
-                } else {
-                    vk::ImageAspectFlags::COLOR
-                },
+                aspect_mask: aspect,
                 base_mip_level: 0,
                 level_count: 1,
                 base_array_layer: 0,
@@ -158,17 +189,19 @@ impl TextureManager {
                 .map_err(|e| crate::error::VulkanError::SamplerCreation(e.to_string()))?
         };
 
+        let upload = match &descriptor.data {
+            Some(data) => self.uploads.upload_image(image, extent, aspect, data)?,
+            None => UploadToken::COMPLETE,
+        };
+
         let texture = Texture {
             image,
             memory,
             view,
             sampler,
             format,
-            extent: vk::Extent3D {
-                width: descriptor.width,
-                height: descriptor.height,
-                depth: 1,
-            },
+            extent,
+            upload,
         };
 
         let handle = ResourceHandle::new();
@@ -184,18 +217,36 @@ impl TextureManager {
             .get(&handle)
             .map(|texture| (texture.view, texture.sampler))
     }
+
+    /// Get the image of a texture, for layout transitions
+    pub fn get_image(&self, handle: ResourceHandle) -> Option<vk::Image> {
+        self.textures
+            .read()
+            .get(&handle)
+            .map(|texture| texture.image)
+    }
+
+    /// Token of the upload of a texture's initial data
+    pub fn upload_token(&self, handle: ResourceHandle) -> Option<UploadToken> {
+        self.textures
+            .read()
+            .get(&handle)
+            .map(|texture| texture.upload)
+    }
 }
 
 impl Drop for TextureManager {
     fn drop(&mut self) {
         let textures = self.textures.get_mut();
         for texture in textures.values() {
+            // The copy may still be writing the image
+            let _ = self.uploads.wait(texture.upload);
             unsafe {
                 self.device.destroy_sampler(texture.sampler, None);
                 self.device.destroy_image_view(texture.view, None);
                 self.device.destroy_image(texture.image, None);
-                self.device.free_memory(texture.memory, None);
             }
+            let _ = self.allocator.free(texture.memory);
         }
     }
 }
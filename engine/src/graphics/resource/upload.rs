@@ -0,0 +1,984 @@
+//! Staging uploads
+//!
+//! Collects buffer and image uploads in a staging ring and records them into
+//! one transfer command buffer per flush, normally once per frame. When the
+//! device has a dedicated transfer queue the copies run there and ownership
+//! of the destinations is handed over to the graphics queue afterwards.
+
+use ash::vk;
+use bytemuck::Pod;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use super::buffer::BufferType;
+use crate::error::{Result, VulkanError};
+use crate::memory::{BufferKind, MemoryAllocator, MemoryBlock, MemoryTag, TypedBuffer};
+
+const DEFAULT_STAGING_SIZE: vk::DeviceSize = 16 * 1024 * 1024;
+
+/// Staging offset alignment of image copies, a multiple of every
+/// power-of-two texel size as `vkCmdCopyBufferToImage` requires
+const IMAGE_COPY_ALIGNMENT: vk::DeviceSize = 16;
+
+/// Staging offset alignment of buffer copies
+const BUFFER_COPY_ALIGNMENT: vk::DeviceSize = 4;
+
+/// Identifies the flush an upload is submitted with. Tokens of later
+/// flushes compare greater.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UploadToken(u64);
+
+impl UploadToken {
+    /// A token that is always complete, for resources without initial data
+    pub const COMPLETE: Self = Self(0);
+}
+
+/// A queue uploads are submitted to
+#[derive(Debug, Clone, Copy)]
+pub struct UploadQueue {
+    pub family_index: u32,
+    pub queue: vk::Queue,
+}
+
+/// How uploaded data is read afterwards. The barriers after the copy make
+/// it visible to these stages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadUse {
+    pub stage: vk::PipelineStageFlags,
+    pub access: vk::AccessFlags,
+}
+
+const SHADER_STAGES: vk::PipelineStageFlags = vk::PipelineStageFlags::from_raw(
+    vk::PipelineStageFlags::VERTEX_SHADER.as_raw()
+        | vk::PipelineStageFlags::FRAGMENT_SHADER.as_raw()
+        | vk::PipelineStageFlags::COMPUTE_SHADER.as_raw(),
+);
+
+impl UploadUse {
+    pub const VERTEX: Self = Self {
+        stage: vk::PipelineStageFlags::VERTEX_INPUT,
+        access: vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+    };
+    pub const INDEX: Self = Self {
+        stage: vk::PipelineStageFlags::VERTEX_INPUT,
+        access: vk::AccessFlags::INDEX_READ,
+    };
+    pub const UNIFORM: Self = Self {
+        stage: SHADER_STAGES,
+        access: vk::AccessFlags::UNIFORM_READ,
+    };
+    pub const STORAGE: Self = Self {
+        stage: SHADER_STAGES,
+        access: vk::AccessFlags::SHADER_READ,
+    };
+    pub const SAMPLED: Self = Self {
+        stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+    };
+    /// For data whose use isn't known up front
+    pub const ANY: Self = Self {
+        stage: vk::PipelineStageFlags::ALL_COMMANDS,
+        access: vk::AccessFlags::MEMORY_READ,
+    };
+}
+
+impl From<BufferKind> for UploadUse {
+    fn from(kind: BufferKind) -> Self {
+        match kind {
+            BufferKind::Vertex => Self::VERTEX,
+            BufferKind::Index => Self::INDEX,
+            BufferKind::Uniform => Self::UNIFORM,
+            BufferKind::Storage => Self::STORAGE,
+        }
+    }
+}
+
+impl From<BufferType> for UploadUse {
+    fn from(buffer_type: BufferType) -> Self {
+        match buffer_type {
+            BufferType::Vertex => Self::VERTEX,
+            BufferType::Index => Self::INDEX,
+            BufferType::Uniform => Self::UNIFORM,
+            BufferType::Storage | BufferType::TransformStorage => Self::STORAGE,
+        }
+    }
+}
+
+/// Configuration for the upload manager
+#[derive(Debug, Clone, Copy)]
+pub struct UploadConfig {
+    /// Size of the staging ring. Larger uploads get a staging buffer of
+    /// their own.
+    pub staging_size: vk::DeviceSize,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            staging_size: DEFAULT_STAGING_SIZE,
+        }
+    }
+}
+
+/// Space in the staging buffer, tracked as byte positions that keep growing
+/// instead of wrapping. The buffer offset of a position is the position
+/// modulo the capacity; everything between `tail` and `head` may still be
+/// read by a copy.
+#[derive(Debug)]
+struct StagingRing {
+    capacity: vk::DeviceSize,
+    head: u64,
+    tail: u64,
+}
+
+impl StagingRing {
+    fn new(capacity: vk::DeviceSize) -> Self {
+        Self {
+            capacity,
+            head: 0,
+            tail: 0,
+        }
+    }
+
+    /// Buffer offset for `size` bytes, or `None` if they don't fit in front
+    /// of the oldest data still in use. Allocations never straddle the end
+    /// of the buffer.
+    fn reserve(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> Option<vk::DeviceSize> {
+        if size > self.capacity {
+            return None;
+        }
+
+        let mut start = self.head.next_multiple_of(alignment);
+        let offset = start % self.capacity;
+        if offset + size > self.capacity {
+            start += self.capacity - offset;
+        }
+        let end = start + size;
+        if end - self.tail > self.capacity {
+            return None;
+        }
+
+        self.head = end;
+        Some(start % self.capacity)
+    }
+
+    /// Marks everything written before `position` as read
+    fn release(&mut self, position: u64) {
+        self.tail = self.tail.max(position);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CopyDst {
+    Buffer {
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+    },
+    Image {
+        image: vk::Image,
+        extent: vk::Extent3D,
+        aspect: vk::ImageAspectFlags,
+    },
+}
+
+/// An upload waiting for the next flush
+#[derive(Debug, Clone, Copy)]
+struct PendingCopy {
+    src: vk::Buffer,
+    src_offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    dst: CopyDst,
+    usage: UploadUse,
+}
+
+/// Which part of the barriers after the copies to record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BarrierHalf {
+    /// Copy and use on the same queue
+    Full,
+    /// Transfer queue side of an ownership transfer
+    Release,
+    /// Graphics queue side of an ownership transfer
+    Acquire,
+}
+
+/// A staging buffer made for one oversized upload, freed with its batch
+struct TemporaryStaging {
+    buffer: vk::Buffer,
+    memory: MemoryBlock,
+}
+
+/// A submitted flush
+struct Batch {
+    token: u64,
+    fence: vk::Fence,
+    transfer_commands: vk::CommandBuffer,
+    graphics_commands: Option<vk::CommandBuffer>,
+    semaphore: Option<vk::Semaphore>,
+    ring_end: u64,
+    temporary: Vec<TemporaryStaging>,
+}
+
+struct UploadState {
+    ring: StagingRing,
+    pending: Vec<PendingCopy>,
+    temporary: Vec<TemporaryStaging>,
+    in_flight: VecDeque<Batch>,
+    /// Token of the batch being collected
+    next_token: u64,
+    completed: u64,
+}
+
+/// Batches uploads into the GPU's buffers and images
+///
+/// Uploads are copied into the staging ring right away and recorded on the
+/// next `flush`. Poll the returned token with `is_complete` or block on it
+/// with `wait`. `flush` submits to the graphics queue, so call it from the
+/// thread that renders.
+pub struct UploadManager {
+    device: Arc<ash::Device>,
+    allocator: Arc<MemoryAllocator>,
+    graphics: UploadQueue,
+    /// Only set for a queue family other than the graphics one
+    transfer: Option<UploadQueue>,
+    transfer_pool: vk::CommandPool,
+    /// Records the acquire barriers when copies run on the transfer queue
+    graphics_pool: Option<vk::CommandPool>,
+    staging: TemporaryStaging,
+    staging_ptr: *mut u8,
+    state: Mutex<UploadState>,
+}
+
+unsafe impl Send for UploadManager {}
+unsafe impl Sync for UploadManager {}
+
+impl UploadManager {
+    /// Create an upload manager. `transfer` is the dedicated transfer queue,
+    /// if the device has one.
+    pub fn new(
+        device: Arc<ash::Device>,
+        allocator: Arc<MemoryAllocator>,
+        graphics: UploadQueue,
+        transfer: Option<UploadQueue>,
+        config: UploadConfig,
+    ) -> Result<Self> {
+        let transfer = transfer.filter(|queue| queue.family_index != graphics.family_index);
+        let copy_family = transfer.unwrap_or(graphics).family_index;
+
+        let transfer_pool = create_command_pool(&device, copy_family)?;
+        let graphics_pool = match transfer {
+            Some(_) => Some(create_command_pool(&device, graphics.family_index)?),
+            None => None,
+        };
+
+        let staging_size = config.staging_size.next_multiple_of(IMAGE_COPY_ALIGNMENT);
+        let staging = create_staging_buffer(&device, &allocator, staging_size, "staging ring")?;
+        let staging_ptr = allocator
+            .map(&staging.memory)
+            .map_err(|e| VulkanError::MemoryMapping(e.to_string()))?
+            as *mut u8;
+
+        Ok(Self {
+            device,
+            allocator,
+            graphics,
+            transfer,
+            transfer_pool,
+            graphics_pool,
+            staging,
+            staging_ptr,
+            state: Mutex::new(UploadState {
+                ring: StagingRing::new(staging_size),
+                pending: Vec::new(),
+                temporary: Vec::new(),
+                in_flight: VecDeque::new(),
+                next_token: 1,
+                completed: 0,
+            }),
+        })
+    }
+
+    /// Whether copies run on a dedicated transfer queue
+    pub fn has_transfer_queue(&self) -> bool {
+        self.transfer.is_some()
+    }
+
+    /// Queue `data` for upload into `buffer` at `offset`. The buffer needs
+    /// `TRANSFER_DST` usage.
+    #[track_caller]
+    pub fn upload_buffer(
+        &self,
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+        data: &[u8],
+        usage: UploadUse,
+    ) -> Result<UploadToken> {
+        self.queue_copy(
+            data,
+            BUFFER_COPY_ALIGNMENT,
+            CopyDst::Buffer { buffer, offset },
+            usage,
+        )
+    }
+
+    /// Queue `data` for upload into the elements of `buffer` starting at
+    /// `first`. The range is bounds checked and the barriers target the
+    /// stages the buffer's kind is read in.
+    #[track_caller]
+    pub fn upload_typed<T: Pod>(
+        &self,
+        buffer: &TypedBuffer<T>,
+        first: usize,
+        data: &[T],
+    ) -> Result<UploadToken> {
+        let (offset, _) = buffer
+            .element_range(first, data.len())
+            .map_err(|e| VulkanError::General(e.to_string()))?;
+        self.queue_copy(
+            bytemuck::cast_slice(data),
+            BUFFER_COPY_ALIGNMENT,
+            CopyDst::Buffer {
+                buffer: buffer.buffer(),
+                offset,
+            },
+            buffer.kind().into(),
+        )
+    }
+
+    /// Queue `data` for upload into the first mip level and layer of
+    /// `image`. The image needs `TRANSFER_DST` usage, its previous contents
+    /// are discarded and it ends up in `SHADER_READ_ONLY_OPTIMAL` layout.
+    #[track_caller]
+    pub fn upload_image(
+        &self,
+        image: vk::Image,
+        extent: vk::Extent3D,
+        aspect: vk::ImageAspectFlags,
+        data: &[u8],
+    ) -> Result<UploadToken> {
+        self.queue_copy(
+            data,
+            IMAGE_COPY_ALIGNMENT,
+            CopyDst::Image {
+                image,
+                extent,
+                aspect,
+            },
+            UploadUse::SAMPLED,
+        )
+    }
+
+    /// Record and submit everything queued since the last flush. Returns
+    /// the token of the submitted batch, `None` if nothing was queued.
+    pub fn flush(&self) -> Result<Option<UploadToken>> {
+        let mut state = self.state.lock();
+        self.retire(&mut state)?;
+        self.submit(&mut state)
+    }
+
+    /// Whether the uploads that returned `token` have completed
+    pub fn is_complete(&self, token: UploadToken) -> bool {
+        let mut state = self.state.lock();
+        // A failed status query leaves the batch in flight
+        let _ = self.retire(&mut state);
+        token.0 <= state.completed
+    }
+
+    /// Block until the uploads that returned `token` completed, flushing
+    /// them first if they are still queued
+    pub fn wait(&self, token: UploadToken) -> Result<()> {
+        let mut state = self.state.lock();
+        if token.0 >= state.next_token {
+            self.submit(&mut state)?;
+        }
+        while state.completed < token.0 && !state.in_flight.is_empty() {
+            self.wait_oldest(&mut state)?;
+        }
+        Ok(())
+    }
+
+    #[track_caller]
+    fn queue_copy(
+        &self,
+        data: &[u8],
+        alignment: vk::DeviceSize,
+        dst: CopyDst,
+        usage: UploadUse,
+    ) -> Result<UploadToken> {
+        if data.is_empty() {
+            return Ok(UploadToken::COMPLETE);
+        }
+
+        let mut state = self.state.lock();
+        let (src, src_offset) = self.stage(&mut state, data, alignment)?;
+        state.pending.push(PendingCopy {
+            src,
+            src_offset,
+            size: data.len() as vk::DeviceSize,
+            dst,
+            usage,
+        });
+        Ok(UploadToken(state.next_token))
+    }
+
+    /// Copy `data` into staging memory. When the ring is full, waits for
+    /// earlier batches to free it, submitting the queued copies first if
+    /// they are what fills it.
+    #[track_caller]
+    fn stage(
+        &self,
+        state: &mut UploadState,
+        data: &[u8],
+        alignment: vk::DeviceSize,
+    ) -> Result<(vk::Buffer, vk::DeviceSize)> {
+        let size = data.len() as vk::DeviceSize;
+        if size > state.ring.capacity {
+            return self.stage_temporary(state, data);
+        }
+
+        loop {
+            if let Some(offset) = state.ring.reserve(size, alignment) {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        data.as_ptr(),
+                        self.staging_ptr.add(offset as usize),
+                        data.len(),
+                    );
+                }
+                return Ok((self.staging.buffer, offset));
+            }
+
+            if !state.pending.is_empty() {
+                self.submit(state)?;
+            }
+            if state.in_flight.is_empty() {
+                return Err(VulkanError::General(
+                    "Staging ring is empty but the upload doesn't fit".to_string(),
+                ));
+            }
+            self.wait_oldest(state)?;
+        }
+    }
+
+    #[track_caller]
+    fn stage_temporary(
+        &self,
+        state: &mut UploadState,
+        data: &[u8],
+    ) -> Result<(vk::Buffer, vk::DeviceSize)> {
+        let staging = create_staging_buffer(
+            &self.device,
+            &self.allocator,
+            data.len() as vk::DeviceSize,
+            "oversized staging",
+        )?;
+        let ptr = match self.allocator.map(&staging.memory) {
+            Ok(ptr) => ptr as *mut u8,
+            Err(e) => {
+                destroy_staging_buffer(&self.device, &self.allocator, staging);
+                return Err(VulkanError::MemoryMapping(e.to_string()));
+            }
+        };
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+        }
+        self.allocator.unmap(&staging.memory);
+
+        let buffer = staging.buffer;
+        state.temporary.push(staging);
+        Ok((buffer, 0))
+    }
+
+    fn submit(&self, state: &mut UploadState) -> Result<Option<UploadToken>> {
+        if state.pending.is_empty() {
+            return Ok(None);
+        }
+
+        let token = state.next_token;
+        let copies = std::mem::take(&mut state.pending);
+        let transfer_commands = self.allocate_commands(self.transfer_pool)?;
+        let graphics_commands = match self.graphics_pool {
+            Some(pool) => Some(self.allocate_commands(pool)?),
+            None => None,
+        };
+
+        unsafe {
+            self.record_copies(transfer_commands, &copies)?;
+
+            let fence = self
+                .device
+                .create_fence(&vk::FenceCreateInfo::default(), None)
+                .map_err(|e| VulkanError::FenceCreation(e.to_string()))?;
+
+            let semaphore = match (self.transfer, graphics_commands) {
+                (Some(transfer), Some(graphics_commands)) => {
+                    let semaphore = self
+                        .device
+                        .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
+                        .map_err(|e| VulkanError::SemaphoreCreation(e.to_string()))?;
+
+                    let signal = [semaphore];
+                    let commands = [transfer_commands];
+                    let submit_info = vk::SubmitInfo::builder()
+                        .command_buffers(&commands)
+                        .signal_semaphores(&signal)
+                        .build();
+                    self.device
+                        .queue_submit(transfer.queue, &[submit_info], vk::Fence::null())
+                        .map_err(|e| VulkanError::QueueSubmit(e.to_string()))?;
+
+                    // Take ownership on the graphics queue once the copies
+                    // are done
+                    let dst_stage = self.record_acquire(graphics_commands, &copies)?;
+                    let wait_stages = [dst_stage];
+                    let commands = [graphics_commands];
+                    let submit_info = vk::SubmitInfo::builder()
+                        .wait_semaphores(&signal)
+                        .wait_dst_stage_mask(&wait_stages)
+                        .command_buffers(&commands)
+                        .build();
+                    self.device
+                        .queue_submit(self.graphics.queue, &[submit_info], fence)
+                        .map_err(|e| VulkanError::QueueSubmit(e.to_string()))?;
+                    Some(semaphore)
+                }
+                _ => {
+                    let commands = [transfer_commands];
+                    let submit_info = vk::SubmitInfo::builder().command_buffers(&commands).build();
+                    self.device
+                        .queue_submit(self.graphics.queue, &[submit_info], fence)
+                        .map_err(|e| VulkanError::QueueSubmit(e.to_string()))?;
+                    None
+                }
+            };
+
+            state.in_flight.push_back(Batch {
+                token,
+                fence,
+                transfer_commands,
+                graphics_commands,
+                semaphore,
+                ring_end: state.ring.head,
+                temporary: std::mem::take(&mut state.temporary),
+            });
+        }
+
+        state.next_token += 1;
+        Ok(Some(UploadToken(token)))
+    }
+
+    fn allocate_commands(&self, pool: vk::CommandPool) -> Result<vk::CommandBuffer> {
+        let alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1)
+            .build();
+        let commands = unsafe {
+            self.device
+                .allocate_command_buffers(&alloc_info)
+                .map_err(|e| VulkanError::CommandBufferAllocation(e.to_string()))?[0]
+        };
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+            .build();
+        unsafe {
+            self.device
+                .begin_command_buffer(commands, &begin_info)
+                .map_err(|e| VulkanError::CommandBufferBegin(e.to_string()))?;
+        }
+        Ok(commands)
+    }
+
+    /// Layout transitions, copies and the barriers after them
+    unsafe fn record_copies(
+        &self,
+        commands: vk::CommandBuffer,
+        copies: &[PendingCopy],
+    ) -> Result<()> {
+        // Images start out in TRANSFER_DST_OPTIMAL, discarding old contents
+        let to_transfer: Vec<_> = copies
+            .iter()
+            .filter_map(|copy| match copy.dst {
+                CopyDst::Image { image, aspect, .. } => Some(
+                    vk::ImageMemoryBarrier::builder()
+                        .old_layout(vk::ImageLayout::UNDEFINED)
+                        .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                        .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .image(image)
+                        .subresource_range(subresource_range(aspect))
+                        .build(),
+                ),
+                CopyDst::Buffer { .. } => None,
+            })
+            .collect();
+        if !to_transfer.is_empty() {
+            self.device.cmd_pipeline_barrier(
+                commands,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &to_transfer,
+            );
+        }
+
+        // Consecutive copies between the same buffers share one command
+        let mut regions: Vec<vk::BufferCopy> = Vec::new();
+        for (index, copy) in copies.iter().enumerate() {
+            match copy.dst {
+                CopyDst::Buffer { buffer, offset } => {
+                    regions.push(vk::BufferCopy {
+                        src_offset: copy.src_offset,
+                        dst_offset: offset,
+                        size: copy.size,
+                    });
+                    let next_matches = copies.get(index + 1).is_some_and(|next| {
+                        next.src == copy.src
+                            && matches!(next.dst, CopyDst::Buffer { buffer: b, .. } if b == buffer)
+                    });
+                    if !next_matches {
+                        self.device
+                            .cmd_copy_buffer(commands, copy.src, buffer, &regions);
+                        regions.clear();
+                    }
+                }
+                CopyDst::Image {
+                    image,
+                    extent,
+                    aspect,
+                } => {
+                    let region = vk::BufferImageCopy::builder()
+                        .buffer_offset(copy.src_offset)
+                        .image_subresource(vk::ImageSubresourceLayers {
+                            aspect_mask: aspect,
+                            mip_level: 0,
+                            base_array_layer: 0,
+                            layer_count: 1,
+                        })
+                        .image_extent(extent)
+                        .build();
+                    self.device.cmd_copy_buffer_to_image(
+                        commands,
+                        copy.src,
+                        image,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        &[region],
+                    );
+                }
+            }
+        }
+
+        let half = if self.transfer.is_some() {
+            BarrierHalf::Release
+        } else {
+            BarrierHalf::Full
+        };
+        let (buffer_barriers, image_barriers, dst_stage) = self.post_copy_barriers(copies, half);
+        self.device.cmd_pipeline_barrier(
+            commands,
+            vk::PipelineStageFlags::TRANSFER,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &buffer_barriers,
+            &image_barriers,
+        );
+
+        self.device
+            .end_command_buffer(commands)
+            .map_err(|e| VulkanError::CommandBufferEnd(e.to_string()))
+    }
+
+    /// Records the acquire half of the ownership transfer, returning the
+    /// stages that wait for it
+    unsafe fn record_acquire(
+        &self,
+        commands: vk::CommandBuffer,
+        copies: &[PendingCopy],
+    ) -> Result<vk::PipelineStageFlags> {
+        let (buffer_barriers, image_barriers, dst_stage) =
+            self.post_copy_barriers(copies, BarrierHalf::Acquire);
+        self.device.cmd_pipeline_barrier(
+            commands,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &buffer_barriers,
+            &image_barriers,
+        );
+        self.device
+            .end_command_buffer(commands)
+            .map_err(|e| VulkanError::CommandBufferEnd(e.to_string()))?;
+        Ok(dst_stage)
+    }
+
+    /// Barriers making the copies visible to their uses, moving images to
+    /// `SHADER_READ_ONLY_OPTIMAL`, and the stages they block
+    fn post_copy_barriers(
+        &self,
+        copies: &[PendingCopy],
+        half: BarrierHalf,
+    ) -> (
+        Vec<vk::BufferMemoryBarrier>,
+        Vec<vk::ImageMemoryBarrier>,
+        vk::PipelineStageFlags,
+    ) {
+        let (src_family, dst_family) = match (half, self.transfer) {
+            (BarrierHalf::Full, _) | (_, None) => {
+                (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED)
+            }
+            (_, Some(transfer)) => (transfer.family_index, self.graphics.family_index),
+        };
+        // The release half only makes the writes available; what they are
+        // made visible to is up to the acquire
+        let src_access = match half {
+            BarrierHalf::Acquire => vk::AccessFlags::empty(),
+            _ => vk::AccessFlags::TRANSFER_WRITE,
+        };
+        let dst_access = |usage: UploadUse| match half {
+            BarrierHalf::Release => vk::AccessFlags::empty(),
+            _ => usage.access,
+        };
+
+        let mut buffer_barriers = Vec::new();
+        let mut image_barriers = Vec::new();
+        let mut dst_stage = vk::PipelineStageFlags::empty();
+        for copy in copies {
+            dst_stage |= copy.usage.stage;
+            match copy.dst {
+                CopyDst::Buffer { buffer, offset } => buffer_barriers.push(
+                    vk::BufferMemoryBarrier::builder()
+                        .src_access_mask(src_access)
+                        .dst_access_mask(dst_access(copy.usage))
+                        .src_queue_family_index(src_family)
+                        .dst_queue_family_index(dst_family)
+                        .buffer(buffer)
+                        .offset(offset)
+                        .size(copy.size)
+                        .build(),
+                ),
+                CopyDst::Image { image, aspect, .. } => image_barriers.push(
+                    vk::ImageMemoryBarrier::builder()
+                        .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                        .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                        .src_access_mask(src_access)
+                        .dst_access_mask(dst_access(copy.usage))
+                        .src_queue_family_index(src_family)
+                        .dst_queue_family_index(dst_family)
+                        .image(image)
+                        .subresource_range(subresource_range(aspect))
+                        .build(),
+                ),
+            }
+        }
+
+        if half == BarrierHalf::Release {
+            dst_stage = vk::PipelineStageFlags::BOTTOM_OF_PIPE;
+        }
+        (buffer_barriers, image_barriers, dst_stage)
+    }
+
+    /// Release the batches that completed, oldest first
+    fn retire(&self, state: &mut UploadState) -> Result<()> {
+        while let Some(batch) = state.in_flight.front() {
+            let done = unsafe {
+                self.device
+                    .get_fence_status(batch.fence)
+                    .map_err(|e| VulkanError::SyncError(e.to_string()))?
+            };
+            if !done {
+                break;
+            }
+            let batch = state.in_flight.pop_front().unwrap();
+            state.ring.release(batch.ring_end);
+            state.completed = batch.token;
+            self.destroy_batch(batch);
+        }
+        Ok(())
+    }
+
+    fn wait_oldest(&self, state: &mut UploadState) -> Result<()> {
+        if let Some(batch) = state.in_flight.front() {
+            unsafe {
+                self.device
+                    .wait_for_fences(&[batch.fence], true, u64::MAX)
+                    .map_err(|e| VulkanError::SyncError(e.to_string()))?;
+            }
+        }
+        self.retire(state)
+    }
+
+    fn destroy_batch(&self, batch: Batch) {
+        unsafe {
+            self.device.destroy_fence(batch.fence, None);
+            if let Some(semaphore) = batch.semaphore {
+                self.device.destroy_semaphore(semaphore, None);
+            }
+            self.device
+                .free_command_buffers(self.transfer_pool, &[batch.transfer_commands]);
+            if let (Some(pool), Some(commands)) = (self.graphics_pool, batch.graphics_commands) {
+                self.device.free_command_buffers(pool, &[commands]);
+            }
+        }
+        for staging in batch.temporary {
+            destroy_staging_buffer(&self.device, &self.allocator, staging);
+        }
+    }
+}
+
+impl Drop for UploadManager {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+        let batches: Vec<Batch> = state.in_flight.drain(..).collect();
+        let temporary: Vec<TemporaryStaging> = state.temporary.drain(..).collect();
+        for batch in batches {
+            unsafe {
+                let _ = self.device.wait_for_fences(&[batch.fence], true, u64::MAX);
+            }
+            self.destroy_batch(batch);
+        }
+        for staging in temporary {
+            destroy_staging_buffer(&self.device, &self.allocator, staging);
+        }
+
+        unsafe {
+            self.device.destroy_command_pool(self.transfer_pool, None);
+            if let Some(pool) = self.graphics_pool {
+                self.device.destroy_command_pool(pool, None);
+            }
+            self.device.destroy_buffer(self.staging.buffer, None);
+        }
+        self.allocator.unmap(&self.staging.memory);
+        let _ = self.allocator.free(self.staging.memory);
+    }
+}
+
+fn subresource_range(aspect: vk::ImageAspectFlags) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: aspect,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
+
+fn create_command_pool(device: &ash::Device, family_index: u32) -> Result<vk::CommandPool> {
+    let pool_info = vk::CommandPoolCreateInfo::builder()
+        .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+        .queue_family_index(family_index)
+        .build();
+    unsafe {
+        device
+            .create_command_pool(&pool_info, None)
+            .map_err(|e| VulkanError::CommandPoolCreation(e.to_string()))
+    }
+}
+
+#[track_caller]
+fn create_staging_buffer(
+    device: &ash::Device,
+    allocator: &MemoryAllocator,
+    size: vk::DeviceSize,
+    name: &'static str,
+) -> Result<TemporaryStaging> {
+    let buffer_info = vk::BufferCreateInfo::builder()
+        .size(size)
+        .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .build();
+    let buffer = unsafe {
+        device
+            .create_buffer(&buffer_info, None)
+            .map_err(|e| VulkanError::BufferCreation(e.to_string()))?
+    };
+
+    let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+    let memory = allocator
+        .allocate_named(
+            requirements,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            MemoryTag::Transfer,
+            name,
+        )
+        .map_err(|e| {
+            unsafe { device.destroy_buffer(buffer, None) };
+            VulkanError::MemoryAllocation(e.to_string())
+        })?;
+
+    let staging = TemporaryStaging { buffer, memory };
+    unsafe {
+        if let Err(e) = device.bind_buffer_memory(buffer, memory.memory, memory.offset) {
+            destroy_staging_buffer(device, allocator, staging);
+            return Err(VulkanError::MemoryBinding(e.to_string()));
+        }
+    }
+    Ok(staging)
+}
+
+fn destroy_staging_buffer(
+    device: &ash::Device,
+    allocator: &MemoryAllocator,
+    staging: TemporaryStaging,
+) {
+    unsafe {
+        device.destroy_buffer(staging.buffer, None);
+    }
+    let _ = allocator.free(staging.memory);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staging_ring_alignment_and_wrap() {
+        let mut ring = StagingRing::new(256);
+        assert_eq!(ring.reserve(10, 4), Some(0));
+        assert_eq!(ring.reserve(10, 16), Some(16));
+        assert_eq!(ring.reserve(200, 4), Some(28));
+
+        // Everything up to the end is in use until released
+        assert_eq!(ring.reserve(64, 4), None);
+        ring.release(26);
+        assert_eq!(ring.reserve(64, 4), None);
+        ring.release(228);
+
+        // Doesn't straddle the end, starts over at zero
+        assert_eq!(ring.reserve(64, 4), Some(0));
+        assert_eq!(ring.head, 256 + 64);
+        assert_eq!(ring.reserve(164, 4), Some(64));
+        assert_eq!(ring.reserve(1, 1), None);
+    }
+
+    #[test]
+    fn test_staging_ring_whole_capacity() {
+        let mut ring = StagingRing::new(256);
+        assert_eq!(ring.reserve(257, 1), None);
+        assert_eq!(ring.reserve(256, 16), Some(0));
+        assert_eq!(ring.reserve(1, 1), None);
+        ring.release(ring.head);
+        assert_eq!(ring.reserve(256, 16), Some(0));
+    }
+
+    #[test]
+    fn test_upload_use_from_buffer_type() {
+        assert_eq!(UploadUse::from(BufferType::Vertex), UploadUse::VERTEX);
+        assert_eq!(UploadUse::from(BufferType::Index), UploadUse::INDEX);
+        assert_eq!(
+            UploadUse::from(BufferType::TransformStorage),
+            UploadUse::STORAGE
+        );
+        assert!(UploadToken::COMPLETE < UploadToken(1));
+    }
+}
@@ -6,7 +6,7 @@ use std::io::Read;
 use std::path::Path;
 use std::sync::Arc;
 
-static MAIN_ENTRY_POINT: &CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"main\0") };
+static MAIN_ENTRY_POINT: &CStr = c"main";
 
 pub struct ShaderModule {
     device: Arc<Device>,
@@ -155,7 +155,7 @@ impl ShaderSet {
 
 impl Drop for ShaderSet {
     fn drop(&mut self) {
-        if let Ok(_) = self.wait_idle() {
+        if self.wait_idle().is_ok() {
             self.vertex.take();
             self.fragment.take();
         }
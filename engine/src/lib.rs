@@ -1,6 +1,8 @@
 //! AshEngine - A Vulkan-based graphics engine written in Rust
 
 pub mod config;
+pub mod ecs;
+pub mod error;
 pub mod graphics;
 pub mod lighting;
 pub mod log_error;
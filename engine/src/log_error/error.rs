@@ -60,6 +60,7 @@ pub fn system_err(msg: impl Into<String>) -> Error {
 }
 
 #[cfg(debug_assertions)]
+#[allow(dead_code)]
 pub(crate) fn debug_err(msg: impl Into<String>) -> Error {
     Error::System(format!("Debug error: {}", msg.into()))
 }
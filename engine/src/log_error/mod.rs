@@ -23,6 +23,8 @@ pub enum LogLevel {
 
 static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
+mod error;
+
 // Re-exports
 pub use self::error::*;
 
@@ -32,7 +34,7 @@ mod debug {
     use crate::log_error::LogConfig;
     use log::{debug, error, info, trace, warn};
 
-    pub fn init(config: LogConfig) -> Result<(), Error> {
+    pub fn init(config: LogConfig) -> Result<()> {
         if INITIALIZED.swap(true, Ordering::SeqCst) {
             return Err(Error::AlreadyInitialized);
         }
@@ -80,7 +82,7 @@ mod release {
     use crate::log_error::LogConfig;
 
     #[inline(always)]
-    pub fn init(_config: LogConfig) -> Result<(), Error> {
+    pub fn init(_config: LogConfig) -> Result<()> {
         Ok(())
     }
 
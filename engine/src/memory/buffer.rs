@@ -1,25 +1,29 @@
 use ash::vk;
-use log::debug;
+use bytemuck::Pod;
+use log::{debug, error};
 use std::sync::Arc;
 
-use super::{MemoryAllocator, MemoryBlock};
-use crate::context::Context;
+use super::{MemoryAllocator, MemoryBlock, MemoryTag};
 use crate::error::{Result, VulkanError};
+use crate::graphics::context::Context;
 
 pub struct Buffer {
     buffer: vk::Buffer,
     memory_block: MemoryBlock,
     size: u64,
     context: Arc<Context>,
+    allocator: Arc<MemoryAllocator>,
 }
 
 impl Buffer {
+    #[track_caller]
     pub fn new(
         context: Arc<Context>,
-        allocator: &MemoryAllocator,
+        allocator: Arc<MemoryAllocator>,
         size: u64,
         usage: vk::BufferUsageFlags,
         properties: vk::MemoryPropertyFlags,
+        tag: MemoryTag,
     ) -> Result<Self> {
         let device = context.device();
 
@@ -36,12 +40,22 @@ impl Buffer {
         };
 
         let memory_requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
-        let memory_block = allocator.allocate(size, memory_requirements, properties)?;
+        let memory_block = match allocator.allocate(memory_requirements, properties, tag) {
+            Ok(block) => block,
+            Err(e) => {
+                unsafe { device.destroy_buffer(buffer, None) };
+                return Err(VulkanError::MemoryAllocation(e.to_string()));
+            }
+        };
 
         unsafe {
-            device
-                .bind_buffer_memory(buffer, memory_block.memory, memory_block.offset)
-                .map_err(|e| VulkanError::MemoryBinding(e.to_string()))?;
+            if let Err(e) =
+                device.bind_buffer_memory(buffer, memory_block.memory, memory_block.offset)
+            {
+                device.destroy_buffer(buffer, None);
+                let _ = allocator.free(memory_block);
+                return Err(VulkanError::MemoryBinding(e.to_string()));
+            }
         }
 
         debug!(
@@ -54,6 +68,7 @@ impl Buffer {
             memory_block,
             size,
             context,
+            allocator,
         })
     }
 
@@ -73,28 +88,39 @@ impl Buffer {
         self.memory_block.offset
     }
 
-    pub fn map<T>(&self) -> Result<BufferView<T>> {
+    // Views the buffer as `T`s. The buffer size must be a whole number of
+    // them; use `TypedBuffer` to have shader layouts checked as well.
+    pub fn map<T: Pod>(&self) -> Result<BufferView<'_, T>> {
         if self.size == 0 {
             return Err(VulkanError::MemoryMapping(
                 "Cannot map empty buffer".to_string(),
             ));
         }
+        let element_size = std::mem::size_of::<T>() as u64;
+        if element_size == 0 || !self.size.is_multiple_of(element_size) {
+            return Err(VulkanError::MemoryMapping(format!(
+                "Buffer of {} bytes is not a whole number of {} byte elements",
+                self.size, element_size
+            )));
+        }
 
-        let ptr = unsafe {
-            self.context
-                .device()
-                .map_memory(
-                    self.memory_block.memory,
-                    self.memory_block.offset,
-                    self.size,
-                    vk::MemoryMapFlags::empty(),
-                )
-                .map_err(|e| VulkanError::MemoryMapping(e.to_string()))?
-        };
+        // Goes through the allocator, other buffers may share the memory
+        let ptr = self
+            .allocator
+            .map(&self.memory_block)
+            .map_err(|e| VulkanError::MemoryMapping(e.to_string()))?;
+
+        if !(ptr as usize).is_multiple_of(std::mem::align_of::<T>()) {
+            self.allocator.unmap(&self.memory_block);
+            return Err(VulkanError::MemoryMapping(format!(
+                "Mapped memory is not aligned for {} byte aligned elements",
+                std::mem::align_of::<T>()
+            )));
+        }
 
         Ok(BufferView {
             ptr: ptr as *mut T,
-            len: (self.size / std::mem::size_of::<T>() as u64) as usize,
+            len: (self.size / element_size) as usize,
             buffer: self,
         })
     }
@@ -105,6 +131,9 @@ impl Drop for Buffer {
         unsafe {
             self.context.device().destroy_buffer(self.buffer, None);
         }
+        if let Err(e) = self.allocator.free(self.memory_block) {
+            error!("Failed to free buffer memory: {}", e);
+        }
     }
 }
 
@@ -126,12 +155,7 @@ impl<'a, T> BufferView<'a, T> {
 
 impl<'a, T> Drop for BufferView<'a, T> {
     fn drop(&mut self) {
-        unsafe {
-            self.buffer
-                .context
-                .device()
-                .unmap_memory(self.buffer.memory_block.memory);
-        }
+        self.buffer.allocator.unmap(&self.buffer.memory_block);
     }
 }
 
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use super::typed::LayoutError;
+
 #[derive(Error, Debug)]
 pub enum MemoryError {
     #[error("Failed to allocate memory: {0}")]
@@ -28,6 +30,12 @@ pub enum MemoryError {
 
     #[error("Invalid memory operation: {0}")]
     InvalidOperation(String),
+
+    #[error("Waiting for fence failed: {0}")]
+    FenceWaitFailed(String),
+
+    #[error("Invalid buffer layout: {0}")]
+    InvalidLayout(LayoutError),
 }
 
 pub type Result<T> = std::result::Result<T, MemoryError>;
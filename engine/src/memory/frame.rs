@@ -0,0 +1,449 @@
+use ash::vk;
+use bytemuck::Pod;
+use log::debug;
+use std::sync::{Arc, Mutex};
+
+use super::error::{MemoryError, Result};
+use super::pool::{align_up, MemoryDevice};
+use super::{MemoryAllocator, MemoryBlock, MemoryTag};
+
+const DEFAULT_FRAME_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
+// The buffer and fence calls the frame allocator makes on top of the memory
+// ones. Implemented by `ash::Device`; tests use a mock.
+pub trait FrameDevice: MemoryDevice {
+    fn create_buffer(
+        &self,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> std::result::Result<vk::Buffer, vk::Result>;
+    fn buffer_memory_requirements(&self, buffer: vk::Buffer) -> vk::MemoryRequirements;
+    fn bind_buffer_memory(
+        &self,
+        buffer: vk::Buffer,
+        memory: vk::DeviceMemory,
+        offset: vk::DeviceSize,
+    ) -> std::result::Result<(), vk::Result>;
+    fn destroy_buffer(&self, buffer: vk::Buffer);
+    // Blocks until `fence` is signaled
+    fn wait_for_fence(&self, fence: vk::Fence) -> std::result::Result<(), vk::Result>;
+}
+
+impl FrameDevice for ash::Device {
+    fn create_buffer(
+        &self,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> std::result::Result<vk::Buffer, vk::Result> {
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .build();
+        unsafe { ash::Device::create_buffer(self, &buffer_info, None) }
+    }
+
+    fn buffer_memory_requirements(&self, buffer: vk::Buffer) -> vk::MemoryRequirements {
+        unsafe { self.get_buffer_memory_requirements(buffer) }
+    }
+
+    fn bind_buffer_memory(
+        &self,
+        buffer: vk::Buffer,
+        memory: vk::DeviceMemory,
+        offset: vk::DeviceSize,
+    ) -> std::result::Result<(), vk::Result> {
+        unsafe { ash::Device::bind_buffer_memory(self, buffer, memory, offset) }
+    }
+
+    fn destroy_buffer(&self, buffer: vk::Buffer) {
+        unsafe { ash::Device::destroy_buffer(self, buffer, None) }
+    }
+
+    fn wait_for_fence(&self, fence: vk::Fence) -> std::result::Result<(), vk::Result> {
+        unsafe { self.wait_for_fences(&[fence], true, u64::MAX) }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameAllocatorConfig {
+    // One arena per frame in flight
+    pub frames_in_flight: usize,
+    // Size of the blocks an arena chains; larger requests get a block of
+    // their own size
+    pub block_size: u64,
+    pub usage: vk::BufferUsageFlags,
+    // Applied to every allocation on top of the requested alignment. Set it
+    // to `minUniformBufferOffsetAlignment` when binding uniforms from here.
+    pub min_alignment: u64,
+    pub tag: MemoryTag,
+}
+
+impl Default for FrameAllocatorConfig {
+    fn default() -> Self {
+        Self {
+            frames_in_flight: 2,
+            block_size: DEFAULT_FRAME_BLOCK_SIZE,
+            usage: vk::BufferUsageFlags::UNIFORM_BUFFER
+                | vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::VERTEX_BUFFER
+                | vk::BufferUsageFlags::INDEX_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_SRC,
+            min_alignment: 16,
+            tag: MemoryTag::Graphics,
+        }
+    }
+}
+
+// Space handed out by `FrameAllocator::allocate`, valid until the frame it
+// was allocated in comes around again
+pub struct FrameAllocation<'a> {
+    pub buffer: vk::Buffer,
+    pub offset: vk::DeviceSize,
+    pub data: &'a mut [u8],
+}
+
+// A persistently mapped buffer an arena allocates from
+struct FrameBlock {
+    buffer: vk::Buffer,
+    memory: MemoryBlock,
+    mapped: *mut u8,
+    size: u64,
+}
+
+#[derive(Default)]
+struct FrameArena {
+    blocks: Vec<FrameBlock>,
+    // Block being filled and the first free byte in it
+    block_index: usize,
+    offset: u64,
+    used_size: u64,
+    // Signals once the GPU is done with this frame's data
+    fence: Option<vk::Fence>,
+}
+
+impl FrameArena {
+    // Blocks written since the last reset
+    fn blocks_in_use(&self) -> usize {
+        if self.used_size == 0 {
+            0
+        } else {
+            self.block_index + 1
+        }
+    }
+}
+
+// Frame-scoped linear allocators for uniform, vertex and staging data, one
+// arena per frame in flight. Allocation bumps an offset in the current
+// arena's mapped blocks and chains another block on overflow. An arena is
+// reset by `begin_frame` once the fence of its previous use signaled, so
+// data is never overwritten while the GPU may still read it.
+//
+//     frames.begin_frame(frame_index)?; // waits on the fence from last time
+//     let (buffer, offset) = frames.upload(&transforms, 0)?;
+//     ...record and submit with `fence`...
+//     frames.end_frame(fence);
+pub struct FrameAllocator<D: FrameDevice = ash::Device> {
+    allocator: Arc<MemoryAllocator<D>>,
+    config: FrameAllocatorConfig,
+    frames: Mutex<Vec<FrameArena>>,
+    current: usize,
+}
+
+// The mapped pointers are only written through disjoint allocations, and the
+// arenas sit behind a mutex
+unsafe impl<D: FrameDevice> Send for FrameAllocator<D> {}
+unsafe impl<D: FrameDevice> Sync for FrameAllocator<D> {}
+
+impl<D: FrameDevice> FrameAllocator<D> {
+    pub fn new(allocator: Arc<MemoryAllocator<D>>, config: FrameAllocatorConfig) -> Self {
+        assert!(config.frames_in_flight > 0, "need at least one frame");
+        Self {
+            allocator,
+            config,
+            frames: Mutex::new(
+                (0..config.frames_in_flight)
+                    .map(|_| FrameArena::default())
+                    .collect(),
+            ),
+            current: 0,
+        }
+    }
+
+    pub fn config(&self) -> FrameAllocatorConfig {
+        self.config
+    }
+
+    pub fn current_frame(&self) -> usize {
+        self.current
+    }
+
+    // Makes `frame` the arena to allocate from. Waits for the fence passed to
+    // `end_frame` the last time this frame was recorded, so call it before
+    // resetting that fence. Blocks chained on overflow are kept for reuse as
+    // long as the frame keeps needing them.
+    pub fn begin_frame(&mut self, frame: usize) -> Result<()> {
+        let frames = self.frames.get_mut().unwrap();
+        assert!(frame < frames.len(), "frame {} out of range", frame);
+        self.current = frame;
+
+        let arena = &mut frames[frame];
+        if let Some(fence) = arena.fence.take() {
+            self.allocator
+                .device()
+                .wait_for_fence(fence)
+                .map_err(|e| MemoryError::FenceWaitFailed(e.to_string()))?;
+        }
+
+        let keep = arena.blocks_in_use().max(1);
+        for block in arena.blocks.drain(keep.min(arena.blocks.len())..) {
+            debug!("Releasing unused frame block of {} bytes", block.size);
+            Self::destroy_block(&self.allocator, block);
+        }
+        arena.block_index = 0;
+        arena.offset = 0;
+        arena.used_size = 0;
+        Ok(())
+    }
+
+    // Records the fence the current frame's work was submitted with
+    pub fn end_frame(&mut self, fence: vk::Fence) {
+        self.frames.get_mut().unwrap()[self.current].fence = Some(fence);
+    }
+
+    // `size` bytes in the current frame, aligned to `alignment` and the
+    // configured minimum. The returned slice is mapped device memory.
+    #[track_caller]
+    pub fn allocate(&self, size: u64, alignment: u64) -> Result<FrameAllocation<'_>> {
+        let alignment = alignment.max(self.config.min_alignment);
+        let mut frames = self.frames.lock().unwrap();
+        let arena = &mut frames[self.current];
+
+        loop {
+            if arena.block_index == arena.blocks.len() {
+                let block = self.create_block(size.max(self.config.block_size))?;
+                arena.blocks.push(block);
+            }
+
+            let block = &arena.blocks[arena.block_index];
+            let offset = align_up(arena.offset, alignment);
+            if offset + size <= block.size {
+                arena.offset = offset + size;
+                arena.used_size += size;
+                // Allocations never overlap and the arena is only reset
+                // through `&mut self`, so no other reference to these bytes
+                // exists while the borrow lives
+                let data = unsafe {
+                    std::slice::from_raw_parts_mut(block.mapped.add(offset as usize), size as usize)
+                };
+                return Ok(FrameAllocation {
+                    buffer: block.buffer,
+                    offset,
+                    data,
+                });
+            }
+
+            // Chain the next block
+            arena.block_index += 1;
+            arena.offset = 0;
+        }
+    }
+
+    // Copies `data` into the current frame, returning where it landed
+    #[track_caller]
+    pub fn upload<T: Pod>(
+        &self,
+        data: &[T],
+        alignment: u64,
+    ) -> Result<(vk::Buffer, vk::DeviceSize)> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        let alignment = alignment.max(std::mem::align_of::<T>() as u64);
+        let allocation = self.allocate(bytes.len() as u64, alignment)?;
+        allocation.data.copy_from_slice(bytes);
+        Ok((allocation.buffer, allocation.offset))
+    }
+
+    // Bytes allocated in the current frame
+    pub fn used_size(&self) -> u64 {
+        self.frames.lock().unwrap()[self.current].used_size
+    }
+
+    // Blocks held by the current frame's arena
+    pub fn block_count(&self) -> usize {
+        self.frames.lock().unwrap()[self.current].blocks.len()
+    }
+
+    #[track_caller]
+    fn create_block(&self, size: u64) -> Result<FrameBlock> {
+        let device = self.allocator.device();
+        let buffer = device
+            .create_buffer(size, self.config.usage)
+            .map_err(|e| MemoryError::AllocationFailed(e.to_string()))?;
+        let requirements = device.buffer_memory_requirements(buffer);
+
+        let memory = match self.allocator.allocate_named(
+            requirements,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            self.config.tag,
+            "frame block",
+        ) {
+            Ok(memory) => memory,
+            Err(e) => {
+                device.destroy_buffer(buffer);
+                return Err(e);
+            }
+        };
+
+        let mapped = device
+            .bind_buffer_memory(buffer, memory.memory, memory.offset)
+            .map_err(|e| MemoryError::AllocationFailed(e.to_string()))
+            .and_then(|_| self.allocator.map(&memory));
+        let mapped = match mapped {
+            Ok(mapped) => mapped as *mut u8,
+            Err(e) => {
+                device.destroy_buffer(buffer);
+                let _ = self.allocator.free(memory);
+                return Err(e);
+            }
+        };
+
+        debug!("Created frame block of {} bytes", size);
+        Ok(FrameBlock {
+            buffer,
+            memory,
+            mapped,
+            size,
+        })
+    }
+
+    fn destroy_block(allocator: &MemoryAllocator<D>, block: FrameBlock) {
+        allocator.unmap(&block.memory);
+        allocator.device().destroy_buffer(block.buffer);
+        let _ = allocator.free(block.memory);
+    }
+}
+
+impl<D: FrameDevice> Drop for FrameAllocator<D> {
+    fn drop(&mut self) {
+        for arena in self.frames.get_mut().unwrap().drain(..) {
+            // The GPU may still read the blocks
+            if let Some(fence) = arena.fence {
+                let _ = self.allocator.device().wait_for_fence(fence);
+            }
+            for block in arena.blocks {
+                Self::destroy_block(&self.allocator, block);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::mock::MockDevice;
+    use crate::memory::AllocatorConfig;
+    use ash::vk::Handle;
+
+    const BLOCK_SIZE: u64 = 64 * 1024;
+
+    fn frames(frames_in_flight: usize) -> (Arc<MockDevice>, FrameAllocator<MockDevice>) {
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties::default();
+        memory_properties.memory_types[0].property_flags =
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        memory_properties.memory_type_count = 1;
+
+        let device = Arc::new(MockDevice::default());
+        let allocator = Arc::new(MemoryAllocator::with_device(
+            device.clone(),
+            memory_properties,
+            AllocatorConfig {
+                block_size: 1024 * 1024,
+                dedicated_threshold: 512 * 1024,
+            },
+        ));
+        let frames = FrameAllocator::new(
+            allocator,
+            FrameAllocatorConfig {
+                frames_in_flight,
+                block_size: BLOCK_SIZE,
+                ..Default::default()
+            },
+        );
+        (device, frames)
+    }
+
+    #[test]
+    fn test_linear_allocation() {
+        let (_device, mut frames) = frames(2);
+        frames.begin_frame(0).unwrap();
+
+        let a = frames.allocate(100, 1).unwrap();
+        a.data.fill(1);
+        let (a_buffer, a_offset) = (a.buffer, a.offset);
+        let b = frames.allocate(64, 256).unwrap();
+        assert_eq!(b.buffer, a_buffer);
+        assert_eq!(a_offset, 0);
+        assert_eq!(b.offset, 256);
+        assert!(b.data.iter().all(|&byte| byte == 0));
+
+        // Uploads honour the minimum alignment
+        let (_, offset) = frames.upload(&[1.0f32, 2.0, 3.0], 4).unwrap();
+        assert_eq!(offset, 320);
+        assert_eq!(frames.used_size(), 100 + 64 + 12);
+    }
+
+    #[test]
+    fn test_overflow_chains_blocks() {
+        let (device, mut frames) = frames(1);
+        frames.begin_frame(0).unwrap();
+        let first = frames.allocate(BLOCK_SIZE - 16, 1).unwrap().buffer;
+        let second = frames.allocate(64, 1).unwrap();
+        assert_ne!(second.buffer, first);
+        assert_eq!(second.offset, 0);
+
+        // Oversized requests get a block of their own
+        let large = frames.allocate(3 * BLOCK_SIZE, 1).unwrap();
+        assert_eq!(large.data.len() as u64, 3 * BLOCK_SIZE);
+        assert_eq!(frames.block_count(), 3);
+        assert_eq!(device.buffer_count(), 3);
+
+        // Blocks the frame keeps using are reused, the rest released
+        frames.begin_frame(0).unwrap();
+        assert_eq!(frames.block_count(), 3);
+        assert_eq!(frames.allocate(16, 1).unwrap().buffer, first);
+        frames.begin_frame(0).unwrap();
+        assert_eq!(frames.block_count(), 1);
+        assert_eq!(device.buffer_count(), 1);
+
+        drop(frames);
+        assert_eq!(device.buffer_count(), 0);
+        assert_eq!(device.mapped_count(), 0);
+    }
+
+    #[test]
+    fn test_reset_waits_for_frame_fence() {
+        let (device, mut frames) = frames(2);
+        let fences: Vec<_> = (1..=2).map(vk::Fence::from_raw).collect();
+
+        frames.begin_frame(0).unwrap();
+        let (frame0, _) = frames.upload(&[7u32; 4], 0).unwrap();
+        frames.end_frame(fences[0]);
+
+        frames.begin_frame(1).unwrap();
+        let (frame1, _) = frames.upload(&[9u32; 4], 0).unwrap();
+        assert_ne!(frame0, frame1);
+        frames.end_frame(fences[1]);
+        assert!(device.waited_fences().is_empty());
+
+        // Frame 0 comes around again: only its own fence is waited on
+        frames.begin_frame(0).unwrap();
+        assert_eq!(device.waited_fences(), [fences[0]]);
+        assert_eq!(frames.used_size(), 0);
+        let allocation = frames.allocate(16, 1).unwrap();
+        assert_eq!((allocation.buffer, allocation.offset), (frame0, 0));
+
+        // Fences still pending are waited on before teardown
+        drop(frames);
+        assert_eq!(device.waited_fences(), fences);
+    }
+}
@@ -1,25 +1,134 @@
+use ash::vk;
 use log::{debug, error, info, warn};
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::panic::Location;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-#[derive(Debug, Default)]
+use super::{MemoryBlock, MemoryTag, Relocation};
+
+#[derive(Debug)]
 pub struct MemoryLogger {
     allocation_count: AtomicUsize,
     deallocation_count: AtomicUsize,
     total_allocated: AtomicU64,
     total_freed: AtomicU64,
     peak_usage: AtomicU64,
+    tags: Mutex<HashMap<MemoryTag, TagStats>>,
+    // Every live allocation, keyed by memory and offset
+    records: Mutex<HashMap<(vk::DeviceMemory, u64), AllocationRecord>>,
     start_time: Instant,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct MemoryLogStats {
+// A live allocation and where it was made
+#[derive(Debug, Clone)]
+pub struct AllocationRecord {
+    // Order of the allocation over the allocator's lifetime
+    pub id: usize,
+    pub memory: vk::DeviceMemory,
+    pub offset: u64,
+    pub size: u64,
+    pub memory_type_index: u32,
+    pub tag: MemoryTag,
+    pub name: &'static str,
+    pub location: &'static Location<'static>,
+    pub dedicated: bool,
+    pub allocated_at: Instant,
+    // Only captured in debug builds with RUST_BACKTRACE set
+    pub backtrace: Option<Arc<Backtrace>>,
+}
+
+// Allocations still alive, grouped by tag. Logged when the allocator is
+// dropped.
+#[derive(Debug, Clone, Default)]
+pub struct LeakReport {
+    pub by_tag: HashMap<MemoryTag, Vec<AllocationRecord>>,
+}
+
+impl LeakReport {
+    pub fn new(records: Vec<AllocationRecord>) -> Self {
+        let mut by_tag: HashMap<MemoryTag, Vec<AllocationRecord>> = HashMap::new();
+        for record in records {
+            by_tag.entry(record.tag).or_default().push(record);
+        }
+        Self { by_tag }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_tag.is_empty()
+    }
+
+    pub fn allocation_count(&self) -> usize {
+        self.by_tag.values().map(Vec::len).sum()
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.by_tag
+            .values()
+            .flatten()
+            .map(|record| record.size)
+            .sum()
+    }
+}
+
+// Usage of one subsystem
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TagStats {
+    pub allocation_count: usize,
+    // Allocations not freed yet
+    pub live_count: usize,
+    pub used_size: u64,
+    pub peak_usage: u64,
+}
+
+// Pool blocks and dedicated allocations of one memory type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryTypeStats {
+    pub total_size: u64,
+    pub used_size: u64,
+    // Unused bytes inside pool blocks
+    pub free_size: u64,
+    pub block_count: u32,
+    pub free_range_count: u32,
+    pub largest_free_range: u64,
+    pub dedicated_count: u32,
+}
+
+impl MemoryTypeStats {
+    // Share of the free bytes outside the largest free range. 0 when the free
+    // space is one range, close to 1 when it is split into many small ones.
+    // Free space spread over several blocks counts as fragmented too, no
+    // allocation can span them.
+    pub fn fragmentation(&self) -> f32 {
+        if self.free_size == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free_range as f32 / self.free_size as f32
+    }
+}
+
+// Combined stats of the allocator: device memory held in pool blocks and
+// dedicated allocations, how much of it is in use, and the usage per tag
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStats {
+    pub total_size: u64,
+    pub used_size: u64,
+    // Unused bytes inside pool blocks
+    pub free_size: u64,
+    pub block_count: u32,
+    // Free ranges across all blocks, a measure of fragmentation
+    pub free_range_count: u32,
+    pub largest_free_range: u64,
+    pub dedicated_count: u32,
+    pub dedicated_size: u64,
     pub allocation_count: usize,
     pub deallocation_count: usize,
-    pub total_allocated: u64,
-    pub total_freed: u64,
     pub peak_usage: u64,
     pub uptime: Duration,
+    pub by_tag: HashMap<MemoryTag, TagStats>,
+    pub by_memory_type: HashMap<u32, MemoryTypeStats>,
 }
 
 impl MemoryLogger {
@@ -31,11 +140,14 @@ impl MemoryLogger {
             total_allocated: AtomicU64::new(0),
             total_freed: AtomicU64::new(0),
             peak_usage: AtomicU64::new(0),
+            tags: Mutex::new(HashMap::new()),
+            records: Mutex::new(HashMap::new()),
             start_time: Instant::now(),
         }
     }
 
-    pub fn log_allocation(&self, size: u64, memory_type: u32) {
+    pub fn log_allocation(&self, block: &MemoryBlock) {
+        let size = block.size;
         let count = self.allocation_count.fetch_add(1, Ordering::SeqCst);
         let total = self.total_allocated.fetch_add(size, Ordering::SeqCst);
         let current_usage = total + size - self.total_freed.load(Ordering::SeqCst);
@@ -54,66 +166,180 @@ impl MemoryLogger {
             }
         }
 
+        let mut tags = self.tags.lock().unwrap();
+        let tag_stats = tags.entry(block.tag).or_default();
+        tag_stats.allocation_count += 1;
+        tag_stats.live_count += 1;
+        tag_stats.used_size += size;
+        tag_stats.peak_usage = tag_stats.peak_usage.max(tag_stats.used_size);
+        drop(tags);
+
+        self.records.lock().unwrap().insert(
+            (block.memory, block.offset),
+            AllocationRecord {
+                id: count,
+                memory: block.memory,
+                offset: block.offset,
+                size,
+                memory_type_index: block.memory_type_index,
+                tag: block.tag,
+                name: block.name,
+                location: block.location,
+                dedicated: block.dedicated,
+                allocated_at: Instant::now(),
+                backtrace: capture_backtrace(),
+            },
+        );
+
         debug!(
-            "Memory allocated: size={}, type={}, total_allocs={}, current_usage={}",
+            "Memory allocated: size={}, type={}, tag={:?}, name={:?}, at={}, total_allocs={}, current_usage={}",
             size,
-            memory_type,
+            block.memory_type_index,
+            block.tag,
+            block.name,
+            block.location,
             count + 1,
             current_usage
         );
     }
 
-    pub fn log_deallocation(&self, size: u64, memory_type: u32) {
+    pub fn log_deallocation(&self, block: &MemoryBlock) {
+        let size = self
+            .records
+            .lock()
+            .unwrap()
+            .remove(&(block.memory, block.offset))
+            .map_or(block.size, |record| record.size);
         let count = self.deallocation_count.fetch_add(1, Ordering::SeqCst);
         let freed = self.total_freed.fetch_add(size, Ordering::SeqCst);
         let current_usage = self.total_allocated.load(Ordering::SeqCst) - (freed + size);
 
+        if let Some(tag_stats) = self.tags.lock().unwrap().get_mut(&block.tag) {
+            tag_stats.live_count -= 1;
+            tag_stats.used_size -= size;
+        }
+
         debug!(
-            "Memory freed: size={}, type={}, total_deallocs={}, current_usage={}",
+            "Memory freed: size={}, type={}, tag={:?}, name={:?}, total_deallocs={}, current_usage={}",
             size,
-            memory_type,
+            block.memory_type_index,
+            block.tag,
+            block.name,
             count + 1,
             current_usage
         );
     }
 
-    pub fn warn_leak(&self, size: u64, memory_type: u32) {
+    // Moves the record of an allocation `defragment` relocated
+    pub fn log_relocation(&self, relocation: &Relocation) {
+        let mut records = self.records.lock().unwrap();
+        if let Some(mut record) = records.remove(&(relocation.src_memory, relocation.src_offset)) {
+            record.memory = relocation.dst_memory;
+            record.offset = relocation.dst_offset;
+            records.insert((record.memory, record.offset), record);
+        }
+    }
+
+    // Snapshot of the live allocations, oldest first
+    pub fn live_allocations(&self) -> Vec<AllocationRecord> {
+        let mut records: Vec<_> = self.records.lock().unwrap().values().cloned().collect();
+        records.sort_by_key(|record| record.id);
+        records
+    }
+
+    pub fn print_leak_report(&self, report: &LeakReport) {
         warn!(
-            "Potential memory leak detected: size={}, type={}",
-            size, memory_type
+            "Memory leak report: {} allocations, {} bytes never freed",
+            report.allocation_count(),
+            report.total_size()
         );
+        for (tag, records) in &report.by_tag {
+            warn!(
+                "  {:?}: {} allocations, {} bytes",
+                tag,
+                records.len(),
+                records.iter().map(|record| record.size).sum::<u64>()
+            );
+            for record in records {
+                warn!(
+                    "    {} bytes {:?} (type {}{}) allocated at {}",
+                    record.size,
+                    record.name,
+                    record.memory_type_index,
+                    if record.dedicated { ", dedicated" } else { "" },
+                    record.location
+                );
+                if let Some(backtrace) = &record.backtrace {
+                    warn!("{}", backtrace);
+                }
+            }
+        }
     }
 
     pub fn log_error(&self, message: &str) {
         error!("Memory error: {}", message);
     }
 
-    pub fn get_stats(&self) -> MemoryLogStats {
-        MemoryLogStats {
+    // The counters kept here; block and dedicated totals are added by the
+    // allocator
+    pub fn get_stats(&self) -> MemoryStats {
+        MemoryStats {
             allocation_count: self.allocation_count.load(Ordering::SeqCst),
             deallocation_count: self.deallocation_count.load(Ordering::SeqCst),
-            total_allocated: self.total_allocated.load(Ordering::SeqCst),
-            total_freed: self.total_freed.load(Ordering::SeqCst),
             peak_usage: self.peak_usage.load(Ordering::SeqCst),
             uptime: self.start_time.elapsed(),
+            by_tag: self.tags.lock().unwrap().clone(),
+            ..Default::default()
         }
     }
 
-    pub fn print_summary(&self) {
-        let stats = self.get_stats();
-        let current_usage = stats.total_allocated - stats.total_freed;
-
+    pub fn print_summary(&self, stats: &MemoryStats) {
         info!("Memory Logger Summary:");
         info!("  Uptime: {:?}", stats.uptime);
         info!("  Total allocations: {}", stats.allocation_count);
         info!("  Total deallocations: {}", stats.deallocation_count);
-        info!("  Total bytes allocated: {}", stats.total_allocated);
-        info!("  Total bytes freed: {}", stats.total_freed);
-        info!("  Current memory usage: {}", current_usage);
+        info!(
+            "  Device memory held: {} ({} blocks, {} dedicated)",
+            stats.total_size, stats.block_count, stats.dedicated_count
+        );
+        info!("  Current memory usage: {}", stats.used_size);
         info!("  Peak memory usage: {}", stats.peak_usage);
+        for (tag, tag_stats) in &stats.by_tag {
+            info!(
+                "  {:?}: {} bytes in {} allocations, peak {}",
+                tag, tag_stats.used_size, tag_stats.live_count, tag_stats.peak_usage
+            );
+        }
+        for (memory_type, type_stats) in &stats.by_memory_type {
+            info!(
+                "  Memory type {}: {} of {} bytes used, {} blocks, fragmentation {:.2}",
+                memory_type,
+                type_stats.used_size,
+                type_stats.total_size,
+                type_stats.block_count,
+                type_stats.fragmentation()
+            );
+        }
 
-        if current_usage > 0 {
-            warn!("  Potential memory leak: {} bytes not freed", current_usage);
+        if stats.used_size > 0 {
+            warn!(
+                "  Potential memory leak: {} bytes not freed",
+                stats.used_size
+            );
         }
     }
 }
+
+#[cfg(debug_assertions)]
+fn capture_backtrace() -> Option<Arc<Backtrace>> {
+    use std::backtrace::BacktraceStatus;
+
+    // `capture` is a no-op unless RUST_BACKTRACE or RUST_LIB_BACKTRACE is set
+    let backtrace = Backtrace::capture();
+    (backtrace.status() == BacktraceStatus::Captured).then(|| Arc::new(backtrace))
+}
+
+#[cfg(not(debug_assertions))]
+fn capture_backtrace() -> Option<Arc<Backtrace>> {
+    None
+}
@@ -0,0 +1,166 @@
+// Fake device for allocator tests. Hands out increasing memory and buffer
+// handles, tracks which are alive and mapped and can refuse chosen memory
+// types. Mapped memory is backed by host memory that keeps its contents
+// across mappings, so tests can write to it and read it back.
+
+use ash::vk::{self, Handle};
+use std::collections::{HashMap, HashSet};
+use std::ffi::c_void;
+use std::sync::Mutex;
+
+use super::frame::FrameDevice;
+use super::pool::MemoryDevice;
+
+// Memory and offset a buffer is bound to
+type Binding = Option<(vk::DeviceMemory, vk::DeviceSize)>;
+
+#[derive(Default)]
+pub struct MockDevice {
+    next: Mutex<u64>,
+    // Size and memory type of every live allocation
+    live: Mutex<HashMap<u64, (vk::DeviceSize, u32)>>,
+    mapped: Mutex<HashSet<u64>>,
+    // Host memory behind each allocation that was mapped at some point
+    contents: Mutex<HashMap<u64, Box<[u8]>>>,
+    exhausted_types: Mutex<HashSet<u32>>,
+    // Size of every live buffer and the memory it is bound to
+    buffers: Mutex<HashMap<u64, (vk::DeviceSize, Binding)>>,
+    waited_fences: Mutex<Vec<vk::Fence>>,
+}
+
+impl MockDevice {
+    // Further allocations from `memory_type_index` fail as out of memory
+    pub fn exhaust(&self, memory_type_index: u32) {
+        self.exhausted_types
+            .lock()
+            .unwrap()
+            .insert(memory_type_index);
+    }
+
+    pub fn live_count(&self) -> usize {
+        self.live.lock().unwrap().len()
+    }
+
+    pub fn mapped_count(&self) -> usize {
+        self.mapped.lock().unwrap().len()
+    }
+
+    pub fn allocation(&self, memory: vk::DeviceMemory) -> Option<(vk::DeviceSize, u32)> {
+        self.live.lock().unwrap().get(&memory.as_raw()).copied()
+    }
+
+    pub fn buffer_count(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    // Fences waited on so far, in order
+    pub fn waited_fences(&self) -> Vec<vk::Fence> {
+        self.waited_fences.lock().unwrap().clone()
+    }
+
+    fn next_handle(&self) -> u64 {
+        let mut next = self.next.lock().unwrap();
+        *next += 1;
+        *next
+    }
+}
+
+impl MemoryDevice for MockDevice {
+    fn allocate_memory(
+        &self,
+        size: vk::DeviceSize,
+        memory_type_index: u32,
+    ) -> Result<vk::DeviceMemory, vk::Result> {
+        if self
+            .exhausted_types
+            .lock()
+            .unwrap()
+            .contains(&memory_type_index)
+        {
+            return Err(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY);
+        }
+        let handle = self.next_handle();
+        self.live
+            .lock()
+            .unwrap()
+            .insert(handle, (size, memory_type_index));
+        Ok(vk::DeviceMemory::from_raw(handle))
+    }
+
+    fn free_memory(&self, memory: vk::DeviceMemory) {
+        assert!(
+            self.live.lock().unwrap().remove(&memory.as_raw()).is_some(),
+            "freed unknown memory {:?}",
+            memory
+        );
+        self.contents.lock().unwrap().remove(&memory.as_raw());
+    }
+
+    fn map_memory(&self, memory: vk::DeviceMemory) -> Result<*mut c_void, vk::Result> {
+        let (size, _) = self.allocation(memory).expect("mapped unknown memory");
+        // Mapping twice is invalid in Vulkan
+        assert!(self.mapped.lock().unwrap().insert(memory.as_raw()));
+        let mut contents = self.contents.lock().unwrap();
+        let host = contents
+            .entry(memory.as_raw())
+            .or_insert_with(|| vec![0; size as usize].into_boxed_slice());
+        Ok(host.as_mut_ptr() as *mut c_void)
+    }
+
+    fn unmap_memory(&self, memory: vk::DeviceMemory) {
+        assert!(self.mapped.lock().unwrap().remove(&memory.as_raw()));
+    }
+}
+
+impl FrameDevice for MockDevice {
+    fn create_buffer(
+        &self,
+        size: vk::DeviceSize,
+        _usage: vk::BufferUsageFlags,
+    ) -> Result<vk::Buffer, vk::Result> {
+        let handle = self.next_handle();
+        self.buffers.lock().unwrap().insert(handle, (size, None));
+        Ok(vk::Buffer::from_raw(handle))
+    }
+
+    fn buffer_memory_requirements(&self, buffer: vk::Buffer) -> vk::MemoryRequirements {
+        let (size, _) = self.buffers.lock().unwrap()[&buffer.as_raw()];
+        vk::MemoryRequirements {
+            size,
+            alignment: 256,
+            memory_type_bits: !0,
+        }
+    }
+
+    fn bind_buffer_memory(
+        &self,
+        buffer: vk::Buffer,
+        memory: vk::DeviceMemory,
+        offset: vk::DeviceSize,
+    ) -> Result<(), vk::Result> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let (_, binding) = buffers
+            .get_mut(&buffer.as_raw())
+            .expect("bound unknown buffer");
+        // Buffers can only be bound once
+        assert!(binding.replace((memory, offset)).is_none());
+        Ok(())
+    }
+
+    fn destroy_buffer(&self, buffer: vk::Buffer) {
+        assert!(
+            self.buffers
+                .lock()
+                .unwrap()
+                .remove(&buffer.as_raw())
+                .is_some(),
+            "destroyed unknown buffer {:?}",
+            buffer
+        );
+    }
+
+    fn wait_for_fence(&self, fence: vk::Fence) -> Result<(), vk::Result> {
+        self.waited_fences.lock().unwrap().push(fence);
+        Ok(())
+    }
+}
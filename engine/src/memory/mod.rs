@@ -1,16 +1,45 @@
 mod buffer;
 mod error;
+mod frame;
 mod logging;
+#[cfg(test)]
+mod mock;
+mod pool;
+mod typed;
 
 use ash::vk;
-use log::{debug, error, info, warn};
+use log::{debug, info};
 use std::collections::HashMap;
+use std::ffi::c_void;
+use std::panic::Location;
 use std::sync::{Arc, Mutex};
 
-use crate::context::Context;
+use crate::graphics::context::Context;
 pub use buffer::Buffer;
 pub use error::{MemoryError, Result};
-use logging::{MemoryLogStats, MemoryLogger};
+pub use frame::{FrameAllocation, FrameAllocator, FrameAllocatorConfig, FrameDevice};
+use logging::MemoryLogger;
+pub use logging::{AllocationRecord, LeakReport, MemoryStats, MemoryTypeStats, TagStats};
+pub use pool::{MemoryDevice, MemoryPool, Relocation};
+#[doc(hidden)]
+pub use typed::field_size;
+pub use typed::{
+    check_layout, validate_layout, BufferKind, BufferLayout, GpuField, GpuLayout, GpuType,
+    IndexElement, LayoutError, LayoutErrorKind, TypedBuffer,
+};
+
+const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_DEDICATED_THRESHOLD: u64 = 32 * 1024 * 1024;
+
+// Subsystem an allocation belongs to, stats are broken down by it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryTag {
+    Graphics,
+    Physics,
+    Text,
+    Transfer,
+    Other(&'static str),
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct MemoryBlock {
@@ -18,221 +47,697 @@ pub struct MemoryBlock {
     pub offset: u64,
     pub size: u64,
     pub memory_type_index: u32,
+    pub tag: MemoryTag,
+    // Debug name given to `allocate_named`, empty otherwise
+    pub name: &'static str,
+    // Where the allocation was requested, see `MemoryAllocator::allocate`
+    pub location: &'static Location<'static>,
+    // Owns its `memory` instead of sharing a pool block
+    pub dedicated: bool,
 }
 
-#[derive(Debug)]
-struct MemoryChunk {
-    memory: vk::DeviceMemory,
+#[derive(Debug, Clone, Copy)]
+pub struct AllocatorConfig {
+    // Size of the blocks smaller allocations are carved from
+    pub block_size: u64,
+    // Allocations at least this large get device memory of their own
+    pub dedicated_threshold: u64,
+}
+
+impl Default for AllocatorConfig {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            dedicated_threshold: DEFAULT_DEDICATED_THRESHOLD,
+        }
+    }
+}
+
+struct DedicatedAllocation {
     size: u64,
-    free_regions: Vec<(u64, u64)>, // (offset, size)
     memory_type_index: u32,
+    mapped: Option<*mut c_void>,
+    map_count: u32,
 }
 
-pub struct MemoryAllocator {
-    context: Arc<Context>,
-    chunks: Mutex<HashMap<u32, Vec<MemoryChunk>>>, // memory_type_index -> chunks
+// The device memory allocator shared by every subsystem. Allocations below
+// the dedicated threshold are sub-allocated from one best-fit pool per memory
+// type, larger ones get their own device memory.
+pub struct MemoryAllocator<D: MemoryDevice = ash::Device> {
+    device: Arc<D>,
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    config: AllocatorConfig,
+    pools: Mutex<HashMap<u32, MemoryPool<D>>>, // memory_type_index -> pool
+    dedicated: Mutex<HashMap<vk::DeviceMemory, DedicatedAllocation>>,
     logger: MemoryLogger,
 }
 
-impl MemoryAllocator {
+// Mapped pointers are only handed out through the allocator, and all of its
+// state sits behind mutexes
+unsafe impl<D: MemoryDevice> Send for MemoryAllocator<D> {}
+unsafe impl<D: MemoryDevice> Sync for MemoryAllocator<D> {}
+
+impl MemoryAllocator<ash::Device> {
     pub fn new(context: Arc<Context>) -> Self {
-        info!("Initializing Memory Allocator");
+        let memory_properties = unsafe {
+            context
+                .instance()
+                .get_physical_device_memory_properties(context.physical_device())
+        };
+        Self::with_device(
+            context.device(),
+            memory_properties,
+            AllocatorConfig::default(),
+        )
+    }
+}
+
+impl<D: MemoryDevice> MemoryAllocator<D> {
+    pub fn with_device(
+        device: Arc<D>,
+        memory_properties: vk::PhysicalDeviceMemoryProperties,
+        config: AllocatorConfig,
+    ) -> Self {
+        info!(
+            "Initializing Memory Allocator: block_size={}, dedicated_threshold={}",
+            config.block_size, config.dedicated_threshold
+        );
         Self {
-            context,
-            chunks: Mutex::new(HashMap::new()),
+            device,
+            memory_properties,
+            config,
+            pools: Mutex::new(HashMap::new()),
+            dedicated: Mutex::new(HashMap::new()),
             logger: MemoryLogger::new(),
         }
     }
 
+    pub fn config(&self) -> AllocatorConfig {
+        self.config
+    }
+
+    pub fn device(&self) -> &Arc<D> {
+        &self.device
+    }
+
+    // The caller's source location is recorded with the block and shows up
+    // in the leak report. Helpers that allocate on behalf of their caller are
+    // marked `#[track_caller]` so the location points past them.
+    #[track_caller]
     pub fn allocate(
         &self,
-        size: u64,
         requirements: vk::MemoryRequirements,
         properties: vk::MemoryPropertyFlags,
+        tag: MemoryTag,
     ) -> Result<MemoryBlock> {
-        let memory_type_index = self
-            .find_memory_type_index(requirements.memory_type_bits, properties)
-            .map_err(|_| MemoryError::UnsupportedMemoryType(requirements.memory_type_bits))?;
-
-        // Round up size to alignment
-        let aligned_size =
-            ((size + requirements.alignment - 1) / requirements.alignment) * requirements.alignment;
-
-        let mut chunks = self.chunks.lock().unwrap();
-        let chunk_list = chunks.entry(memory_type_index).or_insert_with(Vec::new);
-
-        // Try to find space in existing chunks
-        for chunk in chunk_list.iter_mut() {
-            if let Some((offset, _)) = chunk
-                .free_regions
-                .iter()
-                .find(|(_, region_size)| *region_size >= aligned_size)
-                .cloned()
-            {
-                // Remove or shrink the free region
-                chunk
-                    .free_regions
-                    .retain(|&(region_offset, _)| region_offset != offset);
-                if aligned_size < requirements.size {
-                    chunk
-                        .free_regions
-                        .push((offset + aligned_size, requirements.size - aligned_size));
+        self.allocate_named(requirements, properties, tag, "")
+    }
+
+    #[track_caller]
+    pub fn allocate_named(
+        &self,
+        requirements: vk::MemoryRequirements,
+        properties: vk::MemoryPropertyFlags,
+        tag: MemoryTag,
+        name: &'static str,
+    ) -> Result<MemoryBlock> {
+        if requirements.size == 0 {
+            return Err(MemoryError::InvalidOperation(
+                "Cannot allocate zero bytes".to_string(),
+            ));
+        }
+
+        let candidates = self.memory_type_candidates(requirements.memory_type_bits, properties);
+        if candidates.is_empty() {
+            return Err(MemoryError::UnsupportedMemoryType(
+                requirements.memory_type_bits,
+            ));
+        }
+
+        // A full heap isn't fatal while other suitable types are left
+        let mut last_error = None;
+        for memory_type_index in candidates {
+            let result = if requirements.size >= self.config.dedicated_threshold {
+                self.allocate_dedicated(requirements.size, memory_type_index, tag, name)
+            } else {
+                self.allocate_pooled(requirements, memory_type_index, tag, name)
+            };
+            match result {
+                Ok(block) => {
+                    self.logger.log_allocation(&block);
+                    return Ok(block);
                 }
+                Err(e) => {
+                    debug!(
+                        "Allocation of {} bytes from memory type {} failed: {}",
+                        requirements.size, memory_type_index, e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        let error = last_error.unwrap();
+        self.logger.log_error(&format!(
+            "Failed to allocate {} bytes: {}",
+            requirements.size, error
+        ));
+        Err(error)
+    }
 
-                self.logger.log_allocation(aligned_size, memory_type_index);
+    pub fn free(&self, block: MemoryBlock) -> Result<()> {
+        let freed = if block.dedicated {
+            self.dedicated
+                .lock()
+                .unwrap()
+                .remove(&block.memory)
+                .map(|allocation| {
+                    if allocation.mapped.is_some() {
+                        self.device.unmap_memory(block.memory);
+                    }
+                    self.device.free_memory(block.memory);
+                    allocation.size
+                })
+        } else {
+            self.pools
+                .lock()
+                .unwrap()
+                .get_mut(&block.memory_type_index)
+                .and_then(|pool| pool.free(block.memory, block.offset))
+        };
 
-                return Ok(MemoryBlock {
-                    memory: chunk.memory,
-                    offset,
-                    size: aligned_size,
-                    memory_type_index,
-                });
+        match freed {
+            Some(_) => {
+                self.logger.log_deallocation(&block);
+                Ok(())
             }
+            None => Err(MemoryError::InvalidOperation(
+                "Failed to find memory block for freeing".to_string(),
+            )),
         }
+    }
 
-        // Create new chunk if no suitable space found
-        let chunk_size = aligned_size.max(64 * 1024 * 1024); // Minimum 64MB chunks
-        match self.create_chunk(chunk_size, memory_type_index) {
-            Ok(new_chunk) => {
-                let memory = new_chunk.memory;
+    // Pointer to the start of `block`. Memory is mapped once and shared by
+    // every block inside it; pair every call with `unmap`.
+    pub fn map(&self, block: &MemoryBlock) -> Result<*mut c_void> {
+        let flags = self.memory_flags(block);
+        if !flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            return Err(MemoryError::MappingFailed(format!(
+                "Memory type {} is not host visible",
+                block.memory_type_index
+            )));
+        }
 
-                // Add remaining space as a free region
-                if chunk_size > aligned_size {
-                    new_chunk
-                        .free_regions
-                        .push((aligned_size, chunk_size - aligned_size));
-                }
+        if !block.dedicated {
+            let mut pools = self.pools.lock().unwrap();
+            let pool = pools.get_mut(&block.memory_type_index).ok_or_else(|| {
+                MemoryError::InvalidOperation("Block was not allocated here".to_string())
+            })?;
+            return pool.map(block.memory, block.offset);
+        }
 
-                chunk_list.push(new_chunk);
-                self.logger.log_allocation(aligned_size, memory_type_index);
+        let mut dedicated = self.dedicated.lock().unwrap();
+        let allocation = dedicated.get_mut(&block.memory).ok_or_else(|| {
+            MemoryError::InvalidOperation("Block was not allocated here".to_string())
+        })?;
+        let base = match allocation.mapped {
+            Some(base) => base,
+            None => {
+                let base = self
+                    .device
+                    .map_memory(block.memory)
+                    .map_err(|e| MemoryError::MappingFailed(e.to_string()))?;
+                allocation.mapped = Some(base);
+                base
+            }
+        };
+        allocation.map_count += 1;
+        Ok(base)
+    }
 
-                Ok(MemoryBlock {
-                    memory,
-                    offset: 0,
-                    size: aligned_size,
-                    memory_type_index,
-                })
+    // Property flags of the memory type `block` lives in
+    pub fn memory_flags(&self, block: &MemoryBlock) -> vk::MemoryPropertyFlags {
+        self.memory_properties.memory_types[block.memory_type_index as usize].property_flags
+    }
+
+    pub fn unmap(&self, block: &MemoryBlock) {
+        if !block.dedicated {
+            if let Some(pool) = self.pools.lock().unwrap().get_mut(&block.memory_type_index) {
+                pool.unmap(block.memory);
             }
-            Err(e) => {
-                self.logger
-                    .log_error(&format!("Failed to create memory chunk: {}", e));
-                Err(MemoryError::AllocationFailed(e.to_string()))
+            return;
+        }
+
+        if let Some(allocation) = self.dedicated.lock().unwrap().get_mut(&block.memory) {
+            allocation.map_count = allocation.map_count.saturating_sub(1);
+            if allocation.map_count == 0 && allocation.mapped.take().is_some() {
+                self.device.unmap_memory(block.memory);
             }
         }
     }
 
-    pub fn free(&self, block: MemoryBlock) -> Result<()> {
-        let mut chunks = self.chunks.lock().unwrap();
-        if let Some(chunk_list) = chunks.get_mut(&block.memory_type_index) {
-            for chunk in chunk_list.iter_mut() {
-                if chunk.memory == block.memory {
-                    // Add freed region back to free list
-                    chunk.free_regions.push((block.offset, block.size));
-                    // Merge adjacent free regions
-                    chunk.free_regions.sort_by_key(|&(offset, _)| offset);
-                    let mut i = 0;
-                    while i < chunk.free_regions.len() - 1 {
-                        let current = chunk.free_regions[i];
-                        let next = chunk.free_regions[i + 1];
-                        if current.0 + current.1 == next.0 {
-                            chunk.free_regions[i].1 += next.1;
-                            chunk.free_regions.remove(i + 1);
-                        } else {
-                            i += 1;
-                        }
-                    }
+    // Compacts the pools, moving only blocks that hold nothing but `tag`'s
+    // allocations. The caller owns every allocation under that tag and has to
+    // copy and rebind the returned relocations, then call `release_retired`.
+    pub fn defragment(&self, tag: MemoryTag) -> Vec<Relocation> {
+        let relocations: Vec<Relocation> = self
+            .pools
+            .lock()
+            .unwrap()
+            .values_mut()
+            .flat_map(|pool| pool.defragment(|t| t == tag))
+            .collect();
+        for relocation in &relocations {
+            self.logger.log_relocation(relocation);
+        }
+        if !relocations.is_empty() {
+            info!(
+                "Defragmenting {:?} memory: {} allocations moved",
+                tag,
+                relocations.len()
+            );
+        }
+        relocations
+    }
 
-                    self.logger
-                        .log_deallocation(block.size, block.memory_type_index);
-                    return Ok(());
-                }
-            }
+    // Frees the blocks `defragment` emptied
+    pub fn release_retired(&self) {
+        for pool in self.pools.lock().unwrap().values_mut() {
+            pool.release_retired();
         }
+    }
 
-        Err(MemoryError::InvalidOperation(
-            "Failed to find memory block for freeing".to_string(),
-        ))
+    // Memory types allowed by `type_bits` that have all of `properties`, best
+    // first. Types with fewer extra flags are preferred, which keeps
+    // device-local resources out of host-visible heaps and staging buffers
+    // out of the small device-local host-visible heap.
+    pub fn memory_type_candidates(
+        &self,
+        type_bits: u32,
+        properties: vk::MemoryPropertyFlags,
+    ) -> Vec<u32> {
+        let mut candidates: Vec<u32> = (0..self.memory_properties.memory_type_count)
+            .filter(|&i| {
+                type_bits & (1 << i) != 0
+                    && self.memory_properties.memory_types[i as usize]
+                        .property_flags
+                        .contains(properties)
+            })
+            .collect();
+        candidates.sort_by_key(|&i| {
+            let flags = self.memory_properties.memory_types[i as usize].property_flags;
+            (flags.as_raw() & !properties.as_raw()).count_ones()
+        });
+        candidates
     }
 
-    fn create_chunk(&self, size: u64, memory_type_index: u32) -> Result<MemoryChunk> {
-        let device = self.context.device();
-        let alloc_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(size)
-            .memory_type_index(memory_type_index)
-            .build();
+    pub fn find_memory_type_index(
+        &self,
+        type_filter: u32,
+        properties: vk::MemoryPropertyFlags,
+    ) -> Result<u32> {
+        self.memory_type_candidates(type_filter, properties)
+            .first()
+            .copied()
+            .ok_or(MemoryError::UnsupportedMemoryType(type_filter))
+    }
 
-        let memory = unsafe {
-            device
-                .allocate_memory(&alloc_info, None)
-                .map_err(|e| MemoryError::AllocationFailed(e.to_string()))?
-        };
+    #[track_caller]
+    fn allocate_pooled(
+        &self,
+        requirements: vk::MemoryRequirements,
+        memory_type_index: u32,
+        tag: MemoryTag,
+        name: &'static str,
+    ) -> Result<MemoryBlock> {
+        let mut pools = self.pools.lock().unwrap();
+        let pool = pools.entry(memory_type_index).or_insert_with(|| {
+            debug!("Created memory pool for type {}", memory_type_index);
+            MemoryPool::new(
+                self.device.clone(),
+                memory_type_index,
+                self.config.block_size,
+            )
+        });
+        let (memory, offset) = pool.allocate(requirements.size, requirements.alignment, tag)?;
+        Ok(MemoryBlock {
+            memory,
+            offset,
+            size: requirements.size,
+            memory_type_index,
+            tag,
+            name,
+            location: Location::caller(),
+            dedicated: false,
+        })
+    }
 
+    #[track_caller]
+    fn allocate_dedicated(
+        &self,
+        size: u64,
+        memory_type_index: u32,
+        tag: MemoryTag,
+        name: &'static str,
+    ) -> Result<MemoryBlock> {
+        let memory = self
+            .device
+            .allocate_memory(size, memory_type_index)
+            .map_err(|e| MemoryError::AllocationFailed(e.to_string()))?;
         debug!(
-            "Created new memory chunk: size={}, type={}",
-            size, memory_type_index
+            "Created dedicated allocation: size={}, type={}, tag={:?}",
+            size, memory_type_index, tag
         );
 
-        Ok(MemoryChunk {
+        self.dedicated.lock().unwrap().insert(
             memory,
+            DedicatedAllocation {
+                size,
+                memory_type_index,
+                mapped: None,
+                map_count: 0,
+            },
+        );
+        Ok(MemoryBlock {
+            memory,
+            offset: 0,
             size,
-            free_regions: Vec::new(),
             memory_type_index,
+            tag,
+            name,
+            location: Location::caller(),
+            dedicated: true,
         })
     }
 
-    fn find_memory_type_index(
-        &self,
-        type_filter: u32,
-        properties: vk::MemoryPropertyFlags,
-    ) -> Result<u32> {
-        let memory_properties = unsafe {
-            self.context
-                .instance()
-                .get_physical_device_memory_properties(self.context.physical_device())
-        };
+    // Combined stats of the pools and dedicated allocations, with usage
+    // broken down per tag
+    pub fn get_stats(&self) -> MemoryStats {
+        let mut stats = self.logger.get_stats();
+        for pool in self.pools.lock().unwrap().values() {
+            pool.accumulate_stats(&mut stats);
+        }
+        for allocation in self.dedicated.lock().unwrap().values() {
+            stats.total_size += allocation.size;
+            stats.used_size += allocation.size;
+            stats.dedicated_count += 1;
+            stats.dedicated_size += allocation.size;
 
-        for i in 0..memory_properties.memory_type_count {
-            if (type_filter & (1 << i)) != 0
-                && memory_properties.memory_types[i as usize]
-                    .property_flags
-                    .contains(properties)
-            {
-                return Ok(i);
-            }
+            let type_stats = stats
+                .by_memory_type
+                .entry(allocation.memory_type_index)
+                .or_default();
+            type_stats.total_size += allocation.size;
+            type_stats.used_size += allocation.size;
+            type_stats.dedicated_count += 1;
         }
+        stats
+    }
 
-        Err(MemoryError::UnsupportedMemoryType(type_filter))
+    // Every allocation not freed yet with its tag, name and callsite, oldest
+    // first
+    pub fn live_allocations(&self) -> Vec<AllocationRecord> {
+        self.logger.live_allocations()
     }
 
-    pub fn get_stats(&self) -> MemoryLogStats {
-        self.logger.get_stats()
+    // The live allocations grouped by tag. Dropping the allocator logs this
+    // report for whatever is left.
+    pub fn leak_report(&self) -> LeakReport {
+        LeakReport::new(self.live_allocations())
     }
 
     pub fn print_memory_stats(&self) {
-        self.logger.print_summary();
+        self.logger.print_summary(&self.get_stats());
     }
 }
 
-impl Drop for MemoryAllocator {
+impl<D: MemoryDevice> Drop for MemoryAllocator<D> {
     fn drop(&mut self) {
-        let chunks = self.chunks.lock().unwrap();
-        let device = self.context.device();
-
-        for chunk_list in chunks.values() {
-            for chunk in chunk_list {
-                if !chunk.free_regions.is_empty() {
-                    let unfreed_size =
-                        chunk.size - chunk.free_regions.iter().map(|(_, size)| size).sum::<u64>();
-                    if unfreed_size > 0 {
-                        self.logger.warn_leak(unfreed_size, chunk.memory_type_index);
-                    }
-                }
-                unsafe {
-                    device.free_memory(chunk.memory, None);
-                }
+        let stats = self.get_stats();
+
+        let report = self.leak_report();
+        if !report.is_empty() {
+            self.logger.print_leak_report(&report);
+        }
+
+        for (memory, allocation) in self.dedicated.lock().unwrap().drain() {
+            if allocation.mapped.is_some() {
+                self.device.unmap_memory(memory);
             }
+            self.device.free_memory(memory);
         }
+        // Dropping the pools frees their blocks
+        self.pools.lock().unwrap().clear();
+
+        self.logger.print_summary(&stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockDevice;
+
+    const DEVICE_LOCAL: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+    const HOST: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::from_raw(
+        vk::MemoryPropertyFlags::HOST_VISIBLE.as_raw()
+            | vk::MemoryPropertyFlags::HOST_COHERENT.as_raw(),
+    );
+
+    // A discrete GPU: device local, host memory and a small device local
+    // window the host can write to
+    fn allocator() -> (Arc<MockDevice>, MemoryAllocator<MockDevice>) {
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties::default();
+        for (i, flags) in [DEVICE_LOCAL, HOST, DEVICE_LOCAL | HOST]
+            .into_iter()
+            .enumerate()
+        {
+            memory_properties.memory_types[i].property_flags = flags;
+        }
+        memory_properties.memory_type_count = 3;
+
+        let device = Arc::new(MockDevice::default());
+        let allocator = MemoryAllocator::with_device(
+            device.clone(),
+            memory_properties,
+            AllocatorConfig {
+                block_size: 1024 * 1024,
+                dedicated_threshold: 512 * 1024,
+            },
+        );
+        (device, allocator)
+    }
+
+    fn requirements(size: u64) -> vk::MemoryRequirements {
+        vk::MemoryRequirements {
+            size,
+            alignment: 256,
+            memory_type_bits: 0b111,
+        }
+    }
+
+    #[test]
+    fn test_memory_type_selection() {
+        let (device, allocator) = allocator();
+        assert_eq!(
+            allocator.memory_type_candidates(0b111, DEVICE_LOCAL),
+            [0, 2]
+        );
+        assert_eq!(allocator.memory_type_candidates(0b111, HOST), [1, 2]);
+        assert_eq!(allocator.memory_type_candidates(0b110, DEVICE_LOCAL), [2]);
+        assert!(matches!(
+            allocator.allocate(requirements(64), HOST, MemoryTag::Graphics),
+            Ok(MemoryBlock {
+                memory_type_index: 1,
+                ..
+            })
+        ));
+
+        // A full heap falls back to the next suitable type
+        device.exhaust(0);
+        let block = allocator
+            .allocate(requirements(64), DEVICE_LOCAL, MemoryTag::Graphics)
+            .unwrap();
+        assert_eq!(block.memory_type_index, 2);
+        assert!(matches!(
+            allocator.allocate(
+                vk::MemoryRequirements {
+                    memory_type_bits: 0b001,
+                    ..requirements(64)
+                },
+                DEVICE_LOCAL,
+                MemoryTag::Graphics,
+            ),
+            Err(MemoryError::AllocationFailed(_))
+        ));
+        assert!(matches!(
+            allocator.allocate(
+                vk::MemoryRequirements {
+                    memory_type_bits: 0b001,
+                    ..requirements(64)
+                },
+                HOST,
+                MemoryTag::Graphics,
+            ),
+            Err(MemoryError::UnsupportedMemoryType(0b001))
+        ));
+    }
+
+    #[test]
+    fn test_dedicated_threshold_and_mapping() {
+        let (device, allocator) = allocator();
+        let small = allocator
+            .allocate(requirements(1024), HOST, MemoryTag::Physics)
+            .unwrap();
+        let other = allocator
+            .allocate(requirements(1024), HOST, MemoryTag::Graphics)
+            .unwrap();
+        let large = allocator
+            .allocate(requirements(512 * 1024), HOST, MemoryTag::Physics)
+            .unwrap();
+        assert!(!small.dedicated);
+        assert_eq!(small.memory, other.memory);
+        assert!(large.dedicated);
+        assert_eq!(device.allocation(large.memory), Some((512 * 1024, 1)));
+
+        // Both subsystems share one mapping of the block
+        let a = allocator.map(&small).unwrap();
+        let b = allocator.map(&other).unwrap();
+        assert_eq!(b as usize - a as usize, 1024);
+        allocator.map(&large).unwrap();
+        assert_eq!(device.mapped_count(), 2);
+        allocator.unmap(&small);
+        allocator.unmap(&other);
+        assert_eq!(device.mapped_count(), 1);
+
+        let device_local = allocator
+            .allocate(requirements(64), DEVICE_LOCAL, MemoryTag::Graphics)
+            .unwrap();
+        assert!(matches!(
+            allocator.map(&device_local),
+            Err(MemoryError::MappingFailed(_))
+        ));
+
+        // Freeing a mapped dedicated allocation unmaps it
+        allocator.free(large).unwrap();
+        assert_eq!(device.mapped_count(), 0);
+        assert!(allocator.free(large).is_err());
+    }
+
+    #[test]
+    fn test_combined_stats() {
+        let (device, allocator) = allocator();
+        let physics: Vec<_> = (0..3)
+            .map(|_| {
+                allocator
+                    .allocate(requirements(1000), HOST, MemoryTag::Physics)
+                    .unwrap()
+            })
+            .collect();
+        let _text = allocator
+            .allocate(requirements(600 * 1024), DEVICE_LOCAL, MemoryTag::Text)
+            .unwrap();
+        allocator.free(physics[0]).unwrap();
+
+        let stats = allocator.get_stats();
+        assert_eq!(stats.block_count, 1);
+        assert_eq!(stats.dedicated_count, 1);
+        assert_eq!(stats.used_size, 2000 + 600 * 1024);
+        assert_eq!(stats.total_size, 1024 * 1024 + 600 * 1024);
+        assert_eq!(stats.allocation_count, 4);
+        assert_eq!(stats.deallocation_count, 1);
+
+        let physics_stats = stats.by_tag[&MemoryTag::Physics];
+        assert_eq!(physics_stats.live_count, 2);
+        assert_eq!(physics_stats.used_size, 2000);
+        assert_eq!(physics_stats.peak_usage, 3000);
+        assert_eq!(stats.by_tag[&MemoryTag::Text].used_size, 600 * 1024);
+        assert!(!stats.by_tag.contains_key(&MemoryTag::Graphics));
+
+        // Teardown frees everything, leaks included
+        drop(allocator);
+        assert_eq!(device.live_count(), 0);
+    }
+
+    #[test]
+    fn test_leak_report_and_snapshot() {
+        let (_device, allocator) = allocator();
+        let particles = allocator
+            .allocate_named(
+                requirements(400 * 1024),
+                HOST,
+                MemoryTag::Physics,
+                "particles",
+            )
+            .unwrap();
+        let graphics = allocator
+            .allocate(requirements(400 * 1024), HOST, MemoryTag::Graphics)
+            .unwrap();
+        let grid = allocator
+            .allocate_named(requirements(400 * 1024), HOST, MemoryTag::Physics, "grid")
+            .unwrap();
+        assert_eq!(particles.name, "particles");
+        assert_eq!(graphics.name, "");
+        assert_eq!(particles.location.file(), file!());
+        assert_ne!(particles.memory, grid.memory);
+        allocator.free(graphics).unwrap();
+
+        let live = allocator.live_allocations();
+        let names: Vec<_> = live.iter().map(|record| record.name).collect();
+        assert_eq!(names, ["particles", "grid"]);
+        assert_eq!(live[0].location, particles.location);
+
+        // Records follow allocations moved by defragmentation
+        let relocations = allocator.defragment(MemoryTag::Physics);
+        assert_eq!(relocations.len(), 1);
+        let moved = relocations[0];
+        let record = allocator
+            .live_allocations()
+            .into_iter()
+            .find(|record| record.memory == moved.dst_memory && record.offset == moved.dst_offset)
+            .unwrap();
+        assert_eq!(record.size, 400 * 1024);
+        allocator.release_retired();
+
+        let report = allocator.leak_report();
+        assert_eq!(report.allocation_count(), 2);
+        assert_eq!(report.total_size(), 800 * 1024);
+        assert_eq!(report.by_tag[&MemoryTag::Physics].len(), 2);
+        assert!(!report.by_tag.contains_key(&MemoryTag::Graphics));
+    }
+
+    #[test]
+    fn test_fragmentation_per_memory_type() {
+        let (_device, allocator) = allocator();
+        let blocks: Vec<_> = (0..3)
+            .map(|_| {
+                allocator
+                    .allocate(requirements(200 * 1024), HOST, MemoryTag::Graphics)
+                    .unwrap()
+            })
+            .collect();
+        allocator
+            .allocate(requirements(600 * 1024), DEVICE_LOCAL, MemoryTag::Graphics)
+            .unwrap();
+
+        let host = allocator.get_stats().by_memory_type[&1];
+        assert_eq!(host.block_count, 1);
+        assert_eq!(host.free_range_count, 1);
+        assert_eq!(host.fragmentation(), 0.0);
+
+        // A hole in the middle splits the free space
+        allocator.free(blocks[1]).unwrap();
+        let stats = allocator.get_stats();
+        let host = stats.by_memory_type[&1];
+        assert_eq!(host.used_size, 400 * 1024);
+        assert_eq!(host.free_range_count, 2);
+        assert_eq!(host.largest_free_range, 424 * 1024);
+        let expected = 1.0 - 424.0 / 624.0;
+        assert!((host.fragmentation() - expected).abs() < 1e-6);
 
-        self.logger.print_summary();
+        let device_local = stats.by_memory_type[&0];
+        assert_eq!(device_local.dedicated_count, 1);
+        assert_eq!(device_local.used_size, 600 * 1024);
+        assert_eq!(device_local.fragmentation(), 0.0);
     }
 }
@@ -0,0 +1,649 @@
+use ash::vk;
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::sync::Arc;
+
+use super::error::{MemoryError, Result};
+use super::{MemoryStats, MemoryTag};
+
+// The device memory calls the allocator makes. Implemented by `ash::Device`;
+// tests use a mock so the sub-allocation logic runs without a GPU.
+pub trait MemoryDevice: Send + Sync {
+    fn allocate_memory(
+        &self,
+        size: vk::DeviceSize,
+        memory_type_index: u32,
+    ) -> std::result::Result<vk::DeviceMemory, vk::Result>;
+    fn free_memory(&self, memory: vk::DeviceMemory);
+    // Maps the whole allocation
+    fn map_memory(&self, memory: vk::DeviceMemory) -> std::result::Result<*mut c_void, vk::Result>;
+    fn unmap_memory(&self, memory: vk::DeviceMemory);
+}
+
+impl MemoryDevice for ash::Device {
+    fn allocate_memory(
+        &self,
+        size: vk::DeviceSize,
+        memory_type_index: u32,
+    ) -> std::result::Result<vk::DeviceMemory, vk::Result> {
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type_index)
+            .build();
+        unsafe { ash::Device::allocate_memory(self, &alloc_info, None) }
+    }
+
+    fn free_memory(&self, memory: vk::DeviceMemory) {
+        unsafe { ash::Device::free_memory(self, memory, None) }
+    }
+
+    fn map_memory(&self, memory: vk::DeviceMemory) -> std::result::Result<*mut c_void, vk::Result> {
+        unsafe {
+            ash::Device::map_memory(self, memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+        }
+    }
+
+    fn unmap_memory(&self, memory: vk::DeviceMemory) {
+        unsafe { ash::Device::unmap_memory(self, memory) }
+    }
+}
+
+// Byte range inside a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FreeRange {
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+}
+
+impl FreeRange {
+    fn end(&self) -> vk::DeviceSize {
+        self.offset + self.size
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Allocation {
+    size: vk::DeviceSize,
+    alignment: vk::DeviceSize,
+    tag: MemoryTag,
+}
+
+// Offset of the first aligned byte at or after `offset`. Vulkan alignments
+// are powers of two; zero means no requirement.
+pub(super) fn align_up(offset: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    if alignment <= 1 {
+        return offset;
+    }
+    (offset + alignment - 1) & !(alignment - 1)
+}
+
+// One `vk::DeviceMemory` carved into allocations. Free ranges are kept sorted
+// by offset and adjacent ranges are always merged, so a block with no
+// allocations has exactly one free range covering it.
+struct Block {
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    free_ranges: Vec<FreeRange>,
+    allocations: BTreeMap<vk::DeviceSize, Allocation>,
+    mapped: Option<*mut c_void>,
+    // Live `MemoryPool::map` calls on the block
+    map_count: u32,
+}
+
+impl Block {
+    fn new(memory: vk::DeviceMemory, size: vk::DeviceSize) -> Self {
+        Self {
+            memory,
+            size,
+            free_ranges: vec![FreeRange { offset: 0, size }],
+            allocations: BTreeMap::new(),
+            mapped: None,
+            map_count: 0,
+        }
+    }
+
+    fn used_size(&self) -> vk::DeviceSize {
+        self.allocations.values().map(|a| a.size).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.allocations.is_empty()
+    }
+
+    // Best fit inside this block: the free range index, the aligned offset and
+    // the bytes the range has left over
+    fn best_fit(
+        &self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> Option<(usize, vk::DeviceSize, vk::DeviceSize)> {
+        self.free_ranges
+            .iter()
+            .enumerate()
+            .filter_map(|(index, range)| {
+                let offset = align_up(range.offset, alignment);
+                let end = offset.checked_add(size)?;
+                (end <= range.end()).then(|| (index, offset, range.size - size))
+            })
+            .min_by_key(|&(_, _, waste)| waste)
+    }
+
+    // Carves `[offset, offset + size)` out of the free range at `index`. The
+    // alignment padding before it stays free.
+    fn take(&mut self, index: usize, offset: vk::DeviceSize, allocation: Allocation) {
+        let range = self.free_ranges[index];
+        let end = offset + allocation.size;
+        let before = FreeRange {
+            offset: range.offset,
+            size: offset - range.offset,
+        };
+        let after = FreeRange {
+            offset: end,
+            size: range.end() - end,
+        };
+        let remaining: Vec<FreeRange> = [before, after]
+            .into_iter()
+            .filter(|range| range.size > 0)
+            .collect();
+        self.free_ranges.splice(index..=index, remaining);
+        self.allocations.insert(offset, allocation);
+    }
+
+    // Returns the allocation at `offset` to the free list, merging it with
+    // its neighbours. `None` if nothing was allocated there.
+    fn release(&mut self, offset: vk::DeviceSize) -> Option<Allocation> {
+        let allocation = self.allocations.remove(&offset)?;
+        let index = self
+            .free_ranges
+            .partition_point(|range| range.offset < offset);
+        self.free_ranges.insert(
+            index,
+            FreeRange {
+                offset,
+                size: allocation.size,
+            },
+        );
+
+        // Merge with the following range, then with the preceding one
+        if index + 1 < self.free_ranges.len()
+            && self.free_ranges[index].end() == self.free_ranges[index + 1].offset
+        {
+            self.free_ranges[index].size += self.free_ranges[index + 1].size;
+            self.free_ranges.remove(index + 1);
+        }
+        if index > 0 && self.free_ranges[index - 1].end() == self.free_ranges[index].offset {
+            self.free_ranges[index - 1].size += self.free_ranges[index].size;
+            self.free_ranges.remove(index);
+        }
+        Some(allocation)
+    }
+}
+
+// An allocation moved by `MemoryPool::defragment`. The caller copies `size`
+// bytes from the source to the destination and rebinds whatever used it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    pub size: vk::DeviceSize,
+    pub tag: MemoryTag,
+    pub src_memory: vk::DeviceMemory,
+    pub src_offset: vk::DeviceSize,
+    pub dst_memory: vk::DeviceMemory,
+    pub dst_offset: vk::DeviceSize,
+}
+
+// Best-fit sub-allocator over large blocks of one memory type. Every
+// allocation is a `(memory, offset)` pair inside a block; freed ranges are
+// coalesced with their neighbours so the blocks don't fragment over time.
+pub struct MemoryPool<D: MemoryDevice = ash::Device> {
+    device: Arc<D>,
+    memory_type_index: u32,
+    blocks: Vec<Block>,
+    // Blocks emptied by `defragment`, freed once the copies out of them ran
+    retired: Vec<vk::DeviceMemory>,
+    block_size: vk::DeviceSize,
+}
+
+// Mapped pointers are only handed out through the pool
+unsafe impl<D: MemoryDevice> Send for MemoryPool<D> {}
+
+impl<D: MemoryDevice> MemoryPool<D> {
+    pub fn new(device: Arc<D>, memory_type_index: u32, block_size: vk::DeviceSize) -> Self {
+        Self {
+            device,
+            memory_type_index,
+            blocks: Vec::new(),
+            retired: Vec::new(),
+            block_size,
+        }
+    }
+
+    pub fn memory_type_index(&self) -> u32 {
+        self.memory_type_index
+    }
+
+    pub fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+        tag: MemoryTag,
+    ) -> Result<(vk::DeviceMemory, vk::DeviceSize)> {
+        if size == 0 {
+            return Err(MemoryError::InvalidOperation(
+                "Cannot allocate zero bytes".to_string(),
+            ));
+        }
+
+        let block_index = match self.find_best_fit(size, alignment) {
+            Some(index) => index,
+            None => self.allocate_block(size.max(self.block_size))?,
+        };
+        let block = &mut self.blocks[block_index];
+        let (range, offset, _) =
+            block
+                .best_fit(size, alignment)
+                .ok_or(MemoryError::OutOfMemory {
+                    requested: size,
+                    available: block.size,
+                })?;
+        block.take(
+            range,
+            offset,
+            Allocation {
+                size,
+                alignment,
+                tag,
+            },
+        );
+        Ok((block.memory, offset))
+    }
+
+    // Size of the freed allocation, `None` if there was none at that place
+    pub fn free(
+        &mut self,
+        memory: vk::DeviceMemory,
+        offset: vk::DeviceSize,
+    ) -> Option<vk::DeviceSize> {
+        let block = self
+            .blocks
+            .iter_mut()
+            .find(|block| block.memory == memory)?;
+        block.release(offset).map(|allocation| allocation.size)
+    }
+
+    pub fn contains(&self, memory: vk::DeviceMemory) -> bool {
+        self.block_index(memory).is_some()
+    }
+
+    // Pointer to the allocation at `offset`. Blocks are mapped once and the
+    // mapping is shared by everything inside them, since Vulkan forbids
+    // mapping the same memory twice. Pair every call with `unmap`.
+    pub fn map(&mut self, memory: vk::DeviceMemory, offset: vk::DeviceSize) -> Result<*mut c_void> {
+        let device = &self.device;
+        let block = self
+            .blocks
+            .iter_mut()
+            .find(|block| block.memory == memory)
+            .ok_or_else(|| {
+                MemoryError::InvalidOperation("Memory does not belong to this pool".to_string())
+            })?;
+
+        let base = match block.mapped {
+            Some(base) => base,
+            None => {
+                let base = device
+                    .map_memory(memory)
+                    .map_err(|e| MemoryError::MappingFailed(e.to_string()))?;
+                block.mapped = Some(base);
+                base
+            }
+        };
+        block.map_count += 1;
+        Ok(unsafe { base.cast::<u8>().add(offset as usize).cast() })
+    }
+
+    // Releases a `map`, the block is unmapped with its last user
+    pub fn unmap(&mut self, memory: vk::DeviceMemory) {
+        if let Some(block) = self.blocks.iter_mut().find(|block| block.memory == memory) {
+            block.map_count = block.map_count.saturating_sub(1);
+            if block.map_count == 0 && block.mapped.take().is_some() {
+                self.device.unmap_memory(memory);
+            }
+        }
+    }
+
+    // Packs allocations into fewer blocks by evacuating the least used ones
+    // into the free ranges of the others. Only blocks whose allocations all
+    // pass `movable` are evacuated, and only if every allocation fits
+    // elsewhere; mapped blocks are left alone since their pointers are in
+    // use. Sources and destinations never share a block and a block that
+    // received allocations isn't evacuated in the same pass, so the copies
+    // can't overlap and can run in any order.
+    //
+    // The evacuated blocks stay allocated until `release_retired`, which the
+    // caller calls once the returned copies completed.
+    pub fn defragment(&mut self, movable: impl Fn(MemoryTag) -> bool) -> Vec<Relocation> {
+        let mut candidates: Vec<usize> = (0..self.blocks.len())
+            .filter(|&index| {
+                let block = &self.blocks[index];
+                !block.is_empty()
+                    && block.mapped.is_none()
+                    && block.allocations.values().all(|a| movable(a.tag))
+            })
+            .collect();
+        candidates.sort_by_key(|&index| self.blocks[index].used_size());
+
+        let mut relocations = Vec::new();
+        let mut evacuated = Vec::new();
+        let mut destinations = Vec::new();
+        for source in candidates {
+            if destinations.contains(&source) {
+                continue;
+            }
+            let allocations: Vec<(vk::DeviceSize, Allocation)> = self.blocks[source]
+                .allocations
+                .iter()
+                .map(|(&offset, &allocation)| (offset, allocation))
+                .collect();
+
+            // Place every allocation or none
+            let mut placed = Vec::new();
+            for &(src_offset, allocation) in &allocations {
+                let target = (0..self.blocks.len())
+                    .filter(|&index| index != source && !evacuated.contains(&index))
+                    .filter_map(|index| {
+                        let (range, offset, waste) =
+                            self.blocks[index].best_fit(allocation.size, allocation.alignment)?;
+                        Some((index, range, offset, waste))
+                    })
+                    .min_by_key(|&(_, _, _, waste)| waste);
+                let Some((dst, range, dst_offset, _)) = target else {
+                    break;
+                };
+                self.blocks[dst].take(range, dst_offset, allocation);
+                placed.push(Relocation {
+                    size: allocation.size,
+                    tag: allocation.tag,
+                    src_memory: self.blocks[source].memory,
+                    src_offset,
+                    dst_memory: self.blocks[dst].memory,
+                    dst_offset,
+                });
+            }
+
+            if placed.len() < allocations.len() {
+                // Roll back, the block stays as it is
+                for relocation in placed {
+                    let dst = self.block_index(relocation.dst_memory).unwrap();
+                    self.blocks[dst].release(relocation.dst_offset);
+                }
+                continue;
+            }
+
+            for &(offset, _) in &allocations {
+                self.blocks[source].release(offset);
+            }
+            for relocation in &placed {
+                destinations.extend(self.block_index(relocation.dst_memory));
+            }
+            evacuated.push(source);
+            relocations.extend(placed);
+        }
+
+        // Retire the emptied blocks, highest index first so the rest keep
+        // their positions while removing
+        evacuated.sort_unstable();
+        for index in evacuated.into_iter().rev() {
+            let block = self.blocks.remove(index);
+            self.retired.push(block.memory);
+        }
+        relocations
+    }
+
+    // Frees the blocks `defragment` emptied
+    pub fn release_retired(&mut self) {
+        for memory in self.retired.drain(..) {
+            self.device.free_memory(memory);
+        }
+    }
+
+    // Bytes still allocated, reported as leaks on teardown
+    pub fn used_size(&self) -> vk::DeviceSize {
+        self.blocks.iter().map(Block::used_size).sum()
+    }
+
+    pub fn cleanup(&mut self) {
+        self.release_retired();
+        for block in &self.blocks {
+            if block.mapped.is_some() {
+                self.device.unmap_memory(block.memory);
+            }
+            self.device.free_memory(block.memory);
+        }
+        self.blocks.clear();
+    }
+
+    // Adds this pool's blocks to the totals in `stats` and to the entry of
+    // its memory type
+    pub fn accumulate_stats(&self, stats: &mut MemoryStats) {
+        if self.blocks.is_empty() {
+            return;
+        }
+        let mut type_stats = stats
+            .by_memory_type
+            .get(&self.memory_type_index)
+            .copied()
+            .unwrap_or_default();
+        for block in &self.blocks {
+            let used = block.used_size();
+            let largest_free_range = block
+                .free_ranges
+                .iter()
+                .map(|range| range.size)
+                .max()
+                .unwrap_or(0);
+
+            type_stats.total_size += block.size;
+            type_stats.used_size += used;
+            type_stats.free_size += block.size - used;
+            type_stats.block_count += 1;
+            type_stats.free_range_count += block.free_ranges.len() as u32;
+            type_stats.largest_free_range = type_stats.largest_free_range.max(largest_free_range);
+
+            stats.total_size += block.size;
+            stats.used_size += used;
+            stats.free_size += block.size - used;
+            stats.block_count += 1;
+            stats.free_range_count += block.free_ranges.len() as u32;
+            stats.largest_free_range = stats.largest_free_range.max(largest_free_range);
+        }
+        stats
+            .by_memory_type
+            .insert(self.memory_type_index, type_stats);
+    }
+
+    fn block_index(&self, memory: vk::DeviceMemory) -> Option<usize> {
+        self.blocks.iter().position(|block| block.memory == memory)
+    }
+
+    // Block with the tightest fitting free range, ties going to the earlier
+    // block
+    fn find_best_fit(&self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Option<usize> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(index, block)| {
+                let (_, _, waste) = block.best_fit(size, alignment)?;
+                Some((index, waste))
+            })
+            .min_by_key(|&(_, waste)| waste)
+            .map(|(index, _)| index)
+    }
+
+    fn allocate_block(&mut self, size: vk::DeviceSize) -> Result<usize> {
+        let memory = self
+            .device
+            .allocate_memory(size, self.memory_type_index)
+            .map_err(|e| MemoryError::AllocationFailed(e.to_string()))?;
+
+        self.blocks.push(Block::new(memory, size));
+        Ok(self.blocks.len() - 1)
+    }
+}
+
+impl<D: MemoryDevice> Drop for MemoryPool<D> {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::mock::MockDevice;
+
+    const BLOCK_SIZE: u64 = 1024 * 1024;
+    const TAG: MemoryTag = MemoryTag::Physics;
+
+    fn pool() -> (Arc<MockDevice>, MemoryPool<MockDevice>) {
+        let device = Arc::new(MockDevice::default());
+        let pool = MemoryPool::new(device.clone(), 0, BLOCK_SIZE);
+        (device, pool)
+    }
+
+    fn stats(pool: &MemoryPool<MockDevice>) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        pool.accumulate_stats(&mut stats);
+        stats
+    }
+
+    #[test]
+    fn test_sub_allocation_and_alignment() {
+        let (_, mut pool) = pool();
+        let (memory, a) = pool.allocate(100, 1, TAG).unwrap();
+        let (same, b) = pool.allocate(100, 256, TAG).unwrap();
+        assert_eq!(memory, same);
+        assert_eq!(a, 0);
+        assert_eq!(b, 256);
+
+        // The padding between them is still free and gets used
+        let (_, c) = pool.allocate(64, 4, TAG).unwrap();
+        assert_eq!(c, 100);
+
+        let stats = stats(&pool);
+        assert_eq!(stats.block_count, 1);
+        assert_eq!(stats.used_size, 264);
+        assert_eq!(stats.total_size, BLOCK_SIZE);
+
+        // Larger than a block gets a block of its own size
+        let (big, offset) = pool.allocate(BLOCK_SIZE * 2, 16, TAG).unwrap();
+        assert_ne!(big, memory);
+        assert_eq!(offset, 0);
+        assert_eq!(self::stats(&pool).block_count, 2);
+    }
+
+    #[test]
+    fn test_best_fit_and_coalescing() {
+        let (_, mut pool) = pool();
+        let offsets: Vec<_> = [64, 256, 64, 128, 64]
+            .iter()
+            .map(|&size| pool.allocate(size, 1, TAG).unwrap())
+            .collect();
+        let memory = offsets[0].0;
+
+        // Holes of 256 and 128 bytes, the smaller one fits best
+        pool.free(memory, offsets[1].1);
+        pool.free(memory, offsets[3].1);
+        let (_, offset) = pool.allocate(100, 1, TAG).unwrap();
+        assert_eq!(offset, offsets[3].1);
+        assert_eq!(pool.free(memory, offset), Some(100));
+
+        // Freeing everything merges back into a single range
+        for &(memory, offset) in offsets.iter().filter(|(_, o)| *o != offsets[1].1) {
+            pool.free(memory, offset);
+        }
+        let stats = stats(&pool);
+        assert_eq!(stats.used_size, 0);
+        assert_eq!(stats.free_range_count, 1);
+        assert_eq!(stats.largest_free_range, BLOCK_SIZE);
+
+        // Double frees are ignored
+        assert_eq!(pool.free(memory, offsets[0].1), None);
+    }
+
+    #[test]
+    fn test_shared_mapping() {
+        let (device, mut pool) = pool();
+        let (memory, a) = pool.allocate(128, 1, TAG).unwrap();
+        let (_, b) = pool.allocate(128, 1, TAG).unwrap();
+
+        let pa = pool.map(memory, a).unwrap();
+        let pb = pool.map(memory, b).unwrap();
+        assert_eq!(pb as usize - pa as usize, 128);
+
+        pool.unmap(memory);
+        assert_eq!(device.mapped_count(), 1);
+        pool.unmap(memory);
+        assert_eq!(device.mapped_count(), 0);
+    }
+
+    #[test]
+    fn test_defragment() {
+        let (device, mut pool) = pool();
+        let half = BLOCK_SIZE / 2;
+        // Fill two blocks, then leave half of each in use
+        let first: Vec<_> = (0..2)
+            .map(|_| pool.allocate(half, 256, TAG).unwrap())
+            .collect();
+        let second: Vec<_> = (0..2)
+            .map(|_| pool.allocate(half, 256, TAG).unwrap())
+            .collect();
+        assert_eq!(stats(&pool).block_count, 2);
+        pool.free(first[1].0, first[1].1);
+        pool.free(second[0].0, second[0].1);
+
+        // Other subsystems' blocks are not touched
+        assert!(pool.defragment(|tag| tag == MemoryTag::Graphics).is_empty());
+
+        let relocations = pool.defragment(|tag| tag == TAG);
+        assert_eq!(relocations.len(), 1);
+        let moved = relocations[0];
+        assert_eq!(moved.size, half);
+        assert_ne!(moved.src_memory, moved.dst_memory);
+        assert_eq!(moved.dst_offset % 256, 0);
+
+        let stats = stats(&pool);
+        assert_eq!(stats.block_count, 1);
+        assert_eq!(stats.used_size, BLOCK_SIZE);
+        // The evacuated block lives until the copies are done
+        assert_eq!(device.live_count(), 2);
+        pool.release_retired();
+        assert_eq!(device.live_count(), 1);
+
+        // Nothing left to compact
+        assert!(pool.defragment(|_| true).is_empty());
+        pool.cleanup();
+        assert_eq!(device.live_count(), 0);
+    }
+
+    #[test]
+    fn test_defragment_skips_mapped_and_unplaceable() {
+        let (_, mut pool) = pool();
+        let half = BLOCK_SIZE / 2;
+        let a = pool.allocate(half, 1, TAG).unwrap();
+        let b = pool.allocate(half + 1, 1, TAG).unwrap();
+        assert_ne!(a.0, b.0);
+        // Neither block has room for the other's allocation
+        assert!(pool.defragment(|_| true).is_empty());
+
+        // Room now, but the mapped block isn't moved
+        pool.free(a.0, a.1);
+        pool.map(b.0, b.1).unwrap();
+        assert!(pool.defragment(|_| true).is_empty());
+
+        pool.unmap(b.0);
+        let relocations = pool.defragment(|_| true);
+        assert_eq!(relocations.len(), 1);
+        assert_eq!(relocations[0].dst_memory, a.0);
+        assert_eq!(stats(&pool).block_count, 1);
+    }
+}
@@ -0,0 +1,865 @@
+use ash::vk;
+use bytemuck::Pod;
+use log::debug;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use super::error::{MemoryError, Result};
+use super::frame::FrameDevice;
+use super::{MemoryAllocator, MemoryBlock, MemoryTag};
+
+// Layout rules shaders read buffer contents with: uniform blocks use std140,
+// storage blocks std430
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferLayout {
+    Std140,
+    Std430,
+}
+
+// The GLSL type a struct field holds. Integer and boolean vectors lay out
+// like float ones. Matrices are column major with float columns.
+#[derive(Debug, Clone, Copy)]
+pub enum GpuType {
+    Scalar,
+    Vec2,
+    Vec3,
+    Vec4,
+    Mat2,
+    Mat3,
+    Mat4,
+    Array {
+        element: &'static GpuType,
+        len: usize,
+    },
+    // A nested struct, see `GpuType::of`
+    Struct(&'static [GpuField]),
+    // Explicit padding bytes, never read by the shader
+    Padding(usize),
+}
+
+impl GpuType {
+    pub const fn of<T: GpuLayout>() -> Self {
+        GpuType::Struct(T::FIELDS)
+    }
+
+    pub const fn align(&self, layout: BufferLayout) -> usize {
+        let align = match self {
+            GpuType::Scalar => 4,
+            GpuType::Vec2 => 8,
+            GpuType::Vec3 | GpuType::Vec4 => 16,
+            GpuType::Mat2 => GpuType::Vec2.array_align(layout),
+            GpuType::Mat3 | GpuType::Mat4 => 16,
+            GpuType::Array { element, .. } => element.array_align(layout),
+            GpuType::Struct(fields) => {
+                let mut align = 1;
+                let mut i = 0;
+                while i < fields.len() {
+                    let field_align = fields[i].ty.align(layout);
+                    if field_align > align {
+                        align = field_align;
+                    }
+                    i += 1;
+                }
+                align
+            }
+            GpuType::Padding(_) => 1,
+        };
+        // std140 rounds the alignment of arrays and structs up to a vec4
+        match (layout, self) {
+            (BufferLayout::Std140, GpuType::Struct(_)) if align < 16 => 16,
+            _ => align,
+        }
+    }
+
+    // Bytes the value occupies, without trailing space a following field
+    // may use: a vec3 is 12 bytes even though it is aligned to 16
+    pub const fn size(&self, layout: BufferLayout) -> usize {
+        match self {
+            GpuType::Scalar => 4,
+            GpuType::Vec2 => 8,
+            GpuType::Vec3 => 12,
+            GpuType::Vec4 => 16,
+            GpuType::Mat2 => 2 * GpuType::Vec2.array_stride(layout),
+            GpuType::Mat3 => 3 * GpuType::Vec3.array_stride(layout),
+            GpuType::Mat4 => 4 * GpuType::Vec4.array_stride(layout),
+            GpuType::Array { element, len } => *len * element.array_stride(layout),
+            GpuType::Struct(fields) => {
+                let mut end = 0;
+                let mut i = 0;
+                while i < fields.len() {
+                    let field_end = fields[i].offset + fields[i].ty.size(layout);
+                    if field_end > end {
+                        end = field_end;
+                    }
+                    i += 1;
+                }
+                align_to(end, self.align(layout))
+            }
+            GpuType::Padding(size) => *size,
+        }
+    }
+
+    // Distance between array elements of this type
+    pub const fn array_stride(&self, layout: BufferLayout) -> usize {
+        align_to(self.size(layout), self.array_align(layout))
+    }
+
+    const fn array_align(&self, layout: BufferLayout) -> usize {
+        let align = self.align(layout);
+        match layout {
+            BufferLayout::Std140 if align < 16 => 16,
+            _ => align,
+        }
+    }
+}
+
+// A field of a `GpuLayout` struct. `offset` and `size` are the Rust ones.
+#[derive(Debug, Clone, Copy)]
+pub struct GpuField {
+    pub name: &'static str,
+    pub offset: usize,
+    pub size: usize,
+    pub ty: GpuType,
+}
+
+// Describes how a `Pod` struct maps onto a GLSL block. Implement it with
+// `gpu_layout!`, which also checks the description against the layouts
+// listed at compile time.
+pub trait GpuLayout: Pod {
+    const FIELDS: &'static [GpuField];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutErrorKind {
+    // The field doesn't start at a multiple of its alignment
+    Misaligned { offset: usize, align: usize },
+    // The Rust field is a different size than the GLSL one, e.g. a 36 byte
+    // `Mat3` for a 48 byte mat3
+    SizeMismatch { size: usize, expected: usize },
+    // Bytes of the struct no field describes
+    Undeclared { declared: usize, size: usize },
+    // The struct size isn't what the shader steps array elements by
+    StrideMismatch { size: usize, expected: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayoutError {
+    // `None` for errors about the struct as a whole
+    pub field: Option<&'static str>,
+    pub layout: BufferLayout,
+    pub kind: LayoutErrorKind,
+}
+
+impl LayoutError {
+    pub const fn is_field(&self, name: &str) -> bool {
+        match self.field {
+            Some(field) => str_eq(field, name),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(field) = self.field {
+            write!(f, "field `{}`: ", field)?;
+        }
+        match self.kind {
+            LayoutErrorKind::Misaligned { offset, align } => write!(
+                f,
+                "offset {} is not a multiple of the {:?} alignment {}",
+                offset, self.layout, align
+            ),
+            LayoutErrorKind::SizeMismatch { size, expected } => {
+                write!(f, "{} bytes, {:?} expects {}", size, self.layout, expected)
+            }
+            LayoutErrorKind::Undeclared { declared, size } => write!(
+                f,
+                "fields cover {} of the {} bytes, declare padding explicitly",
+                declared, size
+            ),
+            LayoutErrorKind::StrideMismatch { size, expected } => write!(
+                f,
+                "struct is {} bytes, {:?} arrays step by {}",
+                size, self.layout, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+// Checks that `fields` describe a `size` byte struct that shaders read the
+// same way under `layout`
+pub const fn check_layout(
+    fields: &'static [GpuField],
+    size: usize,
+    layout: BufferLayout,
+) -> std::result::Result<(), LayoutError> {
+    let mut declared = 0;
+    let mut i = 0;
+    while i < fields.len() {
+        let field = &fields[i];
+        let align = field.ty.align(layout);
+        if !field.offset.is_multiple_of(align) {
+            return Err(LayoutError {
+                field: Some(field.name),
+                layout,
+                kind: LayoutErrorKind::Misaligned {
+                    offset: field.offset,
+                    align,
+                },
+            });
+        }
+        let expected = field.ty.size(layout);
+        if field.size != expected {
+            return Err(LayoutError {
+                field: Some(field.name),
+                layout,
+                kind: LayoutErrorKind::SizeMismatch {
+                    size: field.size,
+                    expected,
+                },
+            });
+        }
+        declared += field.size;
+        i += 1;
+    }
+
+    if declared != size {
+        return Err(LayoutError {
+            field: None,
+            layout,
+            kind: LayoutErrorKind::Undeclared { declared, size },
+        });
+    }
+    let expected = GpuType::Struct(fields).array_stride(layout);
+    if size != expected {
+        return Err(LayoutError {
+            field: None,
+            layout,
+            kind: LayoutErrorKind::StrideMismatch { size, expected },
+        });
+    }
+    Ok(())
+}
+
+pub fn validate_layout<T: GpuLayout>(layout: BufferLayout) -> Result<()> {
+    check_layout(T::FIELDS, std::mem::size_of::<T>(), layout).map_err(MemoryError::InvalidLayout)
+}
+
+// Implements `GpuLayout` for a struct and fails to compile if the struct
+// doesn't match each listed layout. List every field, padding included, in
+// declaration order:
+//
+//     gpu_layout!(Light: Std140, Std430 {
+//         position: GpuType::Vec3,
+//         radius: GpuType::Scalar,
+//         color: GpuType::Vec4,
+//     });
+#[macro_export]
+macro_rules! gpu_layout {
+    ($ty:ident $(: $($layout:ident),+)? { $($fields:tt)* }) => {
+        $crate::gpu_layout!(@impl $ty { $($fields)* });
+        $crate::gpu_layout!(@checks $ty, [$($($layout),+)?], { $($fields)* });
+    };
+    (@checks $ty:ident, [], $fields:tt) => {};
+    (@checks $ty:ident, [$layout:ident $(, $rest:ident)*], $fields:tt) => {
+        $crate::gpu_layout!(@check $ty, $layout, $fields);
+        $crate::gpu_layout!(@checks $ty, [$($rest),*], $fields);
+    };
+    (@impl $ty:ident { $($field:ident: $gpu:expr),* $(,)? }) => {
+        impl $crate::memory::GpuLayout for $ty {
+            const FIELDS: &'static [$crate::memory::GpuField] = &[$(
+                $crate::memory::GpuField {
+                    name: stringify!($field),
+                    offset: std::mem::offset_of!($ty, $field),
+                    size: $crate::memory::field_size(|value: &$ty| &value.$field),
+                    ty: $gpu,
+                },
+            )*];
+        }
+    };
+    (@check $ty:ident, $layout:ident, { $($field:ident: $gpu:expr),* $(,)? }) => {
+        const _: () = {
+            let result = $crate::memory::check_layout(
+                <$ty as $crate::memory::GpuLayout>::FIELDS,
+                std::mem::size_of::<$ty>(),
+                $crate::memory::BufferLayout::$layout,
+            );
+            $(
+                if let Err(error) = result {
+                    if error.is_field(stringify!($field)) {
+                        panic!(concat!(
+                            "`", stringify!($ty), "::", stringify!($field),
+                            "` does not match the ", stringify!($layout), " layout"
+                        ));
+                    }
+                }
+            )*
+            if result.is_err() {
+                panic!(concat!(
+                    "`", stringify!($ty), "` does not match the ", stringify!($layout),
+                    " layout, check its size and padding"
+                ));
+            }
+        };
+    };
+}
+
+// Size of the field `field` borrows, for `gpu_layout!`
+#[doc(hidden)]
+pub const fn field_size<S, F>(_field: fn(&S) -> &F) -> usize {
+    std::mem::size_of::<F>()
+}
+
+const fn align_to(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+// Index types an index buffer can hold
+pub trait IndexElement: Pod {
+    const INDEX_TYPE: vk::IndexType;
+}
+
+impl IndexElement for u16 {
+    const INDEX_TYPE: vk::IndexType = vk::IndexType::UINT16;
+}
+
+impl IndexElement for u32 {
+    const INDEX_TYPE: vk::IndexType = vk::IndexType::UINT32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferKind {
+    Vertex,
+    Index,
+    Uniform,
+    Storage,
+}
+
+impl BufferKind {
+    // Usage flags buffers of this kind are created with. Every kind can be
+    // filled by a transfer, storage buffers can also be copied out of.
+    pub fn usage(self) -> vk::BufferUsageFlags {
+        let usage = match self {
+            BufferKind::Vertex => vk::BufferUsageFlags::VERTEX_BUFFER,
+            BufferKind::Index => vk::BufferUsageFlags::INDEX_BUFFER,
+            BufferKind::Uniform => vk::BufferUsageFlags::UNIFORM_BUFFER,
+            BufferKind::Storage => {
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC
+            }
+        };
+        usage | vk::BufferUsageFlags::TRANSFER_DST
+    }
+
+    // The layout elements are checked against
+    pub fn layout(self) -> Option<BufferLayout> {
+        match self {
+            BufferKind::Uniform => Some(BufferLayout::Std140),
+            BufferKind::Storage => Some(BufferLayout::Std430),
+            BufferKind::Vertex | BufferKind::Index => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            BufferKind::Vertex => "vertex buffer",
+            BufferKind::Index => "index buffer",
+            BufferKind::Uniform => "uniform buffer",
+            BufferKind::Storage => "storage buffer",
+        }
+    }
+}
+
+// A buffer of `len` elements of `T`. Uniform and storage element types are
+// checked against std140 and std430 when the buffer is created. Buffers in
+// host-coherent memory are written and read element-wise through `write`
+// and `read`; device-local ones are filled by transfers into `buffer()`,
+// using `element_range` for the byte range.
+pub struct TypedBuffer<T: Pod, D: FrameDevice = ash::Device> {
+    allocator: Arc<MemoryAllocator<D>>,
+    buffer: vk::Buffer,
+    memory: MemoryBlock,
+    kind: BufferKind,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Pod, D: FrameDevice> TypedBuffer<T, D> {
+    #[track_caller]
+    pub fn vertex(
+        allocator: Arc<MemoryAllocator<D>>,
+        len: usize,
+        properties: vk::MemoryPropertyFlags,
+        tag: MemoryTag,
+    ) -> Result<Self> {
+        Self::create(allocator, BufferKind::Vertex, len, properties, tag)
+    }
+
+    #[track_caller]
+    fn create(
+        allocator: Arc<MemoryAllocator<D>>,
+        kind: BufferKind,
+        len: usize,
+        properties: vk::MemoryPropertyFlags,
+        tag: MemoryTag,
+    ) -> Result<Self> {
+        let size = (len * std::mem::size_of::<T>()) as vk::DeviceSize;
+        if size == 0 {
+            return Err(MemoryError::InvalidOperation(format!(
+                "Cannot create an empty {}",
+                kind.name()
+            )));
+        }
+
+        let device = allocator.device();
+        let buffer = device
+            .create_buffer(size, kind.usage())
+            .map_err(|e| MemoryError::AllocationFailed(e.to_string()))?;
+        let requirements = device.buffer_memory_requirements(buffer);
+        let memory = match allocator.allocate_named(requirements, properties, tag, kind.name()) {
+            Ok(memory) => memory,
+            Err(e) => {
+                device.destroy_buffer(buffer);
+                return Err(e);
+            }
+        };
+        if let Err(e) = device.bind_buffer_memory(buffer, memory.memory, memory.offset) {
+            device.destroy_buffer(buffer);
+            let _ = allocator.free(memory);
+            return Err(MemoryError::AllocationFailed(e.to_string()));
+        }
+
+        debug!(
+            "Created {} of {} x {} bytes",
+            kind.name(),
+            len,
+            std::mem::size_of::<T>()
+        );
+        Ok(Self {
+            allocator,
+            buffer,
+            memory,
+            kind,
+            len,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn buffer(&self) -> vk::Buffer {
+        self.buffer
+    }
+
+    pub fn kind(&self) -> BufferKind {
+        self.kind
+    }
+
+    pub fn usage(&self) -> vk::BufferUsageFlags {
+        self.kind.usage()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Bytes between elements
+    pub fn stride(&self) -> vk::DeviceSize {
+        std::mem::size_of::<T>() as vk::DeviceSize
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.len as vk::DeviceSize * self.stride()
+    }
+
+    // Whether `write` and `read` work on this buffer
+    pub fn is_host_accessible(&self) -> bool {
+        self.allocator.memory_flags(&self.memory).contains(
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )
+    }
+
+    // Byte offset and size of `count` elements starting at `first`
+    pub fn element_range(
+        &self,
+        first: usize,
+        count: usize,
+    ) -> Result<(vk::DeviceSize, vk::DeviceSize)> {
+        match first.checked_add(count) {
+            Some(end) if end <= self.len => Ok((
+                first as vk::DeviceSize * self.stride(),
+                count as vk::DeviceSize * self.stride(),
+            )),
+            _ => Err(MemoryError::InvalidBufferAccess(format!(
+                "Elements {}..{} out of bounds of a {} with {} elements",
+                first,
+                first.saturating_add(count),
+                self.kind.name(),
+                self.len
+            ))),
+        }
+    }
+
+    // Writes `data` to the elements starting at `first`
+    pub fn write(&self, first: usize, data: &[T]) -> Result<()> {
+        let (offset, size) = self.element_range(first, data.len())?;
+        let mapped = self.map()?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr() as *const u8,
+                mapped.add(offset as usize),
+                size as usize,
+            );
+        }
+        self.allocator.unmap(&self.memory);
+        Ok(())
+    }
+
+    pub fn set(&self, index: usize, value: T) -> Result<()> {
+        self.write(index, std::slice::from_ref(&value))
+    }
+
+    // Reads `count` elements starting at `first`. The GPU must be done
+    // writing them.
+    pub fn read(&self, first: usize, count: usize) -> Result<Vec<T>> {
+        let (offset, size) = self.element_range(first, count)?;
+        let mapped = self.map()?;
+        let mut elements = vec![T::zeroed(); count];
+        let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut elements);
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                mapped.add(offset as usize),
+                bytes.as_mut_ptr(),
+                size as usize,
+            );
+        }
+        self.allocator.unmap(&self.memory);
+        Ok(elements)
+    }
+
+    pub fn get(&self, index: usize) -> Result<T> {
+        Ok(self.read(index, 1)?[0])
+    }
+
+    fn map(&self) -> Result<*mut u8> {
+        if !self.is_host_accessible() {
+            return Err(MemoryError::InvalidBufferAccess(format!(
+                "{} is not in host coherent memory, fill it with a transfer",
+                self.kind.name()
+            )));
+        }
+        Ok(self.allocator.map(&self.memory)? as *mut u8)
+    }
+}
+
+impl<T: IndexElement, D: FrameDevice> TypedBuffer<T, D> {
+    #[track_caller]
+    pub fn index(
+        allocator: Arc<MemoryAllocator<D>>,
+        len: usize,
+        properties: vk::MemoryPropertyFlags,
+        tag: MemoryTag,
+    ) -> Result<Self> {
+        Self::create(allocator, BufferKind::Index, len, properties, tag)
+    }
+
+    pub fn index_type(&self) -> vk::IndexType {
+        T::INDEX_TYPE
+    }
+}
+
+impl<T: GpuLayout, D: FrameDevice> TypedBuffer<T, D> {
+    // Fails if `T` doesn't follow std140
+    #[track_caller]
+    pub fn uniform(
+        allocator: Arc<MemoryAllocator<D>>,
+        len: usize,
+        properties: vk::MemoryPropertyFlags,
+        tag: MemoryTag,
+    ) -> Result<Self> {
+        validate_layout::<T>(BufferLayout::Std140)?;
+        Self::create(allocator, BufferKind::Uniform, len, properties, tag)
+    }
+
+    // Fails if `T` doesn't follow std430
+    #[track_caller]
+    pub fn storage(
+        allocator: Arc<MemoryAllocator<D>>,
+        len: usize,
+        properties: vk::MemoryPropertyFlags,
+        tag: MemoryTag,
+    ) -> Result<Self> {
+        validate_layout::<T>(BufferLayout::Std430)?;
+        Self::create(allocator, BufferKind::Storage, len, properties, tag)
+    }
+}
+
+impl<T: Pod, D: FrameDevice> Drop for TypedBuffer<T, D> {
+    fn drop(&mut self) {
+        self.allocator.device().destroy_buffer(self.buffer);
+        let _ = self.allocator.free(self.memory);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::mock::MockDevice;
+    use crate::memory::AllocatorConfig;
+    use bytemuck::Zeroable;
+
+    const HOST: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::from_raw(
+        vk::MemoryPropertyFlags::HOST_VISIBLE.as_raw()
+            | vk::MemoryPropertyFlags::HOST_COHERENT.as_raw(),
+    );
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, PartialEq, Pod, Zeroable)]
+    struct Light {
+        position: [f32; 3],
+        radius: f32,
+        color: [f32; 4],
+    }
+
+    gpu_layout!(Light: Std140, Std430 {
+        position: GpuType::Vec3,
+        radius: GpuType::Scalar,
+        color: GpuType::Vec4,
+    });
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, Pod, Zeroable)]
+    struct Lights {
+        lights: [Light; 4],
+        count: u32,
+        _padding: [u32; 3],
+    }
+
+    gpu_layout!(Lights: Std140, Std430 {
+        lights: GpuType::Array { element: &GpuType::of::<Light>(), len: 4 },
+        count: GpuType::Scalar,
+        _padding: GpuType::Padding(12),
+    });
+
+    // Fine for std430, but std140 steps float arrays by 16 bytes
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, Pod, Zeroable)]
+    struct Weights {
+        weights: [f32; 4],
+    }
+
+    gpu_layout!(Weights: Std430 {
+        weights: GpuType::Array { element: &GpuType::Scalar, len: 4 },
+    });
+
+    // A mat3 as three packed vec3s, 36 bytes instead of 48
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, Pod, Zeroable)]
+    struct Normals {
+        normal_matrix: [f32; 9],
+        scale: [f32; 3],
+    }
+
+    gpu_layout!(Normals {
+        normal_matrix: GpuType::Mat3,
+        scale: GpuType::Vec3,
+    });
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, Pod, Zeroable)]
+    struct Particle {
+        velocity: [f32; 2],
+        mass: f32,
+        position: [f32; 3],
+    }
+
+    gpu_layout!(Particle {
+        velocity: GpuType::Vec2,
+        mass: GpuType::Scalar,
+        position: GpuType::Vec3,
+    });
+
+    fn allocator() -> Arc<MemoryAllocator<MockDevice>> {
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties::default();
+        memory_properties.memory_types[0].property_flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        memory_properties.memory_types[1].property_flags = HOST;
+        memory_properties.memory_type_count = 2;
+
+        Arc::new(MemoryAllocator::with_device(
+            Arc::new(MockDevice::default()),
+            memory_properties,
+            AllocatorConfig {
+                block_size: 1024 * 1024,
+                dedicated_threshold: 512 * 1024,
+            },
+        ))
+    }
+
+    #[test]
+    fn test_type_layouts() {
+        use BufferLayout::{Std140, Std430};
+
+        assert_eq!(GpuType::Vec3.align(Std430), 16);
+        assert_eq!(GpuType::Vec3.size(Std430), 12);
+        assert_eq!(GpuType::Vec3.array_stride(Std430), 16);
+        assert_eq!(GpuType::Mat2.size(Std140), 32);
+        assert_eq!(GpuType::Mat2.size(Std430), 16);
+        assert_eq!(GpuType::Mat3.size(Std430), 48);
+
+        let floats = GpuType::Array {
+            element: &GpuType::Scalar,
+            len: 4,
+        };
+        assert_eq!(floats.size(Std140), 64);
+        assert_eq!(floats.size(Std430), 16);
+
+        // std140 rounds struct alignment up to 16
+        let particle = GpuType::of::<Particle>();
+        assert_eq!(particle.align(Std430), 16);
+        assert_eq!(GpuType::of::<Weights>().align(Std140), 16);
+        assert_eq!(GpuType::of::<Weights>().align(Std430), 4);
+    }
+
+    #[test]
+    fn test_layout_errors() {
+        let error = validate_layout::<Weights>(BufferLayout::Std140).unwrap_err();
+        assert!(matches!(
+            error,
+            MemoryError::InvalidLayout(LayoutError {
+                field: Some("weights"),
+                kind: LayoutErrorKind::SizeMismatch {
+                    size: 16,
+                    expected: 64
+                },
+                ..
+            })
+        ));
+
+        let error = check_layout(Normals::FIELDS, 48, BufferLayout::Std430).unwrap_err();
+        assert!(error.is_field("normal_matrix"));
+        assert_eq!(
+            error.to_string(),
+            "field `normal_matrix`: 36 bytes, Std430 expects 48"
+        );
+
+        // The vec3 lands on offset 12
+        let error = check_layout(Particle::FIELDS, 24, BufferLayout::Std430).unwrap_err();
+        assert_eq!(
+            error.kind,
+            LayoutErrorKind::Misaligned {
+                offset: 12,
+                align: 16
+            }
+        );
+
+        // Trailing bytes left out of the description
+        let error = check_layout(&Light::FIELDS[..2], 32, BufferLayout::Std430).unwrap_err();
+        assert_eq!(
+            error.kind,
+            LayoutErrorKind::Undeclared {
+                declared: 16,
+                size: 32
+            }
+        );
+        assert_eq!(error.field, None);
+    }
+
+    #[test]
+    fn test_host_buffer_write_read() {
+        let allocator = allocator();
+        let lights: TypedBuffer<Light, MockDevice> =
+            TypedBuffer::storage(allocator.clone(), 8, HOST, MemoryTag::Graphics).unwrap();
+        assert_eq!(lights.size(), 8 * 32);
+        assert!(lights
+            .usage()
+            .contains(vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST));
+        assert!(lights.is_host_accessible());
+
+        let light = Light {
+            position: [1.0, 2.0, 3.0],
+            radius: 4.0,
+            color: [0.5; 4],
+        };
+        lights.write(2, &[light, light]).unwrap();
+        lights.set(7, Light::zeroed()).unwrap();
+        assert_eq!(lights.get(3).unwrap(), light);
+        assert_eq!(lights.read(1, 3).unwrap(), [Light::zeroed(), light, light]);
+        assert_eq!(lights.element_range(2, 2).unwrap(), (64, 64));
+
+        assert!(matches!(
+            lights.write(7, &[light, light]),
+            Err(MemoryError::InvalidBufferAccess(_))
+        ));
+        assert!(lights.read(usize::MAX, 2).is_err());
+
+        drop(lights);
+        assert_eq!(allocator.device().buffer_count(), 0);
+        assert_eq!(allocator.device().mapped_count(), 0);
+    }
+
+    #[test]
+    fn test_buffer_kinds() {
+        let allocator = allocator();
+        let indices: TypedBuffer<u16, MockDevice> = TypedBuffer::index(
+            allocator.clone(),
+            6,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            MemoryTag::Graphics,
+        )
+        .unwrap();
+        assert_eq!(indices.index_type(), vk::IndexType::UINT16);
+        assert!(indices.usage().contains(vk::BufferUsageFlags::INDEX_BUFFER));
+        assert!(!indices.is_host_accessible());
+
+        // Device local buffers are filled by transfers
+        assert!(matches!(
+            indices.write(0, &[0, 1, 2]),
+            Err(MemoryError::InvalidBufferAccess(_))
+        ));
+
+        assert!(matches!(
+            TypedBuffer::<Weights, MockDevice>::uniform(
+                allocator.clone(),
+                1,
+                HOST,
+                MemoryTag::Graphics
+            ),
+            Err(MemoryError::InvalidLayout(_))
+        ));
+        assert!(TypedBuffer::<Lights, MockDevice>::uniform(
+            allocator.clone(),
+            1,
+            HOST,
+            MemoryTag::Graphics
+        )
+        .is_ok());
+        assert!(TypedBuffer::<[f32; 3], MockDevice>::vertex(
+            allocator.clone(),
+            0,
+            HOST,
+            MemoryTag::Graphics
+        )
+        .is_err());
+        assert_eq!(allocator.device().buffer_count(), 1);
+    }
+}
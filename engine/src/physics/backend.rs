@@ -0,0 +1,485 @@
+// Particle simulation behind one interface, so the ECS bridge and tests can
+// run on the Vulkan compute shaders or on a CPU reference. The CPU backend
+// runs the integration math of `particle_update.comp` and the SPH pass of
+// `sph.comp` on the host, keep it in sync with the shaders.
+//
+// Emitters and custom neighbor passes are GPU only. Spawning pops slots off
+// an atomic free list, so which slot a particle lands in isn't reproducible
+// and couldn't be cross-checked anyway.
+
+use glam::Vec3;
+use std::fmt;
+
+use crate::physics::force_field::{ForceField, GpuForceField, MAX_FORCE_FIELDS};
+use crate::physics::gpu_physics::{GpuPhysicsSystem, PhysicsError};
+use crate::physics::particle_grid::{bucket_count, sph_forces, ParticleGrid, SphSettings};
+use crate::physics::particle_layout::{Particle, PushConstants};
+use crate::physics::timestamps::PassTiming;
+
+pub trait ParticleBackend: Send {
+    // Short name for logs
+    fn name(&self) -> &'static str;
+
+    fn capacity(&self) -> usize;
+
+    // Reallocates for `capacity` particles, keeping as many as still fit
+    fn resize(&mut self, capacity: usize) -> Result<(), PhysicsError>;
+
+    fn params(&self) -> PushConstants;
+
+    // Simulation parameters for the following steps. The time step, the
+    // time and the counts are filled in by `step`.
+    fn set_params(&mut self, params: PushConstants);
+
+    fn force_fields(&self) -> &[ForceField];
+
+    fn set_force_fields(&mut self, fields: &[ForceField]) -> Result<(), PhysicsError>;
+
+    fn fluid(&self) -> Option<SphSettings>;
+
+    fn set_fluid(&mut self, settings: Option<SphSettings>) -> Result<(), PhysicsError>;
+
+    // Seconds simulated so far, animates turbulence
+    fn time(&self) -> f32;
+
+    // Replaces the particles. Slots past the end of `particles` are dead.
+    fn update_particles(&mut self, particles: &[Particle]) -> Result<(), PhysicsError>;
+
+    // Particles as of the last step, dead slots included
+    fn get_particle_data(&self) -> Result<Vec<Particle>, PhysicsError>;
+
+    fn step(&mut self, delta_time: f32) -> Result<(), PhysicsError>;
+
+    fn set_debug_enabled(&mut self, enabled: bool);
+
+    // Faults in a row to recover from before giving up, for backends that
+    // can lose their device
+    fn set_max_recovery_attempts(&mut self, _attempts: u32) {}
+
+    // Time spent in each pass of the last step, for backends that measure it
+    fn pass_timings(&self) -> Vec<PassTiming> {
+        Vec::new()
+    }
+}
+
+impl ParticleBackend for GpuPhysicsSystem {
+    fn name(&self) -> &'static str {
+        "vulkan"
+    }
+
+    fn capacity(&self) -> usize {
+        GpuPhysicsSystem::capacity(self)
+    }
+
+    fn resize(&mut self, capacity: usize) -> Result<(), PhysicsError> {
+        GpuPhysicsSystem::resize(self, capacity)
+    }
+
+    fn params(&self) -> PushConstants {
+        GpuPhysicsSystem::params(self)
+    }
+
+    fn set_params(&mut self, params: PushConstants) {
+        GpuPhysicsSystem::set_params(self, params)
+    }
+
+    fn force_fields(&self) -> &[ForceField] {
+        GpuPhysicsSystem::force_fields(self)
+    }
+
+    fn set_force_fields(&mut self, fields: &[ForceField]) -> Result<(), PhysicsError> {
+        GpuPhysicsSystem::set_force_fields(self, fields)
+    }
+
+    fn fluid(&self) -> Option<SphSettings> {
+        GpuPhysicsSystem::fluid(self)
+    }
+
+    fn set_fluid(&mut self, settings: Option<SphSettings>) -> Result<(), PhysicsError> {
+        GpuPhysicsSystem::set_fluid(self, settings)
+    }
+
+    fn time(&self) -> f32 {
+        self.elapsed
+    }
+
+    fn update_particles(&mut self, particles: &[Particle]) -> Result<(), PhysicsError> {
+        GpuPhysicsSystem::update_particles(self, particles)
+    }
+
+    fn get_particle_data(&self) -> Result<Vec<Particle>, PhysicsError> {
+        GpuPhysicsSystem::get_particle_data(self)
+    }
+
+    fn step(&mut self, delta_time: f32) -> Result<(), PhysicsError> {
+        GpuPhysicsSystem::step(self, delta_time)
+    }
+
+    fn set_debug_enabled(&mut self, enabled: bool) {
+        self.debug_enabled = enabled;
+    }
+
+    fn set_max_recovery_attempts(&mut self, attempts: u32) {
+        GpuPhysicsSystem::set_max_recovery_attempts(self, attempts)
+    }
+
+    fn pass_timings(&self) -> Vec<PassTiming> {
+        GpuPhysicsSystem::pass_timings(self).to_vec()
+    }
+}
+
+// Reference implementation on the host, for machines without a GPU and for
+// checking the shaders against
+pub struct CpuParticleBackend {
+    particles: Vec<Particle>,
+    params: PushConstants,
+    force_fields: Vec<ForceField>,
+    fluid: Option<SphSettings>,
+    densities: Vec<f32>,
+    pub(crate) elapsed: f32,
+    debug_enabled: bool,
+}
+
+impl CpuParticleBackend {
+    // A backend with `capacity` dead slots
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            particles: vec![Particle::default().with_lifetime(0.0); capacity],
+            params: PushConstants::new(1.0 / 60.0, capacity as u32),
+            force_fields: Vec::new(),
+            fluid: None,
+            densities: vec![0.0; capacity],
+            elapsed: 0.0,
+            debug_enabled: false,
+        }
+    }
+
+    // A CPU copy of another backend's particles and settings, to step side
+    // by side with it and `cross_check` the results
+    pub fn mirror(backend: &dyn ParticleBackend) -> Result<Self, PhysicsError> {
+        let mut mirror = Self::new(backend.capacity());
+        mirror.set_params(backend.params());
+        mirror.set_force_fields(backend.force_fields())?;
+        mirror.set_fluid(backend.fluid())?;
+        mirror.update_particles(&backend.get_particle_data()?)?;
+        mirror.elapsed = backend.time();
+        Ok(mirror)
+    }
+
+    // SPH densities from the last step, zero for dead particles
+    pub fn densities(&self) -> &[f32] {
+        &self.densities
+    }
+
+    pub fn alive_count(&self) -> u32 {
+        self.particles.iter().filter(|p| p.is_alive()).count() as u32
+    }
+
+    // Compares `particles`, usually read back from the GPU, against this
+    // backend's. Positions, velocities and lifetimes may differ by
+    // `tolerance`, relative to their magnitude once that is above one.
+    // Returns the first particle that differs by more.
+    pub fn cross_check(
+        &self,
+        particles: &[Particle],
+        tolerance: f32,
+    ) -> Result<(), ParticleMismatch> {
+        if particles.len() != self.particles.len() {
+            return Err(ParticleMismatch {
+                index: particles.len().min(self.particles.len()),
+                field: "count",
+                expected: [self.particles.len() as f32, 0.0, 0.0],
+                actual: [particles.len() as f32, 0.0, 0.0],
+            });
+        }
+
+        for (index, (expected, actual)) in self.particles.iter().zip(particles).enumerate() {
+            let fields = [
+                ("position", expected.position, actual.position),
+                ("velocity", expected.velocity, actual.velocity),
+                (
+                    "lifetime",
+                    [expected.lifetime, 0.0, 0.0],
+                    [actual.lifetime, 0.0, 0.0],
+                ),
+            ];
+            for (field, expected, actual) in fields {
+                let close = expected
+                    .iter()
+                    .zip(&actual)
+                    .all(|(&e, &a)| e == a || (e - a).abs() <= tolerance * e.abs().max(1.0));
+                if !close {
+                    return Err(ParticleMismatch {
+                        index,
+                        field,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The enabled fields as the update shader sees them
+    fn packed_fields(&self) -> Vec<GpuForceField> {
+        self.force_fields
+            .iter()
+            .filter(|field| field.enabled)
+            .map(|field| field.to_gpu())
+            .collect()
+    }
+}
+
+impl ParticleBackend for CpuParticleBackend {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn capacity(&self) -> usize {
+        self.particles.len()
+    }
+
+    fn resize(&mut self, capacity: usize) -> Result<(), PhysicsError> {
+        self.particles
+            .resize(capacity, Particle::default().with_lifetime(0.0));
+        self.densities.resize(capacity, 0.0);
+        Ok(())
+    }
+
+    fn params(&self) -> PushConstants {
+        self.params
+    }
+
+    fn set_params(&mut self, params: PushConstants) {
+        self.params = params;
+    }
+
+    fn force_fields(&self) -> &[ForceField] {
+        &self.force_fields
+    }
+
+    fn set_force_fields(&mut self, fields: &[ForceField]) -> Result<(), PhysicsError> {
+        if fields.len() > MAX_FORCE_FIELDS {
+            return Err(PhysicsError::BufferOverflow {
+                message: "Too many force fields".to_string(),
+                required: (fields.len() * std::mem::size_of::<GpuForceField>()) as u64,
+                available: (MAX_FORCE_FIELDS * std::mem::size_of::<GpuForceField>()) as u64,
+            });
+        }
+        self.force_fields = fields.to_vec();
+        Ok(())
+    }
+
+    fn fluid(&self) -> Option<SphSettings> {
+        self.fluid
+    }
+
+    fn set_fluid(&mut self, settings: Option<SphSettings>) -> Result<(), PhysicsError> {
+        self.fluid = settings;
+        Ok(())
+    }
+
+    fn time(&self) -> f32 {
+        self.elapsed
+    }
+
+    fn update_particles(&mut self, particles: &[Particle]) -> Result<(), PhysicsError> {
+        let capacity = self.capacity();
+        if particles.len() > capacity {
+            return Err(PhysicsError::BufferOverflow {
+                message: "More particles than the backend holds".to_string(),
+                required: std::mem::size_of_val(particles) as u64,
+                available: (capacity * std::mem::size_of::<Particle>()) as u64,
+            });
+        }
+        self.particles.clear();
+        self.particles.extend_from_slice(particles);
+        self.particles
+            .resize(capacity, Particle::default().with_lifetime(0.0));
+        Ok(())
+    }
+
+    fn get_particle_data(&self) -> Result<Vec<Particle>, PhysicsError> {
+        Ok(self.particles.clone())
+    }
+
+    // One step of `particle_update.comp`, after `sph.comp` if the fluid is on
+    fn step(&mut self, delta_time: f32) -> Result<(), PhysicsError> {
+        let params = self.params;
+        let time = self.elapsed;
+        let fields = self.packed_fields();
+        let interaction = match self.fluid {
+            Some(settings) => {
+                let grid = ParticleGrid::build(
+                    &self.particles,
+                    settings.smoothing_radius,
+                    bucket_count(self.capacity()),
+                );
+                let (densities, forces) = sph_forces(&self.particles, &grid, &settings);
+                self.densities = densities;
+                Some(forces)
+            }
+            None => None,
+        };
+
+        let lower = Vec3::splat(params.bounds[0]);
+        let upper = Vec3::splat(params.bounds[1]);
+        for (index, particle) in self.particles.iter_mut().enumerate() {
+            if !particle.is_alive() {
+                continue;
+            }
+            if particle.mass > 0.0 {
+                let mut position = Vec3::from(particle.position);
+                let mut velocity = Vec3::from(particle.velocity);
+
+                let mut force: Vec3 = fields
+                    .iter()
+                    .map(|field| field.force(position, velocity, time))
+                    .sum();
+                if let Some(forces) = &interaction {
+                    force += forces[index];
+                }
+                let acceleration = Vec3::from(params.gravity) + force / particle.mass;
+                velocity += acceleration * delta_time;
+
+                let speed = velocity.length();
+                if speed > params.max_velocity {
+                    velocity *= params.max_velocity / speed;
+                }
+
+                position += velocity * delta_time;
+
+                for field in &fields {
+                    field.collide(&mut position, &mut velocity);
+                }
+
+                // Clamp to the bounds and reflect velocity off the walls
+                for axis in 0..3 {
+                    if position[axis] < lower[axis] {
+                        velocity[axis] = velocity[axis].abs();
+                    }
+                    if position[axis] > upper[axis] {
+                        velocity[axis] = -velocity[axis].abs();
+                    }
+                }
+                position = position.clamp(lower, upper);
+
+                particle.position = position.into();
+                particle.velocity = velocity.into();
+            }
+            particle.lifetime -= delta_time;
+        }
+
+        if self.debug_enabled {
+            log::debug!(
+                "CPU particle step: {} of {} particles alive",
+                self.alive_count(),
+                self.capacity()
+            );
+        }
+        self.elapsed += delta_time;
+        Ok(())
+    }
+
+    fn set_debug_enabled(&mut self, enabled: bool) {
+        self.debug_enabled = enabled;
+    }
+}
+
+// First particle where two backends disagree. Scalars such as the lifetime
+// only use the first component.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParticleMismatch {
+    pub index: usize,
+    pub field: &'static str,
+    pub expected: [f32; 3],
+    pub actual: [f32; 3],
+}
+
+impl fmt::Display for ParticleMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Particle {} {} differs: expected {:?}, got {:?}",
+            self.index, self.field, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ParticleMismatch {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::force_field::ForceFieldKind;
+
+    #[test]
+    fn test_cpu_step_matches_update_shader() {
+        let mut backend = CpuParticleBackend::new(4);
+        backend.set_params(
+            PushConstants::new(0.1, 0)
+                .with_gravity([0.0, -10.0, 0.0])
+                .with_bounds(-1.0, 1.0),
+        );
+        backend
+            .update_particles(&[
+                Particle::new([0.0, 0.0, 0.0], [1.0, 0.0, 0.0]),
+                Particle::new([0.95, 0.0, 0.0], [1.0, 0.0, 0.0]),
+                Particle::new([0.0; 3], [0.0; 3]).with_mass(0.0),
+                Particle::new([0.0; 3], [0.0; 3]).with_lifetime(0.05),
+            ])
+            .unwrap();
+        backend.step(0.1).unwrap();
+        let particles = backend.get_particle_data().unwrap();
+
+        // Semi-implicit Euler: velocity first, then position
+        assert_eq!(particles[0].velocity, [1.0, -1.0, 0.0]);
+        assert!((Vec3::from(particles[0].position) - Vec3::new(0.1, -0.1, 0.0)).length() < 1e-6);
+        // Clamped to the bounds and bounced off the wall
+        assert_eq!(particles[1].position[0], 1.0);
+        assert_eq!(particles[1].velocity[0], -1.0);
+        // Pinned
+        assert_eq!(particles[2].position, [0.0; 3]);
+        // Ran out of lifetime
+        assert!(!particles[3].is_alive());
+        assert_eq!(backend.alive_count(), 3);
+        assert_eq!(backend.time(), 0.1);
+    }
+
+    #[test]
+    fn test_mirror_and_cross_check() {
+        let mut reference = CpuParticleBackend::new(8);
+        reference
+            .set_force_fields(&[ForceField::new(
+                ForceFieldKind::Attractor { strength: 5.0 },
+                [0.0; 3],
+            )])
+            .unwrap();
+        reference
+            .update_particles(&[
+                Particle::new([1.0, 2.0, 3.0], [0.0; 3]),
+                Particle::new([-2.0, 0.5, 1.0], [1.0, 0.0, 0.0]),
+            ])
+            .unwrap();
+
+        let mut mirror = CpuParticleBackend::mirror(&reference).unwrap();
+        for _ in 0..10 {
+            reference.step(1.0 / 60.0).unwrap();
+            mirror.step(1.0 / 60.0).unwrap();
+        }
+        let mut particles = mirror.get_particle_data().unwrap();
+        assert_eq!(reference.cross_check(&particles, 1e-6), Ok(()));
+
+        particles[1].velocity[2] += 1e-3;
+        assert!(reference.cross_check(&particles, 1e-2).is_ok());
+        let mismatch = reference.cross_check(&particles, 1e-5).unwrap_err();
+        assert_eq!((mismatch.index, mismatch.field), (1, "velocity"));
+
+        particles.pop();
+        assert_eq!(
+            reference.cross_check(&particles, 1.0).unwrap_err().field,
+            "count"
+        );
+    }
+}
@@ -0,0 +1,707 @@
+use glam::Vec3;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::graphics::debug_draw::DebugDraw;
+use crate::physics::debug_shapes::{CELL_COLOR, SHAPE_COLOR};
+use crate::physics::spatial::{aabb_overlap, BroadPhase};
+
+const NULL_NODE: usize = usize::MAX;
+const DEFAULT_MARGIN: f32 = 0.1; // Fat AABB padding in world units
+
+#[derive(Debug, Clone, Copy)]
+struct Aabb {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Aabb {
+    fn fattened(min: Vec3, max: Vec3, margin: f32) -> Self {
+        Self {
+            min: min - Vec3::splat(margin),
+            max: max + Vec3::splat(margin),
+        }
+    }
+
+    fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    fn contains(&self, min: Vec3, max: Vec3) -> bool {
+        self.min.cmple(min).all() && max.cmple(self.max).all()
+    }
+
+    fn overlaps(&self, other: &Aabb) -> bool {
+        aabb_overlap(self.min, self.max, other.min, other.max)
+    }
+
+    // Distance along the ray at which it enters the box (0 when starting inside)
+    fn ray_entry(&self, origin: Vec3, inv_dir: Vec3, max_dist: f32) -> Option<f32> {
+        let t1 = (self.min - origin) * inv_dir;
+        let t2 = (self.max - origin) * inv_dir;
+        let t_enter = t1.min(t2).max_element().max(0.0);
+        let t_exit = t1.max(t2).min_element();
+
+        if t_enter <= t_exit && t_enter <= max_dist {
+            Some(t_enter)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    aabb: Aabb,
+    parent: usize,
+    child1: usize,
+    child2: usize,
+    height: i32, // 0 for leaves, -1 for free nodes
+    object: usize,
+}
+
+impl Node {
+    fn is_leaf(&self) -> bool {
+        self.child1 == NULL_NODE
+    }
+}
+
+// Nodes pending in a best-first ray traversal, ordered so that the binary heap
+// pops the smallest entry distance first
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RayCandidate {
+    t: f32,
+    node: usize,
+}
+
+impl Eq for RayCandidate {}
+
+impl Ord for RayCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .t
+            .total_cmp(&self.t)
+            .then_with(|| other.node.cmp(&self.node))
+    }
+}
+
+impl PartialOrd for RayCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Incremental dynamic AABB tree. Leaves store fat AABBs (the object bounds
+// grown by `margin`), so objects that move only a little keep their leaf and
+// the tree is only touched when an object escapes its fat box. Inserts pick
+// the sibling with the lowest surface-area cost, and AVL-style rotations keep
+// the tree balanced under sorted or clustered insertion.
+#[derive(Debug, Clone)]
+pub struct DynamicAabbTree {
+    nodes: Vec<Node>,
+    root: usize,
+    free_list: Vec<usize>,
+    proxies: Vec<usize>, // Leaf node of each object, NULL_NODE if absent
+    object_bounds: Vec<Option<(Vec3, Vec3)>>, // Tight AABB of each object
+    margin: f32,
+}
+
+impl DynamicAabbTree {
+    pub fn new() -> Self {
+        Self::with_margin(DEFAULT_MARGIN)
+    }
+
+    pub fn with_margin(margin: f32) -> Self {
+        Self {
+            nodes: Vec::new(),
+            root: NULL_NODE,
+            free_list: Vec::new(),
+            proxies: Vec::new(),
+            object_bounds: Vec::new(),
+            margin,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.root = NULL_NODE;
+        self.free_list.clear();
+        self.proxies.clear();
+        self.object_bounds.clear();
+    }
+
+    pub fn margin(&self) -> f32 {
+        self.margin
+    }
+
+    // Height of the root, 0 for a single leaf and -1 for an empty tree
+    pub fn height(&self) -> i32 {
+        if self.root == NULL_NODE {
+            -1
+        } else {
+            self.nodes[self.root].height
+        }
+    }
+
+    pub fn contains(&self, object_index: usize) -> bool {
+        self.proxies
+            .get(object_index)
+            .is_some_and(|&leaf| leaf != NULL_NODE)
+    }
+
+    pub fn insert(&mut self, object_index: usize, min: Vec3, max: Vec3) {
+        if self.contains(object_index) {
+            self.move_proxy(object_index, min, max);
+            return;
+        }
+
+        if object_index >= self.proxies.len() {
+            self.proxies.resize(object_index + 1, NULL_NODE);
+            self.object_bounds.resize(object_index + 1, None);
+        }
+
+        let leaf = self.allocate_node();
+        self.nodes[leaf].aabb = Aabb::fattened(min, max, self.margin);
+        self.nodes[leaf].height = 0;
+        self.nodes[leaf].object = object_index;
+
+        self.proxies[object_index] = leaf;
+        self.object_bounds[object_index] = Some((min, max));
+        self.insert_leaf(leaf);
+    }
+
+    pub fn remove(&mut self, object_index: usize) {
+        if !self.contains(object_index) {
+            return;
+        }
+
+        let leaf = self.proxies[object_index];
+        self.remove_leaf(leaf);
+        self.free_node(leaf);
+        self.proxies[object_index] = NULL_NODE;
+        self.object_bounds[object_index] = None;
+    }
+
+    // Updates an object's bounds. Returns true when the object left its fat
+    // AABB and had to be reinserted.
+    pub fn move_proxy(&mut self, object_index: usize, min: Vec3, max: Vec3) -> bool {
+        if !self.contains(object_index) {
+            self.insert(object_index, min, max);
+            return true;
+        }
+
+        self.object_bounds[object_index] = Some((min, max));
+        let leaf = self.proxies[object_index];
+        if self.nodes[leaf].aabb.contains(min, max) {
+            return false;
+        }
+
+        self.remove_leaf(leaf);
+        self.nodes[leaf].aabb = Aabb::fattened(min, max, self.margin);
+        self.insert_leaf(leaf);
+        true
+    }
+
+    pub fn bounds(&self, object_index: usize) -> Option<(Vec3, Vec3)> {
+        self.object_bounds.get(object_index).copied().flatten()
+    }
+
+    // Number of object slots indexed, matching `SpatialHash::object_count`
+    pub fn object_count(&self) -> usize {
+        self.object_bounds.len()
+    }
+
+    // Objects whose tight AABB overlaps the given box, sorted by index
+    pub fn query_aabb(&self, min: Vec3, max: Vec3) -> Vec<usize> {
+        let query = Aabb { min, max };
+        let mut result = Vec::new();
+        self.visit_overlapping(&query, |object| {
+            if let Some((obj_min, obj_max)) = self.bounds(object) {
+                if aabb_overlap(min, max, obj_min, obj_max) {
+                    result.push(object);
+                }
+            }
+        });
+        result.sort_unstable();
+        result
+    }
+
+    // Visits leaves hit by the ray in increasing order of the distance at which
+    // the ray enters their fat AABB. That distance is a lower bound for any hit
+    // on the object, so callers can stop once it exceeds their closest hit.
+    pub fn traverse_ray<F>(&self, origin: Vec3, dir: Vec3, max_dist: f32, mut visit: F)
+    where
+        F: FnMut(usize, f32) -> bool,
+    {
+        if self.root == NULL_NODE {
+            return;
+        }
+
+        let dir = dir.normalize_or_zero();
+        if dir == Vec3::ZERO {
+            for (object, &leaf) in self.proxies.iter().enumerate() {
+                if leaf != NULL_NODE && !visit(object, 0.0) {
+                    return;
+                }
+            }
+            return;
+        }
+
+        let inv_dir = dir.recip();
+        let mut heap = BinaryHeap::new();
+        if let Some(t) = self.nodes[self.root]
+            .aabb
+            .ray_entry(origin, inv_dir, max_dist)
+        {
+            heap.push(RayCandidate { t, node: self.root });
+        }
+
+        while let Some(RayCandidate { t, node }) = heap.pop() {
+            let node = &self.nodes[node];
+            if node.is_leaf() {
+                if !visit(node.object, t) {
+                    return;
+                }
+                continue;
+            }
+
+            for child in [node.child1, node.child2] {
+                if let Some(t) = self.nodes[child].aabb.ray_entry(origin, inv_dir, max_dist) {
+                    heap.push(RayCandidate { t, node: child });
+                }
+            }
+        }
+    }
+
+    // Pairs (i < j) whose fat AABBs overlap. Leaves are queried against the
+    // tree in parallel since the traversal only reads it.
+    pub fn get_potential_pairs(&self) -> Vec<(usize, usize)> {
+        use rayon::prelude::*;
+
+        self.proxies
+            .par_iter()
+            .enumerate()
+            .filter(|(_, &leaf)| leaf != NULL_NODE)
+            .flat_map_iter(|(object, &leaf)| {
+                let mut pairs = Vec::new();
+                self.visit_overlapping(&self.nodes[leaf].aabb, |other| {
+                    if other > object {
+                        pairs.push((object, other));
+                    }
+                });
+                pairs
+            })
+            .collect()
+    }
+
+    fn visit_overlapping<F: FnMut(usize)>(&self, query: &Aabb, mut visit: F) {
+        if self.root == NULL_NODE {
+            return;
+        }
+
+        let mut stack = vec![self.root];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.aabb.overlaps(query) {
+                continue;
+            }
+
+            if node.is_leaf() {
+                visit(node.object);
+            } else {
+                stack.push(node.child1);
+                stack.push(node.child2);
+            }
+        }
+    }
+
+    fn allocate_node(&mut self) -> usize {
+        let node = Node {
+            aabb: Aabb {
+                min: Vec3::ZERO,
+                max: Vec3::ZERO,
+            },
+            parent: NULL_NODE,
+            child1: NULL_NODE,
+            child2: NULL_NODE,
+            height: 0,
+            object: usize::MAX,
+        };
+
+        match self.free_list.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn free_node(&mut self, index: usize) {
+        self.nodes[index].height = -1;
+        self.nodes[index].parent = NULL_NODE;
+        self.free_list.push(index);
+    }
+
+    fn insert_leaf(&mut self, leaf: usize) {
+        if self.root == NULL_NODE {
+            self.root = leaf;
+            self.nodes[leaf].parent = NULL_NODE;
+            return;
+        }
+
+        // Descend towards the sibling with the lowest surface-area cost
+        let leaf_aabb = self.nodes[leaf].aabb;
+        let mut index = self.root;
+        while !self.nodes[index].is_leaf() {
+            let node = &self.nodes[index];
+            let area = node.aabb.surface_area();
+            let combined_area = node.aabb.union(&leaf_aabb).surface_area();
+
+            // Cost of making a new parent for this node and the leaf
+            let cost = 2.0 * combined_area;
+            // Minimum cost of pushing the leaf further down the tree
+            let inheritance_cost = 2.0 * (combined_area - area);
+
+            let child_cost = |child: &Node| {
+                let combined = child.aabb.union(&leaf_aabb).surface_area();
+                if child.is_leaf() {
+                    combined + inheritance_cost
+                } else {
+                    combined - child.aabb.surface_area() + inheritance_cost
+                }
+            };
+            let cost1 = child_cost(&self.nodes[node.child1]);
+            let cost2 = child_cost(&self.nodes[node.child2]);
+
+            if cost < cost1 && cost < cost2 {
+                break;
+            }
+            index = if cost1 < cost2 {
+                node.child1
+            } else {
+                node.child2
+            };
+        }
+        let sibling = index;
+
+        // Create a new parent for the sibling and the leaf
+        let old_parent = self.nodes[sibling].parent;
+        let new_parent = self.allocate_node();
+        self.nodes[new_parent].parent = old_parent;
+        self.nodes[new_parent].aabb = leaf_aabb.union(&self.nodes[sibling].aabb);
+        self.nodes[new_parent].height = self.nodes[sibling].height + 1;
+        self.nodes[new_parent].child1 = sibling;
+        self.nodes[new_parent].child2 = leaf;
+        self.nodes[sibling].parent = new_parent;
+        self.nodes[leaf].parent = new_parent;
+
+        if old_parent == NULL_NODE {
+            self.root = new_parent;
+        } else {
+            self.replace_child(old_parent, sibling, new_parent);
+        }
+
+        self.refit_from(self.nodes[leaf].parent);
+    }
+
+    fn remove_leaf(&mut self, leaf: usize) {
+        if leaf == self.root {
+            self.root = NULL_NODE;
+            return;
+        }
+
+        let parent = self.nodes[leaf].parent;
+        let grand_parent = self.nodes[parent].parent;
+        let sibling = if self.nodes[parent].child1 == leaf {
+            self.nodes[parent].child2
+        } else {
+            self.nodes[parent].child1
+        };
+
+        // The sibling takes the parent's place
+        self.nodes[sibling].parent = grand_parent;
+        self.free_node(parent);
+        self.nodes[leaf].parent = NULL_NODE;
+
+        if grand_parent == NULL_NODE {
+            self.root = sibling;
+        } else {
+            self.replace_child(grand_parent, parent, sibling);
+            self.refit_from(grand_parent);
+        }
+    }
+
+    // Walks up from `index`, rebalancing and refitting every ancestor
+    fn refit_from(&mut self, mut index: usize) {
+        while index != NULL_NODE {
+            index = self.balance(index);
+
+            let child1 = self.nodes[index].child1;
+            let child2 = self.nodes[index].child2;
+            self.nodes[index].height = 1 + self.nodes[child1].height.max(self.nodes[child2].height);
+            self.nodes[index].aabb = self.nodes[child1].aabb.union(&self.nodes[child2].aabb);
+
+            index = self.nodes[index].parent;
+        }
+    }
+
+    fn replace_child(&mut self, parent: usize, old_child: usize, new_child: usize) {
+        if self.nodes[parent].child1 == old_child {
+            self.nodes[parent].child1 = new_child;
+        } else {
+            self.nodes[parent].child2 = new_child;
+        }
+    }
+
+    // Rotates the taller child of `a` up if its subtrees differ in height by
+    // more than one. Returns the index of the node now at a's position.
+    fn balance(&mut self, a: usize) -> usize {
+        if self.nodes[a].is_leaf() || self.nodes[a].height < 2 {
+            return a;
+        }
+
+        let b = self.nodes[a].child1;
+        let c = self.nodes[a].child2;
+        let balance = self.nodes[c].height - self.nodes[b].height;
+
+        if balance > 1 {
+            self.rotate_up(a, c, b, false)
+        } else if balance < -1 {
+            self.rotate_up(a, b, c, true)
+        } else {
+            a
+        }
+    }
+
+    // Makes `up` (a child of `a`) the parent of `a`. `up`'s shorter child stays
+    // under `a` in the slot `up` occupied, next to a's other child `keep`.
+    fn rotate_up(&mut self, a: usize, up: usize, keep: usize, up_was_child1: bool) -> usize {
+        let f = self.nodes[up].child1;
+        let g = self.nodes[up].child2;
+
+        // Swap a and up
+        self.nodes[up].child1 = a;
+        self.nodes[up].parent = self.nodes[a].parent;
+        self.nodes[a].parent = up;
+
+        let up_parent = self.nodes[up].parent;
+        if up_parent == NULL_NODE {
+            self.root = up;
+        } else {
+            self.replace_child(up_parent, a, up);
+        }
+
+        // The taller grandchild stays with `up`, the shorter one moves to `a`
+        let (tall, short) = if self.nodes[f].height > self.nodes[g].height {
+            (f, g)
+        } else {
+            (g, f)
+        };
+        self.nodes[up].child2 = tall;
+        if up_was_child1 {
+            self.nodes[a].child1 = short;
+        } else {
+            self.nodes[a].child2 = short;
+        }
+        self.nodes[short].parent = a;
+
+        self.nodes[a].aabb = self.nodes[keep].aabb.union(&self.nodes[short].aabb);
+        self.nodes[a].height = 1 + self.nodes[keep].height.max(self.nodes[short].height);
+        self.nodes[up].aabb = self.nodes[a].aabb.union(&self.nodes[tall].aabb);
+        self.nodes[up].height = 1 + self.nodes[a].height.max(self.nodes[tall].height);
+
+        up
+    }
+}
+
+impl Default for DynamicAabbTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BroadPhase for DynamicAabbTree {
+    fn update(&mut self, bounds: &[(Vec3, Vec3)]) {
+        for (i, &(min, max)) in bounds.iter().enumerate() {
+            self.move_proxy(i, min, max);
+        }
+
+        // Objects past the end of `bounds` no longer exist
+        for i in bounds.len()..self.proxies.len() {
+            self.remove(i);
+        }
+        self.proxies.truncate(bounds.len());
+        self.object_bounds.truncate(bounds.len());
+    }
+
+    fn potential_pairs(&self) -> Vec<(usize, usize)> {
+        self.get_potential_pairs()
+    }
+
+    fn query_aabb(&self, min: Vec3, max: Vec3) -> Vec<usize> {
+        DynamicAabbTree::query_aabb(self, min, max)
+    }
+
+    fn traverse_ray(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+        visit: &mut dyn FnMut(usize, f32) -> bool,
+    ) {
+        DynamicAabbTree::traverse_ray(self, origin, dir, max_dist, visit)
+    }
+
+    fn bounds(&self, object_index: usize) -> Option<(Vec3, Vec3)> {
+        DynamicAabbTree::bounds(self, object_index)
+    }
+
+    fn object_count(&self) -> usize {
+        DynamicAabbTree::object_count(self)
+    }
+
+    fn clone_box(&self) -> Box<dyn BroadPhase> {
+        Box::new(self.clone())
+    }
+
+    // Outlines the fat leaf boxes and the internal nodes above them
+    fn debug_draw(&self, draw: &mut DebugDraw) {
+        for node in self.nodes.iter().filter(|node| node.height >= 0) {
+            let color = if node.is_leaf() {
+                SHAPE_COLOR
+            } else {
+                CELL_COLOR
+            };
+            draw.aabb(node.aabb.min, node.aabb.max, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic mix of small and large boxes
+    fn mixed_boxes(count: usize) -> Vec<(Vec3, Vec3)> {
+        let mut seed = 0x2545_f491_u32;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32
+        };
+
+        (0..count)
+            .map(|i| {
+                let center = Vec3::new(next(), next(), next()) * 100.0;
+                let half = if i % 10 == 0 { 8.0 } else { 0.5 + next() };
+                (center - Vec3::splat(half), center + Vec3::splat(half))
+            })
+            .collect()
+    }
+
+    fn brute_force_pairs(bounds: &[(Vec3, Vec3)]) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        for i in 0..bounds.len() {
+            for j in (i + 1)..bounds.len() {
+                if aabb_overlap(bounds[i].0, bounds[i].1, bounds[j].0, bounds[j].1) {
+                    pairs.push((i, j));
+                }
+            }
+        }
+        pairs
+    }
+
+    fn exact_pairs(tree: &DynamicAabbTree, bounds: &[(Vec3, Vec3)]) -> Vec<(usize, usize)> {
+        let mut pairs: Vec<_> = tree
+            .get_potential_pairs()
+            .into_iter()
+            .filter(|&(i, j)| aabb_overlap(bounds[i].0, bounds[i].1, bounds[j].0, bounds[j].1))
+            .collect();
+        pairs.sort_unstable();
+        pairs
+    }
+
+    #[test]
+    fn test_pairs_match_brute_force() {
+        let mut bounds = mixed_boxes(300);
+        let mut tree = DynamicAabbTree::new();
+        BroadPhase::update(&mut tree, &bounds);
+        assert_eq!(exact_pairs(&tree, &bounds), brute_force_pairs(&bounds));
+
+        // Move everything, some objects far enough to leave their fat AABB
+        for (i, (min, max)) in bounds.iter_mut().enumerate() {
+            let offset = Vec3::new((i % 7) as f32 * 0.3, 0.05, -((i % 3) as f32));
+            *min += offset;
+            *max += offset;
+        }
+        BroadPhase::update(&mut tree, &bounds);
+        assert_eq!(exact_pairs(&tree, &bounds), brute_force_pairs(&bounds));
+
+        bounds.truncate(150);
+        BroadPhase::update(&mut tree, &bounds);
+        assert_eq!(tree.object_count(), 150);
+        assert_eq!(exact_pairs(&tree, &bounds), brute_force_pairs(&bounds));
+    }
+
+    #[test]
+    fn test_sorted_insertion_stays_balanced() {
+        let mut tree = DynamicAabbTree::new();
+        for i in 0..1024 {
+            let min = Vec3::new(i as f32 * 2.0, 0.0, 0.0);
+            tree.insert(i, min, min + Vec3::ONE);
+        }
+
+        // A degenerate list would be 1023 levels deep
+        assert!(tree.height() <= 20, "height {}", tree.height());
+        assert_eq!(
+            tree.query_aabb(Vec3::new(8.5, 0.0, 0.0), Vec3::splat(10.5)),
+            vec![4, 5]
+        );
+    }
+
+    #[test]
+    fn test_small_moves_keep_leaf() {
+        let mut tree = DynamicAabbTree::with_margin(0.5);
+        tree.insert(0, Vec3::ZERO, Vec3::ONE);
+
+        assert!(!tree.move_proxy(0, Vec3::splat(0.2), Vec3::splat(1.2)));
+        assert_eq!(tree.bounds(0), Some((Vec3::splat(0.2), Vec3::splat(1.2))));
+        assert!(tree.move_proxy(0, Vec3::splat(2.0), Vec3::splat(3.0)));
+
+        tree.remove(0);
+        assert_eq!(tree.height(), -1);
+        assert!(tree
+            .query_aabb(Vec3::splat(-10.0), Vec3::splat(10.0))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_ray_visits_nearest_first() {
+        let mut tree = DynamicAabbTree::with_margin(0.0);
+        for (i, x) in [8.0, 2.0, 5.0, 11.0].iter().enumerate() {
+            let min = Vec3::new(*x, -0.5, -0.5);
+            tree.insert(i, min, min + Vec3::ONE);
+        }
+        tree.insert(4, Vec3::new(3.0, 5.0, 0.0), Vec3::new(4.0, 6.0, 1.0));
+
+        let mut visited = Vec::new();
+        tree.traverse_ray(Vec3::ZERO, Vec3::X, 10.0, |object, t| {
+            visited.push((object, t));
+            true
+        });
+        assert_eq!(visited, vec![(1, 2.0), (2, 5.0), (0, 8.0)]);
+    }
+}
@@ -0,0 +1,329 @@
+use glam::Vec3;
+
+use crate::physics::{
+    collision::CollisionManifold,
+    constraints::CollisionConstraint,
+    filter::pair_allowed,
+    material::Damping,
+    physics::{integrate_angular_velocity, integrate_orientation, PhysicsObject, PhysicsWorld},
+    query::sphere_cast_body,
+};
+
+// Bodies moving less than this fraction of their half size per substep are
+// left to the discrete narrow phase
+const CCD_MOTION_THRESHOLD: f32 = 0.5;
+const MAX_CCD_SUBSTEPS: usize = 16;
+// Sweeps per substep, each continuing the leftover motion along the surface hit
+const MAX_CCD_SLIDES: usize = 4;
+// Hits this close to perpendicular to the motion count as sliding contact
+const SLIDE_TOLERANCE: f32 = 1e-3;
+
+impl PhysicsWorld {
+    // Opt-in continuous collision detection for small, fast rigid bodies that
+    // would otherwise tunnel through thin geometry. Deformable bodies ignore
+    // the flag.
+    pub fn set_ccd_enabled(&mut self, index: usize, enabled: bool) {
+        if index >= self.ccd_enabled.len() {
+            self.ccd_enabled.resize(index + 1, false);
+        }
+        self.ccd_enabled[index] = enabled;
+    }
+
+    pub fn is_ccd_enabled(&self, index: usize) -> bool {
+        self.ccd_enabled.get(index).copied().unwrap_or(false)
+    }
+
+    // Integrates the CCD bodies skipped by `parallel_update_positions`. A fast
+    // body's motion is split into substeps so that it advances at most
+    // `CCD_MOTION_THRESHOLD` half sizes at a time, and its inscribed sphere is
+    // swept against the world over each one (conservative advancement). On
+    // impact the body stops at the time of impact and slides the rest of the
+    // substep along the surface. The velocity into the surface is left to a
+    // collision constraint, so the hit bounces and pushes dynamic bodies like
+    // a discrete contact, and it is reported as a contact event.
+    pub(crate) fn integrate_ccd_bodies(&mut self, delta_time: f32) {
+        for index in 0..self.objects.len() {
+            if !self.is_ccd_enabled(index) {
+                continue;
+            }
+            for (other, manifold) in self.integrate_ccd_body(index, delta_time) {
+                self.events.record_contact(index, other, &manifold);
+                let material = self.contact_material(index, other);
+                self.constraints.push(Box::new(
+                    CollisionConstraint::new(index, other)
+                        .with_manifold(manifold)
+                        .with_material(&material),
+                ));
+            }
+        }
+    }
+
+    // Returns the first impact with every body hit, with the normal pointing
+    // from the swept body to the one it hit
+    fn integrate_ccd_body(&self, index: usize, delta_time: f32) -> Vec<(usize, CollisionManifold)> {
+        let mut obj = self.objects[index].borrow_mut();
+        let PhysicsObject::RigidBody {
+            position,
+            velocity,
+            acceleration,
+            orientation,
+            angular_velocity,
+            angular_acceleration,
+            mass,
+            inertia_tensor,
+            bounding_box,
+            ..
+        } = &mut *obj
+        else {
+            return Vec::new();
+        };
+
+        let radius = bounding_box.w;
+        // Kinematic bodies (zero mass) only move with their own velocity
+        let dynamic = *mass != 0.0;
+        let linear_acceleration = if dynamic {
+            self.gravity + *acceleration
+        } else {
+            Vec3::ZERO
+        };
+        let travel = (*velocity + linear_acceleration * delta_time).length() * delta_time;
+        let max_step = radius * CCD_MOTION_THRESHOLD;
+
+        // Sensors never stop at anything, so there is nothing to sweep for
+        let sweep = max_step > 0.0 && travel > max_step && !self.is_sensor(index);
+        let substeps = if sweep {
+            ((travel / max_step).ceil() as usize).clamp(1, MAX_CCD_SUBSTEPS)
+        } else {
+            1
+        };
+
+        let damping = if dynamic {
+            self.body_damping(index)
+        } else {
+            Damping::none()
+        };
+        let step = delta_time / substeps as f32;
+        let mut hits = Vec::new();
+        // Velocity into the surfaces hit, taken out while sliding and given
+        // back for the collision constraints to resolve
+        let mut blocked_velocity = Vec3::ZERO;
+        for _ in 0..substeps {
+            *velocity += linear_acceleration * step;
+            *velocity *= damping.linear_factor(step);
+            let motion = *velocity * step;
+            *position = if sweep {
+                let (position, blocked) =
+                    self.sweep_and_slide(index, *position, radius, motion, velocity, &mut hits);
+                blocked_velocity += blocked;
+                position
+            } else {
+                *position + motion
+            };
+
+            integrate_angular_velocity(
+                angular_velocity,
+                *angular_acceleration,
+                *orientation,
+                *inertia_tensor,
+                *mass,
+                step,
+            );
+            *angular_velocity *= damping.angular_factor(step);
+            integrate_orientation(orientation, *angular_velocity, step);
+        }
+        *velocity += blocked_velocity;
+        hits
+    }
+
+    // Moves the sphere and returns where it ends up and the velocity taken out
+    // against the surfaces it hit. The first hit on each body is added to
+    // `hits`.
+    fn sweep_and_slide(
+        &self,
+        index: usize,
+        mut position: Vec3,
+        radius: f32,
+        mut motion: Vec3,
+        velocity: &mut Vec3,
+        hits: &mut Vec<(usize, CollisionManifold)>,
+    ) -> (Vec3, Vec3) {
+        let mut blocked = Vec3::ZERO;
+        for _ in 0..MAX_CCD_SLIDES {
+            let Some((distance, normal, other)) =
+                self.sweep_sphere(index, position, radius, motion)
+            else {
+                return (position + motion, blocked);
+            };
+
+            let length = motion.length();
+            let dir = motion / length;
+            position += dir * distance;
+
+            if !hits.iter().any(|&(hit, _)| hit == other) {
+                hits.push((
+                    other,
+                    CollisionManifold {
+                        normal: -normal,
+                        penetration: 0.0,
+                        contact_points: vec![position - normal * radius],
+                    },
+                ));
+            }
+
+            // Keep only the tangential part of the velocity and the leftover motion
+            let into_surface = normal * velocity.dot(normal).min(0.0);
+            *velocity -= into_surface;
+            blocked += into_surface;
+            motion = dir * (length - distance);
+            motion -= normal * motion.dot(normal).min(0.0);
+        }
+        (position, blocked)
+    }
+
+    // Earliest impact of a sphere moved by `motion`, as the distance travelled,
+    // the surface normal and the body hit
+    fn sweep_sphere(
+        &self,
+        index: usize,
+        origin: Vec3,
+        radius: f32,
+        motion: Vec3,
+    ) -> Option<(f32, Vec3, usize)> {
+        let max_dist = motion.length();
+        if max_dist <= f32::EPSILON {
+            return None;
+        }
+        let dir = motion / max_dist;
+
+        let end = origin + motion;
+        let min = origin.min(end) - Vec3::splat(radius);
+        let max = origin.max(end) + Vec3::splat(radius);
+
+        let mut best: Option<(f32, Vec3, usize)> = None;
+        for other in self.candidates_in(min, max) {
+            if other == index
+                || self.is_sensor(other)
+                || !pair_allowed(&self.filters, &self.ignored_pairs, index, other)
+            {
+                continue;
+            }
+
+            let obj = self.objects[other].borrow();
+            if let Some((distance, _, normal)) =
+                sphere_cast_body(&obj, origin, radius, dir, max_dist)
+            {
+                // Surfaces the sphere moves along or away from do not stop it
+                if normal.dot(dir) > -SLIDE_TOLERANCE {
+                    continue;
+                }
+                if best.is_none_or(|(best_distance, _, _)| distance < best_distance) {
+                    best = Some((distance, normal, other));
+                }
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::events::EventPhase;
+    use glam::{Mat3, Quat, Vec4};
+
+    fn cube(position: Vec3, velocity: Vec3, half_size: f32) -> PhysicsObject {
+        PhysicsObject::RigidBody {
+            position,
+            velocity,
+            acceleration: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            angular_velocity: Vec3::ZERO,
+            angular_acceleration: Vec3::ZERO,
+            mass: 1.0,
+            inertia_tensor: Mat3::IDENTITY,
+            bounding_box: Vec4::new(0.0, 0.0, 0.0, half_size),
+        }
+    }
+
+    fn position_and_velocity(world: &PhysicsWorld, index: usize) -> (Vec3, Vec3) {
+        match &*world.objects[index].borrow() {
+            PhysicsObject::RigidBody {
+                position, velocity, ..
+            } => (*position, *velocity),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_fast_body_stops_at_wall() {
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        world.add_object(cube(Vec3::new(5.0, 0.0, 0.0), Vec3::ZERO, 0.5));
+        let bullet = world.add_object(cube(Vec3::ZERO, Vec3::new(600.0, 0.0, 0.0), 0.1));
+        world.set_ccd_enabled(bullet, true);
+        world.sync_broad_phase();
+
+        // 10 units in one step would carry the bullet straight through the wall
+        world.integrate_ccd_bodies(1.0 / 60.0);
+
+        let (position, velocity) = position_and_velocity(&world, bullet);
+        assert!((position.x - 4.4).abs() < 1e-2, "{:?}", position);
+        // The velocity into the wall is left for the queued contact
+        assert_eq!(velocity, Vec3::new(600.0, 0.0, 0.0));
+        assert_eq!(world.constraints.len(), 1);
+    }
+
+    #[test]
+    fn test_body_slides_along_floor() {
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        world.add_object(cube(Vec3::new(0.0, -5.0, 0.0), Vec3::ZERO, 5.0));
+        let bullet = world.add_object(cube(
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(240.0, -240.0, 0.0),
+            0.1,
+        ));
+        world.set_ccd_enabled(bullet, true);
+        world.sync_broad_phase();
+
+        world.integrate_ccd_bodies(1.0 / 60.0);
+
+        let (position, velocity) = position_and_velocity(&world, bullet);
+        assert!((position.y - 0.1).abs() < 1e-2, "{:?}", position);
+        assert!((position.x - 4.0).abs() < 1e-2, "{:?}", position);
+        assert_eq!(velocity, Vec3::new(240.0, -240.0, 0.0));
+        assert_eq!(world.constraints.len(), 1);
+    }
+
+    #[test]
+    fn test_fast_body_pushes_dynamic_body() {
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        let target = world.add_object(cube(Vec3::new(5.0, 0.0, 0.0), Vec3::ZERO, 0.5));
+        let bullet = world.add_object(cube(Vec3::ZERO, Vec3::new(600.0, 0.0, 0.0), 0.1));
+        world.set_ccd_enabled(bullet, true);
+
+        world.update(1.0 / 60.0);
+
+        // Equal masses and a restitution of 0.5 split the momentum 1:3
+        let (bullet_position, bullet_velocity) = position_and_velocity(&world, bullet);
+        let (target_position, target_velocity) = position_and_velocity(&world, target);
+        assert!(
+            bullet_position.x < target_position.x,
+            "{:?}",
+            bullet_position
+        );
+        assert!(
+            (bullet_velocity.x - 150.0).abs() < 1.0,
+            "{:?}",
+            bullet_velocity
+        );
+        assert!(
+            (target_velocity.x - 450.0).abs() < 1.0,
+            "{:?}",
+            target_velocity
+        );
+
+        let contacts: Vec<_> = world.events().contacts_for(bullet).collect();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].phase, EventPhase::Begin);
+        assert!(contacts[0].body_a == target || contacts[0].body_b == target);
+    }
+}
@@ -0,0 +1,450 @@
+use glam::{Quat, Vec3};
+
+use crate::physics::{
+    filter::ALL_GROUPS,
+    physics::PhysicsWorld,
+    query::{QueryFilter, QueryShape, RaycastHit},
+};
+
+const DEFAULT_STEP_HEIGHT: f32 = 0.3;
+const DEFAULT_MAX_SLOPE: f32 = std::f32::consts::FRAC_PI_4;
+const DEFAULT_SNAP_DISTANCE: f32 = 0.2;
+const DEFAULT_SKIN_WIDTH: f32 = 0.01;
+// Sweeps per move, each continuing the leftover motion along the surface hit
+const MAX_SLIDES: usize = 4;
+// Moves shorter than this are dropped instead of swept
+const MIN_MOVE_DISTANCE: f32 = 1e-5;
+// Extra distance the ground probe looks below the capsule before a move
+const GROUND_PROBE_DISTANCE: f32 = 0.02;
+
+// Shape and movement settings for a kinematic capsule moved with
+// `PhysicsWorld::move_character`. The capsule is upright along `up`, with
+// `half_height` measured from its center to the center of either cap. It is
+// not a body in the world, it only queries it.
+#[derive(Debug, Clone)]
+pub struct CharacterController {
+    pub radius: f32,
+    pub half_height: f32,
+    pub up: Vec3,
+    // Tallest ledge the character climbs without jumping
+    pub step_height: f32,
+    // Steepest walkable slope, in radians from `up`
+    pub max_slope: f32,
+    // How far the character is pulled down to stay on ground it walks off
+    pub snap_distance: f32,
+    // Gap kept between the capsule and everything it touches
+    pub skin_width: f32,
+    pub collision_mask: u32,
+}
+
+impl CharacterController {
+    pub fn new(radius: f32, half_height: f32) -> Self {
+        Self {
+            radius,
+            half_height,
+            up: Vec3::Y,
+            step_height: DEFAULT_STEP_HEIGHT,
+            max_slope: DEFAULT_MAX_SLOPE,
+            snap_distance: DEFAULT_SNAP_DISTANCE,
+            skin_width: DEFAULT_SKIN_WIDTH,
+            collision_mask: ALL_GROUPS,
+        }
+    }
+
+    pub fn with_up(mut self, up: Vec3) -> Self {
+        self.up = up.normalize();
+        self
+    }
+
+    pub fn with_step_height(mut self, step_height: f32) -> Self {
+        self.step_height = step_height;
+        self
+    }
+
+    pub fn with_max_slope(mut self, max_slope: f32) -> Self {
+        self.max_slope = max_slope;
+        self
+    }
+
+    pub fn with_snap_distance(mut self, snap_distance: f32) -> Self {
+        self.snap_distance = snap_distance;
+        self
+    }
+
+    pub fn with_skin_width(mut self, skin_width: f32) -> Self {
+        self.skin_width = skin_width;
+        self
+    }
+
+    pub fn with_collision_mask(mut self, mask: u32) -> Self {
+        self.collision_mask = mask;
+        self
+    }
+
+    pub fn shape(&self) -> QueryShape {
+        QueryShape::Capsule {
+            radius: self.radius,
+            half_height: self.half_height,
+            orientation: Quat::from_rotation_arc(Vec3::Y, self.up),
+        }
+    }
+
+    // Distance from the capsule center to the bottom of the lower cap
+    pub fn foot_offset(&self) -> f32 {
+        self.half_height + self.radius
+    }
+
+    pub fn is_walkable(&self, normal: Vec3) -> bool {
+        normal.dot(self.up) >= self.max_slope.cos() - f32::EPSILON
+    }
+}
+
+impl Default for CharacterController {
+    // Roughly human sized: 1.8 units tall
+    fn default() -> Self {
+        Self::new(0.3, 0.6)
+    }
+}
+
+// Outcome of `PhysicsWorld::move_character`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CharacterMove {
+    pub position: Vec3,
+    pub grounded: bool,
+    pub ground_normal: Vec3,
+    pub ground_body: Option<usize>,
+    pub hit_wall: bool,
+    pub hit_ceiling: bool,
+}
+
+impl CharacterMove {
+    fn at(position: Vec3, up: Vec3) -> Self {
+        Self {
+            position,
+            grounded: false,
+            ground_normal: up,
+            ground_body: None,
+            hit_wall: false,
+            hit_ceiling: false,
+        }
+    }
+}
+
+impl PhysicsWorld {
+    // Moves a character capsule centered at `position` by `motion` and
+    // returns where it ends up. The motion is split into a horizontal and a
+    // vertical part, each swept and slid along what it hits. Horizontal moves
+    // blocked by a ledge no taller than `step_height` climb it, slopes
+    // steeper than `max_slope` act as walls, and a character that was on the
+    // ground and is not moving up is snapped back down onto it.
+    pub fn move_character(
+        &self,
+        controller: &CharacterController,
+        position: Vec3,
+        motion: Vec3,
+        filter: &QueryFilter,
+    ) -> CharacterMove {
+        let up = controller.up;
+        let mut filter = filter.clone();
+        filter.mask &= controller.collision_mask;
+
+        let was_grounded = self
+            .probe_ground(controller, position, GROUND_PROBE_DISTANCE, &filter)
+            .is_some();
+
+        let vertical = up * motion.dot(up);
+        let horizontal = motion - vertical;
+        let mut result = CharacterMove::at(position, up);
+
+        let mut moved =
+            self.slide_character(controller, position, horizontal, &filter, &mut result);
+        if was_grounded && result.hit_wall && controller.step_height > 0.0 {
+            if let Some(stepped) = self.step_up(controller, position, horizontal, &filter) {
+                let progress = |p: Vec3| (p - position).dot(horizontal);
+                if progress(stepped) > progress(moved) + controller.skin_width {
+                    moved = stepped;
+                    result.hit_wall = false;
+                }
+            }
+        }
+
+        moved = self.slide_character(controller, moved, vertical, &filter, &mut result);
+
+        if !result.grounded && motion.dot(up) <= 0.0 {
+            let reach = if was_grounded {
+                controller.snap_distance
+            } else {
+                GROUND_PROBE_DISTANCE
+            };
+            if let Some(hit) = self.probe_ground(controller, moved, reach, &filter) {
+                if was_grounded {
+                    moved -= up * (hit.distance - controller.skin_width).max(0.0);
+                }
+                result.grounded = true;
+                result.ground_normal = hit.normal;
+                result.ground_body = Some(hit.body);
+            }
+        }
+
+        result.position = moved;
+        result
+    }
+
+    fn slide_character(
+        &self,
+        controller: &CharacterController,
+        mut position: Vec3,
+        mut motion: Vec3,
+        filter: &QueryFilter,
+        result: &mut CharacterMove,
+    ) -> Vec3 {
+        let up = controller.up;
+        let skin = controller.skin_width;
+        let shape = controller.shape();
+        let initial = motion;
+
+        for _ in 0..MAX_SLIDES {
+            let length = motion.length();
+            if length <= MIN_MOVE_DISTANCE {
+                break;
+            }
+            let dir = motion / length;
+
+            let Some(hit) = self.shape_cast(shape, position, dir, length + skin, filter) else {
+                position += motion;
+                break;
+            };
+
+            let travel = (hit.distance - skin).max(0.0).min(length);
+            position += dir * travel;
+            let remaining = dir * (length - travel);
+
+            let mut normal = hit.normal;
+            let rise = normal.dot(up);
+            if controller.is_walkable(normal) {
+                result.grounded = true;
+                result.ground_normal = normal;
+                result.ground_body = Some(hit.body);
+                // Landing stops a fall instead of sliding down the slope
+                if dir.dot(up) < 0.0 && initial.dot(up) < 0.0 {
+                    break;
+                }
+            } else if rise < -controller.max_slope.cos() {
+                result.hit_ceiling = true;
+            } else {
+                // Steep slopes block like walls instead of being climbed
+                result.hit_wall = true;
+                if rise > 0.0 {
+                    let flat = (normal - up * rise).normalize_or_zero();
+                    if flat != Vec3::ZERO {
+                        normal = flat;
+                    }
+                }
+            }
+
+            motion = remaining - normal * remaining.dot(normal).min(0.0);
+            // Bouncing back between two surfaces makes no progress
+            if motion.dot(initial) <= 0.0 {
+                break;
+            }
+        }
+
+        position
+    }
+
+    // Tries to climb over whatever blocked `horizontal`: rise by the step
+    // height, move forward, then drop back onto walkable ground
+    fn step_up(
+        &self,
+        controller: &CharacterController,
+        position: Vec3,
+        horizontal: Vec3,
+        filter: &QueryFilter,
+    ) -> Option<Vec3> {
+        let up = controller.up;
+        let skin = controller.skin_width;
+        let shape = controller.shape();
+
+        let rise = self
+            .shape_cast(shape, position, up, controller.step_height + skin, filter)
+            .map_or(controller.step_height, |hit| (hit.distance - skin).max(0.0));
+        if rise <= MIN_MOVE_DISTANCE {
+            return None;
+        }
+
+        let raised = position + up * rise;
+        let mut scratch = CharacterMove::at(raised, up);
+        let forward = self.slide_character(controller, raised, horizontal, filter, &mut scratch);
+
+        let drop = self.shape_cast(shape, forward, -up, rise + skin, filter)?;
+        let landed = forward - up * (drop.distance - skin).max(0.0);
+
+        // Whatever the capsule landed on must be low enough to step onto
+        let feet = position - up * controller.foot_offset();
+        if (drop.point - feet).dot(up) > controller.step_height + skin {
+            return None;
+        }
+
+        // The capsule's cap usually rests on the ledge's edge, where the hit
+        // normal is tilted, so walkability is judged just past the contact
+        let ahead = horizontal.normalize_or_zero() * skin;
+        let surface = self.raycast(drop.point + up * skin + ahead, -up, skin * 2.0, filter)?;
+        controller.is_walkable(surface.normal).then_some(landed)
+    }
+
+    fn probe_ground(
+        &self,
+        controller: &CharacterController,
+        position: Vec3,
+        distance: f32,
+        filter: &QueryFilter,
+    ) -> Option<RaycastHit> {
+        let up = controller.up;
+        let hit = self.shape_cast(
+            controller.shape(),
+            position,
+            -up,
+            distance + controller.skin_width,
+            filter,
+        )?;
+        controller.is_walkable(hit.normal).then_some(hit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::physics::PhysicsObject;
+    use glam::{Mat3, Vec4};
+
+    fn cube(position: Vec3, half_size: f32) -> PhysicsObject {
+        PhysicsObject::RigidBody {
+            position,
+            velocity: Vec3::ZERO,
+            acceleration: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            angular_velocity: Vec3::ZERO,
+            angular_acceleration: Vec3::ZERO,
+            mass: 1.0,
+            inertia_tensor: Mat3::IDENTITY,
+            bounding_box: Vec4::new(0.0, 0.0, 0.0, half_size),
+        }
+    }
+
+    // Floor with its top face at y = 0
+    fn world_with(cubes: &[(Vec3, f32)]) -> PhysicsWorld {
+        let mut world = PhysicsWorld::new(Vec3::new(0.0, -9.81, 0.0));
+        world.add_object(cube(Vec3::new(0.0, -50.0, 0.0), 50.0));
+        for &(position, half_size) in cubes {
+            world.add_object(cube(position, half_size));
+        }
+        world.sync_broad_phase();
+        world
+    }
+
+    fn standing(controller: &CharacterController, x: f32) -> Vec3 {
+        Vec3::new(x, controller.foot_offset() + controller.skin_width, 0.0)
+    }
+
+    #[test]
+    fn test_slides_along_wall() {
+        // Wall face at x = 1
+        let world = world_with(&[(Vec3::new(6.0, 5.0, 0.0), 5.0)]);
+        let controller = CharacterController::default();
+        let start = standing(&controller, 0.0);
+
+        let result = world.move_character(
+            &controller,
+            start,
+            Vec3::new(2.0, 0.0, 1.0),
+            &QueryFilter::default(),
+        );
+
+        assert!(result.hit_wall);
+        assert!(result.grounded);
+        assert!(result.position.x <= 1.0 - controller.radius + 1e-3);
+        assert!((result.position.z - 1.0).abs() < 1e-2, "{:?}", result);
+        assert!((result.position.y - start.y).abs() < 1e-2, "{:?}", result);
+    }
+
+    #[test]
+    fn test_steps_up_low_ledges_only() {
+        let controller = CharacterController::default().with_step_height(0.3);
+        let start = standing(&controller, 0.0);
+
+        // Ledge 0.2 high starting at x = 1
+        let low = world_with(&[(Vec3::new(3.0, -1.8, 0.0), 2.0)]);
+        let result = low.move_character(
+            &controller,
+            start,
+            Vec3::new(1.5, 0.0, 0.0),
+            &QueryFilter::default(),
+        );
+        assert!(result.grounded);
+        assert!((result.position.x - 1.5).abs() < 1e-2, "{:?}", result);
+        assert!(
+            (result.position.y - (start.y + 0.2)).abs() < 2e-2,
+            "{:?}",
+            result
+        );
+
+        // Ledge 0.5 high blocks the character
+        let high = world_with(&[(Vec3::new(3.0, -1.5, 0.0), 2.0)]);
+        let result = high.move_character(
+            &controller,
+            start,
+            Vec3::new(1.5, 0.0, 0.0),
+            &QueryFilter::default(),
+        );
+        assert!(result.hit_wall);
+        assert!(result.position.x <= 1.0 - controller.radius + 1e-3);
+        assert!((result.position.y - start.y).abs() < 1e-2, "{:?}", result);
+    }
+
+    #[test]
+    fn test_snaps_to_ground_and_lands() {
+        // Floor drops by 0.15 at x = 1
+        let mut world = PhysicsWorld::new(Vec3::new(0.0, -9.81, 0.0));
+        world.add_object(cube(Vec3::new(-49.0, -50.0, 0.0), 50.0));
+        world.add_object(cube(Vec3::new(51.0, -50.15, 0.0), 50.0));
+        world.sync_broad_phase();
+
+        let controller = CharacterController::default().with_snap_distance(0.2);
+        let start = standing(&controller, 0.0);
+
+        // Walking over the drop keeps the character on the ground
+        let result = world.move_character(
+            &controller,
+            start,
+            Vec3::new(2.0, 0.0, 0.0),
+            &QueryFilter::default(),
+        );
+        assert!(result.grounded);
+        assert_eq!(result.ground_body, Some(1));
+        assert!(
+            (result.position.y - (start.y - 0.15)).abs() < 1e-2,
+            "{:?}",
+            result
+        );
+
+        // Falling from above stops on the floor
+        let result = world.move_character(
+            &controller,
+            start + Vec3::Y * 2.0,
+            Vec3::NEG_Y * 5.0,
+            &QueryFilter::default(),
+        );
+        assert!(result.grounded);
+        assert_eq!(result.ground_body, Some(0));
+        assert!((result.position.y - start.y).abs() < 1e-2, "{:?}", result);
+    }
+
+    #[test]
+    fn test_slope_limit() {
+        let controller = CharacterController::default().with_max_slope(30f32.to_radians());
+        assert!(controller.is_walkable(Vec3::Y));
+        assert!(controller.is_walkable(Quat::from_rotation_z(0.5).mul_vec3(Vec3::Y)));
+        assert!(!controller.is_walkable(Quat::from_rotation_z(0.6).mul_vec3(Vec3::Y)));
+        assert!(!controller.is_walkable(Vec3::X));
+    }
+}
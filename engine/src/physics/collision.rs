@@ -1,6 +1,5 @@
 use crate::physics::physics::PhysicsObject;
 use glam::{Quat, Vec3, Vec4};
-use std::cell::RefCell;
 
 #[derive(Debug, Clone)]
 pub struct CollisionManifold {
@@ -28,7 +27,6 @@ impl BoundingVolume {
     pub fn intersects(&self, other: &BoundingVolume) -> bool {
         // Transform to A's local space
         let rel_center = other.center - self.center;
-        let rel_orientation = self.orientation.conjugate() * other.orientation;
 
         // Rotation matrices
         let ra = Mat3::from_quat(self.orientation);
@@ -118,6 +116,24 @@ impl Mat3 {
     }
 }
 
+impl std::ops::Mul for Mat3 {
+    type Output = Mat3;
+
+    fn mul(self, rhs: Mat3) -> Mat3 {
+        Mat3 {
+            cols: [self * rhs.cols[0], self * rhs.cols[1], self * rhs.cols[2]],
+        }
+    }
+}
+
+impl std::ops::Mul<Vec3> for Mat3 {
+    type Output = Vec3;
+
+    fn mul(self, rhs: Vec3) -> Vec3 {
+        self.cols[0] * rhs.x + self.cols[1] * rhs.y + self.cols[2] * rhs.z
+    }
+}
+
 pub fn detect_collision(obj1: &PhysicsObject, obj2: &PhysicsObject) -> Option<CollisionManifold> {
     match (obj1, obj2) {
         (
@@ -222,7 +238,12 @@ fn detect_rigid_rigid_collision(
     ];
 
     for axis in &axes {
-        let axis = axis.normalize();
+        // Orient every axis from body 1 towards body 2, the normal is expected
+        // to point that way and the overlap below is measured along it
+        let mut axis = axis.normalize();
+        if axis.dot(rel_pos) < 0.0 {
+            axis = -axis;
+        }
         let p1_proj = project_box(*p1, bb1.w, *o1, axis);
         let p2_proj = project_box(*p2, bb2.w, *o2, axis);
 
@@ -269,14 +290,14 @@ fn detect_rigid_rigid_collision(
 fn detect_soft_soft_collision(
     p1s: &[Vec3],
     t1s: &[[usize; 4]],
-    bb1: &Vec4,
+    _bb1: &Vec4,
     p2s: &[Vec3],
     t2s: &[[usize; 4]],
-    bb2: &Vec4,
+    _bb2: &Vec4,
 ) -> Option<CollisionManifold> {
     let mut contact_points = Vec::new();
     let mut total_normal = Vec3::ZERO;
-    let mut max_penetration = 0.0;
+    let mut max_penetration: f32 = 0.0;
 
     // Check each vertex of body 1 against each tetrahedron of body 2
     for p1 in p1s {
@@ -324,7 +345,7 @@ fn detect_rigid_soft_collision(
 ) -> Option<CollisionManifold> {
     let mut contact_points = Vec::new();
     let mut total_normal = Vec3::ZERO;
-    let mut max_penetration = 0.0;
+    let mut max_penetration: f32 = 0.0;
 
     // Check soft body vertices against rigid body
     for &pos in soft_positions {
@@ -1,9 +1,19 @@
-use crate::physics::{collision::CollisionManifold, physics::PhysicsObject};
-use glam::{Quat, Vec3, Vec4};
+use crate::physics::{
+    collision::CollisionManifold, material::ContactMaterial, physics::PhysicsObject,
+    rigid_body::world_inverse_inertia,
+};
+use glam::Vec3;
 use std::cell::RefCell;
 
 pub trait Constraint: Send + Sync {
-    fn project(&self, objects: &mut Vec<RefCell<PhysicsObject>>);
+    fn project(&self, objects: &mut [RefCell<PhysicsObject>]);
+    // Called once per substep before the first projection. Compliant (XPBD)
+    // constraints use it to pick up the substep length and reset their
+    // multipliers.
+    fn begin_substep(&self, _delta_time: f32) {}
+    // Indices of the bodies the constraint reads or moves. Bodies sharing a
+    // constraint are solved in the same island.
+    fn bodies(&self) -> Vec<usize>;
     fn clone_box(&self) -> Box<dyn Constraint>;
     fn is_collision_constraint(&self) -> bool {
         false
@@ -37,52 +47,56 @@ impl Clone for DistanceConstraint {
 }
 
 impl Constraint for DistanceConstraint {
-    fn project(&self, objects: &mut Vec<RefCell<PhysicsObject>>) {
+    fn project(&self, objects: &mut [RefCell<PhysicsObject>]) {
         let obj1 = objects[self.object1_index].borrow();
         let obj2 = objects[self.object2_index].borrow();
 
-        match (&*obj1, &*obj2) {
-            (
-                PhysicsObject::RigidBody {
-                    position: p1,
-                    mass: m1,
-                    ..
-                },
-                PhysicsObject::RigidBody {
-                    position: p2,
-                    mass: m2,
-                    ..
-                },
-            ) => {
-                let delta = *p2 - *p1;
-                let distance = delta.length();
-                if distance == 0.0 {
-                    return;
-                }
+        // Only rigid pairs, other cases are handled elsewhere
+        if let (
+            PhysicsObject::RigidBody {
+                position: p1,
+                mass: m1,
+                ..
+            },
+            PhysicsObject::RigidBody {
+                position: p2,
+                mass: m2,
+                ..
+            },
+        ) = (&*obj1, &*obj2)
+        {
+            let delta = *p2 - *p1;
+            let distance = delta.length();
+            if distance == 0.0 {
+                return;
+            }
 
-                let correction = delta * ((distance - self.rest_distance) / distance);
-                let total_mass = m1 + m2;
-                if total_mass == 0.0 {
-                    return;
-                }
+            let correction = delta * ((distance - self.rest_distance) / distance);
+            let (m1, m2) = (*m1, *m2);
+            let total_mass = m1 + m2;
+            if total_mass == 0.0 {
+                return;
+            }
 
-                drop(obj1);
-                drop(obj2);
+            drop(obj1);
+            drop(obj2);
 
-                let mut obj1_mut = objects[self.object1_index].borrow_mut();
-                let mut obj2_mut = objects[self.object2_index].borrow_mut();
+            let mut obj1_mut = objects[self.object1_index].borrow_mut();
+            let mut obj2_mut = objects[self.object2_index].borrow_mut();
 
-                if let PhysicsObject::RigidBody { position, .. } = &mut *obj1_mut {
-                    *position += correction * (*m2 / total_mass);
-                }
-                if let PhysicsObject::RigidBody { position, .. } = &mut *obj2_mut {
-                    *position -= correction * (*m1 / total_mass);
-                }
+            if let PhysicsObject::RigidBody { position, .. } = &mut *obj1_mut {
+                *position += correction * (m2 / total_mass);
+            }
+            if let PhysicsObject::RigidBody { position, .. } = &mut *obj2_mut {
+                *position -= correction * (m1 / total_mass);
             }
-            _ => (), // Other cases handled elsewhere
         }
     }
 
+    fn bodies(&self) -> Vec<usize> {
+        vec![self.object1_index, self.object2_index]
+    }
+
     fn clone_box(&self) -> Box<dyn Constraint> {
         Box::new(self.clone())
     }
@@ -112,7 +126,7 @@ impl Clone for VolumeConstraint {
 }
 
 impl Constraint for VolumeConstraint {
-    fn project(&self, objects: &mut Vec<RefCell<PhysicsObject>>) {
+    fn project(&self, objects: &mut [RefCell<PhysicsObject>]) {
         let mut object = objects[self.object_index].borrow_mut();
 
         if let PhysicsObject::DeformableBody {
@@ -184,6 +198,10 @@ impl Constraint for VolumeConstraint {
         }
     }
 
+    fn bodies(&self) -> Vec<usize> {
+        vec![self.object_index]
+    }
+
     fn clone_box(&self) -> Box<dyn Constraint> {
         Box::new(self.clone())
     }
@@ -207,6 +225,19 @@ impl CollisionConstraint {
             friction: 0.3,
         }
     }
+
+    pub fn with_manifold(mut self, manifold: CollisionManifold) -> Self {
+        self.manifold = Some(manifold);
+        self
+    }
+
+    // Friction and restitution for the contact, usually the combination of
+    // both bodies' materials
+    pub fn with_material(mut self, material: &ContactMaterial) -> Self {
+        self.friction = material.friction;
+        self.restitution = material.restitution;
+        self
+    }
 }
 
 impl Clone for CollisionConstraint {
@@ -222,99 +253,102 @@ impl Clone for CollisionConstraint {
 }
 
 impl Constraint for CollisionConstraint {
-    fn project(&self, objects: &mut Vec<RefCell<PhysicsObject>>) {
+    fn project(&self, objects: &mut [RefCell<PhysicsObject>]) {
         let mut obj1 = objects[self.object1_index].borrow_mut();
         let mut obj2 = objects[self.object2_index].borrow_mut();
 
         if let Some(ref manifold) = self.manifold {
-            match (&mut *obj1, &mut *obj2) {
-                (
-                    PhysicsObject::RigidBody {
-                        position: p1,
-                        velocity: v1,
-                        angular_velocity: w1,
-                        orientation: o1,
-                        mass: m1,
-                        inertia_tensor: i1,
-                        ..
-                    },
-                    PhysicsObject::RigidBody {
-                        position: p2,
-                        velocity: v2,
-                        angular_velocity: w2,
-                        orientation: o2,
-                        mass: m2,
-                        inertia_tensor: i2,
-                        ..
-                    },
-                ) => {
-                    for contact_point in &manifold.contact_points {
-                        let r1 = *contact_point - *p1;
-                        let r2 = *contact_point - *p2;
-
-                        // Calculate relative velocity at contact point
-                        let v1_at_p = *v1 + w1.cross(r1);
-                        let v2_at_p = *v2 + w2.cross(r2);
-                        let rel_vel = v2_at_p - v1_at_p;
-
-                        let vel_along_normal = rel_vel.dot(manifold.normal);
-
-                        // Only resolve if objects are moving toward each other
-                        if vel_along_normal < 0.0 {
-                            // Calculate inverse mass and inertia
-                            let w1 = if *m1 == 0.0 { 0.0 } else { 1.0 / m1 };
-                            let w2 = if *m2 == 0.0 { 0.0 } else { 1.0 / m2 };
-
-                            let i1_world = o1.mul_vec3(*i1);
-                            let i2_world = o2.mul_vec3(*i2);
-
-                            // Calculate angular factors
-                            let angular1 = (i1_world * r1.cross(manifold.normal)).cross(r1);
-                            let angular2 = (i2_world * r2.cross(manifold.normal)).cross(r2);
-
-                            let angular_factor = angular1.dot(manifold.normal) * w1
-                                + angular2.dot(manifold.normal) * w2;
-
-                            // Calculate impulse
-                            let j = -(1.0 + self.restitution) * vel_along_normal
-                                / (w1 + w2 + angular_factor);
-
-                            let impulse = manifold.normal * j;
-
-                            // Apply linear impulse
-                            *v1 -= impulse * w1;
-                            *v2 += impulse * w2;
-
-                            // Apply angular impulse
-                            *w1 -= i1_world * r1.cross(impulse);
-                            *w2 += i2_world * r2.cross(impulse);
-
-                            // Friction
-                            let tangent =
-                                (rel_vel - manifold.normal * vel_along_normal).normalize_or_zero();
-                            if tangent != Vec3::ZERO {
-                                let friction_impulse = -tangent * j * self.friction;
-
-                                *v1 -= friction_impulse * w1;
-                                *v2 += friction_impulse * w2;
-
-                                *w1 -= i1_world * r1.cross(friction_impulse);
-                                *w2 += i2_world * r2.cross(friction_impulse);
-                            }
-
-                            // Positional correction
-                            let percent = 0.2;
-                            let correction = manifold.normal * (manifold.penetration * percent);
-                            *p1 -= correction * w1;
-                            *p2 += correction * w2;
+            // Only rigid pairs, other cases are handled in the base implementation
+            if let (
+                PhysicsObject::RigidBody {
+                    position: p1,
+                    velocity: v1,
+                    angular_velocity: w1,
+                    orientation: o1,
+                    mass: m1,
+                    inertia_tensor: i1,
+                    ..
+                },
+                PhysicsObject::RigidBody {
+                    position: p2,
+                    velocity: v2,
+                    angular_velocity: w2,
+                    orientation: o2,
+                    mass: m2,
+                    inertia_tensor: i2,
+                    ..
+                },
+            ) = (&mut *obj1, &mut *obj2)
+            {
+                for contact_point in &manifold.contact_points {
+                    let r1 = *contact_point - *p1;
+                    let r2 = *contact_point - *p2;
+
+                    // Calculate relative velocity at contact point
+                    let v1_at_p = *v1 + w1.cross(r1);
+                    let v2_at_p = *v2 + w2.cross(r2);
+                    let rel_vel = v2_at_p - v1_at_p;
+
+                    let vel_along_normal = rel_vel.dot(manifold.normal);
+
+                    // Only resolve if objects are moving toward each other
+                    if vel_along_normal < 0.0 {
+                        // Calculate inverse mass and world-space inverse inertia
+                        let inv_m1 = if *m1 == 0.0 { 0.0 } else { 1.0 / *m1 };
+                        let inv_m2 = if *m2 == 0.0 { 0.0 } else { 1.0 / *m2 };
+
+                        let inv_i1 = world_inverse_inertia(*o1, *i1, *m1);
+                        let inv_i2 = world_inverse_inertia(*o2, *i2, *m2);
+
+                        // Calculate angular factors
+                        let angular1 = (inv_i1 * r1.cross(manifold.normal)).cross(r1);
+                        let angular2 = (inv_i2 * r2.cross(manifold.normal)).cross(r2);
+
+                        let angular_factor =
+                            angular1.dot(manifold.normal) + angular2.dot(manifold.normal);
+
+                        // Calculate impulse
+                        let j = -(1.0 + self.restitution) * vel_along_normal
+                            / (inv_m1 + inv_m2 + angular_factor);
+
+                        let impulse = manifold.normal * j;
+
+                        // Apply linear impulse
+                        *v1 -= impulse * inv_m1;
+                        *v2 += impulse * inv_m2;
+
+                        // Apply angular impulse
+                        *w1 -= inv_i1 * r1.cross(impulse);
+                        *w2 += inv_i2 * r2.cross(impulse);
+
+                        // Friction
+                        let tangent =
+                            (rel_vel - manifold.normal * vel_along_normal).normalize_or_zero();
+                        if tangent != Vec3::ZERO {
+                            let friction_impulse = -tangent * j * self.friction;
+
+                            *v1 -= friction_impulse * inv_m1;
+                            *v2 += friction_impulse * inv_m2;
+
+                            *w1 -= inv_i1 * r1.cross(friction_impulse);
+                            *w2 += inv_i2 * r2.cross(friction_impulse);
                         }
+
+                        // Positional correction
+                        let percent = 0.2;
+                        let correction = manifold.normal * (manifold.penetration * percent);
+                        *p1 -= correction * inv_m1;
+                        *p2 += correction * inv_m2;
                     }
                 }
-                _ => (), // Other cases handled in base implementation
             }
         }
     }
 
+    fn bodies(&self) -> Vec<usize> {
+        vec![self.object1_index, self.object2_index]
+    }
+
     fn clone_box(&self) -> Box<dyn Constraint> {
         Box::new(self.clone())
     }
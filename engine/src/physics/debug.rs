@@ -1,35 +1,162 @@
+use glam::Vec3;
+use serde::{Serialize, Serializer};
 use std::fmt;
 use std::time::Duration;
 
-#[derive(Debug, Clone)]
+use crate::physics::logging::warn_with_context;
+use crate::physics::particle_layout::PushConstants;
+use crate::physics::timestamps::PassTiming;
+
+const DEFAULT_HISTOGRAM_BINS: usize = 16;
+// Indices of offending particles kept per sample
+const MAX_REPORTED_PARTICLES: usize = 64;
+// Relative change in total energy since the baseline that counts as
+// diverging
+const DEFAULT_ENERGY_DRIFT_LIMIT: f32 = 0.5;
+
+// Durations go to JSON as fractional milliseconds
+pub(crate) fn serialize_millis<S: Serializer>(
+    time: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(time.as_secs_f64() * 1000.0)
+}
+
+// Counts of values in equal-width bins spanning `min..=max`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Histogram {
+    pub min: f32,
+    pub max: f32,
+    pub counts: Vec<u32>,
+}
+
+impl Histogram {
+    // Spans the finite values, everything else is left out
+    pub fn from_values(values: &[f32], bins: usize) -> Self {
+        let bins = bins.max(1);
+        let (min, max) = values
+            .iter()
+            .filter(|v| v.is_finite())
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &v| {
+                (min.min(v), max.max(v))
+            });
+        let mut histogram = Self {
+            min: if min <= max { min } else { 0.0 },
+            max: if min <= max { max } else { 0.0 },
+            counts: vec![0; bins],
+        };
+        let width = histogram.bin_width();
+        for &value in values.iter().filter(|v| v.is_finite()) {
+            let bin = if width > 0.0 {
+                ((value - histogram.min) / width) as usize
+            } else {
+                0
+            };
+            histogram.counts[bin.min(bins - 1)] += 1;
+        }
+        histogram
+    }
+
+    pub fn bin_width(&self) -> f32 {
+        (self.max - self.min) / self.counts.len().max(1) as f32
+    }
+
+    pub fn total(&self) -> u32 {
+        self.counts.iter().sum()
+    }
+}
+
+// Totals over the alive particles that have mass. Potential energy is
+// measured against gravity from the origin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Conservation {
+    pub kinetic_energy: f32,
+    pub potential_energy: f32,
+    pub momentum: [f32; 3],
+}
+
+impl Conservation {
+    pub fn total_energy(&self) -> f32 {
+        self.kinetic_energy + self.potential_energy
+    }
+
+    // What relative energy drift is measured against
+    fn energy_scale(&self) -> f32 {
+        (self.kinetic_energy.abs() + self.potential_energy.abs()).max(f32::EPSILON)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct DebugStats {
     pub active_particles: u32,
+    pub alive_particles: u32,
     pub particles_in_bounds: u32,
     pub max_velocity_violations: u32,
     pub avg_velocity: f32,
     pub avg_position: [f32; 3],
     pub bounds_violations: [u32; 3], // x, y, z violations
+    #[serde(rename = "compute_time_ms", serialize_with = "serialize_millis")]
     pub compute_time: Duration,
+    // Ranges and distributions over the alive, finite particles
+    pub min_speed: f32,
+    pub max_speed: f32,
+    pub min_position: [f32; 3],
+    pub max_position: [f32; 3],
+    pub speed_histogram: Histogram,
+    pub position_histograms: [Histogram; 3], // x, y, z
+    // Particles with a NaN or infinite position, velocity or lifetime, and
+    // the indices of the first few
+    pub non_finite_particles: u32,
+    pub non_finite_indices: Vec<usize>,
+    pub pass_timings: Vec<PassTiming>,
+    pub conservation: Conservation,
+    // Relative change in total energy since the baseline
+    pub energy_drift: f32,
+    // Change in momentum since the baseline
+    pub momentum_drift: [f32; 3],
+    // Non-finite particles, or energy drifting past the limit
+    pub diverging: bool,
 }
 
 impl Default for DebugStats {
     fn default() -> Self {
         Self {
             active_particles: 0,
+            alive_particles: 0,
             particles_in_bounds: 0,
             max_velocity_violations: 0,
             avg_velocity: 0.0,
             avg_position: [0.0; 3],
             bounds_violations: [0; 3],
             compute_time: Duration::from_secs(0),
+            min_speed: 0.0,
+            max_speed: 0.0,
+            min_position: [0.0; 3],
+            max_position: [0.0; 3],
+            speed_histogram: Histogram::default(),
+            position_histograms: Default::default(),
+            non_finite_particles: 0,
+            non_finite_indices: Vec::new(),
+            pass_timings: Vec::new(),
+            conservation: Conservation::default(),
+            energy_drift: 0.0,
+            momentum_drift: [0.0; 3],
+            diverging: false,
         }
     }
 }
 
+impl DebugStats {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
 impl fmt::Display for DebugStats {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "GPU Physics Debug Statistics:")?;
         writeln!(f, "Active Particles: {}", self.active_particles)?;
+        writeln!(f, "Alive Particles: {}", self.alive_particles)?;
         writeln!(f, "Particles In Bounds: {}", self.particles_in_bounds)?;
         writeln!(
             f,
@@ -47,6 +174,48 @@ impl fmt::Display for DebugStats {
             "Bounds Violations [x, y, z]: [{}, {}, {}]",
             self.bounds_violations[0], self.bounds_violations[1], self.bounds_violations[2]
         )?;
+        writeln!(
+            f,
+            "Speed Range: {:.2} - {:.2}",
+            self.min_speed, self.max_speed
+        )?;
+        writeln!(
+            f,
+            "Position Range: [{:.2}, {:.2}, {:.2}] - [{:.2}, {:.2}, {:.2}]",
+            self.min_position[0],
+            self.min_position[1],
+            self.min_position[2],
+            self.max_position[0],
+            self.max_position[1],
+            self.max_position[2]
+        )?;
+        writeln!(
+            f,
+            "Non-finite Particles: {} {:?}",
+            self.non_finite_particles, self.non_finite_indices
+        )?;
+        writeln!(
+            f,
+            "Total Energy: {:.2} (drift {:+.2}%)",
+            self.conservation.total_energy(),
+            self.energy_drift * 100.0
+        )?;
+        writeln!(
+            f,
+            "Momentum Drift: [{:.2}, {:.2}, {:.2}]",
+            self.momentum_drift[0], self.momentum_drift[1], self.momentum_drift[2]
+        )?;
+        if self.diverging {
+            writeln!(f, "DIVERGING")?;
+        }
+        for pass in &self.pass_timings {
+            writeln!(
+                f,
+                "Pass {}: {:.3}ms",
+                pass.name,
+                pass.time.as_secs_f32() * 1000.0
+            )?;
+        }
         writeln!(
             f,
             "Compute Time: {:.2}ms",
@@ -61,6 +230,10 @@ pub struct DebugVisualization {
     enabled: bool,
     sample_rate: u32, // How often to update stats (in frames)
     frame_counter: u32,
+    histogram_bins: usize,
+    energy_drift_limit: f32,
+    // Alive count and totals drift is measured from
+    baseline: Option<(u32, Conservation)>,
 }
 
 impl DebugVisualization {
@@ -70,9 +243,23 @@ impl DebugVisualization {
             enabled: false,
             sample_rate,
             frame_counter: 0,
+            histogram_bins: DEFAULT_HISTOGRAM_BINS,
+            energy_drift_limit: DEFAULT_ENERGY_DRIFT_LIMIT,
+            baseline: None,
         }
     }
 
+    pub fn with_histogram_bins(mut self, bins: usize) -> Self {
+        self.histogram_bins = bins.max(1);
+        self
+    }
+
+    // Relative energy drift past which the simulation counts as diverging
+    pub fn with_energy_drift_limit(mut self, limit: f32) -> Self {
+        self.energy_drift_limit = limit;
+        self
+    }
+
     pub fn enable(&mut self) {
         self.enabled = true;
     }
@@ -99,28 +286,57 @@ impl DebugVisualization {
         }
     }
 
+    // Measures drift from the next sample on. Happens by itself whenever the
+    // alive count changes, as spawns and deaths add and remove energy.
+    pub fn reset_baseline(&mut self) {
+        self.baseline = None;
+    }
+
+    // Assumes the default gravity, see `update_stats_with_params`
     pub fn update_stats(
         &mut self,
         particles: &[super::Particle],
         compute_time: Duration,
         bounds: [f32; 2],
         max_velocity: f32,
+    ) {
+        let params = PushConstants::new(0.0, particles.len() as u32)
+            .with_bounds(bounds[0], bounds[1])
+            .with_max_velocity(max_velocity);
+        self.update_stats_with_params(particles, compute_time, &params);
+    }
+
+    // Checks the particles against the bounds, velocity limit and gravity
+    // they were simulated with
+    pub fn update_stats_with_params(
+        &mut self,
+        particles: &[super::Particle],
+        compute_time: Duration,
+        params: &PushConstants,
     ) {
         if !self.enabled {
             return;
         }
+        let bounds = params.bounds;
+        let max_velocity = params.max_velocity;
 
-        let mut stats = DebugStats::default();
-        stats.active_particles = particles.len() as u32;
-        stats.compute_time = compute_time;
+        let mut stats = DebugStats {
+            active_particles: particles.len() as u32,
+            alive_particles: particles.iter().filter(|p| p.is_alive()).count() as u32,
+            compute_time,
+            ..Default::default()
+        };
+        let gravity = Vec3::from(params.gravity);
 
         let mut total_velocity = 0.0;
         let mut total_position = [0.0; 3];
         let mut in_bounds = 0;
         let mut velocity_violations = 0;
         let mut bounds_violations = [0; 3];
+        let mut speeds = Vec::with_capacity(particles.len());
+        let mut positions: [Vec<f32>; 3] = Default::default();
 
-        for particle in particles {
+        for (index, particle) in particles.iter().enumerate() {
             // Check velocity
             let velocity = [
                 particle.velocity[0],
@@ -151,13 +367,43 @@ impl DebugVisualization {
             if particle_in_bounds {
                 in_bounds += 1;
             }
+
+            // Dead particles count too, a NaN lifetime hides a particle
+            // from every check below
+            let finite = particle.position.iter().all(|v| v.is_finite())
+                && particle.velocity.iter().all(|v| v.is_finite())
+                && !particle.lifetime.is_nan();
+            if !finite {
+                stats.non_finite_particles += 1;
+                if stats.non_finite_indices.len() < MAX_REPORTED_PARTICLES {
+                    stats.non_finite_indices.push(index);
+                }
+                continue;
+            }
+            if !particle.is_alive() {
+                continue;
+            }
+
+            speeds.push(speed);
+            for (values, &value) in positions.iter_mut().zip(&particle.position) {
+                values.push(value);
+            }
+            if particle.mass > 0.0 {
+                let conservation = &mut stats.conservation;
+                let momentum = Vec3::from(conservation.momentum)
+                    + particle.mass * Vec3::from(particle.velocity);
+                conservation.kinetic_energy += 0.5 * particle.mass * speed * speed;
+                conservation.potential_energy -=
+                    particle.mass * gravity.dot(Vec3::from(particle.position));
+                conservation.momentum = momentum.into();
+            }
         }
 
         // Update averages
         if stats.active_particles > 0 {
             stats.avg_velocity = total_velocity / stats.active_particles as f32;
-            for i in 0..3 {
-                stats.avg_position[i] = total_position[i] / stats.active_particles as f32;
+            for (average, total) in stats.avg_position.iter_mut().zip(total_position) {
+                *average = total / stats.active_particles as f32;
             }
         }
 
@@ -165,9 +411,66 @@ impl DebugVisualization {
         stats.max_velocity_violations = velocity_violations;
         stats.bounds_violations = bounds_violations;
 
+        stats.speed_histogram = Histogram::from_values(&speeds, self.histogram_bins);
+        stats.min_speed = stats.speed_histogram.min;
+        stats.max_speed = stats.speed_histogram.max;
+        for (i, values) in positions.iter().enumerate() {
+            let histogram = Histogram::from_values(values, self.histogram_bins);
+            stats.min_position[i] = histogram.min;
+            stats.max_position[i] = histogram.max;
+            stats.position_histograms[i] = histogram;
+        }
+
+        let (alive, baseline) = match self.baseline {
+            Some((alive, baseline)) if alive == stats.alive_particles => (alive, baseline),
+            _ => (stats.alive_particles, stats.conservation),
+        };
+        self.baseline = Some((alive, baseline));
+        stats.energy_drift =
+            (stats.conservation.total_energy() - baseline.total_energy()) / baseline.energy_scale();
+        for i in 0..3 {
+            stats.momentum_drift[i] = stats.conservation.momentum[i] - baseline.momentum[i];
+        }
+
+        stats.diverging =
+            stats.non_finite_particles > 0 || stats.energy_drift.abs() > self.energy_drift_limit;
+        if stats.diverging && !self.stats.diverging {
+            warn_with_context!(
+                "DEBUG",
+                "Simulation diverging: {} non-finite particles {:?}, energy drift {:+.1}%",
+                stats.non_finite_particles,
+                stats.non_finite_indices,
+                stats.energy_drift * 100.0
+            );
+        }
+
         self.stats = stats;
     }
 
+    // Stats for a dump loaded offline, checked against the dumped parameters
+    pub fn update_stats_from_dump(&mut self, dump: &super::ParticleDump) {
+        let params = PushConstants::new(0.0, dump.particles.len() as u32)
+            .with_gravity(dump.gravity)
+            .with_bounds(dump.bounds[0], dump.bounds[1])
+            .with_max_velocity(dump.max_velocity);
+        self.update_stats_with_params(&dump.particles, Duration::ZERO, &params);
+    }
+
+    // Overrides the alive count with the one the GPU keeps for indirect
+    // draws, see `GpuPhysicsSystem::alive_count`
+    pub fn set_alive_count(&mut self, alive: u32) {
+        self.stats.alive_particles = alive;
+    }
+
+    // Pass timings of the sampled step, see `GpuPhysicsSystem::pass_timings`
+    pub fn set_pass_timings(&mut self, timings: &[PassTiming]) {
+        self.stats.pass_timings = timings.to_vec();
+    }
+
+    pub fn is_diverging(&self) -> bool {
+        self.stats.diverging
+    }
+
     pub fn get_stats(&self) -> DebugStats {
         self.stats.clone()
     }
@@ -175,6 +478,10 @@ impl DebugVisualization {
     pub fn get_stats_string(&self) -> String {
         format!("{}", self.stats)
     }
+
+    pub fn get_stats_json(&self) -> serde_json::Result<String> {
+        self.stats.to_json()
+    }
 }
 
 pub struct ParticleDebugView<'a> {
@@ -209,21 +516,84 @@ mod tests {
         debug.enable();
 
         let test_particles = [
-            Particle {
-                position: [0.0, 0.0, 0.0, 1.0],
-                velocity: [1.0, 1.0, 1.0, 0.0],
-            },
-            Particle {
-                position: [2.0, 2.0, 2.0, 1.0],
-                velocity: [2.0, 2.0, 2.0, 0.0],
-            },
+            Particle::new([0.0, 0.0, 0.0], [1.0, 1.0, 1.0]),
+            Particle::new([2.0, 2.0, 2.0], [2.0, 2.0, 2.0]),
+            Particle::new([0.0, 0.0, 0.0], [0.0, 0.0, 0.0]).with_lifetime(0.0),
         ];
 
         debug.update_stats(&test_particles, Duration::from_millis(16), [-1.0, 1.0], 2.0);
 
         let stats = debug.get_stats();
-        assert_eq!(stats.active_particles, 2);
+        assert_eq!(stats.active_particles, 3);
+        assert_eq!(stats.alive_particles, 2);
         assert_eq!(stats.max_velocity_violations, 1); // Second particle exceeds max velocity of 2.0
         assert_eq!(stats.bounds_violations, [1, 1, 1]); // Second particle outside bounds
     }
+
+    #[test]
+    fn test_histograms_and_ranges() {
+        let mut debug = DebugVisualization::new(1).with_histogram_bins(4);
+        debug.enable();
+
+        let particles = [
+            Particle::new([0.0, 0.0, 0.0], [1.0, 0.0, 0.0]),
+            Particle::new([1.0, 4.0, 0.0], [2.0, 0.0, 0.0]),
+            Particle::new([2.0, 8.0, 0.0], [4.0, 0.0, 0.0]),
+            Particle::new([9.0, 9.0, 9.0], [9.0, 0.0, 0.0]).with_lifetime(0.0),
+        ];
+        debug.update_stats(&particles, Duration::ZERO, [-10.0, 10.0], 10.0);
+
+        let stats = debug.get_stats();
+        assert_eq!((stats.min_speed, stats.max_speed), (1.0, 4.0));
+        assert_eq!(stats.min_position, [0.0, 0.0, 0.0]);
+        assert_eq!(stats.max_position, [2.0, 8.0, 0.0]);
+        assert_eq!(stats.speed_histogram.counts, vec![1, 1, 0, 1]);
+        assert_eq!(stats.position_histograms[1].counts, vec![1, 0, 1, 1]);
+        // Every value lands in the first bin of an empty range
+        assert_eq!(stats.position_histograms[2].counts, vec![3, 0, 0, 0]);
+        assert!(!stats.diverging);
+    }
+
+    #[test]
+    fn test_divergence_detection() {
+        let mut debug = DebugVisualization::new(1).with_energy_drift_limit(0.1);
+        debug.enable();
+        let params = PushConstants::new(0.0, 3).with_gravity([0.0, -10.0, 0.0]);
+
+        let mut particles = vec![
+            Particle::new([0.0, 1.0, 0.0], [0.0, 0.0, 0.0]).with_mass(2.0),
+            Particle::new([0.0, 0.0, 0.0], [3.0, 4.0, 0.0]),
+            Particle::new([0.0, 5.0, 0.0], [1.0, 0.0, 0.0]).with_mass(0.0),
+        ];
+        debug.update_stats_with_params(&particles, Duration::ZERO, &params);
+        let stats = debug.get_stats();
+        assert_eq!(stats.conservation.kinetic_energy, 12.5);
+        assert_eq!(stats.conservation.potential_energy, 20.0);
+        assert_eq!(stats.conservation.momentum, [3.0, 4.0, 0.0]);
+        assert_eq!(stats.energy_drift, 0.0);
+
+        // Falling converts potential into kinetic energy without drift
+        particles[0].position[1] = 0.0;
+        particles[0].velocity[1] = -(20.0f32).sqrt();
+        debug.update_stats_with_params(&particles, Duration::ZERO, &params);
+        assert!(debug.get_stats().energy_drift.abs() < 1e-5);
+        assert!(!debug.is_diverging());
+
+        particles[1].velocity = [30.0, 40.0, 0.0];
+        debug.update_stats_with_params(&particles, Duration::ZERO, &params);
+        assert!(debug.is_diverging());
+
+        particles[1].velocity = [3.0, 4.0, 0.0];
+        particles[2].position[0] = f32::NAN;
+        debug.update_stats_with_params(&particles, Duration::ZERO, &params);
+        let stats = debug.get_stats();
+        assert_eq!(stats.non_finite_particles, 1);
+        assert_eq!(stats.non_finite_indices, vec![2]);
+        assert!(stats.diverging);
+
+        let json: serde_json::Value = serde_json::from_str(&stats.to_json().unwrap()).unwrap();
+        assert_eq!(json["non_finite_indices"], serde_json::json!([2]));
+        assert_eq!(json["diverging"], serde_json::json!(true));
+        assert_eq!(json["compute_time_ms"], serde_json::json!(0.0));
+    }
 }
@@ -0,0 +1,209 @@
+// Debug drawing of the CPU world into a `DebugDraw`, meant to be called every
+// frame after `PhysicsWorld::update`. Draws the collision boxes of rigid
+// bodies (sensors in their own color), soft body tetrahedra or particles,
+// contact points with their normals, velocities and the broad phase, which
+// draws itself through `BroadPhase::debug_draw`.
+
+use glam::Vec3;
+
+use crate::graphics::debug_draw::DebugDraw;
+use crate::physics::physics::{PhysicsObject, PhysicsWorld};
+
+pub const SHAPE_COLOR: [f32; 4] = [0.2, 0.9, 0.3, 1.0];
+pub const SENSOR_COLOR: [f32; 4] = [0.3, 0.6, 1.0, 0.6];
+pub const SOFT_BODY_COLOR: [f32; 4] = [0.9, 0.6, 0.2, 1.0];
+pub const CONTACT_COLOR: [f32; 4] = [1.0, 0.2, 0.2, 1.0];
+pub const VELOCITY_COLOR: [f32; 4] = [1.0, 1.0, 0.3, 1.0];
+pub const CELL_COLOR: [f32; 4] = [0.5, 0.5, 0.5, 0.4];
+// Cells holding more than one object, where pairs come from
+pub const SHARED_CELL_COLOR: [f32; 4] = [1.0, 0.5, 0.0, 0.6];
+
+// Vertex pairs of the six edges of a tetrahedron
+const TETRAHEDRON_EDGES: [(usize, usize); 6] = [(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)];
+
+#[derive(Debug, Clone)]
+pub struct PhysicsDebugOptions {
+    pub shapes: bool,
+    pub contacts: bool,
+    pub velocities: bool,
+    pub broad_phase: bool,
+    // Length of the contact normal arrows
+    pub normal_length: f32,
+    // Seconds of motion the velocity arrows cover
+    pub velocity_scale: f32,
+    // Size of the crosses marking contact points and cloth particles
+    pub point_size: f32,
+}
+
+impl Default for PhysicsDebugOptions {
+    fn default() -> Self {
+        Self {
+            shapes: true,
+            contacts: true,
+            velocities: false,
+            broad_phase: false,
+            normal_length: 0.5,
+            velocity_scale: 0.1,
+            point_size: 0.1,
+        }
+    }
+}
+
+impl PhysicsWorld {
+    pub fn debug_draw(&self, draw: &mut DebugDraw, options: &PhysicsDebugOptions) {
+        for (index, object) in self.objects.iter().enumerate() {
+            if !self.contains_object(index) {
+                continue;
+            }
+            match &*object.borrow() {
+                PhysicsObject::RigidBody {
+                    position,
+                    velocity,
+                    orientation,
+                    bounding_box,
+                    ..
+                } => {
+                    if options.shapes {
+                        let color = if self.is_sensor(index) {
+                            SENSOR_COLOR
+                        } else {
+                            SHAPE_COLOR
+                        };
+                        draw.oriented_box(
+                            *position,
+                            Vec3::splat(bounding_box.w),
+                            *orientation,
+                            color,
+                        );
+                    }
+                    if options.velocities && *velocity != Vec3::ZERO {
+                        draw.arrow(
+                            *position,
+                            *position + *velocity * options.velocity_scale,
+                            VELOCITY_COLOR,
+                        );
+                    }
+                }
+                PhysicsObject::DeformableBody {
+                    positions,
+                    tetrahedra,
+                    ..
+                } => {
+                    if !options.shapes {
+                        continue;
+                    }
+                    // Cloth has no tetrahedra, only its particles are drawn
+                    if tetrahedra.is_empty() {
+                        for &position in positions {
+                            draw.point(position, options.point_size, SOFT_BODY_COLOR);
+                        }
+                    }
+                    for tetrahedron in tetrahedra {
+                        for (a, b) in TETRAHEDRON_EDGES {
+                            draw.line(
+                                positions[tetrahedron[a]],
+                                positions[tetrahedron[b]],
+                                SOFT_BODY_COLOR,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        if options.contacts {
+            for manifold in self
+                .events
+                .contacts()
+                .iter()
+                .filter_map(|contact| contact.manifold.as_ref())
+            {
+                for &point in &manifold.contact_points {
+                    draw.point(point, options.point_size, CONTACT_COLOR);
+                    draw.arrow(
+                        point,
+                        point + manifold.normal * options.normal_length,
+                        CONTACT_COLOR,
+                    );
+                }
+            }
+        }
+
+        if options.broad_phase {
+            self.broad_phase.borrow().backend().debug_draw(draw);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::spatial::{BroadPhase, SpatialHash};
+    use glam::{Mat3, Quat, Vec4};
+
+    fn cube(position: Vec3, velocity: Vec3) -> PhysicsObject {
+        PhysicsObject::RigidBody {
+            position,
+            velocity,
+            acceleration: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            angular_velocity: Vec3::ZERO,
+            angular_acceleration: Vec3::ZERO,
+            mass: 1.0,
+            inertia_tensor: Mat3::IDENTITY,
+            bounding_box: Vec4::new(0.0, 0.0, 0.0, 0.5),
+        }
+    }
+
+    #[test]
+    fn test_world_shapes() {
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        world.add_object(cube(Vec3::ZERO, Vec3::ZERO));
+        world.add_object(cube(Vec3::new(5.0, 0.0, 0.0), Vec3::new(0.0, 10.0, 0.0)));
+        let removed = world.add_object(cube(Vec3::new(9.0, 0.0, 0.0), Vec3::ZERO));
+        world.remove_object(removed);
+
+        let mut draw = DebugDraw::new();
+        world.debug_draw(&mut draw, &PhysicsDebugOptions::default());
+        assert_eq!(draw.line_count(), 24);
+        assert!(draw
+            .vertices()
+            .iter()
+            .all(|v| v.position[0] <= 5.5 && v.color == SHAPE_COLOR));
+
+        draw.clear();
+        let options = PhysicsDebugOptions {
+            shapes: false,
+            velocities: true,
+            ..Default::default()
+        };
+        world.debug_draw(&mut draw, &options);
+        // One arrow, a shaft and four prongs
+        assert_eq!(draw.line_count(), 5);
+        assert_eq!(draw.vertices()[1].position, [5.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn test_spatial_hash_cells() {
+        let mut hash = SpatialHash::new();
+        let size = hash.cell_size();
+        BroadPhase::update(
+            &mut hash,
+            &[
+                (Vec3::splat(0.1 * size), Vec3::splat(0.2 * size)),
+                (Vec3::splat(0.3 * size), Vec3::splat(0.4 * size)),
+                (Vec3::new(2.1, 0.1, 0.1) * size, Vec3::new(2.2, 0.2, 0.2) * size),
+            ],
+        );
+
+        let mut draw = DebugDraw::new();
+        hash.debug_draw(&mut draw);
+        assert_eq!(draw.line_count(), 24);
+        let shared = draw
+            .vertices()
+            .iter()
+            .filter(|v| v.color == SHARED_CELL_COLOR)
+            .count();
+        assert_eq!(shared, 24);
+    }
+}
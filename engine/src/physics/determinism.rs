@@ -0,0 +1,210 @@
+use glam::{Quat, Vec3};
+
+use crate::physics::physics::{PhysicsObject, PhysicsWorld};
+
+// FNV-1a parameters. The hash has to be identical across runs, platforms and
+// compiler versions for golden files, which rules out `DefaultHasher`.
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+impl PhysicsWorld {
+    // Opt-in deterministic stepping. Broad-phase pairs are sorted, constraints
+    // are projected one at a time in insertion order, and integration and
+    // velocity updates run serially in body index order instead of on the
+    // rayon pool, so identical inputs produce bit-identical states on every
+    // run.
+    pub fn set_deterministic(&mut self, enabled: bool) {
+        self.deterministic = enabled;
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    // Hash of the simulated state of every body, built from the exact bit
+    // patterns of positions, orientations and velocities. Bodies are hashed
+    // in index order together with their index, and removed bodies' free
+    // slots are skipped. Two worlds stepped in deterministic mode from the
+    // same inputs report the same hash, which makes it usable for lock-step
+    // replay checks and golden-file tests.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = StateHasher::new();
+        for (index, obj) in self.objects.iter().enumerate() {
+            if self.free_slots.contains(&index) {
+                continue;
+            }
+            hasher.write_u64(index as u64);
+            hash_body(&mut hasher, &obj.borrow());
+        }
+        hasher.finish()
+    }
+}
+
+fn hash_body(hasher: &mut StateHasher, obj: &PhysicsObject) {
+    match obj {
+        PhysicsObject::RigidBody {
+            position,
+            velocity,
+            orientation,
+            angular_velocity,
+            ..
+        } => {
+            hasher.write_u64(0);
+            hasher.write_vec3(*position);
+            hasher.write_vec3(*velocity);
+            hasher.write_quat(*orientation);
+            hasher.write_vec3(*angular_velocity);
+        }
+        PhysicsObject::DeformableBody {
+            positions,
+            velocities,
+            ..
+        } => {
+            hasher.write_u64(1);
+            hasher.write_u64(positions.len() as u64);
+            for (&position, &velocity) in positions.iter().zip(velocities) {
+                hasher.write_vec3(position);
+                hasher.write_vec3(velocity);
+            }
+        }
+    }
+}
+
+// Puts broad-phase pairs into a canonical order. Backends report pairs in
+// hash-map or tree traversal order, which differs between runs.
+pub(crate) fn sort_pairs(pairs: &mut Vec<(usize, usize)>) {
+    for pair in pairs.iter_mut() {
+        if pair.0 > pair.1 {
+            *pair = (pair.1, pair.0);
+        }
+    }
+    pairs.sort_unstable();
+    pairs.dedup();
+}
+
+struct StateHasher(u64);
+
+impl StateHasher {
+    fn new() -> Self {
+        Self(FNV_OFFSET)
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        for byte in value.to_le_bytes() {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn write_f32(&mut self, value: f32) {
+        self.write_u64(value.to_bits() as u64);
+    }
+
+    fn write_vec3(&mut self, value: Vec3) {
+        self.write_f32(value.x);
+        self.write_f32(value.y);
+        self.write_f32(value.z);
+    }
+
+    fn write_quat(&mut self, value: Quat) {
+        self.write_f32(value.x);
+        self.write_f32(value.y);
+        self.write_f32(value.z);
+        self.write_f32(value.w);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::{Mat3, Vec4};
+
+    fn sphere(position: Vec3, velocity: Vec3) -> PhysicsObject {
+        PhysicsObject::RigidBody {
+            position,
+            velocity,
+            acceleration: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            angular_velocity: Vec3::ZERO,
+            angular_acceleration: Vec3::ZERO,
+            mass: 1.0,
+            inertia_tensor: Mat3::IDENTITY,
+            bounding_box: Vec4::new(0.0, 0.0, 0.0, 0.5),
+        }
+    }
+
+    #[test]
+    fn test_sort_pairs_is_canonical() {
+        let mut pairs = vec![(4, 1), (2, 3), (1, 4), (0, 2)];
+        sort_pairs(&mut pairs);
+        assert_eq!(pairs, vec![(0, 2), (1, 4), (2, 3)]);
+    }
+
+    #[test]
+    fn test_state_hash_tracks_state() {
+        let build = || {
+            let mut world = PhysicsWorld::new(Vec3::new(0.0, -9.81, 0.0));
+            world.add_object(sphere(Vec3::ZERO, Vec3::X));
+            world.add_object(sphere(Vec3::new(2.0, 0.0, 0.0), Vec3::ZERO));
+            world
+        };
+
+        let a = build();
+        let b = build();
+        assert_eq!(a.state_hash(), b.state_hash());
+
+        if let PhysicsObject::RigidBody { position, .. } = &mut *b.objects[1].borrow_mut() {
+            position.y += f32::EPSILON;
+        }
+        assert_ne!(a.state_hash(), b.state_hash());
+    }
+
+    // Two stacks of spheres falling onto each other, enough to exercise the
+    // broad phase, contact generation and the solver every step
+    fn falling_stack(order: &[usize]) -> PhysicsWorld {
+        let bodies = [
+            sphere(Vec3::new(0.0, 0.0, 0.0), Vec3::ZERO),
+            sphere(Vec3::new(0.3, 0.9, 0.0), Vec3::new(0.0, -1.0, 0.0)),
+            sphere(Vec3::new(-0.2, 1.8, 0.1), Vec3::new(0.5, -2.0, 0.0)),
+            sphere(Vec3::new(0.8, 0.2, -0.3), Vec3::new(-1.0, 0.0, 0.5)),
+        ];
+
+        let mut world = PhysicsWorld::new(Vec3::new(0.0, -9.81, 0.0));
+        world.set_deterministic(true);
+        for &i in order {
+            world.add_object(bodies[i].clone());
+        }
+        world
+    }
+
+    #[test]
+    fn test_lock_step_worlds_match_every_step() {
+        let mut a = falling_stack(&[0, 1, 2, 3]);
+        let mut b = falling_stack(&[0, 1, 2, 3]);
+
+        for step in 0..120 {
+            a.update(1.0 / 60.0);
+            b.update(1.0 / 60.0);
+            assert_eq!(a.state_hash(), b.state_hash(), "diverged at step {}", step);
+        }
+    }
+
+    #[test]
+    fn test_state_hash_follows_body_indices() {
+        let a = falling_stack(&[0, 1, 2, 3]);
+        // The same bodies under different indices are a different state
+        let b = falling_stack(&[3, 1, 0, 2]);
+        assert_ne!(a.state_hash(), b.state_hash());
+
+        // Free slots don't contribute
+        let c = falling_stack(&[0, 1, 2]);
+        let mut d = falling_stack(&[0, 1, 2, 3]);
+        assert_ne!(c.state_hash(), d.state_hash());
+        d.remove_object(3);
+        assert_eq!(c.state_hash(), d.state_hash());
+    }
+}
@@ -0,0 +1,545 @@
+// Particle dumps: the full particle set with the emitter states and the
+// simulation parameters needed to pick a run back up, for reproducing bug
+// reports and comparing runs offline. Dumps are written as compact binary or
+// as CSV and load into `GpuPhysicsSystem` or, as point masses, into
+// `PhysicsWorld`.
+//
+// Binary dumps start with a small little-endian header followed by the
+// particles exactly as the GPU stores them, so they only load with the
+// particle layout version they were written with:
+//
+//   magic "PDMP", format version, layout version, frame, time,
+//   gravity, bounds, max velocity, particle count, emitter count,
+//   particles, emitter states (time, accumulator, pending, emitted)
+//
+// CSV dumps have three sections, each introduced by a `#` line naming its
+// columns: the parameters, the emitter states and the particles.
+
+use bytemuck::Zeroable;
+use glam::{Mat3, Quat, Vec3, Vec4};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::physics::emitter::EmitterState;
+use crate::physics::gpu_physics::{GpuPhysicsSystem, PhysicsError};
+use crate::physics::particle_layout::{Particle, PushConstants, PARTICLE_LAYOUT_VERSION};
+use crate::physics::physics::{PhysicsObject, PhysicsWorld};
+use crate::physics::rigid_body::sphere_inertia;
+
+const MAGIC: &[u8; 4] = b"PDMP";
+const FORMAT_VERSION: u32 = 1;
+
+const PARAMS_HEADER: &str =
+    "# frame,time,gravity_x,gravity_y,gravity_z,bounds_min,bounds_max,max_velocity";
+const EMITTER_HEADER: &str = "# emitter,time,accumulator,pending,emitted";
+const PARTICLE_HEADER: &str = "# particle,position_x,position_y,position_z,mass,\
+velocity_x,velocity_y,velocity_z,lifetime,color_r,color_g,color_b,color_a";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParticleDump {
+    // Steps simulated before the dump
+    pub frame: u32,
+    // Seconds simulated before the dump
+    pub time: f32,
+    pub gravity: [f32; 3],
+    pub bounds: [f32; 2],
+    pub max_velocity: f32,
+    // Every slot, dead ones included
+    pub particles: Vec<Particle>,
+    // In emitter order
+    pub emitters: Vec<EmitterState>,
+}
+
+impl ParticleDump {
+    // A dump of `particles` with the default parameters and no emitters
+    pub fn new(particles: Vec<Particle>) -> Self {
+        let params = PushConstants::new(0.0, 0);
+        Self {
+            frame: 0,
+            time: 0.0,
+            gravity: params.gravity,
+            bounds: params.bounds,
+            max_velocity: params.max_velocity,
+            particles,
+            emitters: Vec::new(),
+        }
+    }
+
+    pub fn alive_count(&self) -> usize {
+        self.particles.iter().filter(|p| p.is_alive()).count()
+    }
+
+    // Writes CSV if the extension is `csv`, binary otherwise
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut writer = BufWriter::new(File::create(path)?);
+        if is_csv(path) {
+            self.write_csv(&mut writer)?;
+        } else {
+            self.write_binary(&mut writer)?;
+        }
+        writer.flush()
+    }
+
+    // Reads either format, telling them apart by the magic
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        if reader.fill_buf()?.starts_with(MAGIC) {
+            Self::read_binary(reader)
+        } else {
+            Self::read_csv(reader)
+        }
+    }
+
+    pub fn write_binary<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        for value in [FORMAT_VERSION, PARTICLE_LAYOUT_VERSION, self.frame] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        let params = [
+            self.time,
+            self.gravity[0],
+            self.gravity[1],
+            self.gravity[2],
+            self.bounds[0],
+            self.bounds[1],
+            self.max_velocity,
+        ];
+        for value in params {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        for count in [self.particles.len(), self.emitters.len()] {
+            writer.write_all(&(count as u32).to_le_bytes())?;
+        }
+
+        writer.write_all(bytemuck::cast_slice(&self.particles))?;
+        for state in &self.emitters {
+            writer.write_all(&state.time.to_le_bytes())?;
+            writer.write_all(&state.accumulator.to_le_bytes())?;
+            writer.write_all(&state.pending.to_le_bytes())?;
+            writer.write_all(&state.emitted.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_binary<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a particle dump".to_string()));
+        }
+        let format = read_u32(&mut reader)?;
+        if format != FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported dump format version {}",
+                format
+            )));
+        }
+        let layout = read_u32(&mut reader)?;
+        if layout != PARTICLE_LAYOUT_VERSION {
+            return Err(invalid_data(format!(
+                "Dump has particle layout version {}, expected {}",
+                layout, PARTICLE_LAYOUT_VERSION
+            )));
+        }
+
+        let frame = read_u32(&mut reader)?;
+        let mut params = [0.0f32; 7];
+        for value in &mut params {
+            *value = read_f32(&mut reader)?;
+        }
+        let [time, gravity_x, gravity_y, gravity_z, bounds_min, bounds_max, max_velocity] = params;
+        let particle_count = read_u32(&mut reader)? as usize;
+        let emitter_count = read_u32(&mut reader)? as usize;
+
+        // Counts come from the file, grow as the data shows up rather than
+        // trusting them with one allocation
+        let mut particles = Vec::with_capacity(particle_count.min(1 << 16));
+        for _ in 0..particle_count {
+            let mut particle = Particle::zeroed();
+            reader.read_exact(bytemuck::bytes_of_mut(&mut particle))?;
+            particles.push(particle);
+        }
+        let mut emitters = Vec::with_capacity(emitter_count.min(1 << 10));
+        for _ in 0..emitter_count {
+            let time = read_f32(&mut reader)?;
+            let accumulator = read_f32(&mut reader)?;
+            let pending = read_u32(&mut reader)?;
+            let mut emitted = [0u8; 8];
+            reader.read_exact(&mut emitted)?;
+            emitters.push(EmitterState {
+                time,
+                accumulator,
+                pending,
+                emitted: u64::from_le_bytes(emitted),
+            });
+        }
+
+        Ok(Self {
+            frame,
+            time,
+            gravity: [gravity_x, gravity_y, gravity_z],
+            bounds: [bounds_min, bounds_max],
+            max_velocity,
+            particles,
+            emitters,
+        })
+    }
+
+    // Floats are written with the shortest representation that reads back
+    // to the same value, so CSV round trips are exact too
+    pub fn write_csv<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "{}", PARAMS_HEADER)?;
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{}",
+            self.frame,
+            self.time,
+            self.gravity[0],
+            self.gravity[1],
+            self.gravity[2],
+            self.bounds[0],
+            self.bounds[1],
+            self.max_velocity
+        )?;
+
+        writeln!(writer, "{}", EMITTER_HEADER)?;
+        for (index, state) in self.emitters.iter().enumerate() {
+            writeln!(
+                writer,
+                "{},{},{},{},{}",
+                index, state.time, state.accumulator, state.pending, state.emitted
+            )?;
+        }
+
+        writeln!(writer, "{}", PARTICLE_HEADER)?;
+        for (index, p) in self.particles.iter().enumerate() {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                index,
+                p.position[0],
+                p.position[1],
+                p.position[2],
+                p.mass,
+                p.velocity[0],
+                p.velocity[1],
+                p.velocity[2],
+                p.lifetime,
+                p.color[0],
+                p.color[1],
+                p.color[2],
+                p.color[3]
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn read_csv<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut dump = Self::new(Vec::new());
+        let mut section = None;
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('#') {
+                section = Some(match line {
+                    PARAMS_HEADER => PARAMS_HEADER,
+                    EMITTER_HEADER => EMITTER_HEADER,
+                    PARTICLE_HEADER => PARTICLE_HEADER,
+                    _ => {
+                        return Err(invalid_data(format!(
+                            "Unknown section on line {}: {}",
+                            number + 1,
+                            line
+                        )))
+                    }
+                });
+                continue;
+            }
+
+            let row = Row {
+                fields: line.split(',').map(str::trim).collect(),
+                line: number + 1,
+            };
+            match section {
+                Some(PARAMS_HEADER) => {
+                    row.expect_len(8)?;
+                    dump.frame = row.get(0)?;
+                    dump.time = row.get(1)?;
+                    dump.gravity = [row.get(2)?, row.get(3)?, row.get(4)?];
+                    dump.bounds = [row.get(5)?, row.get(6)?];
+                    dump.max_velocity = row.get(7)?;
+                }
+                Some(EMITTER_HEADER) => {
+                    row.expect_len(5)?;
+                    row.expect_index(dump.emitters.len())?;
+                    dump.emitters.push(EmitterState {
+                        time: row.get(1)?,
+                        accumulator: row.get(2)?,
+                        pending: row.get(3)?,
+                        emitted: row.get(4)?,
+                    });
+                }
+                Some(_) => {
+                    row.expect_len(13)?;
+                    row.expect_index(dump.particles.len())?;
+                    dump.particles.push(Particle {
+                        position: [row.get(1)?, row.get(2)?, row.get(3)?],
+                        mass: row.get(4)?,
+                        velocity: [row.get(5)?, row.get(6)?, row.get(7)?],
+                        lifetime: row.get(8)?,
+                        color: [row.get(9)?, row.get(10)?, row.get(11)?, row.get(12)?],
+                    });
+                }
+                None => {
+                    return Err(invalid_data(format!(
+                        "Data before any section header on line {}",
+                        number + 1
+                    )))
+                }
+            }
+        }
+        Ok(dump)
+    }
+}
+
+struct Row<'a> {
+    fields: Vec<&'a str>,
+    line: usize,
+}
+
+impl Row<'_> {
+    fn get<T: FromStr>(&self, index: usize) -> io::Result<T> {
+        self.fields[index].parse().map_err(|_| {
+            invalid_data(format!(
+                "Invalid value '{}' in column {} on line {}",
+                self.fields[index],
+                index + 1,
+                self.line
+            ))
+        })
+    }
+
+    fn expect_len(&self, len: usize) -> io::Result<()> {
+        if self.fields.len() != len {
+            return Err(invalid_data(format!(
+                "Expected {} columns on line {}, found {}",
+                len,
+                self.line,
+                self.fields.len()
+            )));
+        }
+        Ok(())
+    }
+
+    // Rows are dense and in order
+    fn expect_index(&self, expected: usize) -> io::Result<()> {
+        let index: usize = self.get(0)?;
+        if index != expected {
+            return Err(invalid_data(format!(
+                "Expected index {} on line {}, found {}",
+                expected, self.line, index
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn is_csv(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32<R: Read>(reader: &mut R) -> io::Result<f32> {
+    Ok(f32::from_bits(read_u32(reader)?))
+}
+
+impl GpuPhysicsSystem {
+    // The particles as of the last step, with the emitter states and
+    // parameters to resume from
+    pub fn dump(&self) -> Result<ParticleDump, PhysicsError> {
+        let params = self.params();
+        Ok(ParticleDump {
+            frame: self.frame_count,
+            time: self.elapsed,
+            gravity: params.gravity,
+            bounds: params.bounds,
+            max_velocity: params.max_velocity,
+            particles: self.get_particle_data()?,
+            emitters: self
+                .emitters
+                .iter()
+                .map(|emitter| emitter.state())
+                .collect(),
+        })
+    }
+
+    // Resumes from a dump, growing the buffers if it holds more particles.
+    // Emitter states apply to the emitters added so far, in order, so add the
+    // same emitters as the dumped run first.
+    pub fn restore(&mut self, dump: &ParticleDump) -> Result<(), PhysicsError> {
+        if dump.particles.len() > self.capacity() {
+            self.resize(dump.particles.len())?;
+        }
+        self.set_params(
+            self.params()
+                .with_gravity(dump.gravity)
+                .with_bounds(dump.bounds[0], dump.bounds[1])
+                .with_max_velocity(dump.max_velocity),
+        );
+        for (emitter, state) in self.emitters.iter_mut().zip(&dump.emitters) {
+            emitter.set_state(*state);
+        }
+        self.frame_count = dump.frame;
+        self.elapsed = dump.time;
+        self.update_particles(&dump.particles)
+    }
+}
+
+impl PhysicsWorld {
+    // Adds the alive particles of a dump as spheres of `radius` and returns
+    // their body indices, in particle order. Dead particles are skipped.
+    pub fn load_particles(&mut self, dump: &ParticleDump, radius: f32) -> Vec<usize> {
+        self.gravity = Vec3::from(dump.gravity);
+        dump.particles
+            .iter()
+            .filter(|particle| particle.is_alive())
+            .map(|particle| {
+                self.add_object(PhysicsObject::RigidBody {
+                    position: Vec3::from(particle.position),
+                    velocity: Vec3::from(particle.velocity),
+                    acceleration: Vec3::ZERO,
+                    orientation: Quat::IDENTITY,
+                    angular_velocity: Vec3::ZERO,
+                    angular_acceleration: Vec3::ZERO,
+                    mass: particle.mass,
+                    inertia_tensor: if particle.mass > 0.0 {
+                        sphere_inertia(particle.mass, radius)
+                    } else {
+                        Mat3::ZERO
+                    },
+                    bounding_box: Vec4::new(0.0, 0.0, 0.0, radius),
+                })
+            })
+            .collect()
+    }
+
+    // The rigid bodies as particles, one per body slot so indices line up.
+    // Removed bodies and soft bodies come out dead.
+    pub fn particle_dump(&self) -> ParticleDump {
+        let particles = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(index, object)| match &*object.borrow() {
+                PhysicsObject::RigidBody {
+                    position,
+                    velocity,
+                    mass,
+                    ..
+                } if !self.free_slots.contains(&index) => {
+                    Particle::new((*position).into(), (*velocity).into()).with_mass(*mass)
+                }
+                _ => Particle::default().with_lifetime(0.0),
+            })
+            .collect();
+        ParticleDump {
+            gravity: self.gravity.into(),
+            ..ParticleDump::new(particles)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> ParticleDump {
+        ParticleDump {
+            frame: 42,
+            time: 0.7,
+            gravity: [0.0, -3.5, 0.25],
+            bounds: [-10.0, 12.5],
+            max_velocity: 33.0,
+            particles: vec![
+                Particle::new([1.0, 2.0, 3.0], [-0.1, 0.2, 1e-7]).with_color([0.1, 0.2, 0.3, 0.4]),
+                Particle::new([0.0; 3], [0.0; 3]).with_lifetime(0.0),
+                Particle::new([-5.5, 0.0, 9.0], [1.0; 3])
+                    .with_mass(2.5)
+                    .with_lifetime(1.25),
+            ],
+            emitters: vec![
+                EmitterState {
+                    time: 0.7,
+                    accumulator: 0.3,
+                    pending: 5,
+                    emitted: 1 << 40,
+                },
+                EmitterState::default(),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let dump = sample();
+        let mut bytes = Vec::new();
+        dump.write_binary(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 52 + 3 * 48 + 2 * 20);
+        assert_eq!(ParticleDump::read_binary(bytes.as_slice()).unwrap(), dump);
+
+        // Layout changes invalidate old dumps
+        bytes[8..12].copy_from_slice(&(PARTICLE_LAYOUT_VERSION + 1).to_le_bytes());
+        let error = ParticleDump::read_binary(bytes.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(ParticleDump::read_binary(truncated).is_err());
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let dump = sample();
+        let mut text = Vec::new();
+        dump.write_csv(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("\n0,1,2,3,1,-0.1,0.2,0.0000001,inf,0.1,0.2,0.3,0.4\n"));
+        assert_eq!(ParticleDump::read_csv(text.as_bytes()).unwrap(), dump);
+
+        let shuffled = text.replace("\n2,-5.5", "\n3,-5.5");
+        let error = ParticleDump::read_csv(shuffled.as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("Expected index 2"));
+    }
+
+    #[test]
+    fn test_physics_world_round_trip() {
+        let dump = sample();
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        let bodies = world.load_particles(&dump, 0.5);
+        assert_eq!(bodies, vec![0, 1]);
+        assert_eq!(world.gravity, Vec3::new(0.0, -3.5, 0.25));
+
+        world.remove_object(0);
+        let exported = world.particle_dump();
+        assert_eq!(exported.alive_count(), 1);
+        assert_eq!(exported.particles[1].position, [-5.5, 0.0, 9.0]);
+        assert_eq!(exported.particles[1].mass, 2.5);
+        assert_eq!(exported.gravity, dump.gravity);
+    }
+}
@@ -0,0 +1,426 @@
+// Particle emitters for the GPU particle system. Emitters live on the CPU,
+// which only decides how many particles each one spawns per step; placing
+// and launching them happens in `shaders/particle_emit.comp`, which takes
+// free slots from the free list that `particle_update.comp` refills when a
+// particle dies.
+
+use bytemuck::{Pod, Zeroable};
+use memoffset::offset_of;
+use std::mem::size_of;
+
+use crate::physics::gpu_physics::{GpuPhysicsSystem, PhysicsError};
+use crate::physics::particle_layout::{DrawIndirectArgs, Particle};
+use crate::physics::shaders::BlockLayout;
+use ash::vk;
+
+// Capacity of the emitter storage buffer
+pub(crate) const MAX_EMITTERS: usize = 64;
+
+// Shape ids shared with the emit shader
+const SHAPE_POINT: u32 = 0;
+const SHAPE_SPHERE: u32 = 1;
+const SHAPE_CONE: u32 = 2;
+const SHAPE_BOX: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmitterShape {
+    // Spawns at the emitter position, launched along its direction
+    Point,
+    // Spawns inside the sphere, launched outwards
+    Sphere { radius: f32 },
+    // Spawns on a disk of `radius` facing the emitter direction, launched
+    // within `angle` radians of it
+    Cone { angle: f32, radius: f32 },
+    // Spawns inside the box, launched along the emitter direction
+    Box { half_extents: [f32; 3] },
+}
+
+impl EmitterShape {
+    fn id_and_params(&self) -> (u32, [f32; 4]) {
+        match *self {
+            EmitterShape::Point => (SHAPE_POINT, [0.0; 4]),
+            EmitterShape::Sphere { radius } => (SHAPE_SPHERE, [radius, 0.0, 0.0, 0.0]),
+            EmitterShape::Cone { angle, radius } => (SHAPE_CONE, [angle, radius, 0.0, 0.0]),
+            EmitterShape::Box { half_extents } => {
+                let [x, y, z] = half_extents;
+                (SHAPE_BOX, [x, y, z, 0.0])
+            }
+        }
+    }
+}
+
+// Initial speed is uniform in `[min_speed, max_speed]`. The launch direction
+// given by the shape is jittered uniformly by up to `spread` radians.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VelocityDistribution {
+    pub min_speed: f32,
+    pub max_speed: f32,
+    pub spread: f32,
+}
+
+impl VelocityDistribution {
+    pub fn new(min_speed: f32, max_speed: f32, spread: f32) -> Self {
+        Self {
+            min_speed,
+            max_speed,
+            spread,
+        }
+    }
+
+    pub fn constant(speed: f32) -> Self {
+        Self::new(speed, speed, 0.0)
+    }
+}
+
+impl Default for VelocityDistribution {
+    fn default() -> Self {
+        Self::new(1.0, 2.0, 0.2)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmitterBurst {
+    // Seconds after the emitter started
+    pub time: f32,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParticleEmitter {
+    pub position: [f32; 3],
+    pub direction: [f32; 3],
+    pub shape: EmitterShape,
+    // Particles per second
+    pub rate: f32,
+    pub bursts: Vec<EmitterBurst>,
+    pub velocity: VelocityDistribution,
+    // Lifetime in seconds, uniform in `[min, max]`
+    pub lifetime: [f32; 2],
+    pub mass: f32,
+    pub color: [f32; 4],
+    pub enabled: bool,
+    state: EmitterState,
+}
+
+// Per-emitter progress between steps, saved and restored with the emitter
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EmitterState {
+    pub time: f32,
+    // Fractional particles carried over to the next step
+    pub accumulator: f32,
+    // Particles requested through `ParticleEmitter::burst`
+    pub pending: u32,
+    pub emitted: u64,
+}
+
+impl ParticleEmitter {
+    pub fn new(position: [f32; 3], shape: EmitterShape) -> Self {
+        Self {
+            position,
+            direction: [0.0, 1.0, 0.0],
+            shape,
+            rate: 10.0,
+            bursts: Vec::new(),
+            velocity: VelocityDistribution::default(),
+            lifetime: [1.0, 2.0],
+            mass: 1.0,
+            color: [1.0; 4],
+            enabled: true,
+            state: EmitterState::default(),
+        }
+    }
+
+    pub fn with_direction(mut self, direction: [f32; 3]) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    pub fn with_rate(mut self, rate: f32) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    pub fn with_burst(mut self, time: f32, count: u32) -> Self {
+        self.bursts.push(EmitterBurst { time, count });
+        self
+    }
+
+    pub fn with_velocity(mut self, velocity: VelocityDistribution) -> Self {
+        self.velocity = velocity;
+        self
+    }
+
+    pub fn with_lifetime(mut self, min: f32, max: f32) -> Self {
+        self.lifetime = [min, max];
+        self
+    }
+
+    pub fn with_mass(mut self, mass: f32) -> Self {
+        self.mass = mass;
+        self
+    }
+
+    pub fn with_color(mut self, color: [f32; 4]) -> Self {
+        self.color = color;
+        self
+    }
+
+    // Spawns `count` particles on the next step
+    pub fn burst(&mut self, count: u32) {
+        self.state.pending += count;
+    }
+
+    pub fn state(&self) -> EmitterState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: EmitterState) {
+        self.state = state;
+    }
+
+    // Advances the emitter by `delta_time` and returns how many particles it
+    // spawns during the step
+    pub fn advance(&mut self, delta_time: f32) -> u32 {
+        let start = self.state.time;
+        self.state.time += delta_time;
+        let mut count = std::mem::take(&mut self.state.pending);
+        if !self.enabled {
+            return count;
+        }
+
+        self.state.accumulator += self.rate.max(0.0) * delta_time;
+        let whole = self.state.accumulator.floor();
+        self.state.accumulator -= whole;
+        count += whole as u32;
+
+        for burst in &self.bursts {
+            if burst.time >= start && burst.time < self.state.time {
+                count += burst.count;
+            }
+        }
+        count
+    }
+
+    pub(crate) fn to_gpu(&self, spawn_count: u32, spawn_offset: u32, seed: u32) -> GpuEmitter {
+        let (shape, shape_params) = self.shape.id_and_params();
+        GpuEmitter {
+            position: self.position,
+            shape,
+            direction: self.direction,
+            spawn_count,
+            shape_params,
+            speed: [self.velocity.min_speed, self.velocity.max_speed],
+            lifetime: self.lifetime,
+            color: self.color,
+            spread: self.velocity.spread,
+            mass: self.mass,
+            spawn_offset,
+            seed,
+        }
+    }
+}
+
+// Emitter as laid out in the emitter storage buffer. `spawn_offset` is the
+// exclusive prefix sum of `spawn_count` over the emitters before it, which
+// lets each emit invocation find its emitter.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Pod, Zeroable)]
+pub(crate) struct GpuEmitter {
+    pub position: [f32; 3],
+    pub shape: u32,
+    pub direction: [f32; 3],
+    pub spawn_count: u32,
+    pub shape_params: [f32; 4],
+    pub speed: [f32; 2],
+    pub lifetime: [f32; 2],
+    pub color: [f32; 4],
+    pub spread: f32,
+    pub mass: f32,
+    pub spawn_offset: u32,
+    pub seed: u32,
+}
+
+impl GpuEmitter {
+    pub(crate) fn layout() -> BlockLayout {
+        BlockLayout::new("Emitter", size_of::<Self>())
+            .with_member("position", offset_of!(Self, position), 12)
+            .with_member("shape", offset_of!(Self, shape), 4)
+            .with_member("direction", offset_of!(Self, direction), 12)
+            .with_member("spawn_count", offset_of!(Self, spawn_count), 4)
+            .with_member("shape_params", offset_of!(Self, shape_params), 16)
+            .with_member("speed", offset_of!(Self, speed), 8)
+            .with_member("lifetime", offset_of!(Self, lifetime), 8)
+            .with_member("color", offset_of!(Self, color), 16)
+            .with_member("spread", offset_of!(Self, spread), 4)
+            .with_member("mass", offset_of!(Self, mass), 4)
+            .with_member("spawn_offset", offset_of!(Self, spawn_offset), 4)
+            .with_member("seed", offset_of!(Self, seed), 4)
+    }
+}
+
+impl GpuPhysicsSystem {
+    // Adds an emitter and returns its index
+    pub fn add_emitter(&mut self, emitter: ParticleEmitter) -> Result<usize, PhysicsError> {
+        if self.emitters.len() >= MAX_EMITTERS {
+            return Err(PhysicsError::BufferOverflow {
+                message: "Too many particle emitters".to_string(),
+                required: ((self.emitters.len() + 1) * size_of::<GpuEmitter>()) as u64,
+                available: (MAX_EMITTERS * size_of::<GpuEmitter>()) as u64,
+            });
+        }
+        self.emitters.push(emitter);
+        Ok(self.emitters.len() - 1)
+    }
+
+    pub fn emitter_mut(&mut self, index: usize) -> Option<&mut ParticleEmitter> {
+        self.emitters.get_mut(index)
+    }
+
+    // Removes the emitter at `index`; later emitters shift down by one.
+    // Particles it already spawned live out their lifetime.
+    pub fn remove_emitter(&mut self, index: usize) -> Option<ParticleEmitter> {
+        (index < self.emitters.len()).then(|| self.emitters.remove(index))
+    }
+
+    pub fn emitters(&self) -> &[ParticleEmitter] {
+        &self.emitters
+    }
+
+    // Particles alive after the last step, as counted by the update shader
+    pub fn alive_count(&self) -> u32 {
+        self.lifetime_buffers
+            .as_ref()
+            .map(|buffers| buffers.draw_args.read::<DrawIndirectArgs>(0, 1)[0].instance_count)
+            .unwrap_or(0)
+    }
+
+    // Buffer and offset of the `DrawIndirectArgs` for `cmd_draw_indirect`.
+    // One instance is drawn per alive particle; the vertex shader finds its
+    // particle through `alive_index_buffer` at `gl_InstanceIndex`.
+    pub fn indirect_draw_buffer(&self) -> Option<(vk::Buffer, vk::DeviceSize)> {
+        self.lifetime_buffers
+            .as_ref()
+            .map(|buffers| (buffers.draw_args.buffer, 0))
+    }
+
+    pub fn alive_index_buffer(&self) -> Option<vk::Buffer> {
+        self.lifetime_buffers
+            .as_ref()
+            .map(|buffers| buffers.alive_list.buffer)
+    }
+
+    // Advances the emitters and uploads the ones spawning this step. Returns
+    // the number of uploaded emitters and of particles they spawn.
+    pub(crate) fn upload_emitters(&mut self, delta_time: f32) -> Result<(u32, u32), PhysicsError> {
+        let (packed, spawn_count) =
+            schedule_emitters(&mut self.emitters, delta_time, self.frame_count);
+        if let (Some(buffers), false) = (&self.lifetime_buffers, packed.is_empty()) {
+            buffers.emitters.write(0, &packed)?;
+        }
+        Ok((packed.len() as u32, spawn_count))
+    }
+}
+
+// Advances every emitter and packs the ones spawning this step for upload.
+// Returns the packed emitters and the total number of spawn requests.
+pub(crate) fn schedule_emitters(
+    emitters: &mut [ParticleEmitter],
+    delta_time: f32,
+    frame: u32,
+) -> (Vec<GpuEmitter>, u32) {
+    let mut packed = Vec::new();
+    let mut total = 0;
+    for (index, emitter) in emitters.iter_mut().enumerate() {
+        let count = emitter.advance(delta_time);
+        if count == 0 {
+            continue;
+        }
+        let seed = hash(frame ^ hash(index as u32));
+        packed.push(emitter.to_gpu(count, total, seed));
+        emitter.state.emitted += count as u64;
+        total += count;
+    }
+    (packed, total)
+}
+
+// Indices of dead particles, the initial contents of the free list
+pub(crate) fn free_slots(particles: &[Particle]) -> Vec<u32> {
+    particles
+        .iter()
+        .enumerate()
+        .filter(|(_, particle)| !particle.is_alive())
+        .map(|(index, _)| index as u32)
+        .collect()
+}
+
+// PCG hash, the same one the emit shader seeds its generator with
+pub(crate) fn hash(value: u32) -> u32 {
+    let state = value.wrapping_mul(747_796_405).wrapping_add(2_891_336_453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277_803_737);
+    (word >> 22) ^ word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_and_bursts() {
+        let mut emitter = ParticleEmitter::new([0.0; 3], EmitterShape::Point)
+            .with_rate(30.0)
+            .with_burst(0.04, 100);
+
+        // 30/s at 60 Hz spawns one particle every other step
+        let counts: Vec<u32> = (0..4).map(|_| emitter.advance(1.0 / 60.0)).collect();
+        assert_eq!(counts, vec![0, 1, 100, 1]);
+
+        emitter.burst(5);
+        emitter.enabled = false;
+        assert_eq!(emitter.advance(1.0 / 60.0), 5);
+        assert_eq!(emitter.advance(1.0 / 60.0), 0);
+    }
+
+    #[test]
+    fn test_schedule_packs_spawning_emitters() {
+        let mut emitters = vec![
+            ParticleEmitter::new([0.0; 3], EmitterShape::Sphere { radius: 1.0 }).with_rate(0.0),
+            ParticleEmitter::new(
+                [1.0; 3],
+                EmitterShape::Cone {
+                    angle: 0.3,
+                    radius: 0.1,
+                },
+            )
+            .with_rate(120.0),
+            ParticleEmitter::new(
+                [2.0; 3],
+                EmitterShape::Box {
+                    half_extents: [1.0, 2.0, 3.0],
+                },
+            )
+            .with_rate(60.0),
+        ];
+
+        let (packed, total) = schedule_emitters(&mut emitters, 1.0 / 60.0, 7);
+        assert_eq!(total, 3);
+        assert_eq!(packed.len(), 2);
+        assert_eq!((packed[0].shape, packed[0].spawn_offset), (SHAPE_CONE, 0));
+        assert_eq!((packed[1].shape, packed[1].spawn_offset), (SHAPE_BOX, 2));
+        assert_eq!(packed[1].shape_params, [1.0, 2.0, 3.0, 0.0]);
+        assert_ne!(packed[0].seed, packed[1].seed);
+        assert_eq!(emitters[1].state().emitted, 2);
+
+        assert_eq!(size_of::<GpuEmitter>(), 96);
+    }
+
+    #[test]
+    fn test_free_slots() {
+        let particles = [
+            Particle::default(),
+            Particle::default().with_lifetime(0.0),
+            Particle::default().with_lifetime(0.5),
+            Particle::default().with_lifetime(-1.0),
+        ];
+        assert_eq!(free_slots(&particles), vec![1, 3]);
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::physics::{collision::CollisionManifold, filter::ordered_pair};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventPhase {
+    Begin,
+    Stay,
+    End,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContactEvent {
+    pub body_a: usize,
+    pub body_b: usize,
+    pub phase: EventPhase,
+    // Latest manifold for the pair, `None` once the bodies have separated
+    pub manifold: Option<CollisionManifold>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TriggerEvent {
+    pub sensor: usize,
+    pub other: usize,
+    pub phase: EventPhase,
+}
+
+// Contact and trigger events produced by the last `PhysicsWorld::update`.
+// Overlaps are accumulated over all substeps of a step and compared against
+// the previous step to derive begin/stay/end phases.
+#[derive(Debug, Default)]
+pub struct CollisionEvents {
+    contacts: Vec<ContactEvent>,
+    triggers: Vec<TriggerEvent>,
+    step_contacts: HashMap<(usize, usize), CollisionManifold>,
+    step_triggers: HashSet<(usize, usize)>,
+    active_contacts: HashSet<(usize, usize)>,
+    active_triggers: HashSet<(usize, usize)>,
+}
+
+impl CollisionEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contacts(&self) -> &[ContactEvent] {
+        &self.contacts
+    }
+
+    pub fn triggers(&self) -> &[TriggerEvent] {
+        &self.triggers
+    }
+
+    pub fn contacts_for(&self, body: usize) -> impl Iterator<Item = &ContactEvent> {
+        self.contacts
+            .iter()
+            .filter(move |e| e.body_a == body || e.body_b == body)
+    }
+
+    pub fn triggers_for(&self, body: usize) -> impl Iterator<Item = &TriggerEvent> {
+        self.triggers
+            .iter()
+            .filter(move |e| e.sensor == body || e.other == body)
+    }
+
+    pub fn is_touching(&self, a: usize, b: usize) -> bool {
+        self.active_contacts.contains(&ordered_pair(a, b))
+    }
+
+    // Forget everything involving a removed body, so a body added into the
+    // same slot doesn't inherit its contacts and get stale Stay/End events
+    pub(crate) fn remove_body(&mut self, body: usize) {
+        let involves = |&(a, b): &(usize, usize)| a == body || b == body;
+        self.contacts
+            .retain(|e| e.body_a != body && e.body_b != body);
+        self.triggers
+            .retain(|e| e.sensor != body && e.other != body);
+        self.step_contacts.retain(|pair, _| !involves(pair));
+        self.step_triggers.retain(|pair| !involves(pair));
+        self.active_contacts.retain(|pair| !involves(pair));
+        self.active_triggers.retain(|pair| !involves(pair));
+    }
+
+    pub(crate) fn begin_step(&mut self) {
+        self.contacts.clear();
+        self.triggers.clear();
+        self.step_contacts.clear();
+        self.step_triggers.clear();
+    }
+
+    pub(crate) fn record_contact(&mut self, a: usize, b: usize, manifold: &CollisionManifold) {
+        self.step_contacts
+            .insert(ordered_pair(a, b), manifold.clone());
+    }
+
+    pub(crate) fn record_trigger(&mut self, sensor: usize, other: usize) {
+        self.step_triggers.insert((sensor, other));
+    }
+
+    pub(crate) fn end_step(&mut self) {
+        let mut contact_pairs: Vec<_> = self.step_contacts.keys().copied().collect();
+        contact_pairs.sort_unstable();
+        for pair in contact_pairs {
+            let phase = if self.active_contacts.contains(&pair) {
+                EventPhase::Stay
+            } else {
+                EventPhase::Begin
+            };
+            self.contacts.push(ContactEvent {
+                body_a: pair.0,
+                body_b: pair.1,
+                phase,
+                manifold: self.step_contacts.get(&pair).cloned(),
+            });
+        }
+
+        let mut ended: Vec<_> = self
+            .active_contacts
+            .iter()
+            .filter(|pair| !self.step_contacts.contains_key(pair))
+            .copied()
+            .collect();
+        ended.sort_unstable();
+        self.contacts
+            .extend(ended.into_iter().map(|(body_a, body_b)| ContactEvent {
+                body_a,
+                body_b,
+                phase: EventPhase::End,
+                manifold: None,
+            }));
+
+        let mut trigger_pairs: Vec<_> = self.step_triggers.iter().copied().collect();
+        trigger_pairs.sort_unstable();
+        for (sensor, other) in trigger_pairs {
+            let phase = if self.active_triggers.contains(&(sensor, other)) {
+                EventPhase::Stay
+            } else {
+                EventPhase::Begin
+            };
+            self.triggers.push(TriggerEvent {
+                sensor,
+                other,
+                phase,
+            });
+        }
+
+        let mut ended: Vec<_> = self
+            .active_triggers
+            .difference(&self.step_triggers)
+            .copied()
+            .collect();
+        ended.sort_unstable();
+        self.triggers
+            .extend(ended.into_iter().map(|(sensor, other)| TriggerEvent {
+                sensor,
+                other,
+                phase: EventPhase::End,
+            }));
+
+        self.active_contacts = self.step_contacts.keys().copied().collect();
+        self.active_triggers = std::mem::take(&mut self.step_triggers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::physics::{PhysicsObject, PhysicsWorld};
+    use glam::{Mat3, Quat, Vec3, Vec4};
+
+    fn manifold() -> CollisionManifold {
+        CollisionManifold {
+            normal: Vec3::Y,
+            penetration: 0.1,
+            contact_points: vec![Vec3::ZERO],
+        }
+    }
+
+    #[test]
+    fn test_contact_phases() {
+        let mut events = CollisionEvents::new();
+
+        events.begin_step();
+        events.record_contact(3, 1, &manifold());
+        events.end_step();
+        assert_eq!(events.contacts().len(), 1);
+        assert_eq!(events.contacts()[0].phase, EventPhase::Begin);
+        assert_eq!(
+            (events.contacts()[0].body_a, events.contacts()[0].body_b),
+            (1, 3)
+        );
+        assert!(events.is_touching(3, 1));
+
+        events.begin_step();
+        events.record_contact(1, 3, &manifold());
+        events.end_step();
+        assert_eq!(events.contacts()[0].phase, EventPhase::Stay);
+
+        events.begin_step();
+        events.end_step();
+        assert_eq!(events.contacts()[0].phase, EventPhase::End);
+        assert!(events.contacts()[0].manifold.is_none());
+        assert!(!events.is_touching(1, 3));
+
+        events.begin_step();
+        events.end_step();
+        assert!(events.contacts().is_empty());
+    }
+
+    #[test]
+    fn test_trigger_phases() {
+        let mut events = CollisionEvents::new();
+
+        events.begin_step();
+        events.record_trigger(5, 2);
+        events.record_trigger(5, 2); // reported again by a later substep
+        events.end_step();
+        assert_eq!(
+            events.triggers(),
+            &[TriggerEvent {
+                sensor: 5,
+                other: 2,
+                phase: EventPhase::Begin
+            }]
+        );
+
+        events.begin_step();
+        events.record_trigger(5, 2);
+        events.end_step();
+        assert_eq!(events.triggers()[0].phase, EventPhase::Stay);
+        assert_eq!(events.triggers_for(2).count(), 1);
+
+        events.begin_step();
+        events.end_step();
+        assert_eq!(events.triggers()[0].phase, EventPhase::End);
+    }
+
+    fn cube(position: Vec3, velocity: Vec3) -> PhysicsObject {
+        PhysicsObject::RigidBody {
+            position,
+            velocity,
+            acceleration: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            angular_velocity: Vec3::ZERO,
+            angular_acceleration: Vec3::ZERO,
+            mass: 1.0,
+            inertia_tensor: Mat3::IDENTITY,
+            bounding_box: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn test_contact_phases_through_update() {
+        let mut world = PhysicsWorld::new(Vec3::new(0.0, -9.81, 0.0));
+        // Static floor with its top face at y = 0 and a box resting on it
+        let floor = world.add_object(PhysicsObject::RigidBody {
+            position: Vec3::new(0.0, -10.0, 0.0),
+            velocity: Vec3::ZERO,
+            acceleration: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            angular_velocity: Vec3::ZERO,
+            angular_acceleration: Vec3::ZERO,
+            mass: 0.0,
+            inertia_tensor: Mat3::ZERO,
+            bounding_box: Vec4::new(0.0, 0.0, 0.0, 10.0),
+        });
+        let body = world.add_object(cube(Vec3::new(0.0, 1.0, 0.0), Vec3::ZERO));
+
+        let mut phases = Vec::new();
+        for step in 0..90 {
+            // Launch the box off the floor after a second of resting
+            if step == 60 {
+                if let PhysicsObject::RigidBody { velocity, .. } =
+                    &mut *world.objects[body].borrow_mut()
+                {
+                    *velocity = Vec3::new(0.0, 5.0, 0.0);
+                }
+            }
+            world.update(1.0 / 60.0);
+            phases.extend(world.events().contacts_for(body).map(|e| {
+                assert_eq!((e.body_a, e.body_b), (floor, body));
+                e.phase
+            }));
+        }
+
+        // One begin, a stay for every resting step and an end on launch
+        assert_eq!(phases.len(), 61, "{:?}", phases);
+        assert_eq!(phases[0], EventPhase::Begin);
+        assert!(phases[1..60].iter().all(|&phase| phase == EventPhase::Stay));
+        assert_eq!(phases[60], EventPhase::End);
+        assert!(!world.events().is_touching(floor, body));
+    }
+
+    #[test]
+    fn test_reused_slot_has_no_stale_events() {
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        let a = world.add_object(cube(Vec3::ZERO, Vec3::ZERO));
+        let b = world.add_object(cube(Vec3::new(1.5, 0.0, 0.0), Vec3::ZERO));
+
+        world.update(1.0 / 60.0);
+        assert!(world.events().is_touching(a, b));
+
+        // Remove b while it's touching and put a far away body into its slot
+        world.remove_object(b);
+        let c = world.add_object(cube(Vec3::new(10.0, 0.0, 0.0), Vec3::ZERO));
+        assert_eq!(c, b);
+        assert!(!world.events().is_touching(a, c));
+        assert_eq!(world.events().contacts_for(c).count(), 0);
+
+        world.update(1.0 / 60.0);
+        assert_eq!(world.events().contacts_for(c).count(), 0);
+        assert_eq!(world.events().contacts_for(a).count(), 0);
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+
+pub const ALL_GROUPS: u32 = u32::MAX;
+
+// Per-body collision layer. A body belongs to the groups set in `group` and
+// only interacts with bodies whose group intersects its `mask`. Sensors
+// report overlaps as trigger events but never get a physical response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollisionFilter {
+    pub group: u32,
+    pub mask: u32,
+    pub is_sensor: bool,
+}
+
+impl CollisionFilter {
+    pub fn new(group: u32, mask: u32) -> Self {
+        Self {
+            group,
+            mask,
+            is_sensor: false,
+        }
+    }
+
+    pub fn sensor(group: u32, mask: u32) -> Self {
+        Self {
+            group,
+            mask,
+            is_sensor: true,
+        }
+    }
+
+    pub fn can_collide(&self, other: &CollisionFilter) -> bool {
+        (self.group & other.mask) != 0 && (other.group & self.mask) != 0
+    }
+}
+
+impl Default for CollisionFilter {
+    fn default() -> Self {
+        Self {
+            group: 1,
+            mask: ALL_GROUPS,
+            is_sensor: false,
+        }
+    }
+}
+
+// Body pairs that never collide regardless of their filters, e.g. bodies
+// joined by a constraint
+#[derive(Debug, Clone, Default)]
+pub struct IgnoredPairs {
+    pairs: HashSet<(usize, usize)>,
+}
+
+impl IgnoredPairs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, a: usize, b: usize) -> bool {
+        self.pairs.insert(ordered_pair(a, b))
+    }
+
+    pub fn remove(&mut self, a: usize, b: usize) -> bool {
+        self.pairs.remove(&ordered_pair(a, b))
+    }
+
+    pub fn contains(&self, a: usize, b: usize) -> bool {
+        self.pairs.contains(&ordered_pair(a, b))
+    }
+
+    // Forgets every pair involving `body`
+    pub fn remove_body(&mut self, body: usize) {
+        self.pairs.retain(|&(a, b)| a != body && b != body);
+    }
+
+    pub fn clear(&mut self) {
+        self.pairs.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+// Broad-phase pair test combining layer masks and the ignore list
+pub fn pair_allowed(
+    filters: &[CollisionFilter],
+    ignored: &IgnoredPairs,
+    a: usize,
+    b: usize,
+) -> bool {
+    let filter_a = filters.get(a).copied().unwrap_or_default();
+    let filter_b = filters.get(b).copied().unwrap_or_default();
+
+    // Two sensors have nothing to report to each other
+    if filter_a.is_sensor && filter_b.is_sensor {
+        return false;
+    }
+
+    filter_a.can_collide(&filter_b) && !ignored.contains(a, b)
+}
+
+pub(crate) fn ordered_pair(a: usize, b: usize) -> (usize, usize) {
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
@@ -0,0 +1,452 @@
+// Force fields acting on GPU particles. The CPU keeps the list and uploads it
+// every step; `shaders/particle_update.comp` evaluates every field for every
+// particle. The math below mirrors the shader so it can be tested without a
+// device, keep the two in sync.
+
+use bytemuck::{Pod, Zeroable};
+use glam::Vec3;
+use memoffset::offset_of;
+use std::mem::size_of;
+
+use crate::physics::emitter::hash;
+use crate::physics::gpu_physics::{GpuPhysicsSystem, PhysicsError};
+use crate::physics::shaders::BlockLayout;
+
+// Capacity of the force field storage buffer
+pub(crate) const MAX_FORCE_FIELDS: usize = 32;
+
+// Kind ids shared with the update shader
+const KIND_ATTRACTOR: u32 = 0;
+const KIND_VORTEX: u32 = 1;
+const KIND_DRAG: u32 = 2;
+const KIND_TURBULENCE: u32 = 3;
+const KIND_PLANE: u32 = 4;
+const KIND_SPHERE: u32 = 5;
+
+// Falloff ids shared with the update shader
+const FALLOFF_NONE: u32 = 0;
+const FALLOFF_LINEAR: u32 = 1;
+const FALLOFF_SMOOTH: u32 = 2;
+const FALLOFF_INVERSE_SQUARE: u32 = 3;
+
+// Strengths are forces, so heavier particles respond less. Colliders are not
+// forces and ignore the falloff.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ForceFieldKind {
+    // Pulls particles towards the field position
+    Attractor {
+        strength: f32,
+    },
+    // Pushes particles away from the field position
+    Repulsor {
+        strength: f32,
+    },
+    // Spins particles around `axis` through the field position, following
+    // the right-hand rule
+    Vortex {
+        axis: [f32; 3],
+        strength: f32,
+    },
+    // Opposes velocity, proportional to speed
+    Drag {
+        coefficient: f32,
+    },
+    // Curl noise, which swirls particles without bunching them up. The noise
+    // scrolls through space at `speed` units per second.
+    Turbulence {
+        strength: f32,
+        frequency: f32,
+        speed: f32,
+    },
+    // Keeps particles on the side of the plane `normal` points to, the plane
+    // going through the field position. `restitution` is the fraction of
+    // normal speed kept on a bounce.
+    Plane {
+        normal: [f32; 3],
+        restitution: f32,
+    },
+    // Keeps particles out of the sphere around the field position
+    Sphere {
+        radius: f32,
+        restitution: f32,
+    },
+}
+
+// How a field weakens with distance from its position. Vortices measure the
+// distance from their axis instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Falloff {
+    // Full strength everywhere
+    None,
+    // Fades linearly to zero at `radius`
+    Linear { radius: f32 },
+    // Fades to zero at `radius` along a smoothstep curve
+    Smooth { radius: f32 },
+    // Full strength within `radius`, inverse square beyond it
+    InverseSquare { radius: f32 },
+}
+
+impl Falloff {
+    fn id_and_radius(&self) -> (u32, f32) {
+        match *self {
+            Falloff::None => (FALLOFF_NONE, 0.0),
+            Falloff::Linear { radius } => (FALLOFF_LINEAR, radius),
+            Falloff::Smooth { radius } => (FALLOFF_SMOOTH, radius),
+            Falloff::InverseSquare { radius } => (FALLOFF_INVERSE_SQUARE, radius),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ForceField {
+    pub kind: ForceFieldKind,
+    pub position: [f32; 3],
+    pub falloff: Falloff,
+    pub enabled: bool,
+}
+
+impl ForceField {
+    pub fn new(kind: ForceFieldKind, position: [f32; 3]) -> Self {
+        Self {
+            kind,
+            position,
+            falloff: Falloff::None,
+            enabled: true,
+        }
+    }
+
+    pub fn with_falloff(mut self, falloff: Falloff) -> Self {
+        self.falloff = falloff;
+        self
+    }
+
+    pub(crate) fn to_gpu(self) -> GpuForceField {
+        let (kind, axis, strength, params) = match self.kind {
+            ForceFieldKind::Attractor { strength } => {
+                (KIND_ATTRACTOR, [0.0; 3], strength, [0.0; 2])
+            }
+            ForceFieldKind::Repulsor { strength } => {
+                (KIND_ATTRACTOR, [0.0; 3], -strength, [0.0; 2])
+            }
+            ForceFieldKind::Vortex { axis, strength } => (
+                KIND_VORTEX,
+                Vec3::from(axis).normalize_or_zero().to_array(),
+                strength,
+                [0.0; 2],
+            ),
+            ForceFieldKind::Drag { coefficient } => (KIND_DRAG, [0.0; 3], coefficient, [0.0; 2]),
+            ForceFieldKind::Turbulence {
+                strength,
+                frequency,
+                speed,
+            } => (KIND_TURBULENCE, [0.0; 3], strength, [frequency, speed]),
+            ForceFieldKind::Plane {
+                normal,
+                restitution,
+            } => (
+                KIND_PLANE,
+                Vec3::from(normal).normalize_or_zero().to_array(),
+                0.0,
+                [0.0, restitution],
+            ),
+            ForceFieldKind::Sphere {
+                radius,
+                restitution,
+            } => (KIND_SPHERE, [0.0; 3], 0.0, [radius, restitution]),
+        };
+        let (falloff, falloff_radius) = self.falloff.id_and_radius();
+        GpuForceField {
+            position: self.position,
+            kind,
+            axis,
+            falloff,
+            strength,
+            falloff_radius,
+            params,
+        }
+    }
+}
+
+// Force field as laid out in the force field storage buffer
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Pod, Zeroable)]
+pub(crate) struct GpuForceField {
+    pub position: [f32; 3],
+    pub kind: u32,
+    // Vortex axis or plane normal, normalized
+    pub axis: [f32; 3],
+    pub falloff: u32,
+    pub strength: f32,
+    pub falloff_radius: f32,
+    // Turbulence: frequency and speed. Sphere: radius and restitution.
+    // Plane: unused and restitution.
+    pub params: [f32; 2],
+}
+
+impl GpuForceField {
+    pub(crate) fn layout() -> BlockLayout {
+        BlockLayout::new("ForceField", size_of::<Self>())
+            .with_member("position", offset_of!(Self, position), 12)
+            .with_member("kind", offset_of!(Self, kind), 4)
+            .with_member("axis", offset_of!(Self, axis), 12)
+            .with_member("falloff", offset_of!(Self, falloff), 4)
+            .with_member("strength", offset_of!(Self, strength), 4)
+            .with_member("falloff_radius", offset_of!(Self, falloff_radius), 4)
+            .with_member("params", offset_of!(Self, params), 8)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_collider(&self) -> bool {
+        self.kind == KIND_PLANE || self.kind == KIND_SPHERE
+    }
+
+    fn weight(&self, distance: f32) -> f32 {
+        let radius = self.falloff_radius.max(1e-6);
+        match self.falloff {
+            FALLOFF_LINEAR => (1.0 - distance / radius).max(0.0),
+            FALLOFF_SMOOTH => {
+                let t = (distance / radius).clamp(0.0, 1.0);
+                1.0 - t * t * (3.0 - 2.0 * t)
+            }
+            FALLOFF_INVERSE_SQUARE => (radius / distance.max(radius)).powi(2),
+            _ => 1.0,
+        }
+    }
+
+    // Force on a particle at `position` moving at `velocity`, `time` seconds
+    // into the simulation
+    pub(crate) fn force(&self, position: Vec3, velocity: Vec3, time: f32) -> Vec3 {
+        let center = Vec3::from(self.position);
+        let offset = position - center;
+        match self.kind {
+            KIND_ATTRACTOR => {
+                let distance = offset.length();
+                if distance < 1e-4 {
+                    return Vec3::ZERO;
+                }
+                -offset / distance * self.strength * self.weight(distance)
+            }
+            KIND_VORTEX => {
+                let axis = Vec3::from(self.axis);
+                let radial = offset - axis * offset.dot(axis);
+                let distance = radial.length();
+                if distance < 1e-4 {
+                    return Vec3::ZERO;
+                }
+                axis.cross(radial) / distance * self.strength * self.weight(distance)
+            }
+            KIND_DRAG => -velocity * self.strength * self.weight(offset.length()),
+            KIND_TURBULENCE => {
+                let [frequency, speed] = self.params;
+                let sample = position * frequency + Vec3::splat(time * speed);
+                curl_noise(sample) * self.strength * self.weight(offset.length())
+            }
+            _ => Vec3::ZERO,
+        }
+    }
+
+    // Pushes a particle out of a collider and bounces its velocity
+    pub(crate) fn collide(&self, position: &mut Vec3, velocity: &mut Vec3) {
+        let center = Vec3::from(self.position);
+        let [radius, restitution] = self.params;
+        let normal = match self.kind {
+            KIND_PLANE => {
+                let normal = Vec3::from(self.axis);
+                let depth = (*position - center).dot(normal);
+                if depth >= 0.0 {
+                    return;
+                }
+                *position -= normal * depth;
+                normal
+            }
+            KIND_SPHERE => {
+                let offset = *position - center;
+                let distance = offset.length();
+                if distance >= radius {
+                    return;
+                }
+                let normal = if distance > 1e-6 {
+                    offset / distance
+                } else {
+                    Vec3::Y
+                };
+                *position = center + normal * radius;
+                normal
+            }
+            _ => return,
+        };
+        let normal_speed = velocity.dot(normal);
+        if normal_speed < 0.0 {
+            *velocity -= normal * normal_speed * (1.0 + restitution);
+        }
+    }
+}
+
+// Value noise in [-1, 1] on the integer lattice, hashed like the shader does
+fn lattice(x: i32, y: i32, z: i32) -> f32 {
+    let h = hash(x as u32 ^ hash(y as u32 ^ hash(z as u32)));
+    h as f32 / u32::MAX as f32 * 2.0 - 1.0
+}
+
+fn value_noise(p: Vec3) -> f32 {
+    let cell = p.floor();
+    let t = p - cell;
+    let t = t * t * (Vec3::splat(3.0) - 2.0 * t);
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+
+    let mix = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let x00 = mix(lattice(x, y, z), lattice(x + 1, y, z), t.x);
+    let x10 = mix(lattice(x, y + 1, z), lattice(x + 1, y + 1, z), t.x);
+    let x01 = mix(lattice(x, y, z + 1), lattice(x + 1, y, z + 1), t.x);
+    let x11 = mix(lattice(x, y + 1, z + 1), lattice(x + 1, y + 1, z + 1), t.x);
+    mix(mix(x00, x10, t.y), mix(x01, x11, t.y), t.z)
+}
+
+// Vector potential whose curl gives the turbulence. The components sample
+// the noise far apart so they are uncorrelated.
+fn potential(p: Vec3) -> Vec3 {
+    Vec3::new(
+        value_noise(p),
+        value_noise(p + Vec3::new(31.4, 47.2, 12.9)),
+        value_noise(p + Vec3::new(-19.1, 27.7, 71.3)),
+    )
+}
+
+// Curl of the potential by central differences, divergence free by
+// construction
+pub(crate) fn curl_noise(p: Vec3) -> Vec3 {
+    const EPSILON: f32 = 0.01;
+    let dx = Vec3::new(EPSILON, 0.0, 0.0);
+    let dy = Vec3::new(0.0, EPSILON, 0.0);
+    let dz = Vec3::new(0.0, 0.0, EPSILON);
+    let ddx = potential(p + dx) - potential(p - dx);
+    let ddy = potential(p + dy) - potential(p - dy);
+    let ddz = potential(p + dz) - potential(p - dz);
+    Vec3::new(ddy.z - ddz.y, ddz.x - ddx.z, ddx.y - ddy.x) / (2.0 * EPSILON)
+}
+
+impl GpuPhysicsSystem {
+    // Replaces the force fields, taking effect on the next step
+    pub fn set_force_fields(&mut self, fields: &[ForceField]) -> Result<(), PhysicsError> {
+        if fields.len() > MAX_FORCE_FIELDS {
+            return Err(PhysicsError::BufferOverflow {
+                message: "Too many force fields".to_string(),
+                required: (fields.len() * size_of::<GpuForceField>()) as u64,
+                available: (MAX_FORCE_FIELDS * size_of::<GpuForceField>()) as u64,
+            });
+        }
+        self.force_fields = fields.to_vec();
+        Ok(())
+    }
+
+    pub fn force_fields(&self) -> &[ForceField] {
+        &self.force_fields
+    }
+
+    // Uploads the enabled force fields and returns how many there are
+    pub(crate) fn upload_force_fields(&mut self) -> Result<u32, PhysicsError> {
+        let packed: Vec<GpuForceField> = self
+            .force_fields
+            .iter()
+            .filter(|field| field.enabled)
+            .map(|field| field.to_gpu())
+            .collect();
+        if let (Some(buffer), false) = (&self.field_buffer, packed.is_empty()) {
+            buffer.write(0, &packed)?;
+        }
+        Ok(packed.len() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approx(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-4
+    }
+
+    #[test]
+    fn test_attractor_and_falloff() {
+        let attractor = ForceField::new(ForceFieldKind::Attractor { strength: 4.0 }, [0.0; 3])
+            .with_falloff(Falloff::Linear { radius: 10.0 })
+            .to_gpu();
+        let force = attractor.force(Vec3::new(5.0, 0.0, 0.0), Vec3::ZERO, 0.0);
+        assert!(approx(force, Vec3::new(-2.0, 0.0, 0.0)));
+        assert_eq!(
+            attractor.force(Vec3::new(0.0, 12.0, 0.0), Vec3::ZERO, 0.0),
+            Vec3::ZERO
+        );
+
+        let repulsor = ForceField::new(ForceFieldKind::Repulsor { strength: 4.0 }, [0.0; 3])
+            .with_falloff(Falloff::InverseSquare { radius: 1.0 })
+            .to_gpu();
+        let force = repulsor.force(Vec3::new(0.0, 2.0, 0.0), Vec3::ZERO, 0.0);
+        assert!(approx(force, Vec3::new(0.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn test_vortex_and_drag() {
+        let vortex = ForceField::new(
+            ForceFieldKind::Vortex {
+                axis: [0.0, 2.0, 0.0],
+                strength: 3.0,
+            },
+            [0.0; 3],
+        )
+        .to_gpu();
+        // Height along the axis doesn't matter
+        let force = vortex.force(Vec3::new(1.0, 5.0, 0.0), Vec3::ZERO, 0.0);
+        assert!(approx(force, Vec3::new(0.0, 0.0, -3.0)));
+
+        let drag = ForceField::new(ForceFieldKind::Drag { coefficient: 0.5 }, [0.0; 3]).to_gpu();
+        let force = drag.force(Vec3::ZERO, Vec3::new(2.0, -4.0, 0.0), 0.0);
+        assert!(approx(force, Vec3::new(-1.0, 2.0, 0.0)));
+    }
+
+    #[test]
+    fn test_curl_noise_is_divergence_free() {
+        let h = 1e-2;
+        for p in [Vec3::new(0.3, -1.7, 2.2), Vec3::new(-0.9, 0.4, 0.7)] {
+            let divergence: f32 = [Vec3::X, Vec3::Y, Vec3::Z]
+                .iter()
+                .map(|&axis| (curl_noise(p + axis * h) - curl_noise(p - axis * h)).dot(axis))
+                .sum::<f32>()
+                / (2.0 * h);
+            assert!(curl_noise(p).length() > 0.1);
+            assert!(divergence.abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_colliders_bounce() {
+        let floor = ForceField::new(
+            ForceFieldKind::Plane {
+                normal: [0.0, 1.0, 0.0],
+                restitution: 0.5,
+            },
+            [0.0; 3],
+        )
+        .to_gpu();
+        assert!(floor.is_collider());
+        let mut position = Vec3::new(1.0, -0.2, 0.0);
+        let mut velocity = Vec3::new(1.0, -4.0, 0.0);
+        floor.collide(&mut position, &mut velocity);
+        assert!(approx(position, Vec3::new(1.0, 0.0, 0.0)));
+        assert!(approx(velocity, Vec3::new(1.0, 2.0, 0.0)));
+
+        let ball = ForceField::new(
+            ForceFieldKind::Sphere {
+                radius: 2.0,
+                restitution: 1.0,
+            },
+            [0.0; 3],
+        )
+        .to_gpu();
+        let mut position = Vec3::new(1.0, 0.0, 0.0);
+        let mut velocity = Vec3::new(-3.0, 0.0, 0.0);
+        ball.collide(&mut position, &mut velocity);
+        assert!(approx(position, Vec3::new(2.0, 0.0, 0.0)));
+        assert!(approx(velocity, Vec3::new(3.0, 0.0, 0.0)));
+    }
+}
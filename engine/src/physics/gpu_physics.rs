@@ -1,12 +1,22 @@
-use crate::physics::memory::{BufferPool, MemoryStats};
-use crate::physics::shaders::{compile_shader, ShaderModule};
+use crate::memory::{AllocatorConfig, MemoryAllocator, MemoryError, MemoryStats};
+use crate::physics::backend::ParticleBackend;
+use crate::physics::emitter::{free_slots, GpuEmitter, ParticleEmitter, MAX_EMITTERS};
+use crate::physics::force_field::{ForceField, GpuForceField, MAX_FORCE_FIELDS};
+use crate::physics::memory::BufferPool;
+use crate::physics::particle_grid::{GridBuffers, GridPipelines, NeighborPass, SphSettings};
+use crate::physics::particle_layout::{
+    validate_shader_layout, DrawIndirectArgs, Particle, PushConstants, FREE_LIST_HEADER,
+    PARTICLE_BINDINGS, PARTICLE_DRAW_VERTICES,
+};
+use crate::physics::recovery::{PhysicsHealth, Recovery};
+use crate::physics::shaders::{compile_shader, include_options, ShaderModule};
+use crate::physics::timestamps::{GpuTimestamps, PassTiming};
 use ash::{self, vk};
+use bytemuck::Pod;
 use std::ptr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-pub use crate::physics::debug::{DebugStats, DebugVisualization};
-
 use crate::physics::logging::{error_with_context, log_error_chain};
 use std::error::Error;
 
@@ -45,9 +55,11 @@ pub enum PhysicsError {
 impl std::error::Error for PhysicsError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            Self::DeviceLost { source, .. } => source.as_ref().map(|e| e.as_ref()),
-            Self::InitializationFailed { source, .. } => source.as_ref().map(|e| e.as_ref()),
-            Self::SynchronizationError { source, .. } => source.as_ref().map(|e| e.as_ref()),
+            Self::DeviceLost { source, .. }
+            | Self::InitializationFailed { source, .. }
+            | Self::SynchronizationError { source, .. } => {
+                source.as_deref().map(|e| e as &(dyn Error + 'static))
+            }
             _ => None,
         }
     }
@@ -105,6 +117,15 @@ impl std::fmt::Display for PhysicsError {
 }
 
 impl PhysicsError {
+    // Errors that leave the GPU state unknown, which `step` recovers from
+    pub fn is_device_error(&self) -> bool {
+        matches!(
+            self,
+            Self::DeviceLost { .. } | Self::SynchronizationError { .. }
+        )
+    }
+
+    #[allow(dead_code)]
     pub(crate) fn log_error(&self, file: &'static str, line: u32) {
         let context = match self {
             Self::DeviceLost { .. } => "DEVICE_LOST",
@@ -119,22 +140,107 @@ impl PhysicsError {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct Particle {
-    pub position: [f32; 4], // Using vec4 for GPU alignment
-    pub velocity: [f32; 4], // Using vec4 for GPU alignment
+impl From<MemoryError> for PhysicsError {
+    fn from(e: MemoryError) -> Self {
+        match e {
+            MemoryError::OutOfMemory {
+                requested,
+                available,
+            } => PhysicsError::OutOfMemory {
+                message: "Shared memory allocator is out of memory".to_string(),
+                size: requested,
+                available,
+            },
+            e => PhysicsError::InitializationFailed {
+                message: e.to_string(),
+                component: "MemoryAllocator".to_string(),
+                source: Some(Box::new(e)),
+            },
+        }
+    }
+}
+
+// Host-visible buffer that stays mapped for its whole life
+pub(crate) struct HostBuffer {
+    pub(crate) buffer: vk::Buffer,
+    pub(crate) size: vk::DeviceSize,
+    mapped: *mut std::ffi::c_void,
+}
+
+// The mapping belongs to the buffer and is only touched through it
+unsafe impl Send for HostBuffer {}
+
+impl HostBuffer {
+    pub(crate) fn write<T: Pod>(&self, byte_offset: usize, data: &[T]) -> Result<(), PhysicsError> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        let required = (byte_offset + bytes.len()) as u64;
+        if required > self.size {
+            return Err(PhysicsError::BufferOverflow {
+                message: "Write past the end of a mapped buffer".to_string(),
+                required,
+                available: self.size,
+            });
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                (self.mapped as *mut u8).add(byte_offset),
+                bytes.len(),
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read<T: Pod>(&self, byte_offset: usize, count: usize) -> Vec<T> {
+        let mut data = vec![T::zeroed(); count];
+        let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut data);
+        let available = (self.size as usize).saturating_sub(byte_offset);
+        let len = bytes.len().min(available);
+        unsafe {
+            ptr::copy_nonoverlapping(
+                (self.mapped as *const u8).add(byte_offset),
+                bytes.as_mut_ptr(),
+                len,
+            );
+        }
+        data
+    }
 }
 
 struct ParticleBufferPair {
-    front: (vk::Buffer, vk::DeviceMemory, vk::DeviceSize),
-    back: (vk::Buffer, vk::DeviceMemory, vk::DeviceSize),
-    mapped_front: *mut std::ffi::c_void,
-    mapped_back: *mut std::ffi::c_void,
+    front: HostBuffer,
+    back: HostBuffer,
+}
+
+impl ParticleBufferPair {
+    // The buffer the next step reads. Steps ping-pong between the two, so the
+    // output of one step is the input of the next.
+    fn input(&self, frame: usize) -> &HostBuffer {
+        if frame.is_multiple_of(2) {
+            &self.front
+        } else {
+            &self.back
+        }
+    }
+
+    fn output(&self, frame: usize) -> &HostBuffer {
+        self.input(frame + 1)
+    }
+}
+
+// Buffers behind emission: the emitters spawning this step, the free list of
+// dead slots, the list of alive slots and the indirect draw arguments
+pub(crate) struct LifetimeBuffers {
+    pub(crate) emitters: HostBuffer,
+    pub(crate) free_list: HostBuffer,
+    pub(crate) alive_list: HostBuffer,
+    pub(crate) draw_args: HostBuffer,
 }
 
 pub struct ParticleDescriptorSets {
     layout: vk::DescriptorSetLayout,
     pool: vk::DescriptorPool,
+    // One per ping-pong direction
     sets: Vec<vk::DescriptorSet>,
 }
 
@@ -151,6 +257,7 @@ pub struct SystemState {
     pub last_error: Option<PhysicsError>,
     pub recovery_attempts: u32,
     pub needs_reset: bool,
+    pub health: PhysicsHealth,
 }
 
 impl Default for SystemState {
@@ -160,78 +267,139 @@ impl Default for SystemState {
             last_error: None,
             recovery_attempts: 0,
             needs_reset: false,
+            health: PhysicsHealth::Running,
         }
     }
 }
 
 pub struct GpuPhysicsSystem {
-    device: Arc<ash::Device>,
-    physical_device: vk::PhysicalDevice,
-    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    pub(crate) device: Arc<ash::Device>,
     particle_buffers: Option<ParticleBufferPair>,
-    buffer_pool: BufferPool,
+    pub(crate) lifetime_buffers: Option<LifetimeBuffers>,
+    pub(crate) emitters: Vec<ParticleEmitter>,
+    pub(crate) force_fields: Vec<ForceField>,
+    pub(crate) field_buffer: Option<HostBuffer>,
+    pub(crate) grid_buffers: Option<GridBuffers>,
+    pub(crate) grid_pipelines: Option<GridPipelines>,
+    pub(crate) neighbor_passes: Vec<NeighborPass>,
+    pub(crate) grid_cell_size: Option<f32>,
+    pub(crate) fluid: Option<SphSettings>,
+    pub(crate) particle_capacity: u32,
+    pub(crate) buffer_pool: BufferPool,
     buffer_size: vk::DeviceSize,
     descriptor_sets: Option<ParticleDescriptorSets>,
     sync_primitives: Option<SynchronizationPrimitives>,
     compute_pipeline: Option<vk::Pipeline>,
-    pipeline_layout: Option<vk::PipelineLayout>,
+    emit_pipeline: Option<vk::Pipeline>,
+    pub(crate) pipeline_layout: Option<vk::PipelineLayout>,
     compute_queue: vk::Queue,
     queue_family_index: u32,
-    current_frame: usize,
-    state: SystemState,
-    max_recovery_attempts: u32,
+    pub(crate) current_frame: usize,
+    pub(crate) frame_count: u32,
+    // Simulated seconds, animates turbulence
+    pub(crate) elapsed: f32,
+    params: PushConstants,
+    pub(crate) state: SystemState,
+    pub(crate) max_recovery_attempts: u32,
+    pub(crate) recovery: Recovery,
+    pub(crate) timestamps: Option<GpuTimestamps>,
+    pub(crate) timestamp_period: Option<f32>,
+    pub(crate) pass_timings: Vec<PassTiming>,
     pub debug_enabled: bool, // Make this field public
 }
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug)]
-pub struct PushConstants {
-    delta_time: f32,
-    max_velocity: f32,
-    bounds: [f32; 2],
+pub(crate) const WORKGROUP_SIZE: u32 = 256;
+
+// Blocks of the allocator `new` creates when none is shared
+const PRIVATE_BLOCK_SIZE: u64 = 1024 * 1024;
+
+// How long a step may take before the device is considered lost
+const STEP_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub(crate) fn vulkan_error(component: &str, action: &str, e: vk::Result) -> PhysicsError {
+    error_with_context!("VULKAN", "Failed to {}: {}", action, e);
+    if e == vk::Result::ERROR_DEVICE_LOST {
+        return PhysicsError::DeviceLost {
+            message: format!("Device lost while trying to {}", action),
+            source: Some(Box::new(e)),
+        };
+    }
+    PhysicsError::InitializationFailed {
+        message: format!("Failed to {}: {}", action, e),
+        component: component.to_string(),
+        source: Some(Box::new(e)),
+    }
 }
 
 impl GpuPhysicsSystem {
+    // Creates the system with an allocator of its own over the physical
+    // device's memory properties, as returned by
+    // `Instance::get_physical_device_memory_properties`. Use
+    // `with_allocator` to share the engine's allocator instead.
     pub fn new(
         device: Arc<ash::Device>,
-        physical_device: vk::PhysicalDevice,
+        memory_properties: vk::PhysicalDeviceMemoryProperties,
         queue_family_index: u32,
+    ) -> Result<Self, PhysicsError> {
+        let allocator = Arc::new(MemoryAllocator::with_device(
+            device.clone(),
+            memory_properties,
+            AllocatorConfig {
+                block_size: PRIVATE_BLOCK_SIZE,
+                ..Default::default()
+            },
+        ));
+        Self::with_allocator(device, queue_family_index, allocator)
+    }
+
+    pub fn with_allocator(
+        device: Arc<ash::Device>,
+        queue_family_index: u32,
+        allocator: Arc<MemoryAllocator>,
     ) -> Result<Self, PhysicsError> {
         unsafe {
-            let memory_properties = device.get_physical_device_memory_properties(physical_device);
             let compute_queue = device.get_device_queue(queue_family_index, 0);
-
-            // Create buffer pool with initial size
-            let initial_pool_size = 1024 * 1024; // 1MB initial size
-            let buffer_pool =
-                BufferPool::new(device.clone(), queue_family_index, initial_pool_size)?;
+            let buffer_pool = BufferPool::new(device.clone(), allocator);
 
             Ok(Self {
                 device,
-                physical_device,
-                memory_properties,
                 particle_buffers: None,
+                lifetime_buffers: None,
+                emitters: Vec::new(),
+                force_fields: Vec::new(),
+                field_buffer: None,
+                grid_buffers: None,
+                grid_pipelines: None,
+                neighbor_passes: Vec::new(),
+                grid_cell_size: None,
+                fluid: None,
+                particle_capacity: 0,
                 buffer_pool,
                 buffer_size: 0,
                 descriptor_sets: None,
                 sync_primitives: None,
                 compute_pipeline: None,
+                emit_pipeline: None,
                 pipeline_layout: None,
                 compute_queue,
                 queue_family_index,
                 current_frame: 0,
+                frame_count: 0,
+                elapsed: 0.0,
+                params: PushConstants::new(1.0 / 60.0, 0),
                 state: SystemState::default(),
                 max_recovery_attempts: 3,
+                recovery: Recovery::default(),
+                timestamps: None,
+                timestamp_period: None,
+                pass_timings: Vec::new(),
                 debug_enabled: false,
             })
         }
     }
 
-    pub fn initialize(
-        &mut self,
-        particle_count: usize,
-        shader_module: ShaderModule,
-    ) -> Result<(), PhysicsError> {
+    // The compute pipelines compile their own shaders
+    pub fn initialize(&mut self, particle_count: usize) -> Result<(), PhysicsError> {
         use crate::physics::logging::info_with_context;
 
         info_with_context!(
@@ -241,107 +409,303 @@ impl GpuPhysicsSystem {
         );
 
         if self.state.needs_reset {
-            info_with_context!("RECOVERY", "System needs reset, attempting recovery");
-            self.try_recover()?;
+            info_with_context!("RECOVERY", "System needs reset, starting over");
+            self.release_gpu_resources();
+            self.buffer_pool.cleanup();
+            self.recovery.fallback = None;
+            self.state = SystemState::default();
         }
 
-        let buffer_size = (particle_count * std::mem::size_of::<Particle>()) as u64;
+        self.allocate_particle_buffers(particle_count)?;
+
+        // Create rest of resources
+        self.create_descriptor_sets()?;
+        self.create_compute_pipeline()?;
+        self.create_sync_primitives()?;
+
+        self.state.is_initialized = true;
+
+        // Every slot starts out dead and on the free list
+        self.update_particles(&[])
+    }
+
+    // Simulation parameters for the following steps. The time step, the
+    // time and the particle, emitter, spawn and field counts are filled in
+    // by `step`.
+    pub fn set_params(&mut self, params: PushConstants) {
+        self.params = params;
+    }
+
+    pub fn params(&self) -> PushConstants {
+        self.params
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.particle_capacity as usize
+    }
+
+    #[track_caller]
+    pub(crate) fn allocate_host_buffer(
+        &mut self,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> Result<HostBuffer, PhysicsError> {
+        let (buffer, _, offset) = self.buffer_pool.allocate_buffer(size, usage)?;
+
+        // Buffers share memory blocks, which the pool maps once for all of them
+        let mapped = self.buffer_pool.map(buffer).map_err(|e| {
+            error_with_context!(
+                "MEMORY",
+                "Failed to map buffer memory at offset {}: {}",
+                offset,
+                e
+            );
+            e
+        })?;
+
+        Ok(HostBuffer {
+            buffer,
+            size,
+            mapped,
+        })
+    }
+
+    pub(crate) fn free_host_buffer(&mut self, buffer: HostBuffer) {
+        self.buffer_pool.unmap(buffer.buffer);
+        self.buffer_pool.free_buffer(buffer.buffer);
+    }
+
+    // (Re)allocates the particle, free list and alive list buffers for
+    // `particle_count` slots. Emitter and draw argument buffers don't depend
+    // on the count and are only allocated once.
+    pub(crate) fn allocate_particle_buffers(
+        &mut self,
+        particle_count: usize,
+    ) -> Result<(), PhysicsError> {
+        use crate::physics::logging::info_with_context;
+
+        let buffer_size = (particle_count.max(1) * std::mem::size_of::<Particle>()) as u64;
         info_with_context!(
             "MEMORY",
             "Allocating particle buffers with size: {} bytes",
             buffer_size
         );
 
-        // Create particle buffers using buffer pool
-        let (front_buffer, front_memory, front_offset) = self.buffer_pool.allocate_buffer(
-            buffer_size,
-            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+        if let Some(buffers) = self.particle_buffers.take() {
+            self.free_host_buffer(buffers.front);
+            self.free_host_buffer(buffers.back);
+        }
+        let storage = vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST;
+        let front = self.allocate_host_buffer(buffer_size, storage)?;
+        let back = self.allocate_host_buffer(buffer_size, storage)?;
+        self.particle_buffers = Some(ParticleBufferPair { front, back });
+
+        let index_size = (particle_count.max(1) * std::mem::size_of::<u32>()) as u64;
+        let (emitters, draw_args) = match self.lifetime_buffers.take() {
+            Some(buffers) => {
+                self.free_host_buffer(buffers.free_list);
+                self.free_host_buffer(buffers.alive_list);
+                (buffers.emitters, buffers.draw_args)
+            }
+            None => (
+                self.allocate_host_buffer(
+                    (MAX_EMITTERS * std::mem::size_of::<GpuEmitter>()) as u64,
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                )?,
+                self.allocate_host_buffer(
+                    std::mem::size_of::<DrawIndirectArgs>() as u64,
+                    vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER,
+                )?,
+            ),
+        };
+        let free_list = self.allocate_host_buffer(FREE_LIST_HEADER as u64 + index_size, storage)?;
+        let alive_list = self.allocate_host_buffer(
+            index_size,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::VERTEX_BUFFER,
         )?;
+        self.lifetime_buffers = Some(LifetimeBuffers {
+            emitters,
+            free_list,
+            alive_list,
+            draw_args,
+        });
+
+        if self.field_buffer.is_none() {
+            self.field_buffer = Some(self.allocate_host_buffer(
+                (MAX_FORCE_FIELDS * std::mem::size_of::<GpuForceField>()) as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+            )?);
+        }
 
-        let (back_buffer, back_memory, back_offset) = self.buffer_pool.allocate_buffer(
-            buffer_size,
-            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
-        )?;
+        self.allocate_grid_buffers(particle_count)?;
 
-        // Map buffers
-        unsafe {
-            let front_ptr = self
-                .device
-                .map_memory(
-                    front_memory,
-                    front_offset,
-                    vk::WHOLE_SIZE,
-                    vk::MemoryMapFlags::empty(),
-                )
-                .map_err(|e| {
-                    error_with_context!(
-                        "MEMORY",
-                        "Failed to map front buffer memory at offset {}: {}",
-                        front_offset,
-                        e
-                    );
-                    PhysicsError::InitializationFailed {
-                        message: format!("Failed to map front buffer memory: {}", e),
-                        component: "BufferMapping".to_string(),
-                        source: Some(Box::new(e)),
-                    }
-                })?;
+        self.buffer_size = buffer_size;
+        self.particle_capacity = particle_count as u32;
+        Ok(())
+    }
 
-            let back_ptr = self
-                .device
-                .map_memory(
-                    back_memory,
-                    back_offset,
-                    vk::WHOLE_SIZE,
-                    vk::MemoryMapFlags::empty(),
-                )
-                .map_err(|e| {
-                    error_with_context!(
-                        "MEMORY",
-                        "Failed to map back buffer memory at offset {}: {}",
-                        back_offset,
-                        e
-                    );
-                    PhysicsError::InitializationFailed {
-                        message: format!("Failed to map back buffer memory: {}", e),
-                        component: "BufferMapping".to_string(),
-                        source: Some(Box::new(e)),
-                    }
-                })?;
+    pub(crate) fn create_descriptor_sets(&mut self) -> Result<(), PhysicsError> {
+        let bindings: Vec<_> = PARTICLE_BINDINGS
+            .iter()
+            .map(|&binding| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                    .build()
+            })
+            .collect();
+
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let layout = unsafe {
+            self.device
+                .create_descriptor_set_layout(&layout_info, None)
+                .map_err(|e| vulkan_error("DescriptorSets", "create descriptor set layout", e))?
+        };
+
+        let pool_sizes = [vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 2 * bindings.len() as u32,
+        }];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .pool_sizes(&pool_sizes)
+            .max_sets(2);
+        let pool = unsafe {
+            self.device
+                .create_descriptor_pool(&pool_info, None)
+                .map_err(|e| vulkan_error("DescriptorSets", "create descriptor pool", e))?
+        };
+
+        let layouts = [layout, layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts);
+        let sets = unsafe {
+            self.device
+                .allocate_descriptor_sets(&allocate_info)
+                .map_err(|e| vulkan_error("DescriptorSets", "allocate descriptor sets", e))?
+        };
 
-            self.particle_buffers = Some(ParticleBufferPair {
-                front: (front_buffer, front_memory, front_offset),
-                back: (back_buffer, back_memory, back_offset),
-                mapped_front: front_ptr,
-                mapped_back: back_ptr,
+        self.descriptor_sets = Some(ParticleDescriptorSets { layout, pool, sets });
+        self.update_descriptor_sets()
+    }
+
+    fn update_descriptor_sets(&mut self) -> Result<(), PhysicsError> {
+        let (Some(descriptor_sets), Some(particles), Some(lifetime), Some(fields), Some(grid)) = (
+            &self.descriptor_sets,
+            &self.particle_buffers,
+            &self.lifetime_buffers,
+            &self.field_buffer,
+            &self.grid_buffers,
+        ) else {
+            return Err(PhysicsError::InvalidOperation {
+                message: "Descriptor sets updated before buffers were created".to_string(),
+                operation: "update_descriptor_sets".to_string(),
+                state: format!("{:?}", self.state),
             });
+        };
+
+        for (frame, &set) in descriptor_sets.sets.iter().enumerate() {
+            let buffers = [
+                particles.input(frame),
+                particles.output(frame),
+                &lifetime.emitters,
+                &lifetime.free_list,
+                &lifetime.alive_list,
+                &lifetime.draw_args,
+                fields,
+                &grid.counts,
+                &grid.starts,
+                &grid.entries,
+                &grid.sorted,
+                &grid.densities,
+                &grid.forces,
+            ];
+            let infos: Vec<[vk::DescriptorBufferInfo; 1]> = buffers
+                .iter()
+                .map(|buffer| {
+                    [vk::DescriptorBufferInfo {
+                        buffer: buffer.buffer,
+                        offset: 0,
+                        range: vk::WHOLE_SIZE,
+                    }]
+                })
+                .collect();
+            let writes: Vec<_> = PARTICLE_BINDINGS
+                .iter()
+                .zip(&infos)
+                .map(|(&binding, info)| {
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(binding)
+                        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                        .buffer_info(info)
+                        .build()
+                })
+                .collect();
+            unsafe {
+                self.device.update_descriptor_sets(&writes, &[]);
+            }
         }
+        Ok(())
+    }
 
-        self.buffer_size = buffer_size;
+    pub(crate) fn create_sync_primitives(&mut self) -> Result<(), PhysicsError> {
+        let pool_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(self.queue_family_index)
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
 
-        // Create rest of resources
-        self.create_descriptor_sets()?;
-        self.create_compute_pipeline(shader_module)?;
-        self.create_sync_primitives()?;
+        unsafe {
+            let command_pool = self
+                .device
+                .create_command_pool(&pool_info, None)
+                .map_err(|e| vulkan_error("Synchronization", "create command pool", e))?;
+
+            let allocate_info = vk::CommandBufferAllocateInfo::builder()
+                .command_pool(command_pool)
+                .level(vk::CommandBufferLevel::PRIMARY)
+                .command_buffer_count(1);
+            let command_buffer = self
+                .device
+                .allocate_command_buffers(&allocate_info)
+                .map_err(|e| vulkan_error("Synchronization", "allocate command buffer", e))?[0];
 
-        self.state.is_initialized = true;
+            let compute_fence = self
+                .device
+                .create_fence(&vk::FenceCreateInfo::default(), None)
+                .map_err(|e| vulkan_error("Synchronization", "create fence", e))?;
+            let compute_semaphore = self
+                .device
+                .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
+                .map_err(|e| vulkan_error("Synchronization", "create semaphore", e))?;
+
+            self.sync_primitives = Some(SynchronizationPrimitives {
+                compute_fence,
+                compute_semaphore,
+                command_pool,
+                command_buffer,
+            });
+        }
         Ok(())
     }
 
-    fn create_compute_pipeline(&mut self, shader_module: ShaderModule) -> Result<(), PhysicsError> {
+    pub(crate) fn create_compute_pipeline(&mut self) -> Result<(), PhysicsError> {
         use crate::physics::logging::{debug_with_context, info_with_context};
 
-        info_with_context!("PIPELINE", "Creating compute pipeline");
+        info_with_context!("PIPELINE", "Creating compute pipelines");
 
         // Create pipeline layout
         debug_with_context!("PIPELINE", "Building pipeline layout with push constants");
+        let set_layouts = [self.descriptor_sets.as_ref().unwrap().layout];
+        let push_constant_ranges = [vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: std::mem::size_of::<PushConstants>() as u32,
+        }];
         let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
-            .set_layouts(&self.descriptor_sets.as_ref().unwrap().layout)
-            .push_constant_ranges(&[vk::PushConstantRange {
-                stage_flags: vk::ShaderStageFlags::COMPUTE,
-                offset: 0,
-                size: std::mem::size_of::<PushConstants>() as u32,
-            }])
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges)
             .build();
 
         let pipeline_layout = unsafe {
@@ -360,34 +724,67 @@ impl GpuPhysicsSystem {
         info_with_context!("PIPELINE", "Pipeline layout created successfully");
         self.pipeline_layout = Some(pipeline_layout);
 
-        // Create compute pipeline
+        self.emit_pipeline = Some(self.create_pipeline(
+            "particle_emit",
+            include_str!("shaders/particle_emit.comp"),
+            pipeline_layout,
+        )?);
+        self.compute_pipeline = Some(self.create_pipeline(
+            "particle_update",
+            include_str!("shaders/particle_update.comp"),
+            pipeline_layout,
+        )?);
+        self.create_grid_pipelines(pipeline_layout)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn create_pipeline(
+        &self,
+        name: &str,
+        source: &str,
+        pipeline_layout: vk::PipelineLayout,
+    ) -> Result<vk::Pipeline, PhysicsError> {
+        use crate::physics::logging::{debug_with_context, info_with_context};
+
         let shader_entry_name = std::ffi::CString::new("main").unwrap();
 
         debug_with_context!("SHADER", "Configuring shader compilation options");
-        let mut compile_options = shaderc::CompileOptions::new().unwrap();
+        let mut compile_options =
+            include_options().ok_or_else(|| PhysicsError::InitializationFailed {
+                message: "Failed to create shader compile options".to_string(),
+                component: "ShaderCompilation".to_string(),
+                source: None,
+            })?;
         if self.debug_enabled {
             debug_with_context!("SHADER", "Debug mode enabled, adding DEBUG macro");
             compile_options.add_macro_definition("DEBUG", Some("1"));
         }
 
-        info_with_context!("SHADER", "Compiling particle update compute shader");
+        info_with_context!("SHADER", "Compiling {} compute shader", name);
         let spirv_code = match compile_shader(
-            include_str!("shaders/particle_update.comp"),
+            source,
             shaderc::ShaderKind::Compute,
             "main",
             Some(&compile_options),
         ) {
             Ok(code) => code,
             Err(e) => {
-                error_with_context!("SHADER", "Failed to compile compute shader: {}", e);
+                error_with_context!("SHADER", "Failed to compile {}: {}", name, e);
                 return Err(PhysicsError::InitializationFailed {
-                    message: format!("Failed to compile compute shader: {}", e),
+                    message: format!("Failed to compile {}: {}", name, e),
                     component: "ShaderCompilation".to_string(),
-                    source: Some(Box::new(e)),
+                    source: Some(e.to_string().into()),
                 });
             }
         };
 
+        debug_with_context!("SHADER", "Validating {} layout against SPIR-V", name);
+        validate_shader_layout(&spirv_code).map_err(|e| {
+            error_with_context!("SHADER", "{}: {}", name, e);
+            e
+        })?;
+
         info_with_context!("SHADER", "Creating shader module from SPIR-V code");
         let shader_module = match ShaderModule::new(self.device.clone(), &spirv_code) {
             Ok(module) => module,
@@ -396,16 +793,11 @@ impl GpuPhysicsSystem {
                 return Err(PhysicsError::InitializationFailed {
                     message: format!("Failed to create shader module: {}", e),
                     component: "ShaderModule".to_string(),
-                    source: Some(Box::new(e)),
+                    source: Some(e.to_string().into()),
                 });
             }
         };
 
-        let shader_stage_info = vk::PipelineShaderStageCreateInfo::builder()
-            .stage(vk::ShaderStageFlags::COMPUTE)
-            .module(shader_module.get_module())
-            .name(&shader_entry_name);
-
         // Set specialization constants for workgroup size
         let specialization_map_entries = [
             vk::SpecializationMapEntry {
@@ -425,22 +817,16 @@ impl GpuPhysicsSystem {
             },
         ];
 
-        let workgroup_size_x = 256u32;
-        let workgroup_size_y = 1u32;
-        let workgroup_size_z = 1u32;
-
-        let specialization_data: [u32; 3] = [workgroup_size_x, workgroup_size_y, workgroup_size_z];
+        let specialization_data: [u32; 3] = [WORKGROUP_SIZE, 1, 1];
 
         let specialization_info = vk::SpecializationInfo::builder()
             .map_entries(&specialization_map_entries)
-            .data(unsafe {
-                std::slice::from_raw_parts(
-                    specialization_data.as_ptr() as *const u8,
-                    specialization_data.len() * std::mem::size_of::<u32>(),
-                )
-            });
+            .data(bytemuck::cast_slice(&specialization_data));
 
-        let shader_stage_info = shader_stage_info
+        let shader_stage_info = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(shader_module.get_module())
+            .name(&shader_entry_name)
             .specialization_info(&specialization_info)
             .build();
 
@@ -449,90 +835,307 @@ impl GpuPhysicsSystem {
             .stage(shader_stage_info)
             .build();
 
-        let compute_pipeline = unsafe {
+        let pipelines = unsafe {
             self.device
                 .create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
-                .map_err(|e| {
-                    PhysicsError::InitializationFailed(format!(
-                        "Failed to create compute pipeline: {:?}",
-                        e
-                    ))
-                })?[0]
+                .map_err(|(_, e)| vulkan_error("ComputePipeline", "create compute pipeline", e))?
         };
 
-        self.compute_pipeline = Some(compute_pipeline);
+        Ok(pipelines[0])
+    }
 
+    // Uploads particles into the buffer the next step reads. Slots past the
+    // end of `particles` are cleared to dead particles, and every dead slot
+    // goes on the free list for emitters to reuse. The upload also becomes
+    // the snapshot to recover from.
+    pub fn update_particles(&mut self, particles: &[Particle]) -> Result<(), PhysicsError> {
+        if let Some(fallback) = &mut self.recovery.fallback {
+            return fallback.update_particles(particles);
+        }
+        let capacity = self.capacity();
+        if particles.len() > capacity {
+            return Err(PhysicsError::BufferOverflow {
+                message: "More particles than the buffers hold".to_string(),
+                required: std::mem::size_of_val(particles) as u64,
+                available: self.buffer_size,
+            });
+        }
+        let (Some(buffers), Some(lifetime)) = (&self.particle_buffers, &self.lifetime_buffers)
+        else {
+            return Err(PhysicsError::InvalidOperation {
+                message: "Particles uploaded before initialization".to_string(),
+                operation: "update_particles".to_string(),
+                state: format!("{:?}", self.state),
+            });
+        };
+
+        let mut slots = particles.to_vec();
+        slots.resize(capacity, Particle::default().with_lifetime(0.0));
+        buffers.input(self.current_frame).write(0, &slots)?;
+
+        let free = free_slots(&slots);
+        lifetime.free_list.write(0, &[free.len() as i32])?;
+        lifetime.free_list.write(FREE_LIST_HEADER, &free)?;
+
+        self.recovery.snapshot = slots;
+        self.recovery.snapshot_time = self.elapsed;
         Ok(())
     }
 
-    pub fn resize(&mut self, new_particle_count: usize) -> Result<(), PhysicsError> {
-        let new_size = (new_particle_count * std::mem::size_of::<Particle>()) as u64;
-
-        // Free old buffers
-        if let Some(buffers) = &self.particle_buffers {
-            self.buffer_pool
-                .free_buffer(buffers.front.0, buffers.front.1, buffers.front.2);
-            self.buffer_pool
-                .free_buffer(buffers.back.0, buffers.back.1, buffers.back.2);
+    // Particles as of the last step, dead slots included
+    pub fn get_particle_data(&self) -> Result<Vec<Particle>, PhysicsError> {
+        if let Some(fallback) = &self.recovery.fallback {
+            return fallback.get_particle_data();
         }
+        let buffers =
+            self.particle_buffers
+                .as_ref()
+                .ok_or_else(|| PhysicsError::InvalidOperation {
+                    message: "Particles read before initialization".to_string(),
+                    operation: "get_particle_data".to_string(),
+                    state: format!("{:?}", self.state),
+                })?;
+        Ok(buffers.input(self.current_frame).read(0, self.capacity()))
+    }
 
-        // Allocate new buffers
-        let (front_buffer, front_memory, front_offset) = self.buffer_pool.allocate_buffer(
-            new_size,
-            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
-        )?;
+    // Runs one step on the GPU: spawns particles from the emitters, then
+    // integrates every particle, retiring the ones whose lifetime ran out.
+    // Blocks until the GPU is done. Device losses are recovered from, see
+    // `physics::recovery`, so an error means the step did not happen.
+    pub fn step(&mut self, delta_time: f32) -> Result<(), PhysicsError> {
+        match self.state.health {
+            PhysicsHealth::Degraded => return self.step_fallback(delta_time),
+            PhysicsHealth::Failed => {
+                return Err(PhysicsError::InvalidOperation {
+                    message: "GPU physics failed and has no CPU fallback".to_string(),
+                    operation: "step".to_string(),
+                    state: format!("{:?}", self.state),
+                })
+            }
+            PhysicsHealth::Recovering => {
+                // The last rebuild failed, count it and try again
+                if let Err(e) = self.try_recover() {
+                    return self.recover_step(e, delta_time);
+                }
+            }
+            PhysicsHealth::Running => {}
+        }
 
-        let (back_buffer, back_memory, back_offset) = self.buffer_pool.allocate_buffer(
-            new_size,
-            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
-        )?;
+        match self.step_gpu(delta_time) {
+            Ok(()) => {
+                self.state.record_success();
+                self.snapshot_if_due()
+            }
+            Err(e) if e.is_device_error() => self.recover_step(e, delta_time),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn step_gpu(&mut self, delta_time: f32) -> Result<(), PhysicsError> {
+        if !self.state.is_initialized {
+            return Err(PhysicsError::InvalidOperation {
+                message: "Stepped before initialization".to_string(),
+                operation: "step".to_string(),
+                state: format!("{:?}", self.state),
+            });
+        }
+
+        let (emitter_count, spawn_count) = self.upload_emitters(delta_time)?;
+        let field_count = self.upload_force_fields()?;
+        let mut push_constants = PushConstants {
+            delta_time,
+            particle_count: self.particle_capacity,
+            emitter_count,
+            spawn_count,
+            field_count,
+            time: self.elapsed,
+            ..self.params
+        };
+        self.grid_push_constants(&mut push_constants);
+        self.ensure_timestamps()?;
+
+        if let Some(lifetime) = &self.lifetime_buffers {
+            lifetime
+                .draw_args
+                .write(0, &[DrawIndirectArgs::new(PARTICLE_DRAW_VERTICES)])?;
+        }
+
+        self.record_compute_commands(&push_constants)?;
+        self.submit_compute()?;
+        self.resolve_timestamps()?;
+        if let Some(error) = self.recovery.injected_fault(self.frame_count) {
+            return Err(error);
+        }
+
+        self.current_frame = (self.current_frame + 1) % 2;
+        self.frame_count = self.frame_count.wrapping_add(1);
+        self.elapsed += delta_time;
+        Ok(())
+    }
+
+    fn record_compute_commands(&self, push_constants: &PushConstants) -> Result<(), PhysicsError> {
+        let (Some(sync), Some(descriptor_sets), Some(layout), Some(emit), Some(update)) = (
+            &self.sync_primitives,
+            &self.descriptor_sets,
+            self.pipeline_layout,
+            self.emit_pipeline,
+            self.compute_pipeline,
+        ) else {
+            return Err(PhysicsError::InvalidOperation {
+                message: "Compute resources missing".to_string(),
+                operation: "record_compute_commands".to_string(),
+                state: format!("{:?}", self.state),
+            });
+        };
+        let cmd = sync.command_buffer;
+        let set = [descriptor_sets.sets[self.current_frame % 2]];
 
-        // Map new buffers
         unsafe {
-            let front_ptr = self
-                .device
-                .map_memory(
-                    front_memory,
-                    front_offset,
-                    vk::WHOLE_SIZE,
-                    vk::MemoryMapFlags::empty(),
-                )
-                .map_err(|e| {
-                    PhysicsError::InitializationFailed(format!(
-                        "Failed to map front buffer memory: {}",
-                        e
-                    ))
-                })?;
+            self.device
+                .reset_command_buffer(cmd, vk::CommandBufferResetFlags::empty())
+                .map_err(|e| vulkan_error("CommandBuffer", "reset command buffer", e))?;
+            let begin_info = vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            self.device
+                .begin_command_buffer(cmd, &begin_info)
+                .map_err(|e| vulkan_error("CommandBuffer", "begin command buffer", e))?;
+            self.begin_timestamps(cmd);
+
+            self.device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                layout,
+                0,
+                &set,
+                &[],
+            );
+            self.device.cmd_push_constants(
+                cmd,
+                layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                bytemuck::bytes_of(push_constants),
+            );
+
+            if push_constants.spawn_count > 0 {
+                self.device
+                    .cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, emit);
+                self.device.cmd_dispatch(
+                    cmd,
+                    push_constants.spawn_count.div_ceil(WORKGROUP_SIZE),
+                    1,
+                    1,
+                );
+                self.mark_pass(cmd, "emit");
+
+                // Spawned particles and the popped free list feed the update
+                let barrier = vk::MemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+                    .build();
+                self.device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[barrier],
+                    &[],
+                    &[],
+                );
+            }
 
-            let back_ptr = self
-                .device
-                .map_memory(
-                    back_memory,
-                    back_offset,
-                    vk::WHOLE_SIZE,
-                    vk::MemoryMapFlags::empty(),
+            self.record_grid_passes(cmd, layout, push_constants);
+
+            self.device
+                .cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, update);
+            self.device
+                .cmd_dispatch(cmd, self.particle_capacity.div_ceil(WORKGROUP_SIZE), 1, 1);
+            self.mark_pass(cmd, "update");
+
+            // Results are read back on the host and the alive list and draw
+            // arguments drive indirect draws
+            let barrier = vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(
+                    vk::AccessFlags::HOST_READ
+                        | vk::AccessFlags::INDIRECT_COMMAND_READ
+                        | vk::AccessFlags::SHADER_READ,
                 )
-                .map_err(|e| {
-                    PhysicsError::InitializationFailed(format!(
-                        "Failed to map back buffer memory: {}",
-                        e
-                    ))
-                })?;
+                .build();
+            self.device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::HOST
+                    | vk::PipelineStageFlags::DRAW_INDIRECT
+                    | vk::PipelineStageFlags::VERTEX_SHADER,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
 
-            self.particle_buffers = Some(ParticleBufferPair {
-                front: (front_buffer, front_memory, front_offset),
-                back: (back_buffer, back_memory, back_offset),
-                mapped_front: front_ptr,
-                mapped_back: back_ptr,
-            });
+            self.device
+                .end_command_buffer(cmd)
+                .map_err(|e| vulkan_error("CommandBuffer", "end command buffer", e))?;
         }
+        Ok(())
+    }
 
-        self.buffer_size = new_size;
+    fn submit_compute(&self) -> Result<(), PhysicsError> {
+        let sync = self
+            .sync_primitives
+            .as_ref()
+            .ok_or_else(|| PhysicsError::InvalidOperation {
+                message: "Submitted before initialization".to_string(),
+                operation: "submit_compute".to_string(),
+                state: format!("{:?}", self.state),
+            })?;
+        let command_buffers = [sync.command_buffer];
+        let submit_info = vk::SubmitInfo::builder()
+            .command_buffers(&command_buffers)
+            .build();
+
+        unsafe {
+            self.device
+                .reset_fences(&[sync.compute_fence])
+                .map_err(|e| vulkan_error("Synchronization", "reset compute fence", e))?;
+            self.device
+                .queue_submit(self.compute_queue, &[submit_info], sync.compute_fence)
+                .map_err(|e| vulkan_error("Submission", "submit compute work", e))?;
+
+            let started = Instant::now();
+            match self.device.wait_for_fences(
+                &[sync.compute_fence],
+                true,
+                STEP_TIMEOUT.as_nanos() as u64,
+            ) {
+                Ok(()) => Ok(()),
+                Err(vk::Result::TIMEOUT) => Err(PhysicsError::DeviceLost {
+                    message: format!("Compute step did not finish within {:?}", started.elapsed()),
+                    source: None,
+                }),
+                Err(e) => Err(vulkan_error("Synchronization", "wait for compute fence", e)),
+            }
+        }
+    }
+
+    pub fn resize(&mut self, new_particle_count: usize) -> Result<(), PhysicsError> {
+        if let Some(fallback) = &mut self.recovery.fallback {
+            fallback.resize(new_particle_count)?;
+            self.particle_capacity = new_particle_count as u32;
+            return Ok(());
+        }
+
+        // Keep as many particles as still fit
+        let mut particles = self.get_particle_data().unwrap_or_default();
+        particles.truncate(new_particle_count);
+
+        self.allocate_particle_buffers(new_particle_count)?;
 
         // Update descriptor sets
         self.update_descriptor_sets()?;
 
-        Ok(())
+        self.update_particles(&particles)
     }
 
     // ... [Previous implementations with updated error handling] ...
@@ -541,18 +1144,58 @@ impl GpuPhysicsSystem {
     }
 
     pub fn cleanup(&mut self) {
-        if let Some(buffers) = &self.particle_buffers {
-            unsafe {
-                // Unmap memory
-                self.device.unmap_memory(buffers.front.1);
-                self.device.unmap_memory(buffers.back.1);
-            }
-        }
+        self.release_gpu_resources();
 
         // Cleanup buffer pool
         self.buffer_pool.cleanup();
+        self.state.is_initialized = false;
+    }
+
+    // Destroys everything created on the device except the memory pool.
+    // Emitters, force fields and neighbor passes stay configured.
+    pub(crate) fn release_gpu_resources(&mut self) {
+        unsafe {
+            // Nothing may be in flight while resources go away
+            let _ = self.device.device_wait_idle();
+
+            if let Some(sync) = self.sync_primitives.take() {
+                self.device.destroy_fence(sync.compute_fence, None);
+                self.device.destroy_semaphore(sync.compute_semaphore, None);
+                self.device.destroy_command_pool(sync.command_pool, None);
+            }
+            self.destroy_grid_pipelines();
+            self.destroy_timestamps();
+            for pipeline in [self.compute_pipeline.take(), self.emit_pipeline.take()]
+                .into_iter()
+                .flatten()
+            {
+                self.device.destroy_pipeline(pipeline, None);
+            }
+            if let Some(layout) = self.pipeline_layout.take() {
+                self.device.destroy_pipeline_layout(layout, None);
+            }
+            if let Some(descriptor_sets) = self.descriptor_sets.take() {
+                self.device
+                    .destroy_descriptor_pool(descriptor_sets.pool, None);
+                self.device
+                    .destroy_descriptor_set_layout(descriptor_sets.layout, None);
+            }
+        }
 
-        // ... [Rest of cleanup] ...
+        if let Some(buffers) = self.particle_buffers.take() {
+            self.free_host_buffer(buffers.front);
+            self.free_host_buffer(buffers.back);
+        }
+        if let Some(buffers) = self.lifetime_buffers.take() {
+            self.free_host_buffer(buffers.emitters);
+            self.free_host_buffer(buffers.free_list);
+            self.free_host_buffer(buffers.alive_list);
+            self.free_host_buffer(buffers.draw_args);
+        }
+        if let Some(buffer) = self.field_buffer.take() {
+            self.free_host_buffer(buffer);
+        }
+        self.free_grid_buffers();
     }
 }
 
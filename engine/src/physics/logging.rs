@@ -1,5 +1,5 @@
 use env_logger::Builder;
-use log::{Level, LevelFilter};
+use log::LevelFilter;
 use std::io::Write;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -39,20 +39,14 @@ pub fn init_logging() {
 
     builder
         .format(|buf, record| {
-            let context = record
-                .key_values()
-                .get("context")
-                .and_then(|v| v.as_str())
-                .unwrap_or("");
-
+            // The context is part of the message, see `log_with_context`
             writeln!(
                 buf,
-                "[{} {} {}:{}] {} {}",
+                "[{} {} {}:{}] {}",
                 record.level(),
                 chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
                 record.file().unwrap_or("unknown"),
                 record.line().unwrap_or(0),
-                context,
                 record.args()
             )
         })
@@ -65,8 +59,8 @@ pub fn init_logging() {
 macro_rules! log_with_context {
     ($level:expr, $context:expr, $($arg:tt)+) => {
         log::log!(
-            $level,
             target: "physics",
+            $level,
             "{} [{}:{}] {}",
             $context,
             file!(),
@@ -105,6 +99,10 @@ macro_rules! debug_with_context {
     };
 }
 
+// `#[macro_export]` puts the macros at the crate root, make them
+// importable from here as well
+pub use crate::{debug_with_context, error_with_context, info_with_context, warn_with_context};
+
 // Error chain tracking
 pub fn log_error_chain<E: std::error::Error>(
     error: &E,
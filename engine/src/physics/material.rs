@@ -0,0 +1,316 @@
+use glam::Vec3;
+
+use crate::physics::{
+    physics::{PhysicsObject, PhysicsWorld},
+    rigid_body::cuboid_inertia,
+};
+
+// Surface and bulk properties of a body. Contacts combine the materials of
+// both bodies: friction by geometric mean, restitution by taking the larger.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhysicsMaterial {
+    pub friction: f32,
+    pub restitution: f32,
+    // Mass per unit volume, used to derive mass and inertia from the shape
+    pub density: f32,
+}
+
+impl PhysicsMaterial {
+    pub fn new(friction: f32, restitution: f32, density: f32) -> Self {
+        Self {
+            friction,
+            restitution,
+            density,
+        }
+    }
+
+    pub fn with_friction(mut self, friction: f32) -> Self {
+        self.friction = friction;
+        self
+    }
+
+    pub fn with_restitution(mut self, restitution: f32) -> Self {
+        self.restitution = restitution;
+        self
+    }
+
+    pub fn with_density(mut self, density: f32) -> Self {
+        self.density = density;
+        self
+    }
+
+    // Coefficients used for a contact between bodies made of `self` and `other`
+    pub fn combine(&self, other: &PhysicsMaterial) -> ContactMaterial {
+        ContactMaterial {
+            friction: (self.friction * other.friction).sqrt(),
+            restitution: self.restitution.max(other.restitution),
+        }
+    }
+}
+
+// Surface properties of a contact between two bodies
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContactMaterial {
+    pub friction: f32,
+    pub restitution: f32,
+}
+
+impl Default for PhysicsMaterial {
+    fn default() -> Self {
+        Self {
+            friction: 0.3,
+            restitution: 0.5,
+            density: 1.0,
+        }
+    }
+}
+
+// Velocity damping in 1/s. Each step scales velocities by
+// `1 / (1 + delta_time * coefficient)`, which stays stable for any step size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Damping {
+    pub linear: f32,
+    pub angular: f32,
+}
+
+impl Damping {
+    pub fn new(linear: f32, angular: f32) -> Self {
+        Self { linear, angular }
+    }
+
+    pub fn none() -> Self {
+        Self::new(0.0, 0.0)
+    }
+
+    pub fn linear_factor(&self, delta_time: f32) -> f32 {
+        1.0 / (1.0 + delta_time * self.linear)
+    }
+
+    pub fn angular_factor(&self, delta_time: f32) -> f32 {
+        1.0 / (1.0 + delta_time * self.angular)
+    }
+}
+
+impl Default for Damping {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl PhysicsObject {
+    // Recomputes mass and inertia from the body's shape and `density`.
+    // Rigid bodies are cubes with half size `bounding_box.w`; bodies with zero
+    // mass are static and stay that way. Deformable bodies spread the mass of
+    // each tetrahedron over its corners, pinned particles keep their infinite
+    // mass, and bodies without tetrahedra are left unchanged.
+    pub fn apply_density(&mut self, density: f32) {
+        match self {
+            PhysicsObject::RigidBody {
+                mass,
+                inertia_tensor,
+                bounding_box,
+                ..
+            } => {
+                if *mass == 0.0 {
+                    return;
+                }
+                let half_size = bounding_box.w;
+                *mass = density * (2.0 * half_size).powi(3);
+                *inertia_tensor = cuboid_inertia(*mass, Vec3::splat(half_size));
+            }
+            PhysicsObject::DeformableBody {
+                positions,
+                masses,
+                tetrahedra,
+                ..
+            } => {
+                if tetrahedra.is_empty() {
+                    return;
+                }
+                let mut lumped = vec![0.0; masses.len()];
+                for tet in tetrahedra.iter() {
+                    let [a, b, c, d] = tet.map(|i| positions[i]);
+                    let volume = (b - a).cross(c - a).dot(d - a).abs() / 6.0;
+                    for &i in tet {
+                        lumped[i] += volume * density / 4.0;
+                    }
+                }
+                for (mass, lumped) in masses.iter_mut().zip(lumped) {
+                    if mass.is_finite() && lumped > 0.0 {
+                        *mass = lumped;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl PhysicsWorld {
+    // Adds a body whose mass and inertia are derived from `material.density`
+    pub fn add_object_with_material(
+        &mut self,
+        mut object: PhysicsObject,
+        material: PhysicsMaterial,
+    ) -> usize {
+        object.apply_density(material.density);
+        let index = self.add_object(object);
+        self.materials[index] = material;
+        index
+    }
+
+    // Assigns a material and recomputes the body's mass and inertia from it
+    pub fn set_material(&mut self, index: usize, material: PhysicsMaterial) {
+        if index >= self.materials.len() {
+            self.materials.resize(index + 1, PhysicsMaterial::default());
+        }
+        self.materials[index] = material;
+        if let Some(object) = self.objects.get(index) {
+            object.borrow_mut().apply_density(material.density);
+        }
+    }
+
+    pub fn material(&self, index: usize) -> PhysicsMaterial {
+        self.materials.get(index).copied().unwrap_or_default()
+    }
+
+    // Damping for every body without an override
+    pub fn set_damping(&mut self, damping: Damping) {
+        self.damping = damping;
+    }
+
+    // Overrides the world damping for one body, `None` reverts to it
+    pub fn set_body_damping(&mut self, index: usize, damping: Option<Damping>) {
+        if index >= self.body_damping.len() {
+            self.body_damping.resize(index + 1, None);
+        }
+        self.body_damping[index] = damping;
+    }
+
+    pub fn body_damping(&self, index: usize) -> Damping {
+        self.body_damping
+            .get(index)
+            .copied()
+            .flatten()
+            .unwrap_or(self.damping)
+    }
+
+    pub(crate) fn contact_material(&self, a: usize, b: usize) -> ContactMaterial {
+        self.material(a).combine(&self.material(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::{Mat3, Quat, Vec4};
+
+    fn cube(mass: f32, half_size: f32) -> PhysicsObject {
+        PhysicsObject::RigidBody {
+            position: Vec3::ZERO,
+            velocity: Vec3::ZERO,
+            acceleration: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            angular_velocity: Vec3::ZERO,
+            angular_acceleration: Vec3::ZERO,
+            mass,
+            inertia_tensor: Mat3::IDENTITY,
+            bounding_box: Vec4::new(0.0, 0.0, 0.0, half_size),
+        }
+    }
+
+    fn mass_and_inertia(world: &PhysicsWorld, index: usize) -> (f32, Mat3) {
+        match &*world.objects[index].borrow() {
+            PhysicsObject::RigidBody {
+                mass,
+                inertia_tensor,
+                ..
+            } => (*mass, *inertia_tensor),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_mass_from_density() {
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        let steel = PhysicsMaterial::default().with_density(7.8);
+        let block = world.add_object_with_material(cube(1.0, 0.5), steel);
+        let floor = world.add_object_with_material(cube(0.0, 10.0), steel);
+
+        let (mass, inertia) = mass_and_inertia(&world, block);
+        assert!((mass - 7.8).abs() < 1e-5);
+        assert!(inertia.abs_diff_eq(Mat3::from_diagonal(Vec3::splat(7.8 / 6.0)), 1e-5));
+        assert_eq!(world.material(block), steel);
+
+        // Static bodies keep their zero mass
+        assert_eq!(mass_and_inertia(&world, floor).0, 0.0);
+
+        world.set_material(block, steel.with_density(1.0));
+        assert!((mass_and_inertia(&world, block).0 - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_contact_material_and_damping() {
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        let ice =
+            world.add_object_with_material(cube(1.0, 0.5), PhysicsMaterial::new(0.04, 0.1, 0.9));
+        let rubber =
+            world.add_object_with_material(cube(1.0, 0.5), PhysicsMaterial::new(1.0, 0.8, 1.1));
+
+        let contact = world.contact_material(ice, rubber);
+        assert!((contact.friction - 0.2).abs() < 1e-5);
+        assert_eq!(contact.restitution, 0.8);
+
+        world.set_damping(Damping::new(0.5, 1.0));
+        world.set_body_damping(rubber, Some(Damping::none()));
+        assert_eq!(world.body_damping(ice), Damping::new(0.5, 1.0));
+        assert_eq!(world.body_damping(rubber).linear_factor(1.0), 1.0);
+        assert!((world.body_damping(ice).linear_factor(2.0) - 0.5).abs() < 1e-6);
+
+        world.set_body_damping(rubber, None);
+        assert_eq!(world.body_damping(rubber), Damping::new(0.5, 1.0));
+    }
+
+    // Drops a cube onto a static floor with some sideways speed and returns
+    // its highest upward speed after the first impact and its final
+    // horizontal speed
+    fn drop_on_floor(material: PhysicsMaterial) -> (f32, f32) {
+        let mut world = PhysicsWorld::new(Vec3::new(0.0, -9.81, 0.0));
+        let mut floor = cube(0.0, 10.0);
+        let mut body = cube(1.0, 0.5);
+        if let PhysicsObject::RigidBody { position, .. } = &mut floor {
+            *position = Vec3::new(0.0, -10.0, 0.0);
+        }
+        if let PhysicsObject::RigidBody {
+            position, velocity, ..
+        } = &mut body
+        {
+            *position = Vec3::new(0.0, 1.5, 0.0);
+            *velocity = Vec3::new(4.0, 0.0, 0.0);
+        }
+        world.add_object_with_material(floor, material);
+        let body = world.add_object_with_material(body, material);
+
+        let mut rebound: f32 = 0.0;
+        let mut horizontal = 0.0;
+        for _ in 0..60 {
+            world.update(1.0 / 60.0);
+            if let PhysicsObject::RigidBody { velocity, .. } = &*world.objects[body].borrow() {
+                rebound = rebound.max(velocity.y);
+                horizontal = velocity.x;
+            }
+        }
+        (rebound, horizontal)
+    }
+
+    #[test]
+    fn test_materials_change_contact_outcome() {
+        let (dead, _) = drop_on_floor(PhysicsMaterial::new(0.0, 0.0, 1.0));
+        let (bouncy, _) = drop_on_floor(PhysicsMaterial::new(0.0, 0.9, 1.0));
+        assert!(bouncy > dead + 1.0, "{} vs {}", bouncy, dead);
+
+        let (_, slippery) = drop_on_floor(PhysicsMaterial::new(0.0, 0.0, 1.0));
+        let (_, rough) = drop_on_floor(PhysicsMaterial::new(1.0, 0.0, 1.0));
+        assert!(slippery > 3.5, "{}", slippery);
+        assert!(rough < slippery - 1.0, "{} vs {}", rough, slippery);
+    }
+}
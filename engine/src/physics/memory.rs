@@ -1,263 +1,247 @@
 use super::PhysicsError;
+use crate::memory::{MemoryAllocator, MemoryBlock, MemoryStats, MemoryTag};
 use ash::{self, vk};
-use std::collections::VecDeque;
+use std::ffi::c_void;
 use std::sync::Arc;
 
-const MIN_POOL_SIZE: u64 = 1024 * 1024; // 1MB minimum pool size
-const MAX_POOL_SIZE: u64 = 256 * 1024 * 1024; // 256MB maximum pool size
+// Particle buffers are written and read back by the host every frame
+const BUFFER_MEMORY: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::from_raw(
+    vk::MemoryPropertyFlags::HOST_VISIBLE.as_raw()
+        | vk::MemoryPropertyFlags::HOST_COHERENT.as_raw(),
+);
 
-pub struct MemoryBlock {
-    memory: vk::DeviceMemory,
+#[derive(Debug, Clone, Copy)]
+struct PooledBuffer {
+    buffer: vk::Buffer,
+    block: MemoryBlock,
     size: vk::DeviceSize,
-    offset: vk::DeviceSize,
-    is_free: bool,
+    usage: vk::BufferUsageFlags,
 }
 
-pub struct MemoryPool {
-    device: Arc<ash::Device>,
-    memory_type_index: u32,
-    blocks: Vec<MemoryBlock>,
-    free_blocks: VecDeque<usize>,
-    total_size: vk::DeviceSize,
-    used_size: vk::DeviceSize,
-    block_size: vk::DeviceSize,
+// A buffer replaced by `BufferPool::defragment`. Descriptors and anything
+// else referring to `old` have to switch to `new`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferRelocation {
+    pub old: vk::Buffer,
+    pub new: vk::Buffer,
+    pub memory: vk::DeviceMemory,
+    pub offset: vk::DeviceSize,
 }
 
-impl MemoryPool {
-    pub fn new(
-        device: Arc<ash::Device>,
-        memory_type_index: u32,
-        initial_size: vk::DeviceSize,
-    ) -> Result<Self, PhysicsError> {
-        let block_size = initial_size.max(MIN_POOL_SIZE).min(MAX_POOL_SIZE);
+// The physics buffers, allocated from the shared `MemoryAllocator` under
+// `MemoryTag::Physics`
+pub struct BufferPool {
+    device: Arc<ash::Device>,
+    allocator: Arc<MemoryAllocator>,
+    buffers: Vec<PooledBuffer>,
+    // Buffers replaced by `defragment`, destroyed with the retired blocks
+    retired: Vec<vk::Buffer>,
+}
 
-        Ok(Self {
+impl BufferPool {
+    pub fn new(device: Arc<ash::Device>, allocator: Arc<MemoryAllocator>) -> Self {
+        Self {
             device,
-            memory_type_index,
-            blocks: Vec::new(),
-            free_blocks: VecDeque::new(),
-            total_size: 0,
-            used_size: 0,
-            block_size,
-        })
+            allocator,
+            buffers: Vec::new(),
+            retired: Vec::new(),
+        }
     }
 
-    pub fn allocate(
-        &mut self,
-        size: vk::DeviceSize,
-        alignment: vk::DeviceSize,
-    ) -> Result<(vk::DeviceMemory, vk::DeviceSize), PhysicsError> {
-        // Try to find a free block that fits
-        if let Some(block_index) = self.find_free_block(size, alignment) {
-            let block = &mut self.blocks[block_index];
-            block.is_free = false;
-            self.used_size += size;
-            return Ok((block.memory, block.offset));
-        }
+    pub fn allocator(&self) -> &Arc<MemoryAllocator> {
+        &self.allocator
+    }
 
-        // Need to allocate a new block
-        let new_block_size = size.max(self.block_size);
-        let alloc_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(new_block_size)
-            .memory_type_index(self.memory_type_index)
+    fn create_buffer(
+        &self,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> Result<vk::Buffer, PhysicsError> {
+        // Transfers are always allowed so `defragment` can move the buffer
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(size)
+            .usage(usage | vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
             .build();
 
-        let memory = unsafe {
+        unsafe {
             self.device
-                .allocate_memory(&alloc_info, None)
-                .map_err(|e| {
-                    PhysicsError::OutOfMemory(format!("Failed to allocate memory block: {}", e))
-                })?
-        };
-
-        let block = MemoryBlock {
-            memory,
-            size: new_block_size,
-            offset: 0,
-            is_free: false,
-        };
-
-        self.blocks.push(block);
-        self.total_size += new_block_size;
-        self.used_size += size;
-
-        Ok((memory, 0))
-    }
-
-    pub fn free(&mut self, memory: vk::DeviceMemory, offset: vk::DeviceSize) {
-        if let Some(index) = self
-            .blocks
-            .iter()
-            .position(|block| block.memory == memory && block.offset == offset)
-        {
-            self.blocks[index].is_free = true;
-            self.free_blocks.push_back(index);
-
-            // Coalesce adjacent free blocks
-            self.coalesce_free_blocks();
+                .create_buffer(&buffer_info, None)
+                .map_err(|e| PhysicsError::InitializationFailed {
+                    message: format!("Failed to create buffer: {}", e),
+                    component: "BufferPool".to_string(),
+                    source: Some(Box::new(e)),
+                })
         }
     }
 
-    pub fn cleanup(&mut self) {
+    fn bind(
+        &self,
+        buffer: vk::Buffer,
+        memory: vk::DeviceMemory,
+        offset: vk::DeviceSize,
+    ) -> Result<(), PhysicsError> {
         unsafe {
-            for block in &self.blocks {
-                self.device.free_memory(block.memory, None);
-            }
-        }
-        self.blocks.clear();
-        self.free_blocks.clear();
-        self.total_size = 0;
-        self.used_size = 0;
-    }
-
-    fn find_free_block(&self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Option<usize> {
-        for &index in &self.free_blocks {
-            let block = &self.blocks[index];
-            if block.is_free && block.size >= size {
-                // Check alignment
-                let aligned_offset = (block.offset + alignment - 1) & !(alignment - 1);
-                if aligned_offset + size <= block.offset + block.size {
-                    return Some(index);
-                }
-            }
+            self.device
+                .bind_buffer_memory(buffer, memory, offset)
+                .map_err(|e| PhysicsError::InitializationFailed {
+                    message: format!("Failed to bind buffer memory: {}", e),
+                    component: "BufferPool".to_string(),
+                    source: Some(Box::new(e)),
+                })
         }
-        None
     }
 
-    fn coalesce_free_blocks(&mut self) {
-        let mut i = 0;
-        while i < self.blocks.len() - 1 {
-            if self.blocks[i].is_free && self.blocks[i + 1].is_free {
-                // Merge blocks
-                let next_block = self.blocks.remove(i + 1);
-                let current_block = &mut self.blocks[i];
-                current_block.size += next_block.size;
+    // Tracked under the caller's location in the allocator's leak report
+    #[track_caller]
+    pub fn allocate_buffer(
+        &mut self,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> Result<(vk::Buffer, vk::DeviceMemory, vk::DeviceSize), PhysicsError> {
+        let buffer = self.create_buffer(size, usage)?;
+        let mem_requirements = unsafe { self.device.get_buffer_memory_requirements(buffer) };
 
-                // Update free blocks list
-                if let Some(pos) = self.free_blocks.iter().position(|&idx| idx == i + 1) {
-                    self.free_blocks.remove(pos);
-                }
-            } else {
-                i += 1;
+        let block = match self
+            .allocator
+            .allocate(mem_requirements, BUFFER_MEMORY, MemoryTag::Physics)
+        {
+            Ok(block) => block,
+            Err(e) => {
+                unsafe { self.device.destroy_buffer(buffer, None) };
+                return Err(e.into());
             }
+        };
+
+        if let Err(e) = self.bind(buffer, block.memory, block.offset) {
+            unsafe { self.device.destroy_buffer(buffer, None) };
+            let _ = self.allocator.free(block);
+            return Err(e);
         }
+
+        self.buffers.push(PooledBuffer {
+            buffer,
+            block,
+            size,
+            usage,
+        });
+        Ok((buffer, block.memory, block.offset))
     }
 
-    pub fn get_stats(&self) -> MemoryStats {
-        MemoryStats {
-            total_size: self.total_size,
-            used_size: self.used_size,
-            free_size: self.total_size - self.used_size,
-            block_count: self.blocks.len() as u32,
-            free_block_count: self.free_blocks.len() as u32,
+    pub fn free_buffer(&mut self, buffer: vk::Buffer) {
+        unsafe {
+            self.device.destroy_buffer(buffer, None);
+        }
+        if let Some(pos) = self.buffers.iter().position(|b| b.buffer == buffer) {
+            let pooled = self.buffers.swap_remove(pos);
+            let _ = self.allocator.free(pooled.block);
         }
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-pub struct MemoryStats {
-    pub total_size: vk::DeviceSize,
-    pub used_size: vk::DeviceSize,
-    pub free_size: vk::DeviceSize,
-    pub block_count: u32,
-    pub free_block_count: u32,
-}
-
-impl Drop for MemoryPool {
-    fn drop(&mut self) {
-        self.cleanup();
+    // Pointer to the start of `buffer`. Memory shared with other buffers is
+    // mapped once for all of them; pair every call with `unmap`.
+    pub fn map(&mut self, buffer: vk::Buffer) -> Result<*mut c_void, PhysicsError> {
+        let pooled = self.find(buffer)?;
+        Ok(self.allocator.map(&pooled.block)?)
     }
-}
 
-#[derive(Debug)]
-pub struct BufferPool {
-    device: Arc<ash::Device>,
-    memory_pool: MemoryPool,
-    buffers: Vec<vk::Buffer>,
-}
+    pub fn unmap(&mut self, buffer: vk::Buffer) {
+        if let Ok(pooled) = self.find(buffer) {
+            self.allocator.unmap(&pooled.block);
+        }
+    }
 
-impl BufferPool {
-    pub fn new(
-        device: Arc<ash::Device>,
-        memory_type_index: u32,
-        initial_size: vk::DeviceSize,
-    ) -> Result<Self, PhysicsError> {
-        Ok(Self {
-            device: device.clone(),
-            memory_pool: MemoryPool::new(device, memory_type_index, initial_size)?,
-            buffers: Vec::new(),
-        })
+    fn find(&self, buffer: vk::Buffer) -> Result<PooledBuffer, PhysicsError> {
+        self.buffers
+            .iter()
+            .find(|b| b.buffer == buffer)
+            .copied()
+            .ok_or_else(|| PhysicsError::InvalidOperation {
+                message: "Buffer does not belong to this pool".to_string(),
+                operation: "map".to_string(),
+                state: format!("{} buffers", self.buffers.len()),
+            })
     }
 
-    pub fn allocate_buffer(
+    // Compacts the physics memory, recording a copy into `command_buffer` for
+    // every buffer that moved. The moved buffers are recreated at their new
+    // location; once the commands completed, switch users over to the new
+    // handles and call `release_retired`. Only blocks holding nothing but
+    // physics allocations are touched.
+    pub fn defragment(
         &mut self,
-        size: vk::DeviceSize,
-        usage: vk::BufferUsageFlags,
-    ) -> Result<(vk::Buffer, vk::DeviceMemory, vk::DeviceSize), PhysicsError> {
-        // Create buffer
-        let buffer_info = vk::BufferCreateInfo::builder()
-            .size(size)
-            .usage(usage)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE)
-            .build();
-
-        let buffer = unsafe {
-            self.device.create_buffer(&buffer_info, None).map_err(|e| {
-                PhysicsError::InitializationFailed(format!("Failed to create buffer: {}", e))
-            })?
-        };
-
-        // Get memory requirements
-        let mem_requirements = unsafe { self.device.get_buffer_memory_requirements(buffer) };
-
-        // Allocate memory from pool
-        let (memory, offset) = self
-            .memory_pool
-            .allocate(mem_requirements.size, mem_requirements.alignment)?;
+        command_buffer: vk::CommandBuffer,
+    ) -> Result<Vec<BufferRelocation>, PhysicsError> {
+        let mut moved = Vec::new();
+        for relocation in self.allocator.defragment(MemoryTag::Physics) {
+            let Some(index) = self.buffers.iter().position(|b| {
+                b.block.memory == relocation.src_memory && b.block.offset == relocation.src_offset
+            }) else {
+                continue;
+            };
+            let old = self.buffers[index];
+            let new = self.create_buffer(old.size, old.usage)?;
+            self.bind(new, relocation.dst_memory, relocation.dst_offset)?;
+
+            let region = vk::BufferCopy {
+                src_offset: 0,
+                dst_offset: 0,
+                size: old.size,
+            };
+            unsafe {
+                self.device
+                    .cmd_copy_buffer(command_buffer, old.buffer, new, &[region]);
+            }
 
-        // Bind buffer to memory
-        unsafe {
-            self.device
-                .bind_buffer_memory(buffer, memory, offset)
-                .map_err(|e| {
-                    PhysicsError::InitializationFailed(format!(
-                        "Failed to bind buffer memory: {}",
-                        e
-                    ))
-                })?;
+            self.buffers[index] = PooledBuffer {
+                buffer: new,
+                block: MemoryBlock {
+                    memory: relocation.dst_memory,
+                    offset: relocation.dst_offset,
+                    ..old.block
+                },
+                ..old
+            };
+            self.retired.push(old.buffer);
+            moved.push(BufferRelocation {
+                old: old.buffer,
+                new,
+                memory: relocation.dst_memory,
+                offset: relocation.dst_offset,
+            });
         }
-
-        self.buffers.push(buffer);
-        Ok((buffer, memory, offset))
+        Ok(moved)
     }
 
-    pub fn free_buffer(
-        &mut self,
-        buffer: vk::Buffer,
-        memory: vk::DeviceMemory,
-        offset: vk::DeviceSize,
-    ) {
+    // Destroys the buffers and blocks left behind by `defragment`
+    pub fn release_retired(&mut self) {
         unsafe {
-            self.device.destroy_buffer(buffer, None);
-        }
-        self.memory_pool.free(memory, offset);
-        if let Some(pos) = self.buffers.iter().position(|&b| b == buffer) {
-            self.buffers.swap_remove(pos);
+            for buffer in self.retired.drain(..) {
+                self.device.destroy_buffer(buffer, None);
+            }
         }
+        self.allocator.release_retired();
     }
 
     pub fn cleanup(&mut self) {
         unsafe {
-            for buffer in &self.buffers {
-                self.device.destroy_buffer(*buffer, None);
+            for buffer in self.retired.drain(..) {
+                self.device.destroy_buffer(buffer, None);
+            }
+        }
+        for pooled in self.buffers.drain(..) {
+            unsafe {
+                self.device.destroy_buffer(pooled.buffer, None);
             }
+            let _ = self.allocator.free(pooled.block);
         }
-        self.buffers.clear();
-        self.memory_pool.cleanup();
+        self.allocator.release_retired();
     }
 
+    // Stats of the shared allocator, `by_tag[&MemoryTag::Physics]` holds the
+    // physics share
     pub fn get_memory_stats(&self) -> MemoryStats {
-        self.memory_pool.get_stats()
+        self.allocator.get_stats()
     }
 }
 
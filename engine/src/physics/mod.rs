@@ -2,20 +2,86 @@
 //!
 //! This module provides a high-performance particle physics implementation with:
 //! - Double buffering for efficient GPU-CPU synchronization
-//! - Memory pooling and dynamic resizing
-//! - Debug visualization and profiling support
-//! - Comprehensive error handling and recovery
+//! - GPU emitters, force fields and colliders
+//! - GPU neighbor search with SPH fluid as the first consumer
+//! - Buffers from the engine's shared memory allocator, with optional
+//!   defragmentation
+//! - A CPU reference backend for machines without a GPU and for cross-checks
+//! - Debug statistics with histograms, divergence detection and per-pass
+//!   GPU timings
+//! - Device loss recovery with a CPU fallback
+//! - Particle dumps to binary or CSV files for offline replay
 //! - Enhanced logging and error tracking
+//!
+//! Alongside it lives the CPU rigid/soft body world (`PhysicsWorld`) with
+//! a spatial-hash or dynamic AABB tree broad phase, collision layers, trigger
+//! volumes, opt-in continuous collision detection, scene queries, a kinematic
+//! character controller, XPBD cloth and tetrahedral soft bodies and an opt-in
+//! deterministic stepping mode with state hashing. Its shapes, contacts and
+//! broad phase can be drawn through `graphics::DebugDraw`.
 
+mod backend;
+mod bvh;
+mod ccd;
+mod character;
+mod collision;
+mod constraints;
 mod debug;
+mod debug_shapes;
+mod determinism;
+mod dump;
+mod emitter;
+mod events;
+mod filter;
+mod force_field;
 mod gpu_physics;
 pub mod logging;
+mod material;
 mod memory;
+mod particle_grid;
+mod particle_layout;
+#[allow(clippy::module_inception)]
+pub mod physics;
+mod query;
+mod recovery;
+mod rigid_body;
 mod shaders;
+mod soft_body;
+mod solver;
+mod spatial;
+mod timestamps;
+
+pub use backend::{CpuParticleBackend, ParticleBackend, ParticleMismatch};
+pub use bvh::DynamicAabbTree;
+pub use character::{CharacterController, CharacterMove};
+pub use constraints::{Constraint, DistanceConstraint, VolumeConstraint};
+pub use debug::{Conservation, DebugStats, DebugVisualization, Histogram, ParticleDebugView};
+pub use debug_shapes::PhysicsDebugOptions;
+pub use dump::ParticleDump;
+pub use emitter::{
+    EmitterBurst, EmitterShape, EmitterState, ParticleEmitter, VelocityDistribution,
+};
+pub use events::{CollisionEvents, ContactEvent, EventPhase, TriggerEvent};
+pub use filter::{CollisionFilter, IgnoredPairs, ALL_GROUPS};
+pub use force_field::{Falloff, ForceField, ForceFieldKind};
+pub use gpu_physics::{GpuPhysicsSystem, PhysicsError, SystemState};
+pub use material::{ContactMaterial, Damping, PhysicsMaterial};
+pub use memory::{BufferPool, BufferRelocation};
+pub use particle_grid::SphSettings;
+pub use particle_layout::{
+    validate_shader_layout, DrawIndirectArgs, Particle, PushConstants, PARTICLE_DRAW_VERTICES,
+    PARTICLE_LAYOUT_VERSION,
+};
+pub use physics::{PhysicsObject, PhysicsWorld};
+pub use query::{QueryFilter, QueryShape, RaycastHit};
+pub use recovery::{FaultHook, PhysicsHealth};
+pub use rigid_body::{cuboid_inertia, sphere_inertia};
+pub use soft_body::{ClothBuilder, SoftBodyBuilder, SoftBodyConstraint, SoftBodyHandle};
+pub use spatial::{BroadPhase, BroadPhaseKind, SpatialHash};
+pub use timestamps::PassTiming;
 
-pub use debug::{DebugStats, DebugVisualization, ParticleDebugView};
-pub use gpu_physics::{GpuPhysicsSystem, Particle, PhysicsError, PushConstants, SystemState};
-pub use memory::{BufferPool, MemoryStats};
+// Memory stats come from the engine-wide allocator the buffers live in
+pub use crate::memory::MemoryStats;
 
 // Re-export logging macros and initialization
 pub use logging::{
@@ -55,17 +121,17 @@ impl Default for PhysicsConfig {
     }
 }
 
-/// Create a new physics system with the specified configuration
-pub fn create_physics_system(
-    device: std::sync::Arc<ash::Device>,
-    physical_device: ash::vk::PhysicalDevice,
-    queue_family_index: u32,
+/// Set up a physics system on the given backend, either a
+/// `GpuPhysicsSystem` or a `CpuParticleBackend`, with the specified
+/// configuration
+pub fn create_physics_system<B: ParticleBackend>(
+    mut physics: B,
     config: Option<PhysicsConfig>,
-) -> Result<(GpuPhysicsSystem, DebugVisualization), PhysicsError> {
+) -> Result<(B, DebugVisualization), PhysicsError> {
     let config = config.unwrap_or_default();
 
-    let mut physics = GpuPhysicsSystem::new(device, physical_device, queue_family_index)?;
-    physics.debug_enabled = config.debug_enabled; // Set the debug flag
+    physics.set_debug_enabled(config.debug_enabled);
+    physics.set_max_recovery_attempts(config.max_recovery_attempts);
 
     let mut debug = DebugVisualization::new(config.debug_sample_rate);
     if config.debug_enabled {
@@ -78,8 +144,10 @@ pub fn create_physics_system(
 /// Re-export common types and traits
 pub mod prelude {
     pub use super::{
-        create_physics_system, DebugStats, DebugVisualization, GpuPhysicsSystem, MemoryStats,
-        Particle, PhysicsConfig, PhysicsError,
+        create_physics_system, CollisionEvents, CollisionFilter, CpuParticleBackend, DebugStats,
+        DebugVisualization, GpuPhysicsSystem, MemoryStats, Particle, ParticleBackend,
+        PhysicsConfig, PhysicsError, PhysicsMaterial, PhysicsObject, PhysicsWorld, QueryFilter,
+        RaycastHit,
     };
 }
 
@@ -97,4 +165,17 @@ mod tests {
         assert_eq!(config.initial_pool_size, 1024 * 1024);
         assert_eq!(config.max_recovery_attempts, 3);
     }
+
+    #[test]
+    fn test_create_cpu_physics_system() {
+        let config = PhysicsConfig {
+            debug_enabled: true,
+            ..Default::default()
+        };
+        let (physics, debug) =
+            create_physics_system(CpuParticleBackend::new(16), Some(config)).unwrap();
+        assert_eq!(physics.name(), "cpu");
+        assert_eq!(physics.capacity(), 16);
+        assert!(debug.is_enabled());
+    }
 }
@@ -0,0 +1,628 @@
+// Neighbor search for GPU particles. Every step, a counting sort in three
+// compute passes (`grid_hash.comp`, `grid_prefix_sum.comp` and
+// `grid_reorder.comp`) groups the alive particles by the hashed grid cell they
+// are in. Neighbor passes then look up the particles near each particle
+// through `shaders/particle_grid.glsl` and leave forces for
+// `particle_update.comp` to apply. SPH density and pressure (`sph.comp`) is
+// the built-in neighbor pass.
+//
+// `ParticleGrid` and `sph_forces` mirror the shaders on the CPU so they can be
+// tested without a device, keep the two in sync.
+
+use ash::vk;
+use glam::{IVec3, Vec3};
+use std::f32::consts::PI;
+use std::mem::size_of;
+
+use crate::physics::emitter::hash;
+use crate::physics::gpu_physics::{GpuPhysicsSystem, HostBuffer, PhysicsError};
+use crate::physics::particle_layout::{Particle, PushConstants};
+
+const GRID_NEIGHBOR_CELLS: u32 = 27;
+const INVALID_BUCKET: u32 = u32::MAX;
+
+// Sub-passes of `sph.comp`: densities, then forces
+const SPH_SUB_PASSES: u32 = 2;
+const SPH_PASS_NAME: &str = "sph";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SphSettings {
+    // Interaction radius, also the neighbor grid cell size
+    pub smoothing_radius: f32,
+    // Density the fluid settles at. Depends on particle mass and spacing, a
+    // particle alone has a density of about `1.57 * mass / radius^3`.
+    pub rest_density: f32,
+    // Pressure per unit of density above the rest density
+    pub stiffness: f32,
+    pub viscosity: f32,
+}
+
+impl SphSettings {
+    pub fn new(smoothing_radius: f32, rest_density: f32) -> Self {
+        Self {
+            smoothing_radius,
+            rest_density,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_stiffness(mut self, stiffness: f32) -> Self {
+        self.stiffness = stiffness;
+        self
+    }
+
+    pub fn with_viscosity(mut self, viscosity: f32) -> Self {
+        self.viscosity = viscosity;
+        self
+    }
+
+    fn pressure(&self, density: f32) -> f32 {
+        self.stiffness * (density - self.rest_density).max(0.0)
+    }
+}
+
+impl Default for SphSettings {
+    fn default() -> Self {
+        Self {
+            smoothing_radius: 1.0,
+            rest_density: 5.0,
+            stiffness: 20.0,
+            viscosity: 0.1,
+        }
+    }
+}
+
+// Number of hash buckets for `capacity` particles, a power of two
+pub(crate) fn bucket_count(capacity: usize) -> u32 {
+    capacity.max(1).next_power_of_two() as u32
+}
+
+pub(crate) fn grid_cell(position: Vec3, cell_size: f32) -> IVec3 {
+    (position / cell_size).floor().as_ivec3()
+}
+
+pub(crate) fn grid_bucket(cell: IVec3, bucket_count: u32) -> u32 {
+    hash(cell.x as u32 ^ hash(cell.y as u32 ^ hash(cell.z as u32))) & (bucket_count - 1)
+}
+
+fn neighbor_offset(n: u32) -> IVec3 {
+    IVec3::new((n % 3) as i32, ((n / 3) % 3) as i32, (n / 9) as i32) - IVec3::ONE
+}
+
+// The sorted grid as the GPU passes leave it
+pub(crate) struct ParticleGrid {
+    cell_size: f32,
+    bucket_count: u32,
+    // First sorted index of every bucket, plus the total at the end
+    starts: Vec<u32>,
+    // Alive particle indices grouped by bucket
+    sorted: Vec<u32>,
+}
+
+impl ParticleGrid {
+    pub(crate) fn build(particles: &[Particle], cell_size: f32, bucket_count: u32) -> Self {
+        // grid_hash.comp
+        let mut counts = vec![0u32; bucket_count as usize];
+        let entries: Vec<(u32, u32)> = particles
+            .iter()
+            .map(|particle| {
+                if !particle.is_alive() {
+                    return (INVALID_BUCKET, 0);
+                }
+                let cell = grid_cell(Vec3::from(particle.position), cell_size);
+                let bucket = grid_bucket(cell, bucket_count);
+                let rank = counts[bucket as usize];
+                counts[bucket as usize] += 1;
+                (bucket, rank)
+            })
+            .collect();
+
+        // grid_prefix_sum.comp
+        let mut starts = Vec::with_capacity(counts.len() + 1);
+        let mut total = 0;
+        for count in counts {
+            starts.push(total);
+            total += count;
+        }
+        starts.push(total);
+
+        // grid_reorder.comp
+        let mut sorted = vec![0u32; total as usize];
+        for (index, &(bucket, rank)) in entries.iter().enumerate() {
+            if bucket != INVALID_BUCKET {
+                sorted[(starts[bucket as usize] + rank) as usize] = index as u32;
+            }
+        }
+
+        Self {
+            cell_size,
+            bucket_count,
+            starts,
+            sorted,
+        }
+    }
+
+    // Indices of the particles in the cells around `position`, each at most
+    // once. Includes particles from distant cells sharing a bucket, so
+    // callers still need to check distances.
+    pub(crate) fn neighbors(&self, position: Vec3) -> Vec<u32> {
+        let cell = grid_cell(position, self.cell_size);
+        let mut visited = Vec::with_capacity(GRID_NEIGHBOR_CELLS as usize);
+        let mut neighbors = Vec::new();
+        for n in 0..GRID_NEIGHBOR_CELLS {
+            let bucket = grid_bucket(cell + neighbor_offset(n), self.bucket_count);
+            if visited.contains(&bucket) {
+                continue;
+            }
+            visited.push(bucket);
+            let range =
+                self.starts[bucket as usize] as usize..self.starts[bucket as usize + 1] as usize;
+            neighbors.extend_from_slice(&self.sorted[range]);
+        }
+        neighbors
+    }
+}
+
+// SPH densities and forces as `sph.comp` computes them. Dead particles get
+// zero for both.
+pub(crate) fn sph_forces(
+    particles: &[Particle],
+    grid: &ParticleGrid,
+    settings: &SphSettings,
+) -> (Vec<f32>, Vec<Vec3>) {
+    let h = settings.smoothing_radius;
+    let h2 = h * h;
+    let poly6 = 315.0 / (64.0 * PI * h.powi(9));
+    let spiky = -45.0 / (PI * h.powi(6));
+    let laplacian = 45.0 / (PI * h.powi(6));
+
+    let densities: Vec<f32> = particles
+        .iter()
+        .map(|particle| {
+            if !particle.is_alive() {
+                return 0.0;
+            }
+            let position = Vec3::from(particle.position);
+            grid.neighbors(position)
+                .into_iter()
+                .map(|j| &particles[j as usize])
+                .map(|other| {
+                    let r2 = position.distance_squared(Vec3::from(other.position));
+                    if r2 < h2 {
+                        other.mass * poly6 * (h2 - r2).powi(3)
+                    } else {
+                        0.0
+                    }
+                })
+                .sum()
+        })
+        .collect();
+
+    let forces = particles
+        .iter()
+        .enumerate()
+        .map(|(i, particle)| {
+            if !particle.is_alive() {
+                return Vec3::ZERO;
+            }
+            let position = Vec3::from(particle.position);
+            let velocity = Vec3::from(particle.velocity);
+            let density = densities[i].max(1e-6);
+            let pressure_term = settings.pressure(density) / (density * density);
+
+            let mut acceleration = Vec3::ZERO;
+            for j in grid.neighbors(position) {
+                let j = j as usize;
+                if j == i {
+                    continue;
+                }
+                let other = &particles[j];
+                let offset = position - Vec3::from(other.position);
+                let r = offset.length();
+                if r >= h || r < 1e-6 {
+                    continue;
+                }
+                let other_density = densities[j].max(1e-6);
+                let other_term = settings.pressure(other_density) / (other_density * other_density);
+                let gradient = spiky * (h - r) * (h - r) * offset / r;
+                acceleration -= other.mass * (pressure_term + other_term) * gradient;
+                acceleration +=
+                    settings.viscosity * other.mass * (Vec3::from(other.velocity) - velocity)
+                        / other_density
+                        * laplacian
+                        * (h - r);
+            }
+            acceleration * particle.mass
+        })
+        .collect();
+
+    (densities, forces)
+}
+
+// Buffers behind the neighbor grid and the neighbor passes
+pub(crate) struct GridBuffers {
+    pub(crate) counts: HostBuffer,
+    pub(crate) starts: HostBuffer,
+    pub(crate) entries: HostBuffer,
+    pub(crate) sorted: HostBuffer,
+    pub(crate) densities: HostBuffer,
+    pub(crate) forces: HostBuffer,
+}
+
+pub(crate) struct GridPipelines {
+    pub(crate) hash: vk::Pipeline,
+    pub(crate) prefix_sum: vk::Pipeline,
+    pub(crate) reorder: vk::Pipeline,
+}
+
+// A compute pass run over all particles after the grid is built, `sub_passes`
+// times in a row with `params.pass_index` counting up. Keeps its source so
+// the pipeline can be rebuilt after a device loss.
+pub(crate) struct NeighborPass {
+    name: String,
+    source: String,
+    pipeline: vk::Pipeline,
+    sub_passes: u32,
+}
+
+impl GpuPhysicsSystem {
+    // Builds the neighbor grid every step with the given cell size, or stops
+    // building it. Neighbor passes only see particles within one cell of
+    // each other, so the cell size should be at least their interaction
+    // radius.
+    pub fn set_neighbor_grid(&mut self, cell_size: Option<f32>) {
+        self.grid_cell_size = cell_size.filter(|size| *size > 0.0);
+    }
+
+    // Adds a compute pass that runs after the grid is built and before the
+    // particles are integrated. The source can `#include
+    // "particle_common.glsl"` and `"particle_grid.glsl"`, and writes forces
+    // to binding 12 for the update pass to apply.
+    pub fn add_neighbor_pass(
+        &mut self,
+        name: &str,
+        source: &str,
+        sub_passes: u32,
+    ) -> Result<(), PhysicsError> {
+        let layout = self
+            .pipeline_layout
+            .ok_or_else(|| PhysicsError::InvalidOperation {
+                message: "Neighbor pass added before initialization".to_string(),
+                operation: "add_neighbor_pass".to_string(),
+                state: format!("{:?}", self.state),
+            })?;
+        let pipeline = self.create_pipeline(name, source, layout)?;
+        self.neighbor_passes.push(NeighborPass {
+            name: name.to_string(),
+            source: source.to_string(),
+            pipeline,
+            sub_passes: sub_passes.max(1),
+        });
+        Ok(())
+    }
+
+    pub fn remove_neighbor_pass(&mut self, name: &str) -> bool {
+        let Some(index) = self
+            .neighbor_passes
+            .iter()
+            .position(|pass| pass.name == name)
+        else {
+            return false;
+        };
+        let pass = self.neighbor_passes.remove(index);
+        unsafe {
+            let _ = self.device.device_wait_idle();
+            self.device.destroy_pipeline(pass.pipeline, None);
+        }
+        true
+    }
+
+    // Turns SPH fluid simulation on or off. Enabling it also turns on the
+    // neighbor grid with the smoothing radius as cell size.
+    pub fn set_fluid(&mut self, settings: Option<SphSettings>) -> Result<(), PhysicsError> {
+        let enabled = self
+            .neighbor_passes
+            .iter()
+            .any(|pass| pass.name == SPH_PASS_NAME);
+        match settings {
+            Some(settings) => {
+                if !enabled {
+                    self.add_neighbor_pass(
+                        SPH_PASS_NAME,
+                        include_str!("shaders/sph.comp"),
+                        SPH_SUB_PASSES,
+                    )?;
+                }
+                self.set_neighbor_grid(Some(settings.smoothing_radius));
+            }
+            None => {
+                self.remove_neighbor_pass(SPH_PASS_NAME);
+                if self.neighbor_passes.is_empty() {
+                    self.set_neighbor_grid(None);
+                }
+            }
+        }
+        self.fluid = settings;
+        Ok(())
+    }
+
+    pub fn fluid(&self) -> Option<SphSettings> {
+        self.fluid
+    }
+
+    // SPH densities from the last step, zero for dead particles
+    pub fn densities(&self) -> Vec<f32> {
+        self.grid_buffers
+            .as_ref()
+            .map(|buffers| buffers.densities.read(0, self.capacity()))
+            .unwrap_or_default()
+    }
+
+    // Fills in the grid and fluid parameters for this step
+    pub(crate) fn grid_push_constants(&self, push_constants: &mut PushConstants) {
+        push_constants.cell_size = self.grid_cell_size.unwrap_or(0.0);
+        push_constants.cell_count = bucket_count(self.capacity());
+        if let Some(fluid) = self.fluid {
+            push_constants.rest_density = fluid.rest_density;
+            push_constants.stiffness = fluid.stiffness;
+            push_constants.viscosity = fluid.viscosity;
+        }
+    }
+
+    pub(crate) fn allocate_grid_buffers(&mut self, capacity: usize) -> Result<(), PhysicsError> {
+        self.free_grid_buffers();
+
+        let storage = vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST;
+        let capacity = capacity.max(1);
+        let buckets = bucket_count(capacity) as usize;
+        let index_size = (capacity * size_of::<u32>()) as u64;
+        let buffers = GridBuffers {
+            counts: self.allocate_host_buffer((buckets * size_of::<u32>()) as u64, storage)?,
+            starts: self
+                .allocate_host_buffer(((buckets + 1) * size_of::<u32>()) as u64, storage)?,
+            entries: self.allocate_host_buffer(2 * index_size, storage)?,
+            sorted: self.allocate_host_buffer(index_size, storage)?,
+            densities: self.allocate_host_buffer(index_size, storage)?,
+            forces: self
+                .allocate_host_buffer((capacity * size_of::<[f32; 4]>()) as u64, storage)?,
+        };
+        buffers.densities.write(0, &vec![0.0f32; capacity])?;
+        buffers.forces.write(0, &vec![[0.0f32; 4]; capacity])?;
+        self.grid_buffers = Some(buffers);
+        Ok(())
+    }
+
+    pub(crate) fn free_grid_buffers(&mut self) {
+        if let Some(buffers) = self.grid_buffers.take() {
+            for buffer in [
+                buffers.counts,
+                buffers.starts,
+                buffers.entries,
+                buffers.sorted,
+                buffers.densities,
+                buffers.forces,
+            ] {
+                self.free_host_buffer(buffer);
+            }
+        }
+    }
+
+    // Creates the counting sort pipelines and recreates the ones of the
+    // neighbor passes added so far
+    pub(crate) fn create_grid_pipelines(
+        &mut self,
+        layout: vk::PipelineLayout,
+    ) -> Result<(), PhysicsError> {
+        for index in 0..self.neighbor_passes.len() {
+            let pass = &self.neighbor_passes[index];
+            let pipeline = self.create_pipeline(&pass.name, &pass.source, layout)?;
+            self.neighbor_passes[index].pipeline = pipeline;
+        }
+        self.grid_pipelines = Some(GridPipelines {
+            hash: self.create_pipeline(
+                "grid_hash",
+                include_str!("shaders/grid_hash.comp"),
+                layout,
+            )?,
+            prefix_sum: self.create_pipeline(
+                "grid_prefix_sum",
+                include_str!("shaders/grid_prefix_sum.comp"),
+                layout,
+            )?,
+            reorder: self.create_pipeline(
+                "grid_reorder",
+                include_str!("shaders/grid_reorder.comp"),
+                layout,
+            )?,
+        });
+        Ok(())
+    }
+
+    // Destroys the pipelines, the neighbor passes stay registered
+    pub(crate) fn destroy_grid_pipelines(&mut self) {
+        let pipelines = self.grid_pipelines.take();
+        unsafe {
+            if let Some(pipelines) = pipelines {
+                self.device.destroy_pipeline(pipelines.hash, None);
+                self.device.destroy_pipeline(pipelines.prefix_sum, None);
+                self.device.destroy_pipeline(pipelines.reorder, None);
+            }
+            for pass in &mut self.neighbor_passes {
+                self.device.destroy_pipeline(pass.pipeline, None);
+                pass.pipeline = vk::Pipeline::null();
+            }
+        }
+    }
+
+    // Records the counting sort and the neighbor passes. Does nothing while
+    // the grid is off.
+    pub(crate) fn record_grid_passes(
+        &self,
+        cmd: vk::CommandBuffer,
+        layout: vk::PipelineLayout,
+        push_constants: &PushConstants,
+    ) {
+        let (Some(_), Some(buffers), Some(pipelines)) = (
+            self.grid_cell_size,
+            &self.grid_buffers,
+            &self.grid_pipelines,
+        ) else {
+            return;
+        };
+        let particle_groups = self
+            .particle_capacity
+            .div_ceil(super::gpu_physics::WORKGROUP_SIZE);
+
+        unsafe {
+            self.device
+                .cmd_fill_buffer(cmd, buffers.counts.buffer, 0, vk::WHOLE_SIZE, 0);
+            self.grid_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+            );
+
+            for (pipeline, groups) in [
+                (pipelines.hash, particle_groups),
+                (pipelines.prefix_sum, 1),
+                (pipelines.reorder, particle_groups),
+            ] {
+                self.device
+                    .cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, pipeline);
+                self.device.cmd_dispatch(cmd, groups, 1, 1);
+                self.grid_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::AccessFlags::SHADER_WRITE,
+                );
+            }
+            self.mark_pass(cmd, "grid");
+
+            for pass in &self.neighbor_passes {
+                self.device
+                    .cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, pass.pipeline);
+                for pass_index in 0..pass.sub_passes {
+                    let constants = PushConstants {
+                        pass_index,
+                        ..*push_constants
+                    };
+                    self.device.cmd_push_constants(
+                        cmd,
+                        layout,
+                        vk::ShaderStageFlags::COMPUTE,
+                        0,
+                        bytemuck::bytes_of(&constants),
+                    );
+                    self.device.cmd_dispatch(cmd, particle_groups, 1, 1);
+                    self.grid_barrier(
+                        cmd,
+                        vk::PipelineStageFlags::COMPUTE_SHADER,
+                        vk::AccessFlags::SHADER_WRITE,
+                    );
+                }
+                self.mark_pass(cmd, &pass.name);
+            }
+
+            // Later passes expect the step's own push constants
+            self.device.cmd_push_constants(
+                cmd,
+                layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                bytemuck::bytes_of(push_constants),
+            );
+        }
+    }
+
+    unsafe fn grid_barrier(
+        &self,
+        cmd: vk::CommandBuffer,
+        src_stage: vk::PipelineStageFlags,
+        src_access: vk::AccessFlags,
+    ) {
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(src_access)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+            .build();
+        self.device.cmd_pipeline_barrier(
+            cmd,
+            src_stage,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::DependencyFlags::empty(),
+            &[barrier],
+            &[],
+            &[],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particles() -> Vec<Particle> {
+        let mut particles: Vec<Particle> = (0..64)
+            .map(|i| {
+                let i = i as f32;
+                Particle::new(
+                    [
+                        (i * 0.37).sin() * 3.0,
+                        (i * 0.91).cos() * 3.0,
+                        i * 0.05 - 1.6,
+                    ],
+                    [0.0; 3],
+                )
+            })
+            .collect();
+        particles[5].lifetime = 0.0;
+        particles
+    }
+
+    #[test]
+    fn test_grid_finds_all_neighbors() {
+        let particles = particles();
+        // Few buckets so distant cells collide
+        let grid = ParticleGrid::build(&particles, 1.0, 8);
+        assert_eq!(grid.sorted.len(), 63);
+        assert_eq!(*grid.starts.last().unwrap(), 63);
+
+        for particle in particles.iter().filter(|p| p.is_alive()) {
+            let position = Vec3::from(particle.position);
+            let mut found = grid.neighbors(position);
+            let count = found.len();
+            found.sort_unstable();
+            found.dedup();
+            assert_eq!(found.len(), count, "a particle was visited twice");
+
+            for (j, other) in particles.iter().enumerate() {
+                let close = position.distance(Vec3::from(other.position)) < 1.0;
+                if close && other.is_alive() {
+                    assert!(found.contains(&(j as u32)));
+                }
+            }
+            assert!(!found.contains(&5));
+        }
+    }
+
+    #[test]
+    fn test_sph_pressure_pushes_apart() {
+        let settings = SphSettings::new(1.0, 1.0)
+            .with_stiffness(10.0)
+            .with_viscosity(0.0);
+        let particles = [
+            Particle::new([0.0, 0.0, 0.0], [0.0; 3]),
+            Particle::new([0.5, 0.0, 0.0], [0.0; 3]),
+            Particle::new([5.0, 0.0, 0.0], [0.0; 3]),
+        ];
+        let grid = ParticleGrid::build(&particles, settings.smoothing_radius, bucket_count(3));
+        let (densities, forces) = sph_forces(&particles, &grid, &settings);
+
+        // Alone, a particle only feels its own kernel
+        assert!((densities[2] - 315.0 / (64.0 * PI)).abs() < 1e-4);
+        assert_eq!(forces[2], Vec3::ZERO);
+
+        assert!(densities[0] > densities[2]);
+        assert!(forces[0].x < 0.0 && forces[1].x > 0.0);
+        assert!((forces[0] + forces[1]).length() < 1e-4);
+    }
+}
@@ -0,0 +1,514 @@
+// Memory layout shared by the CPU and the particle compute shaders
+// (`shaders/particle_emit.comp` and `shaders/particle_update.comp`).
+//
+// The structs below are the single source of truth. The shaders mirror them
+// with std430 rules, the shared parts in `shaders/particle_common.glsl`, and
+// declare `PARTICLE_LAYOUT_VERSION` as specialization constant 3;
+// `validate_shader_layout` reflects the compiled SPIR-V at startup and
+// refuses to build a pipeline if anything disagrees. Bump the version on both
+// sides whenever a field changes.
+
+use bytemuck::{Pod, Zeroable};
+use memoffset::offset_of;
+use std::mem::size_of;
+
+use crate::physics::emitter::GpuEmitter;
+use crate::physics::force_field::GpuForceField;
+use crate::physics::gpu_physics::PhysicsError;
+use crate::physics::shaders::{BlockLayout, ShaderReflection};
+
+pub const PARTICLE_LAYOUT_VERSION: u32 = 4;
+
+// Specialization constant holding the version in the shader. 0-2 are the
+// workgroup size.
+pub const LAYOUT_VERSION_CONSTANT_ID: u32 = 3;
+
+pub const PARTICLE_DESCRIPTOR_SET: u32 = 0;
+pub const PARTICLE_INPUT_BINDING: u32 = 0;
+pub const PARTICLE_OUTPUT_BINDING: u32 = 1;
+// Emitters spawning this step, see `emitter::GpuEmitter`
+pub const EMITTER_BINDING: u32 = 2;
+// `{ int count; uint indices[]; }`, slots of dead particles
+pub const FREE_LIST_BINDING: u32 = 3;
+// `{ uint indices[]; }`, slots of the particles alive after the step
+pub const ALIVE_LIST_BINDING: u32 = 4;
+// `DrawIndirectArgs`, the instance count is the number of alive particles
+pub const DRAW_ARGS_BINDING: u32 = 5;
+// Force fields and colliders, see `force_field::GpuForceField`
+pub const FORCE_FIELD_BINDING: u32 = 6;
+// Neighbor grid, see `particle_grid` and `shaders/particle_grid.glsl`:
+// `{ uint counts[]; }` per bucket
+pub const GRID_COUNT_BINDING: u32 = 7;
+// `{ uint starts[]; }` per bucket plus one
+pub const GRID_START_BINDING: u32 = 8;
+// `{ uvec2 entries[]; }` bucket and rank per particle
+pub const GRID_ENTRY_BINDING: u32 = 9;
+// `{ uint indices[]; }` alive particles sorted by bucket
+pub const GRID_SORTED_BINDING: u32 = 10;
+// `{ float densities[]; }` per particle, written by SPH
+pub const DENSITY_BINDING: u32 = 11;
+// `{ vec4 forces[]; }` per particle, written by neighbor passes
+pub const INTERACTION_FORCE_BINDING: u32 = 12;
+
+pub(crate) const PARTICLE_BINDINGS: [u32; 13] = [
+    PARTICLE_INPUT_BINDING,
+    PARTICLE_OUTPUT_BINDING,
+    EMITTER_BINDING,
+    FREE_LIST_BINDING,
+    ALIVE_LIST_BINDING,
+    DRAW_ARGS_BINDING,
+    FORCE_FIELD_BINDING,
+    GRID_COUNT_BINDING,
+    GRID_START_BINDING,
+    GRID_ENTRY_BINDING,
+    GRID_SORTED_BINDING,
+    DENSITY_BINDING,
+    INTERACTION_FORCE_BINDING,
+];
+
+// Each alive particle is drawn as one instance of a two-triangle billboard
+pub const PARTICLE_DRAW_VERTICES: u32 = 6;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Pod, Zeroable)]
+pub struct Particle {
+    pub position: [f32; 3],
+    // Zero pins the particle in place
+    pub mass: f32,
+    pub velocity: [f32; 3],
+    // Seconds left to live, the particle is dead once it reaches zero
+    pub lifetime: f32,
+    pub color: [f32; 4],
+}
+
+impl Particle {
+    // A particle of unit mass that lives forever
+    pub fn new(position: [f32; 3], velocity: [f32; 3]) -> Self {
+        Self {
+            position,
+            mass: 1.0,
+            velocity,
+            lifetime: f32::INFINITY,
+            color: [1.0; 4],
+        }
+    }
+
+    pub fn with_mass(mut self, mass: f32) -> Self {
+        self.mass = mass;
+        self
+    }
+
+    pub fn with_lifetime(mut self, lifetime: f32) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    pub fn with_color(mut self, color: [f32; 4]) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.lifetime > 0.0
+    }
+
+    pub(crate) fn layout() -> BlockLayout {
+        BlockLayout::new("Particle", size_of::<Self>())
+            .with_member("position", offset_of!(Self, position), 12)
+            .with_member("mass", offset_of!(Self, mass), 4)
+            .with_member("velocity", offset_of!(Self, velocity), 12)
+            .with_member("lifetime", offset_of!(Self, lifetime), 4)
+            .with_member("color", offset_of!(Self, color), 16)
+    }
+}
+
+impl Default for Particle {
+    fn default() -> Self {
+        Self::new([0.0; 3], [0.0; 3])
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Pod, Zeroable)]
+pub struct PushConstants {
+    pub gravity: [f32; 3],
+    pub delta_time: f32,
+    // Minimum and maximum coordinate on every axis
+    pub bounds: [f32; 2],
+    pub max_velocity: f32,
+    pub particle_count: u32,
+    pub emitter_count: u32,
+    // Particles spawned this step over all emitters
+    pub spawn_count: u32,
+    pub field_count: u32,
+    // Seconds since the simulation started, animates turbulence
+    pub time: f32,
+    // Neighbor grid cell size, zero while the grid is off
+    pub cell_size: f32,
+    // Neighbor grid hash buckets, a power of two
+    pub cell_count: u32,
+    // Sub-pass of the neighbor pass being run
+    pub pass_index: u32,
+    // SPH fluid parameters, see `particle_grid::SphSettings`
+    pub rest_density: f32,
+    pub stiffness: f32,
+    pub viscosity: f32,
+}
+
+impl PushConstants {
+    pub fn new(delta_time: f32, particle_count: u32) -> Self {
+        Self {
+            gravity: [0.0, -9.81, 0.0],
+            delta_time,
+            bounds: [-100.0, 100.0],
+            max_velocity: 100.0,
+            particle_count,
+            emitter_count: 0,
+            spawn_count: 0,
+            field_count: 0,
+            time: 0.0,
+            cell_size: 0.0,
+            cell_count: 0,
+            pass_index: 0,
+            rest_density: 0.0,
+            stiffness: 0.0,
+            viscosity: 0.0,
+        }
+    }
+
+    pub fn with_gravity(mut self, gravity: [f32; 3]) -> Self {
+        self.gravity = gravity;
+        self
+    }
+
+    pub fn with_bounds(mut self, min: f32, max: f32) -> Self {
+        self.bounds = [min, max];
+        self
+    }
+
+    pub fn with_max_velocity(mut self, max_velocity: f32) -> Self {
+        self.max_velocity = max_velocity;
+        self
+    }
+
+    pub(crate) fn layout() -> BlockLayout {
+        BlockLayout::new("PushConstants", size_of::<Self>())
+            .with_member("gravity", offset_of!(Self, gravity), 12)
+            .with_member("delta_time", offset_of!(Self, delta_time), 4)
+            .with_member("bounds", offset_of!(Self, bounds), 8)
+            .with_member("max_velocity", offset_of!(Self, max_velocity), 4)
+            .with_member("particle_count", offset_of!(Self, particle_count), 4)
+            .with_member("emitter_count", offset_of!(Self, emitter_count), 4)
+            .with_member("spawn_count", offset_of!(Self, spawn_count), 4)
+            .with_member("field_count", offset_of!(Self, field_count), 4)
+            .with_member("time", offset_of!(Self, time), 4)
+            .with_member("cell_size", offset_of!(Self, cell_size), 4)
+            .with_member("cell_count", offset_of!(Self, cell_count), 4)
+            .with_member("pass_index", offset_of!(Self, pass_index), 4)
+            .with_member("rest_density", offset_of!(Self, rest_density), 4)
+            .with_member("stiffness", offset_of!(Self, stiffness), 4)
+            .with_member("viscosity", offset_of!(Self, viscosity), 4)
+    }
+}
+
+// Matches `VkDrawIndirectCommand`, so the buffer can be handed straight to
+// `vkCmdDrawIndirect`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Pod, Zeroable)]
+pub struct DrawIndirectArgs {
+    pub vertex_count: u32,
+    pub instance_count: u32,
+    pub first_vertex: u32,
+    pub first_instance: u32,
+}
+
+impl DrawIndirectArgs {
+    // Arguments before a step, with no particle counted alive yet
+    pub fn new(vertex_count: u32) -> Self {
+        Self {
+            vertex_count,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn layout() -> BlockLayout {
+        BlockLayout::new("DrawArgs", size_of::<Self>())
+            .with_member("vertex_count", offset_of!(Self, vertex_count), 4)
+            .with_member("instance_count", offset_of!(Self, instance_count), 4)
+            .with_member("first_vertex", offset_of!(Self, first_vertex), 4)
+            .with_member("first_instance", offset_of!(Self, first_instance), 4)
+    }
+}
+
+// Byte offset of the indices in the free list buffer, after the count
+pub(crate) const FREE_LIST_HEADER: usize = size_of::<i32>();
+
+fn free_list_layout() -> BlockLayout {
+    BlockLayout::new("FreeList", FREE_LIST_HEADER)
+        .with_member("count", 0, 4)
+        .with_member("indices", FREE_LIST_HEADER, 0)
+}
+
+// A block holding nothing but a runtime array
+fn array_layout(name: &str, member: &str) -> BlockLayout {
+    BlockLayout::new(name, 0).with_member(member, 0, 0)
+}
+
+// What a particle shader may declare at each binding
+enum ExpectedBuffer {
+    // A runtime array of structs
+    Elements(BlockLayout),
+    // A block checked as a whole
+    Block(BlockLayout),
+}
+
+fn expected_buffers() -> [(u32, ExpectedBuffer); 13] {
+    [
+        (
+            PARTICLE_INPUT_BINDING,
+            ExpectedBuffer::Elements(Particle::layout()),
+        ),
+        (
+            PARTICLE_OUTPUT_BINDING,
+            ExpectedBuffer::Elements(Particle::layout()),
+        ),
+        (
+            EMITTER_BINDING,
+            ExpectedBuffer::Elements(GpuEmitter::layout()),
+        ),
+        (FREE_LIST_BINDING, ExpectedBuffer::Block(free_list_layout())),
+        (
+            ALIVE_LIST_BINDING,
+            ExpectedBuffer::Block(array_layout("AliveList", "indices")),
+        ),
+        (
+            DRAW_ARGS_BINDING,
+            ExpectedBuffer::Block(DrawIndirectArgs::layout()),
+        ),
+        (
+            FORCE_FIELD_BINDING,
+            ExpectedBuffer::Elements(GpuForceField::layout()),
+        ),
+        (
+            GRID_COUNT_BINDING,
+            ExpectedBuffer::Block(array_layout("GridCounts", "counts")),
+        ),
+        (
+            GRID_START_BINDING,
+            ExpectedBuffer::Block(array_layout("GridStarts", "starts")),
+        ),
+        (
+            GRID_ENTRY_BINDING,
+            ExpectedBuffer::Block(array_layout("GridEntries", "entries")),
+        ),
+        (
+            GRID_SORTED_BINDING,
+            ExpectedBuffer::Block(array_layout("GridSorted", "indices")),
+        ),
+        (
+            DENSITY_BINDING,
+            ExpectedBuffer::Block(array_layout("Densities", "densities")),
+        ),
+        (
+            INTERACTION_FORCE_BINDING,
+            ExpectedBuffer::Block(array_layout("InteractionForces", "forces")),
+        ),
+    ]
+}
+
+// Checks a compiled particle shader against the CPU layout, listing every
+// mismatch in the error. Shaders only declare the bindings they use, but
+// every one of them must match.
+pub fn validate_shader_layout(spirv: &[u32]) -> Result<(), PhysicsError> {
+    let reflection =
+        ShaderReflection::parse(spirv).map_err(|e| PhysicsError::InitializationFailed {
+            message: e.to_string(),
+            component: "ParticleLayout".to_string(),
+            source: Some(Box::new(e)),
+        })?;
+
+    let mut mismatches = Vec::new();
+
+    match reflection.spec_constant(LAYOUT_VERSION_CONSTANT_ID) {
+        Some(PARTICLE_LAYOUT_VERSION) => {}
+        Some(version) => mismatches.push(format!(
+            "shader layout version {}, expected {}",
+            version, PARTICLE_LAYOUT_VERSION
+        )),
+        None => mismatches.push(format!(
+            "shader does not declare the layout version as constant {}",
+            LAYOUT_VERSION_CONSTANT_ID
+        )),
+    }
+
+    match reflection.push_constants() {
+        Some(block) => mismatches.extend(block.mismatches(&PushConstants::layout())),
+        None => mismatches.push("shader has no push constant block".to_string()),
+    }
+
+    for (binding, expected) in expected_buffers() {
+        if reflection
+            .storage_buffer_block(PARTICLE_DESCRIPTOR_SET, binding)
+            .is_none()
+        {
+            continue;
+        }
+        let found = match expected {
+            ExpectedBuffer::Elements(layout) => {
+                match reflection.storage_buffer_element(PARTICLE_DESCRIPTOR_SET, binding) {
+                    Some((stride, element)) => {
+                        let mut found = element.mismatches(&layout);
+                        if stride != layout.size {
+                            found.push(format!(
+                                "{} stride {} bytes, expected {}",
+                                layout.name, stride, layout.size
+                            ));
+                        }
+                        found
+                    }
+                    None => vec![format!("expected an array of {}", layout.name)],
+                }
+            }
+            ExpectedBuffer::Block(layout) => reflection
+                .storage_buffer_block(PARTICLE_DESCRIPTOR_SET, binding)
+                .map(|block| block.mismatches(&layout))
+                .unwrap_or_default(),
+        };
+        mismatches.extend(
+            found
+                .into_iter()
+                .map(|m| format!("binding {}: {}", binding, m)),
+        );
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(PhysicsError::InitializationFailed {
+            message: format!(
+                "Particle shader layout does not match the CPU layout: {}",
+                mismatches.join("; ")
+            ),
+            component: "ParticleLayout".to_string(),
+            source: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::shaders::assembler::*;
+
+    fn particle_module(mass_offset: u32, version: u32) -> SpirvAssembler {
+        let mut module = SpirvAssembler::new();
+        let constants = module.structure(
+            "PushConstants",
+            &[
+                ("gravity", VEC3, 0),
+                ("delta_time", FLOAT, 12),
+                ("bounds", VEC2, 16),
+                ("max_velocity", FLOAT, 24),
+                ("particle_count", UINT, 28),
+                ("emitter_count", UINT, 32),
+                ("spawn_count", UINT, 36),
+                ("field_count", UINT, 40),
+                ("time", FLOAT, 44),
+                ("cell_size", FLOAT, 48),
+                ("cell_count", UINT, 52),
+                ("pass_index", UINT, 56),
+                ("rest_density", FLOAT, 60),
+                ("stiffness", FLOAT, 64),
+                ("viscosity", FLOAT, 68),
+            ],
+        );
+        module.push_constant_block(constants);
+        let particle = module.structure(
+            "Particle",
+            &[
+                ("position", VEC3, 0),
+                ("mass", FLOAT, mass_offset),
+                ("velocity", VEC3, 16),
+                ("lifetime", FLOAT, 28),
+                ("color", VEC4, 32),
+            ],
+        );
+        module.storage_buffer(0, PARTICLE_INPUT_BINDING, particle, 48);
+        module.storage_buffer(0, PARTICLE_OUTPUT_BINDING, particle, 48);
+        module.spec_constant(LAYOUT_VERSION_CONSTANT_ID, version);
+        module
+    }
+
+    fn lifetime_buffers(module: &mut SpirvAssembler, count: u32) {
+        let indices = module.runtime_array(UINT, 4);
+        let free_list =
+            module.structure("FreeList", &[("count", count, 0), ("indices", indices, 4)]);
+        module.storage_block(0, FREE_LIST_BINDING, free_list);
+        let draw_args = module.structure(
+            "DrawArgs",
+            &[
+                ("vertex_count", UINT, 0),
+                ("instance_count", UINT, 4),
+                ("first_vertex", UINT, 8),
+                ("first_instance", UINT, 12),
+            ],
+        );
+        module.storage_block(0, DRAW_ARGS_BINDING, draw_args);
+    }
+
+    #[test]
+    fn test_layout_is_std430_compatible() {
+        assert_eq!(size_of::<Particle>(), 48);
+        assert_eq!(size_of::<PushConstants>(), 72);
+
+        let mut module = particle_module(12, PARTICLE_LAYOUT_VERSION);
+        lifetime_buffers(&mut module, INT);
+        assert!(validate_shader_layout(&module.finish()).is_ok());
+    }
+
+    #[test]
+    fn test_drift_is_rejected() {
+        let module = particle_module(16, PARTICLE_LAYOUT_VERSION);
+        let error = validate_shader_layout(&module.finish())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("binding 0: Particle.mass"));
+        assert!(error.contains("binding 1: Particle.mass"));
+
+        let module = particle_module(12, PARTICLE_LAYOUT_VERSION + 1);
+        let error = validate_shader_layout(&module.finish())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("layout version"));
+
+        // A 64-bit counter in the free list
+        let mut module = particle_module(12, PARTICLE_LAYOUT_VERSION);
+        lifetime_buffers(&mut module, VEC2);
+        let error = validate_shader_layout(&module.finish())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("binding 3: FreeList"));
+    }
+
+    #[test]
+    fn test_particle_shaders_match_layout() {
+        let options = crate::physics::shaders::include_options().unwrap();
+        for source in [
+            include_str!("shaders/particle_emit.comp"),
+            include_str!("shaders/particle_update.comp"),
+            include_str!("shaders/grid_hash.comp"),
+            include_str!("shaders/grid_prefix_sum.comp"),
+            include_str!("shaders/grid_reorder.comp"),
+            include_str!("shaders/sph.comp"),
+        ] {
+            let spirv = crate::physics::shaders::compile_shader(
+                source,
+                shaderc::ShaderKind::Compute,
+                "main",
+                Some(&options),
+            )
+            .unwrap();
+            validate_shader_layout(&spirv).unwrap();
+        }
+    }
+}
@@ -1,11 +1,16 @@
-use glam::{Quat, Vec3, Vec4};
-use std::cell::RefCell;
+use glam::{Mat3, Quat, Vec3, Vec4};
+use std::cell::{Cell, RefCell};
 
 use crate::physics::{
-    collision::{detect_collision, BoundingVolume, CollisionManifold},
-    constraints::Constraint,
+    collision::{detect_collision, CollisionManifold},
+    constraints::{CollisionConstraint, Constraint},
+    determinism::sort_pairs,
+    events::CollisionEvents,
+    filter::{pair_allowed, CollisionFilter, IgnoredPairs},
+    material::{Damping, PhysicsMaterial},
+    rigid_body::integrate_gyroscopic,
     solver::{ConstraintSolver, IslandSolver},
-    spatial::ParallelBroadPhase,
+    spatial::{BroadPhaseKind, ParallelBroadPhase},
 };
 
 #[derive(Clone)]
@@ -18,7 +23,8 @@ pub enum PhysicsObject {
         angular_velocity: Vec3,
         angular_acceleration: Vec3,
         mass: f32,
-        inertia_tensor: Vec3,
+        // Local (body space) inertia tensor about the center of mass
+        inertia_tensor: Mat3,
         bounding_box: Vec4,
     },
     DeformableBody {
@@ -35,11 +41,25 @@ pub enum PhysicsObject {
 
 pub struct PhysicsWorld {
     pub objects: Vec<RefCell<PhysicsObject>>,
+    // Indices of removed bodies, reused by the next `add_object`
+    pub free_slots: Vec<usize>,
+    pub filters: Vec<CollisionFilter>,
+    pub ignored_pairs: IgnoredPairs,
+    pub ccd_enabled: Vec<bool>,
+    pub materials: Vec<PhysicsMaterial>,
+    pub damping: Damping,
+    pub body_damping: Vec<Option<Damping>>,
+    pub deterministic: bool,
+    pub events: CollisionEvents,
     pub gravity: Vec3,
     pub constraints: Vec<Box<dyn Constraint>>,
     pub num_iterations: usize,
     pub substeps: usize,
-    pub broad_phase: ParallelBroadPhase,
+    // Built by every substep and rebuilt lazily by the first scene query
+    // after bodies moved
+    pub broad_phase: RefCell<ParallelBroadPhase>,
+    // Set when bodies may have moved since the broad phase was last built
+    pub broad_phase_dirty: Cell<bool>,
     pub constraint_solver: ConstraintSolver,
     pub island_solver: IslandSolver,
 }
@@ -48,59 +68,238 @@ impl PhysicsWorld {
     pub fn new(gravity: Vec3) -> Self {
         PhysicsWorld {
             objects: Vec::new(),
+            free_slots: Vec::new(),
+            filters: Vec::new(),
+            ignored_pairs: IgnoredPairs::new(),
+            ccd_enabled: Vec::new(),
+            materials: Vec::new(),
+            damping: Damping::none(),
+            body_damping: Vec::new(),
+            deterministic: false,
+            events: CollisionEvents::new(),
             gravity,
             constraints: Vec::new(),
             num_iterations: 10,
             substeps: 1,
-            broad_phase: ParallelBroadPhase::new(),
+            broad_phase: RefCell::new(ParallelBroadPhase::new()),
+            broad_phase_dirty: Cell::new(true),
             constraint_solver: ConstraintSolver::new(),
             island_solver: IslandSolver::new(),
         }
     }
 
-    pub fn add_object(&mut self, object: PhysicsObject) {
+    pub fn add_object(&mut self, object: PhysicsObject) -> usize {
+        self.add_object_with_filter(object, CollisionFilter::default())
+    }
+
+    pub fn add_object_with_filter(
+        &mut self,
+        object: PhysicsObject,
+        filter: CollisionFilter,
+    ) -> usize {
+        self.broad_phase_dirty.set(true);
+        if let Some(index) = self.free_slots.pop() {
+            *self.objects[index].borrow_mut() = object;
+            self.set_collision_filter(index, filter);
+            self.set_ccd_enabled(index, false);
+            self.set_body_damping(index, None);
+            if let Some(material) = self.materials.get_mut(index) {
+                *material = PhysicsMaterial::default();
+            }
+            return index;
+        }
+
         self.objects.push(RefCell::new(object));
+        self.filters.push(filter);
+        self.ccd_enabled.push(false);
+        self.materials.push(PhysicsMaterial::default());
+        self.body_damping.push(None);
+        self.objects.len() - 1
+    }
+
+    // Removes a body without shifting the indices of the others. The slot
+    // keeps an inert static body that collides with nothing until
+    // `add_object` reuses it. Constraints attached to the body are left to
+    // the caller.
+    pub fn remove_object(&mut self, index: usize) {
+        if !self.contains_object(index) {
+            return;
+        }
+
+        *self.objects[index].borrow_mut() = PhysicsObject::RigidBody {
+            position: Vec3::ZERO,
+            velocity: Vec3::ZERO,
+            acceleration: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            angular_velocity: Vec3::ZERO,
+            angular_acceleration: Vec3::ZERO,
+            mass: 0.0,
+            inertia_tensor: Mat3::ZERO,
+            bounding_box: Vec4::ZERO,
+        };
+        self.set_collision_filter(index, CollisionFilter::new(0, 0));
+        self.set_ccd_enabled(index, false);
+        self.ignored_pairs.remove_body(index);
+        self.events.remove_body(index);
+        self.free_slots.push(index);
+        self.broad_phase_dirty.set(true);
+    }
+
+    pub fn contains_object(&self, index: usize) -> bool {
+        index < self.objects.len() && !self.free_slots.contains(&index)
+    }
+
+    pub fn set_collision_filter(&mut self, index: usize, filter: CollisionFilter) {
+        if index >= self.filters.len() {
+            self.filters.resize(index + 1, CollisionFilter::default());
+        }
+        self.filters[index] = filter;
+    }
+
+    // Stop two bodies from colliding with each other, independent of their
+    // collision filters
+    pub fn ignore_pair(&mut self, a: usize, b: usize) {
+        self.ignored_pairs.insert(a, b);
+    }
+
+    pub fn unignore_pair(&mut self, a: usize, b: usize) {
+        self.ignored_pairs.remove(a, b);
+    }
+
+    // Contact and trigger events generated by the last `update`
+    pub fn events(&self) -> &CollisionEvents {
+        &self.events
+    }
+
+    // Switches the broad-phase structure. The new one is filled on the next
+    // `update` or scene query.
+    pub fn set_broad_phase(&mut self, kind: BroadPhaseKind) {
+        self.broad_phase = RefCell::new(ParallelBroadPhase::with_kind(kind));
+        self.broad_phase_dirty.set(true);
     }
 
     pub fn add_constraint(&mut self, constraint: Box<dyn Constraint>) {
         self.constraints.push(constraint);
     }
 
+    // Steps the world. Bodies move after the broad phase is built, so the
+    // first scene query afterwards rebuilds it.
     pub fn update(&mut self, delta_time: f32) {
         let sub_delta_time = delta_time / self.substeps as f32;
+        self.events.begin_step();
         for _ in 0..self.substeps {
             self.sub_update(sub_delta_time);
         }
+        self.clear_accumulated_forces();
+        self.events.end_step();
+    }
+
+    // Forces applied before `update` act on every substep of it
+    fn clear_accumulated_forces(&mut self) {
+        for object in &mut self.objects {
+            if let PhysicsObject::RigidBody {
+                acceleration,
+                angular_acceleration,
+                ..
+            } = object.get_mut()
+            {
+                *acceleration = Vec3::ZERO;
+                *angular_acceleration = Vec3::ZERO;
+            }
+        }
     }
 
     fn sub_update(&mut self, delta_time: f32) {
-        // Phase 1: Position update and external forces
+        // Phase 1: Position update and external forces. Bodies with CCD enabled
+        // are integrated separately and swept against the world.
         self.parallel_update_positions(delta_time);
+        if self.ccd_enabled.contains(&true) {
+            // CCD sweeps query the broad phase for what is in the way. They
+            // hold the swept body borrowed, so it can't be rebuilt lazily.
+            self.sync_broad_phase();
+        }
+        self.integrate_ccd_bodies(delta_time);
 
         // Phase 2: Parallel broad-phase collision detection
         let aabb_pairs = self.gather_aabb_pairs();
-        let potential_collisions = self.broad_phase.update(&aabb_pairs);
+        let filters = &self.filters;
+        let ignored_pairs = &self.ignored_pairs;
+        let mut potential_collisions = self.broad_phase.get_mut().update(&aabb_pairs, |i, j| {
+            pair_allowed(filters, ignored_pairs, i, j)
+        });
+        self.broad_phase_dirty.set(false);
+        if self.deterministic {
+            sort_pairs(&mut potential_collisions);
+        }
 
-        // Phase 3: Parallel narrow-phase collision detection
-        let collision_constraints = self.parallel_collision_detection(&potential_collisions);
-        self.constraints.extend(collision_constraints);
+        // Phase 3: Narrow-phase collision detection. Results keep the pair
+        // order, so collision constraints are added in a stable order.
+        let collisions = self.detect_collisions(&potential_collisions);
+        for (i, j, manifold) in collisions {
+            // Sensors only report overlaps, they never push bodies apart
+            if self.is_sensor(i) {
+                self.events.record_trigger(i, j);
+            } else if self.is_sensor(j) {
+                self.events.record_trigger(j, i);
+            } else {
+                self.events.record_contact(i, j, &manifold);
+                let material = self.contact_material(i, j);
+                self.constraints.push(Box::new(
+                    CollisionConstraint::new(i, j)
+                        .with_manifold(manifold)
+                        .with_material(&material),
+                ));
+            }
+        }
 
-        // Phase 4: Parallel constraint solving with islands
-        self.constraint_solver.solve_constraints(self, delta_time);
+        // Phase 4: Constraint solving with islands
+        for constraint in &self.constraints {
+            constraint.begin_substep(delta_time);
+        }
+        if self.deterministic {
+            self.constraint_solver.solve_constraints_ordered(
+                &mut self.objects,
+                &self.constraints,
+                self.num_iterations,
+                delta_time,
+            );
+        } else {
+            let islands = self
+                .island_solver
+                .build_islands(self.objects.len(), &self.constraints);
+            self.constraint_solver.solve_constraints(
+                &mut self.objects,
+                &self.constraints,
+                islands,
+                self.num_iterations,
+                delta_time,
+            );
+        }
 
         // Phase 5: Parallel velocity update
         self.parallel_update_velocities(delta_time);
 
         // Clean up temporary collision constraints
         self.constraints.retain(|c| !c.is_collision_constraint());
+        self.broad_phase_dirty.set(true);
     }
 
-    fn parallel_update_positions(&self, delta_time: f32) {
+    // Bodies are integrated independently of each other, on the rayon pool
+    // unless the world is deterministic
+    fn parallel_update_positions(&mut self, delta_time: f32) {
         use rayon::prelude::*;
 
-        self.objects.par_iter().for_each(|obj| {
-            let mut obj = obj.borrow_mut();
-            match &mut *obj {
+        let ccd_enabled = &self.ccd_enabled;
+        let body_damping = &self.body_damping;
+        let world_damping = self.damping;
+        let gravity = self.gravity;
+        let integrate = |(index, object): (usize, &mut RefCell<PhysicsObject>)| {
+            let damping = body_damping
+                .get(index)
+                .copied()
+                .flatten()
+                .unwrap_or(world_damping);
+            match object.get_mut() {
                 PhysicsObject::RigidBody {
                     position,
                     velocity,
@@ -108,47 +307,71 @@ impl PhysicsWorld {
                     orientation,
                     angular_velocity,
                     angular_acceleration,
+                    mass,
+                    inertia_tensor,
                     ..
-                } => {
-                    *velocity += self.gravity * delta_time;
-                    *velocity += *acceleration * delta_time;
+                } if !ccd_enabled.get(index).copied().unwrap_or(false) => {
+                    // Zero mass bodies are static or kinematic and only
+                    // move with the velocity they were given
+                    let dynamic = *mass != 0.0;
+                    if dynamic {
+                        *velocity += gravity * delta_time;
+                        *velocity += *acceleration * delta_time;
+                        *velocity *= damping.linear_factor(delta_time);
+                    }
                     *position += *velocity * delta_time;
 
-                    let angle = angular_velocity.length() * delta_time;
-                    if angle != 0.0 {
-                        let axis = *angular_velocity / angle;
-                        let rotation = Quat::from_axis_angle(axis, angle);
-                        *orientation = rotation * *orientation;
-                        orientation.normalize();
+                    integrate_angular_velocity(
+                        angular_velocity,
+                        *angular_acceleration,
+                        *orientation,
+                        *inertia_tensor,
+                        *mass,
+                        delta_time,
+                    );
+                    if dynamic {
+                        *angular_velocity *= damping.angular_factor(delta_time);
                     }
-
-                    *angular_velocity += *angular_acceleration * delta_time;
-                    *acceleration = Vec3::ZERO;
-                    *angular_acceleration = Vec3::ZERO;
+                    integrate_orientation(orientation, *angular_velocity, delta_time);
                 }
+                // Swept separately by `integrate_ccd_bodies`
+                PhysicsObject::RigidBody { .. } => {}
                 PhysicsObject::DeformableBody {
                     positions,
                     prev_positions,
                     velocities,
+                    masses,
                     ..
                 } => {
-                    positions
-                        .par_iter_mut()
-                        .zip(prev_positions.par_iter_mut())
-                        .zip(velocities.par_iter_mut())
-                        .for_each(|((pos, prev), vel)| {
-                            *prev = *pos;
-                            *vel += self.gravity * delta_time;
+                    let damping = damping.linear_factor(delta_time);
+                    for (((pos, prev), vel), mass) in positions
+                        .iter_mut()
+                        .zip(prev_positions.iter_mut())
+                        .zip(velocities.iter_mut())
+                        .zip(masses.iter())
+                    {
+                        *prev = *pos;
+                        // Pinned particles have infinite mass and stay put
+                        if mass.is_finite() {
+                            *vel += gravity * delta_time;
+                            *vel *= damping;
                             *pos += *vel * delta_time;
-                        });
+                        }
+                    }
                 }
             }
-        });
+        };
+
+        if self.deterministic {
+            self.objects.iter_mut().enumerate().for_each(integrate);
+        } else {
+            self.objects.par_iter_mut().enumerate().for_each(integrate);
+        }
     }
 
-    fn gather_aabb_pairs(&self) -> Vec<(Vec3, Vec3)> {
+    pub(crate) fn gather_aabb_pairs(&self) -> Vec<(Vec3, Vec3)> {
         self.objects
-            .par_iter()
+            .iter()
             .map(|obj| {
                 let obj = obj.borrow();
                 match &*obj {
@@ -195,47 +418,83 @@ impl PhysicsWorld {
             .collect()
     }
 
-    fn parallel_collision_detection(
+    // Bodies live in `RefCell`s, so the narrow phase runs on the calling
+    // thread. Results keep the pair order.
+    fn detect_collisions(
         &self,
         potential_collisions: &[(usize, usize)],
-    ) -> Vec<Box<dyn Constraint>> {
-        use rayon::prelude::*;
-
+    ) -> Vec<(usize, usize, CollisionManifold)> {
         potential_collisions
-            .par_iter()
+            .iter()
             .filter_map(|&(i, j)| {
                 let obj1 = self.objects[i].borrow();
                 let obj2 = self.objects[j].borrow();
 
-                detect_collision(&*obj1, &*obj2).map(|_| {
-                    Box::new(crate::physics::constraints::CollisionConstraint::new(i, j))
-                        as Box<dyn Constraint>
-                })
+                detect_collision(&obj1, &obj2).map(|manifold| (i, j, manifold))
             })
             .collect()
     }
 
-    fn parallel_update_velocities(&self, delta_time: f32) {
+    pub(crate) fn is_sensor(&self, index: usize) -> bool {
+        self.filters.get(index).is_some_and(|f| f.is_sensor)
+    }
+
+    fn parallel_update_velocities(&mut self, delta_time: f32) {
         use rayon::prelude::*;
 
-        self.objects.par_iter().for_each(|obj| {
-            let mut obj = obj.borrow_mut();
+        let update = |object: &mut RefCell<PhysicsObject>| {
             if let PhysicsObject::DeformableBody {
                 positions,
                 prev_positions,
                 velocities,
                 ..
-            } = &mut *obj
+            } = object.get_mut()
             {
-                positions
-                    .par_iter()
-                    .zip(prev_positions.par_iter())
-                    .zip(velocities.par_iter_mut())
-                    .for_each(|((pos, prev), vel)| {
-                        *vel = (*pos - *prev) / delta_time;
-                    });
+                for ((pos, prev), vel) in positions
+                    .iter()
+                    .zip(prev_positions.iter())
+                    .zip(velocities.iter_mut())
+                {
+                    *vel = (*pos - *prev) / delta_time;
+                }
             }
-        });
+        };
+
+        if self.deterministic {
+            self.objects.iter_mut().for_each(update);
+        } else {
+            self.objects.par_iter_mut().for_each(update);
+        }
+    }
+}
+
+// Applies the accumulated angular acceleration and the gyroscopic torque of
+// a spinning body. Static bodies (zero mass) never rotate on their own.
+pub(crate) fn integrate_angular_velocity(
+    angular_velocity: &mut Vec3,
+    angular_acceleration: Vec3,
+    orientation: Quat,
+    inertia_tensor: Mat3,
+    mass: f32,
+    delta_time: f32,
+) {
+    if mass == 0.0 {
+        return;
+    }
+    *angular_velocity += angular_acceleration * delta_time;
+    integrate_gyroscopic(angular_velocity, orientation, inertia_tensor, delta_time);
+}
+
+// Rotates `orientation` by the angle swept at `angular_velocity` over `delta_time`
+pub(crate) fn integrate_orientation(
+    orientation: &mut Quat,
+    angular_velocity: Vec3,
+    delta_time: f32,
+) {
+    let speed = angular_velocity.length();
+    if speed != 0.0 {
+        let rotation = Quat::from_axis_angle(angular_velocity / speed, speed * delta_time);
+        *orientation = (rotation * *orientation).normalize();
     }
 }
 
@@ -244,11 +503,21 @@ impl Clone for PhysicsWorld {
     fn clone(&self) -> Self {
         PhysicsWorld {
             objects: self.objects.clone(),
+            free_slots: self.free_slots.clone(),
+            filters: self.filters.clone(),
+            ignored_pairs: self.ignored_pairs.clone(),
+            ccd_enabled: self.ccd_enabled.clone(),
+            materials: self.materials.clone(),
+            damping: self.damping,
+            body_damping: self.body_damping.clone(),
+            deterministic: self.deterministic,
+            events: CollisionEvents::new(),
             gravity: self.gravity,
             constraints: self.constraints.iter().map(|c| c.clone_box()).collect(),
             num_iterations: self.num_iterations,
             substeps: self.substeps,
-            broad_phase: ParallelBroadPhase::new(),
+            broad_phase: self.broad_phase.clone(),
+            broad_phase_dirty: self.broad_phase_dirty.clone(),
             constraint_solver: ConstraintSolver::new(),
             island_solver: IslandSolver::new(),
         }
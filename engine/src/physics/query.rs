@@ -0,0 +1,987 @@
+use glam::{Quat, Vec3};
+use std::cell::Ref;
+
+use crate::physics::{
+    collision::BoundingVolume,
+    filter::{CollisionFilter, ALL_GROUPS},
+    physics::{PhysicsObject, PhysicsWorld},
+    spatial::{aabb_overlap, ParallelBroadPhase},
+};
+
+const CAST_TOLERANCE: f32 = 1e-3;
+const MAX_ADVANCEMENT_STEPS: usize = 64;
+const BISECTION_STEPS: usize = 16;
+// Alternating projections between a capsule's segment and a body
+const SEGMENT_ITERATIONS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit {
+    pub body: usize,
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub distance: f32,
+}
+
+// Restricts which bodies a query reports. `mask` is tested against each
+// body's collision group, `exclude` lists body indices to skip (usually the
+// body issuing the query). Sensors are skipped unless `include_sensors` is set.
+#[derive(Debug, Clone)]
+pub struct QueryFilter {
+    pub mask: u32,
+    pub exclude: Vec<usize>,
+    pub include_sensors: bool,
+}
+
+impl QueryFilter {
+    pub fn new(mask: u32) -> Self {
+        Self {
+            mask,
+            exclude: Vec::new(),
+            include_sensors: false,
+        }
+    }
+
+    pub fn with_sensors(mut self) -> Self {
+        self.include_sensors = true;
+        self
+    }
+
+    pub fn excluding(mut self, body: usize) -> Self {
+        self.exclude.push(body);
+        self
+    }
+
+    pub fn accepts(&self, body: usize, filter: &CollisionFilter) -> bool {
+        (filter.group & self.mask) != 0
+            && (self.include_sensors || !filter.is_sensor)
+            && !self.exclude.contains(&body)
+    }
+}
+
+impl Default for QueryFilter {
+    fn default() -> Self {
+        Self::new(ALL_GROUPS)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryShape {
+    Sphere {
+        radius: f32,
+    },
+    Box {
+        half_extents: Vec3,
+        orientation: Quat,
+    },
+    // Segment of length `2 * half_height` along the local Y axis, inflated
+    // by `radius`
+    Capsule {
+        radius: f32,
+        half_height: f32,
+        orientation: Quat,
+    },
+}
+
+impl QueryShape {
+    pub fn bounding_radius(&self) -> f32 {
+        match self {
+            QueryShape::Sphere { radius } => *radius,
+            QueryShape::Box { half_extents, .. } => half_extents.length(),
+            QueryShape::Capsule {
+                radius,
+                half_height,
+                ..
+            } => radius + half_height,
+        }
+    }
+}
+
+impl PhysicsWorld {
+    // Rebuild the broad-phase structure from current body positions. Queries
+    // do this on their own after `update` or after bodies were added or
+    // removed, but bodies moved directly through `objects` need an explicit
+    // sync, the world cannot tell that they changed.
+    pub fn sync_broad_phase(&mut self) {
+        let aabbs = self.gather_aabb_pairs();
+        self.broad_phase.get_mut().rebuild(&aabbs);
+        self.broad_phase_dirty.set(false);
+    }
+
+    pub fn raycast(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+        filter: &QueryFilter,
+    ) -> Option<RaycastHit> {
+        let dir = dir.normalize_or_zero();
+        if dir == Vec3::ZERO {
+            return None;
+        }
+
+        let mut best: Option<RaycastHit> = None;
+        self.current_broad_phase()
+            .traverse_ray(origin, dir, max_dist, |index, t_entry| {
+                // Bodies first reached after the closest hit cannot be any closer
+                if best.is_some_and(|hit| hit.distance <= t_entry) {
+                    return false;
+                }
+                self.closest_ray_hit(index, origin, dir, max_dist, filter, &mut best);
+                true
+            });
+
+        best
+    }
+
+    // Every body hit along the ray, closest first
+    pub fn raycast_all(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+        filter: &QueryFilter,
+    ) -> Vec<RaycastHit> {
+        let dir = dir.normalize_or_zero();
+        if dir == Vec3::ZERO {
+            return Vec::new();
+        }
+
+        let mut hits = Vec::new();
+        let mut test = |index: usize| {
+            if self.query_accepts(index, filter) {
+                hits.extend(self.raycast_body(index, origin, dir, max_dist));
+            }
+        };
+
+        self.current_broad_phase()
+            .traverse_ray(origin, dir, max_dist, |index, _| {
+                test(index);
+                true
+            });
+
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits
+    }
+
+    pub fn sphere_cast(
+        &self,
+        origin: Vec3,
+        radius: f32,
+        dir: Vec3,
+        max_dist: f32,
+        filter: &QueryFilter,
+    ) -> Option<RaycastHit> {
+        self.shape_cast(QueryShape::Sphere { radius }, origin, dir, max_dist, filter)
+    }
+
+    // Sweeps `shape` from `origin` along `dir` and reports the first body it
+    // touches. `point` and `normal` are taken on the surface of the hit body.
+    pub fn shape_cast(
+        &self,
+        shape: QueryShape,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+        filter: &QueryFilter,
+    ) -> Option<RaycastHit> {
+        let dir = dir.normalize_or_zero();
+        if dir == Vec3::ZERO {
+            return None;
+        }
+
+        let end = origin + dir * max_dist;
+        let reach = Vec3::splat(shape.bounding_radius());
+        let candidates = self.candidates_in(origin.min(end) - reach, origin.max(end) + reach);
+
+        let mut best: Option<RaycastHit> = None;
+        for index in candidates {
+            if !self.query_accepts(index, filter) {
+                continue;
+            }
+            let limit = best.map_or(max_dist, |hit| hit.distance);
+            let obj = self.objects[index].borrow();
+            let hit = match shape {
+                QueryShape::Sphere { radius } => sphere_cast_body(&obj, origin, radius, dir, limit),
+                QueryShape::Box {
+                    half_extents,
+                    orientation,
+                } => box_cast_body(&obj, origin, half_extents, orientation, dir, limit),
+                QueryShape::Capsule {
+                    radius,
+                    half_height,
+                    orientation,
+                } => capsule_cast_body(&obj, origin, radius, half_height, orientation, dir, limit),
+            };
+            if let Some((distance, point, normal)) = hit {
+                best = Some(RaycastHit {
+                    body: index,
+                    point,
+                    normal,
+                    distance,
+                });
+            }
+        }
+
+        best
+    }
+
+    pub fn overlap_sphere(&self, center: Vec3, radius: f32, filter: &QueryFilter) -> Vec<usize> {
+        let reach = Vec3::splat(radius);
+        self.candidates_in(center - reach, center + reach)
+            .into_iter()
+            .filter(|&index| self.query_accepts(index, filter))
+            .filter(|&index| {
+                let obj = self.objects[index].borrow();
+                let (closest, inside) = closest_point_on_body(&obj, center);
+                inside || closest.distance_squared(center) <= radius * radius
+            })
+            .collect()
+    }
+
+    pub fn overlap_aabb(&self, min: Vec3, max: Vec3, filter: &QueryFilter) -> Vec<usize> {
+        let query_volume = BoundingVolume {
+            center: (min + max) * 0.5,
+            half_extents: (max - min) * 0.5,
+            orientation: Quat::IDENTITY,
+        };
+
+        self.candidates_in(min, max)
+            .into_iter()
+            .filter(|&index| self.query_accepts(index, filter))
+            .filter(|&index| match &*self.objects[index].borrow() {
+                PhysicsObject::RigidBody {
+                    position,
+                    orientation,
+                    bounding_box,
+                    ..
+                } => BoundingVolume::from_aabb(*position, *bounding_box, *orientation)
+                    .intersects(&query_volume),
+                PhysicsObject::DeformableBody { positions, .. } => {
+                    let (body_min, body_max) = points_bounds(positions);
+                    aabb_overlap(min, max, body_min, body_max)
+                }
+            })
+            .collect()
+    }
+
+    pub fn collision_filter(&self, index: usize) -> CollisionFilter {
+        self.filters.get(index).copied().unwrap_or_default()
+    }
+
+    fn query_accepts(&self, index: usize, filter: &QueryFilter) -> bool {
+        filter.accepts(index, &self.collision_filter(index))
+    }
+
+    // The broad phase, first rebuilt if bodies moved, were added or were
+    // removed since it was last built
+    fn current_broad_phase(&self) -> Ref<'_, ParallelBroadPhase> {
+        if self.broad_phase_dirty.get()
+            || self.broad_phase.borrow().object_count() != self.objects.len()
+        {
+            let aabbs = self.gather_aabb_pairs();
+            self.broad_phase.borrow_mut().rebuild(&aabbs);
+            self.broad_phase_dirty.set(false);
+        }
+        self.broad_phase.borrow()
+    }
+
+    pub(crate) fn candidates_in(&self, min: Vec3, max: Vec3) -> Vec<usize> {
+        self.current_broad_phase().query_aabb(min, max)
+    }
+
+    fn closest_ray_hit(
+        &self,
+        index: usize,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+        filter: &QueryFilter,
+        best: &mut Option<RaycastHit>,
+    ) {
+        if !self.query_accepts(index, filter) {
+            return;
+        }
+        let limit = best.map_or(max_dist, |hit| hit.distance);
+        if let Some(hit) = self.raycast_body(index, origin, dir, limit) {
+            *best = Some(hit);
+        }
+    }
+
+    fn raycast_body(
+        &self,
+        index: usize,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+    ) -> Option<RaycastHit> {
+        let obj = self.objects[index].borrow();
+        let (distance, normal) = match &*obj {
+            PhysicsObject::RigidBody {
+                position,
+                orientation,
+                bounding_box,
+                ..
+            } => ray_box(
+                origin,
+                dir,
+                *position,
+                Vec3::splat(bounding_box.w),
+                *orientation,
+            )?,
+            PhysicsObject::DeformableBody {
+                positions,
+                tetrahedra,
+                bounding_box,
+                ..
+            } => {
+                if tetrahedra.is_empty() {
+                    ray_sphere(origin, dir, centroid(positions), bounding_box.w)?
+                } else {
+                    ray_tetrahedra(origin, dir, positions, tetrahedra)?
+                }
+            }
+        };
+
+        if distance > max_dist {
+            return None;
+        }
+
+        Some(RaycastHit {
+            body: index,
+            point: origin + dir * distance,
+            normal,
+            distance,
+        })
+    }
+}
+
+pub(crate) fn sphere_cast_body(
+    obj: &PhysicsObject,
+    origin: Vec3,
+    radius: f32,
+    dir: Vec3,
+    max_dist: f32,
+) -> Option<(f32, Vec3, Vec3)> {
+    // Conservative advancement: the sphere can always travel the distance
+    // between its surface and the body without passing through it
+    let mut t = 0.0;
+    for _ in 0..MAX_ADVANCEMENT_STEPS {
+        let center = origin + dir * t;
+        let (closest, inside) = closest_point_on_body(obj, center);
+        let gap = if inside {
+            0.0
+        } else {
+            closest.distance(center) - radius
+        };
+
+        if gap <= CAST_TOLERANCE {
+            let normal = (center - closest).normalize_or_zero();
+            let normal = if normal == Vec3::ZERO { -dir } else { normal };
+            return Some((t, closest, normal));
+        }
+
+        t += gap;
+        if t > max_dist {
+            return None;
+        }
+    }
+    None
+}
+
+// Same conservative advancement as `sphere_cast_body`, measuring the gap
+// from the capsule's segment instead of a single center
+fn capsule_cast_body(
+    obj: &PhysicsObject,
+    origin: Vec3,
+    radius: f32,
+    half_height: f32,
+    orientation: Quat,
+    dir: Vec3,
+    max_dist: f32,
+) -> Option<(f32, Vec3, Vec3)> {
+    let axis = orientation.mul_vec3(Vec3::Y) * half_height;
+    let mut t = 0.0;
+    for _ in 0..MAX_ADVANCEMENT_STEPS {
+        let center = origin + dir * t;
+        let (nearest, closest, inside) =
+            closest_points_segment_body(obj, center - axis, center + axis);
+        let gap = if inside {
+            0.0
+        } else {
+            closest.distance(nearest) - radius
+        };
+
+        if gap <= CAST_TOLERANCE {
+            let normal = (nearest - closest).normalize_or_zero();
+            let normal = if normal == Vec3::ZERO { -dir } else { normal };
+            return Some((t, closest, normal));
+        }
+
+        t += gap;
+        if t > max_dist {
+            return None;
+        }
+    }
+    None
+}
+
+// Closest pair between the segment `a`-`b` and the body's surface, found by
+// projecting back and forth between the two. Returns the point on the
+// segment, the point on the body and whether the segment enters the body.
+fn closest_points_segment_body(obj: &PhysicsObject, a: Vec3, b: Vec3) -> (Vec3, Vec3, bool) {
+    let mut nearest = (a + b) * 0.5;
+    for _ in 0..SEGMENT_ITERATIONS {
+        let (closest, inside) = closest_point_on_body(obj, nearest);
+        if inside {
+            return (nearest, closest, true);
+        }
+        let next = closest_point_on_segment(closest, a, b);
+        let converged = next.distance_squared(nearest) <= CAST_TOLERANCE * CAST_TOLERANCE;
+        nearest = next;
+        if converged {
+            break;
+        }
+    }
+
+    let (closest, inside) = closest_point_on_body(obj, nearest);
+    (nearest, closest, inside)
+}
+
+fn closest_point_on_segment(p: Vec3, a: Vec3, b: Vec3) -> Vec3 {
+    let ab = b - a;
+    let length_squared = ab.length_squared();
+    if length_squared <= f32::EPSILON {
+        return a;
+    }
+    a + ab * ((p - a).dot(ab) / length_squared).clamp(0.0, 1.0)
+}
+
+fn box_cast_body(
+    obj: &PhysicsObject,
+    origin: Vec3,
+    half_extents: Vec3,
+    orientation: Quat,
+    dir: Vec3,
+    max_dist: f32,
+) -> Option<(f32, Vec3, Vec3)> {
+    let bounding_radius = half_extents.length();
+    let min_step = half_extents.min_element().max(CAST_TOLERANCE);
+    let overlaps = |t: f32| box_overlaps_body(obj, origin + dir * t, half_extents, orientation);
+
+    // Advance the bounding sphere conservatively, then march in steps no
+    // larger than the box's thinnest half extent until the box itself overlaps
+    let mut t: f32 = 0.0;
+    let mut last_clear: Option<f32> = None;
+    for _ in 0..MAX_ADVANCEMENT_STEPS * 4 {
+        if overlaps(t) {
+            let hit_t = match last_clear {
+                Some(mut clear) => {
+                    let mut blocked = t;
+                    for _ in 0..BISECTION_STEPS {
+                        let mid = (clear + blocked) * 0.5;
+                        if overlaps(mid) {
+                            blocked = mid;
+                        } else {
+                            clear = mid;
+                        }
+                    }
+                    clear
+                }
+                None => 0.0,
+            };
+
+            let center = origin + dir * hit_t;
+            let (closest, _) = closest_point_on_body(obj, center);
+            let normal = (center - closest).normalize_or_zero();
+            let normal = if normal == Vec3::ZERO { -dir } else { normal };
+            return Some((hit_t, closest, normal));
+        }
+
+        if t >= max_dist {
+            return None;
+        }
+
+        let (closest, _) = closest_point_on_body(obj, origin + dir * t);
+        let gap = closest.distance(origin + dir * t) - bounding_radius;
+        last_clear = Some(t);
+        t = (t + gap.max(min_step)).min(max_dist);
+    }
+    None
+}
+
+fn box_overlaps_body(
+    obj: &PhysicsObject,
+    center: Vec3,
+    half_extents: Vec3,
+    orientation: Quat,
+) -> bool {
+    let volume = BoundingVolume {
+        center,
+        half_extents,
+        orientation,
+    };
+
+    match obj {
+        PhysicsObject::RigidBody {
+            position,
+            orientation: body_orientation,
+            bounding_box,
+            ..
+        } => BoundingVolume::from_aabb(*position, *bounding_box, *body_orientation)
+            .intersects(&volume),
+        PhysicsObject::DeformableBody { positions, .. } => {
+            let to_local = orientation.conjugate();
+            positions.iter().any(|&p| {
+                let local = to_local.mul_vec3(p - center);
+                local.abs().cmple(half_extents).all()
+            }) || closest_point_on_body(obj, center).1
+        }
+    }
+}
+
+// Closest point on the body's surface, and whether `point` lies inside it
+pub(crate) fn closest_point_on_body(obj: &PhysicsObject, point: Vec3) -> (Vec3, bool) {
+    match obj {
+        PhysicsObject::RigidBody {
+            position,
+            orientation,
+            bounding_box,
+            ..
+        } => {
+            let half = Vec3::splat(bounding_box.w);
+            let local = orientation.conjugate().mul_vec3(point - *position);
+            let clamped = local.clamp(-half, half);
+            if clamped == local {
+                (point, true)
+            } else {
+                (*position + orientation.mul_vec3(clamped), false)
+            }
+        }
+        PhysicsObject::DeformableBody {
+            positions,
+            tetrahedra,
+            bounding_box,
+            ..
+        } => {
+            if tetrahedra.is_empty() {
+                let center = centroid(positions);
+                let offset = point - center;
+                if offset.length() <= bounding_box.w {
+                    return (point, true);
+                }
+                return (center + offset.normalize() * bounding_box.w, false);
+            }
+
+            let mut best = point;
+            let mut best_dist = f32::INFINITY;
+            for tet in tetrahedra {
+                let p = [
+                    positions[tet[0]],
+                    positions[tet[1]],
+                    positions[tet[2]],
+                    positions[tet[3]],
+                ];
+                if point_in_tetrahedron(point, &p) {
+                    return (point, true);
+                }
+                for [a, b, c] in tetrahedron_faces(&p) {
+                    let candidate = closest_point_on_triangle(point, a, b, c);
+                    let dist = candidate.distance_squared(point);
+                    if dist < best_dist {
+                        best_dist = dist;
+                        best = candidate;
+                    }
+                }
+            }
+            (best, false)
+        }
+    }
+}
+
+// Slab test against an oriented cube. Rays starting inside report a hit at
+// distance zero facing back along the ray.
+pub(crate) fn ray_box(
+    origin: Vec3,
+    dir: Vec3,
+    center: Vec3,
+    half_extents: Vec3,
+    orientation: Quat,
+) -> Option<(f32, Vec3)> {
+    let to_local = orientation.conjugate();
+    let local_origin = to_local.mul_vec3(origin - center);
+    let local_dir = to_local.mul_vec3(dir);
+
+    let mut t_enter = f32::NEG_INFINITY;
+    let mut t_exit = f32::INFINITY;
+    let mut enter_normal = Vec3::ZERO;
+
+    for axis in 0..3 {
+        if local_dir[axis].abs() < f32::EPSILON {
+            if local_origin[axis].abs() > half_extents[axis] {
+                return None;
+            }
+            continue;
+        }
+
+        let inv = 1.0 / local_dir[axis];
+        let mut t_near = (-half_extents[axis] - local_origin[axis]) * inv;
+        let mut t_far = (half_extents[axis] - local_origin[axis]) * inv;
+        if t_near > t_far {
+            std::mem::swap(&mut t_near, &mut t_far);
+        }
+
+        if t_near > t_enter {
+            t_enter = t_near;
+            enter_normal = Vec3::ZERO;
+            enter_normal[axis] = -local_dir[axis].signum();
+        }
+        t_exit = t_exit.min(t_far);
+
+        if t_enter > t_exit || t_exit < 0.0 {
+            return None;
+        }
+    }
+
+    if t_enter < 0.0 {
+        return Some((0.0, -dir));
+    }
+
+    Some((t_enter, orientation.mul_vec3(enter_normal)))
+}
+
+pub(crate) fn ray_sphere(
+    origin: Vec3,
+    dir: Vec3,
+    center: Vec3,
+    radius: f32,
+) -> Option<(f32, Vec3)> {
+    let offset = origin - center;
+    let b = offset.dot(dir);
+    let c = offset.length_squared() - radius * radius;
+    if c <= 0.0 {
+        return Some((0.0, -dir));
+    }
+
+    let discriminant = b * b - c;
+    if b > 0.0 || discriminant < 0.0 {
+        return None;
+    }
+
+    let t = -b - discriminant.sqrt();
+    let normal = (origin + dir * t - center).normalize_or_zero();
+    Some((t, normal))
+}
+
+fn ray_tetrahedra(
+    origin: Vec3,
+    dir: Vec3,
+    positions: &[Vec3],
+    tetrahedra: &[[usize; 4]],
+) -> Option<(f32, Vec3)> {
+    let mut best: Option<(f32, Vec3)> = None;
+
+    for tet in tetrahedra {
+        let p = [
+            positions[tet[0]],
+            positions[tet[1]],
+            positions[tet[2]],
+            positions[tet[3]],
+        ];
+        if point_in_tetrahedron(origin, &p) {
+            return Some((0.0, -dir));
+        }
+
+        for [a, b, c] in tetrahedron_faces(&p) {
+            if let Some((t, normal)) = ray_triangle(origin, dir, a, b, c) {
+                if best.is_none_or(|(best_t, _)| t < best_t) {
+                    best = Some((t, normal));
+                }
+            }
+        }
+    }
+
+    best
+}
+
+// Double-sided Möller–Trumbore; the normal always faces the ray origin
+pub(crate) fn ray_triangle(
+    origin: Vec3,
+    dir: Vec3,
+    a: Vec3,
+    b: Vec3,
+    c: Vec3,
+) -> Option<(f32, Vec3)> {
+    let edge1 = b - a;
+    let edge2 = c - a;
+    let p = dir.cross(edge2);
+    let det = edge1.dot(p);
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let s = origin - a;
+    let u = s.dot(p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = s.cross(edge1);
+    let v = dir.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = edge2.dot(q) * inv_det;
+    if t < 0.0 {
+        return None;
+    }
+
+    let normal = edge1.cross(edge2).normalize_or_zero();
+    let normal = if normal.dot(dir) > 0.0 {
+        -normal
+    } else {
+        normal
+    };
+    Some((t, normal))
+}
+
+fn point_in_tetrahedron(point: Vec3, p: &[Vec3; 4]) -> bool {
+    let signed_volume = |a: Vec3, b: Vec3, c: Vec3, d: Vec3| (b - a).cross(c - a).dot(d - a);
+    let reference = signed_volume(p[0], p[1], p[2], p[3]);
+    if reference == 0.0 {
+        return false;
+    }
+
+    let volumes = [
+        signed_volume(point, p[1], p[2], p[3]),
+        signed_volume(p[0], point, p[2], p[3]),
+        signed_volume(p[0], p[1], point, p[3]),
+        signed_volume(p[0], p[1], p[2], point),
+    ];
+    volumes.iter().all(|v| v * reference >= 0.0)
+}
+
+fn tetrahedron_faces(p: &[Vec3; 4]) -> [[Vec3; 3]; 4] {
+    [
+        [p[0], p[1], p[2]],
+        [p[0], p[1], p[3]],
+        [p[0], p[2], p[3]],
+        [p[1], p[2], p[3]],
+    ]
+}
+
+// Ericson, Real-Time Collision Detection, 5.1.5
+pub(crate) fn closest_point_on_triangle(p: Vec3, a: Vec3, b: Vec3, c: Vec3) -> Vec3 {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+
+    let bp = p - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let cp = p - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denom = 1.0 / (va + vb + vc);
+    let v = vb * denom;
+    let w = vc * denom;
+    a + ab * v + ac * w
+}
+
+fn centroid(positions: &[Vec3]) -> Vec3 {
+    if positions.is_empty() {
+        return Vec3::ZERO;
+    }
+    positions.iter().sum::<Vec3>() / positions.len() as f32
+}
+
+fn points_bounds(positions: &[Vec3]) -> (Vec3, Vec3) {
+    positions.iter().fold(
+        (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+        |(min, max), &p| (min.min(p), max.max(p)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::{Mat3, Vec4};
+
+    fn cube(position: Vec3, half_size: f32) -> PhysicsObject {
+        PhysicsObject::RigidBody {
+            position,
+            velocity: Vec3::ZERO,
+            acceleration: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            angular_velocity: Vec3::ZERO,
+            angular_acceleration: Vec3::ZERO,
+            mass: 1.0,
+            inertia_tensor: Mat3::IDENTITY,
+            bounding_box: Vec4::new(0.0, 0.0, 0.0, half_size),
+        }
+    }
+
+    fn world_with_cubes(positions: &[Vec3]) -> PhysicsWorld {
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        for &position in positions {
+            world.add_object(cube(position, 1.0));
+        }
+        world.sync_broad_phase();
+        world
+    }
+
+    #[test]
+    fn test_raycast_returns_closest_hit() {
+        let world = world_with_cubes(&[Vec3::new(10.0, 0.0, 0.0), Vec3::new(5.0, 0.0, 0.0)]);
+
+        let hit = world
+            .raycast(Vec3::ZERO, Vec3::X, 100.0, &QueryFilter::default())
+            .unwrap();
+        assert_eq!(hit.body, 1);
+        assert!((hit.distance - 4.0).abs() < 1e-4);
+        assert!((hit.normal - Vec3::NEG_X).length() < 1e-4);
+
+        let hits = world.raycast_all(Vec3::ZERO, Vec3::X, 100.0, &QueryFilter::default());
+        assert_eq!(hits.iter().map(|h| h.body).collect::<Vec<_>>(), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_raycast_respects_filter_and_range() {
+        let mut world = world_with_cubes(&[Vec3::new(5.0, 0.0, 0.0), Vec3::new(10.0, 0.0, 0.0)]);
+        world.set_collision_filter(0, CollisionFilter::new(0b10, ALL_GROUPS));
+
+        let hit = world
+            .raycast(Vec3::ZERO, Vec3::X, 100.0, &QueryFilter::new(0b01))
+            .unwrap();
+        assert_eq!(hit.body, 1);
+
+        assert!(world
+            .raycast(Vec3::ZERO, Vec3::X, 3.0, &QueryFilter::default())
+            .is_none());
+        assert!(world
+            .raycast(
+                Vec3::ZERO,
+                Vec3::X,
+                100.0,
+                &QueryFilter::new(0b01).excluding(1)
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn test_sphere_cast_stops_at_surface() {
+        let world = world_with_cubes(&[Vec3::new(10.0, 0.0, 0.0)]);
+
+        let hit = world
+            .sphere_cast(Vec3::ZERO, 0.5, Vec3::X, 100.0, &QueryFilter::default())
+            .unwrap();
+        assert!((hit.distance - 8.5).abs() < 1e-2);
+        assert!((hit.point.x - 9.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn test_overlap_queries() {
+        let world = world_with_cubes(&[Vec3::new(0.0, 0.0, 0.0), Vec3::new(20.0, 0.0, 0.0)]);
+
+        let found = world.overlap_sphere(Vec3::new(2.5, 0.0, 0.0), 2.0, &QueryFilter::default());
+        assert_eq!(found, vec![0]);
+
+        let found = world.overlap_aabb(
+            Vec3::new(-30.0, -1.0, -1.0),
+            Vec3::new(30.0, 1.0, 1.0),
+            &QueryFilter::default(),
+        );
+        assert_eq!(found, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_unbounded_raycast_terminates() {
+        let world = world_with_cubes(&[Vec3::new(10.0, 0.0, 0.0), Vec3::new(30.0, 5.0, 0.0)]);
+
+        let hit = world
+            .raycast(Vec3::ZERO, Vec3::X, f32::MAX, &QueryFilter::default())
+            .unwrap();
+        assert_eq!(hit.body, 0);
+
+        // Misses everything, the walk has to stop at the edge of the occupied cells
+        let filter = QueryFilter::default();
+        assert!(world
+            .raycast(Vec3::ZERO, Vec3::Y, f32::MAX, &filter)
+            .is_none());
+        assert!(world
+            .raycast(Vec3::new(-1e6, 0.0, 0.0), Vec3::NEG_X, f32::MAX, &filter)
+            .is_none());
+        let hits = world.raycast_all(Vec3::new(-1e6, 0.0, 0.0), Vec3::X, f32::MAX, &filter);
+        assert_eq!(hits.iter().map(|h| h.body).collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn test_queries_see_reused_slot() {
+        let mut world = world_with_cubes(&[Vec3::new(10.0, 0.0, 0.0)]);
+
+        // Same body count as the synced broad phase, but a different place
+        world.remove_object(0);
+        assert_eq!(world.add_object(cube(Vec3::new(-10.0, 0.0, 0.0), 1.0)), 0);
+
+        let filter = QueryFilter::default();
+        assert!(world.raycast(Vec3::ZERO, Vec3::X, 100.0, &filter).is_none());
+        let hit = world
+            .raycast(Vec3::ZERO, Vec3::NEG_X, 100.0, &filter)
+            .unwrap();
+        assert_eq!(hit.body, 0);
+        assert_eq!(
+            world.overlap_sphere(Vec3::new(-10.0, 0.0, 0.0), 1.0, &filter),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn test_queries_rebuild_broad_phase_after_update() {
+        let mut world = world_with_cubes(&[Vec3::new(10.0, 0.0, 0.0)]);
+        if let PhysicsObject::RigidBody { velocity, .. } = &mut *world.objects[0].borrow_mut() {
+            *velocity = Vec3::new(0.0, 60.0, 0.0);
+        }
+
+        // The cube moves 10 units up, well clear of where the broad phase
+        // was built
+        world.update(1.0 / 6.0);
+        assert!(world.broad_phase_dirty.get());
+
+        let filter = QueryFilter::default();
+        assert!(world.raycast(Vec3::ZERO, Vec3::X, 100.0, &filter).is_none());
+        assert!(!world.broad_phase_dirty.get());
+        let hit = world
+            .raycast(Vec3::new(0.0, 10.0, 0.0), Vec3::X, 100.0, &filter)
+            .unwrap();
+        assert_eq!(hit.body, 0);
+    }
+}
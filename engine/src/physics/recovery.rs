@@ -0,0 +1,335 @@
+// Device loss recovery for GPU particles. `GpuPhysicsSystem::step` treats
+// `DeviceLost` and synchronization errors as faults and moves through the
+// health states:
+//
+//   Running -> Recovering -> Running   rebuilt from the last snapshot
+//   Recovering -> Degraded             out of attempts, stepping on the CPU
+//   Recovering -> Failed               out of attempts and no CPU fallback
+//
+// Rebuilding recreates every buffer, pipeline and sync object and uploads the
+// last CPU-side snapshot of the particles, taken on every upload and every
+// `snapshot_interval` steps. Steps since the snapshot are lost. Once degraded,
+// `CpuParticleBackend` integrates the particles and emitters pause until
+// `try_recover` succeeds.
+//
+// Faults can be injected with `set_fault_hook` to exercise all of this.
+
+use crate::physics::backend::{CpuParticleBackend, ParticleBackend};
+use crate::physics::gpu_physics::{GpuPhysicsSystem, PhysicsError, SystemState};
+use crate::physics::logging::{error_with_context, info_with_context, warn_with_context};
+use crate::physics::particle_layout::Particle;
+
+// Steps between particle snapshots
+const DEFAULT_SNAPSHOT_INTERVAL: u32 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PhysicsHealth {
+    // Stepping on the GPU
+    #[default]
+    Running,
+    // Out of recovery attempts, stepping on the CPU fallback
+    Degraded,
+    // Rebuilding the GPU resources after a fault
+    Recovering,
+    // Out of recovery attempts without a fallback, steps fail
+    Failed,
+}
+
+// Called after every GPU step with the frame count. Returning an error fails
+// the step with it as if the device had reported it.
+pub type FaultHook = Box<dyn FnMut(u32) -> Option<PhysicsError> + Send>;
+
+pub(crate) struct Recovery {
+    pub(crate) snapshot: Vec<Particle>,
+    // Simulated time the snapshot was taken at
+    pub(crate) snapshot_time: f32,
+    snapshot_interval: u32,
+    cpu_fallback: bool,
+    // Set while degraded
+    pub(crate) fallback: Option<CpuParticleBackend>,
+    fault_hook: Option<FaultHook>,
+}
+
+impl Default for Recovery {
+    fn default() -> Self {
+        Self {
+            snapshot: Vec::new(),
+            snapshot_time: 0.0,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            cpu_fallback: true,
+            fallback: None,
+            fault_hook: None,
+        }
+    }
+}
+
+impl Recovery {
+    pub(crate) fn injected_fault(&mut self, frame: u32) -> Option<PhysicsError> {
+        self.fault_hook.as_mut().and_then(|hook| hook(frame))
+    }
+}
+
+impl SystemState {
+    // Records a fault and returns the state to move to. Gives up once more
+    // than `max_attempts` faults happened in a row.
+    pub(crate) fn record_failure(
+        &mut self,
+        error: PhysicsError,
+        max_attempts: u32,
+        cpu_fallback: bool,
+    ) -> PhysicsHealth {
+        self.recovery_attempts += 1;
+        self.last_error = Some(error);
+        self.needs_reset = true;
+        self.health = if self.recovery_attempts <= max_attempts {
+            PhysicsHealth::Recovering
+        } else if cpu_fallback {
+            PhysicsHealth::Degraded
+        } else {
+            PhysicsHealth::Failed
+        };
+        self.health
+    }
+
+    // A GPU step went through, the faults so far are behind us
+    pub(crate) fn record_success(&mut self) {
+        self.recovery_attempts = 0;
+        self.needs_reset = false;
+        self.health = PhysicsHealth::Running;
+    }
+}
+
+impl GpuPhysicsSystem {
+    pub fn health(&self) -> PhysicsHealth {
+        self.state.health
+    }
+
+    // Faults in a row recovered from before giving up on the GPU
+    pub fn set_max_recovery_attempts(&mut self, attempts: u32) {
+        self.max_recovery_attempts = attempts;
+    }
+
+    // Whether to keep simulating on the CPU after giving up on the GPU,
+    // rather than failing every step. On by default.
+    pub fn set_cpu_fallback(&mut self, enabled: bool) {
+        self.recovery.cpu_fallback = enabled;
+    }
+
+    // Steps between particle snapshots, zero to only snapshot uploads.
+    // Every snapshot reads the whole particle buffer back.
+    pub fn set_snapshot_interval(&mut self, steps: u32) {
+        self.recovery.snapshot_interval = steps;
+    }
+
+    pub fn set_fault_hook(&mut self, hook: Option<FaultHook>) {
+        self.recovery.fault_hook = hook;
+    }
+
+    // Rebuilds every GPU resource and resumes from the last snapshot, or from
+    // the CPU fallback's particles while degraded. Brings a degraded or failed
+    // system back onto the GPU, which then gets a fresh set of attempts.
+    pub fn try_recover(&mut self) -> Result<(), PhysicsError> {
+        let previous = self.state.health;
+        if matches!(previous, PhysicsHealth::Degraded | PhysicsHealth::Failed) {
+            self.state.recovery_attempts = 0;
+        }
+        self.state.health = PhysicsHealth::Recovering;
+        info_with_context!(
+            "RECOVERY",
+            "Rebuilding GPU physics, attempt {} of {}",
+            self.state.recovery_attempts.max(1),
+            self.max_recovery_attempts
+        );
+
+        match self.rebuild() {
+            Ok(()) => {
+                self.state.health = PhysicsHealth::Running;
+                self.state.needs_reset = false;
+                info_with_context!("RECOVERY", "GPU physics rebuilt");
+                Ok(())
+            }
+            Err(e) => {
+                if previous != PhysicsHealth::Running {
+                    self.state.health = previous;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn rebuild(&mut self) -> Result<(), PhysicsError> {
+        let (particles, time) = match &self.recovery.fallback {
+            Some(fallback) => (fallback.get_particle_data()?, fallback.time()),
+            None => (self.recovery.snapshot.clone(), self.recovery.snapshot_time),
+        };
+        let capacity = (self.particle_capacity as usize).max(particles.len());
+
+        self.release_gpu_resources();
+        self.buffer_pool.cleanup();
+        self.state.is_initialized = false;
+        self.current_frame = 0;
+
+        self.allocate_particle_buffers(capacity)?;
+        self.create_descriptor_sets()?;
+        self.create_compute_pipeline()?;
+        self.create_sync_primitives()?;
+        self.state.is_initialized = true;
+
+        // Uploads go to the fallback while there is one
+        let fallback = self.recovery.fallback.take();
+        self.elapsed = time;
+        self.update_particles(&particles).inspect_err(|_| {
+            self.recovery.fallback = fallback;
+        })
+    }
+
+    // Handles a faulted step: rebuilds and retries, or gives up on the GPU
+    pub(crate) fn recover_step(
+        &mut self,
+        error: PhysicsError,
+        delta_time: f32,
+    ) -> Result<(), PhysicsError> {
+        warn_with_context!("RECOVERY", "GPU physics step failed: {}", error);
+        let health = self.state.record_failure(
+            error,
+            self.max_recovery_attempts,
+            self.recovery.cpu_fallback,
+        );
+        match health {
+            PhysicsHealth::Degraded => {
+                self.enter_fallback();
+                self.step_fallback(delta_time)
+            }
+            PhysicsHealth::Failed => {
+                error_with_context!(
+                    "RECOVERY",
+                    "Giving up on GPU physics after {} faults",
+                    self.state.recovery_attempts
+                );
+                self.release_gpu_resources();
+                self.state.is_initialized = false;
+                Err(PhysicsError::DeviceLost {
+                    message: format!(
+                        "GPU physics failed after {} faults",
+                        self.state.recovery_attempts
+                    ),
+                    source: None,
+                })
+            }
+            PhysicsHealth::Running | PhysicsHealth::Recovering => match self.try_recover() {
+                Ok(()) => self.step(delta_time),
+                Err(e) => self.recover_step(e, delta_time),
+            },
+        }
+    }
+
+    // Moves the simulation onto the CPU, starting from the last snapshot
+    fn enter_fallback(&mut self) {
+        error_with_context!(
+            "RECOVERY",
+            "Giving up on GPU physics after {} faults, falling back to the CPU",
+            self.state.recovery_attempts
+        );
+        self.release_gpu_resources();
+        self.state.is_initialized = false;
+
+        let mut fallback = CpuParticleBackend::new(self.capacity());
+        // Can't fail, the snapshot holds at most `capacity` particles
+        let _ = fallback.update_particles(&self.recovery.snapshot);
+        fallback.elapsed = self.recovery.snapshot_time;
+        self.elapsed = self.recovery.snapshot_time;
+        self.recovery.fallback = Some(fallback);
+    }
+
+    pub(crate) fn step_fallback(&mut self, delta_time: f32) -> Result<(), PhysicsError> {
+        let params = self.params();
+        let fallback =
+            self.recovery
+                .fallback
+                .as_mut()
+                .ok_or_else(|| PhysicsError::InvalidOperation {
+                    message: "Degraded without a CPU fallback".to_string(),
+                    operation: "step_fallback".to_string(),
+                    state: format!("{:?}", self.state),
+                })?;
+
+        // Settings may change while degraded
+        fallback.set_params(params);
+        fallback.set_force_fields(&self.force_fields)?;
+        fallback.set_fluid(self.fluid)?;
+        fallback.step(delta_time)?;
+
+        self.frame_count = self.frame_count.wrapping_add(1);
+        self.elapsed += delta_time;
+        Ok(())
+    }
+
+    // Reads the particles back every `snapshot_interval` steps
+    pub(crate) fn snapshot_if_due(&mut self) -> Result<(), PhysicsError> {
+        let interval = self.recovery.snapshot_interval;
+        if interval == 0 || !self.frame_count.is_multiple_of(interval) {
+            return Ok(());
+        }
+        self.recovery.snapshot = self.get_particle_data()?;
+        self.recovery.snapshot_time = self.elapsed;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_lost() -> PhysicsError {
+        PhysicsError::DeviceLost {
+            message: "injected".to_string(),
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_failures_escalate() {
+        let mut state = SystemState::default();
+        assert_eq!(
+            state.record_failure(device_lost(), 2, true),
+            PhysicsHealth::Recovering
+        );
+        assert_eq!(
+            state.record_failure(device_lost(), 2, true),
+            PhysicsHealth::Recovering
+        );
+        assert_eq!(
+            state.record_failure(device_lost(), 2, true),
+            PhysicsHealth::Degraded
+        );
+        assert!(state.needs_reset);
+        assert!(matches!(
+            state.last_error,
+            Some(PhysicsError::DeviceLost { .. })
+        ));
+
+        // Only faults in a row count
+        state.record_success();
+        assert_eq!(state.health, PhysicsHealth::Running);
+        assert_eq!(state.recovery_attempts, 0);
+        assert_eq!(
+            state.record_failure(device_lost(), 0, false),
+            PhysicsHealth::Failed
+        );
+    }
+
+    #[test]
+    fn test_fault_hook() {
+        let mut recovery = Recovery::default();
+        assert!(recovery.injected_fault(0).is_none());
+
+        recovery.fault_hook = Some(Box::new(|frame| (frame % 3 == 2).then(device_lost)));
+        let faults: Vec<bool> = (0..6)
+            .map(|frame| recovery.injected_fault(frame).is_some())
+            .collect();
+        assert_eq!(faults, vec![false, false, true, false, false, true]);
+        assert!(recovery
+            .injected_fault(2)
+            .is_some_and(|error| error.is_device_error()));
+    }
+}
@@ -2,40 +2,11 @@ use ash::vk;
 use shaderc;
 use std::sync::Arc;
 
-pub struct ShaderModule {
-    device: Arc<ash::Device>,
-    module: vk::ShaderModule,
-}
-
-impl ShaderModule {
-    pub fn new(
-        device: Arc<ash::Device>,
-        spirv_code: &[u32],
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let create_info = vk::ShaderModuleCreateInfo::builder()
-            .code(spirv_code)
-            .build();
-
-        let module = unsafe { device.create_shader_module(&create_info, None)? };
-
-        Ok(Self { device, module })
-    }
+mod reflect;
 
-    pub fn get_module(&self) -> vk::ShaderModule {
-        self.module
-    }
-}
-
-impl Drop for ShaderModule {
-    fn drop(&mut self) {
-        unsafe {
-            self.device.destroy_shader_module(self.module, None);
-        }
-    }
-}
-
-use shaderc;
-use std::sync::Arc;
+#[cfg(test)]
+pub(crate) use reflect::assembler;
+pub(crate) use reflect::{BlockLayout, ShaderReflection};
 
 pub struct ShaderModule {
     device: Arc<ash::Device>,
@@ -69,6 +40,29 @@ impl Drop for ShaderModule {
     }
 }
 
+// Files particle shaders can `#include`
+const INCLUDES: [(&str, &str); 2] = [
+    ("particle_common.glsl", include_str!("particle_common.glsl")),
+    ("particle_grid.glsl", include_str!("particle_grid.glsl")),
+];
+
+// Compile options that resolve `#include "particle_common.glsl"` and friends.
+// Shaders enable the directive with `GL_GOOGLE_include_directive`.
+pub fn include_options() -> Option<shaderc::CompileOptions<'static>> {
+    let mut options = shaderc::CompileOptions::new()?;
+    options.set_include_callback(|requested, _include_type, _requesting, _depth| {
+        INCLUDES
+            .iter()
+            .find(|(name, _)| *name == requested)
+            .map(|(name, content)| shaderc::ResolvedInclude {
+                resolved_name: name.to_string(),
+                content: content.to_string(),
+            })
+            .ok_or_else(|| format!("Unknown shader include `{}`", requested))
+    });
+    Some(options)
+}
+
 pub fn compile_shader(
     source: &str,
     shader_kind: shaderc::ShaderKind,
@@ -0,0 +1,41 @@
+// Declarations shared by every particle shader. The layout mirrors
+// physics::particle_layout; the engine reflects each shader at startup and
+// refuses to run it if these blocks drift from the Rust structs, so change
+// both together and bump the version.
+
+layout(constant_id = 3) const uint PARTICLE_LAYOUT_VERSION = 4;
+
+layout(local_size_x_id = 0, local_size_y_id = 1, local_size_z_id = 2) in;
+
+struct Particle {
+    vec3 position;
+    float mass;     // 0 pins the particle in place
+    vec3 velocity;
+    float lifetime; // seconds left, dead at <= 0
+    vec4 color;
+};
+
+layout(push_constant) uniform PushConstants {
+    vec3 gravity;
+    float delta_time;
+    vec2 bounds;       // x: min, y: max on every axis
+    float max_velocity;
+    uint particle_count;
+    uint emitter_count;
+    uint spawn_count;
+    uint field_count;
+    float time;        // seconds since the simulation started
+    float cell_size;   // neighbor grid cell size, 0 when the grid is off
+    uint cell_count;   // neighbor grid buckets, a power of two
+    uint pass_index;   // which sub-pass of a neighbor pass is running
+    float rest_density;
+    float stiffness;
+    float viscosity;
+} params;
+
+// PCG hash, matches emitter::hash on the CPU
+uint hash(uint value) {
+    uint state = value * 747796405u + 2891336453u;
+    uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
@@ -0,0 +1,63 @@
+// Neighbor search over the particle grid that grid_hash.comp,
+// grid_prefix_sum.comp and grid_reorder.comp build every step. Include after
+// particle_common.glsl. Cells hash into `params.cell_count` buckets, so a
+// bucket may hold particles from distant cells too; consumers filter by
+// distance. Iterate the particles near `position` with:
+//
+//     ivec3 cell = grid_cell(position);
+//     for (uint n = 0u; n < GRID_NEIGHBOR_CELLS; n++) {
+//         uvec2 range = grid_neighbor_range(cell, n);
+//         for (uint k = range.x; k < range.y; k++) {
+//             uint j = grid_sorted.indices[k];
+//             ...
+//         }
+//     }
+//
+// Mirrored on the CPU by physics::particle_grid.
+
+const uint GRID_INVALID_BUCKET = 0xffffffffu;
+const uint GRID_NEIGHBOR_CELLS = 27u;
+
+// Particles per bucket, cleared by the CPU before hashing
+layout(std430, set = 0, binding = 7) buffer GridCounts {
+    uint counts[];
+} grid_counts;
+
+// First sorted index of every bucket, plus the total at the end
+layout(std430, set = 0, binding = 8) buffer GridStarts {
+    uint starts[];
+} grid_starts;
+
+// Bucket of every particle and its rank within the bucket
+layout(std430, set = 0, binding = 9) buffer GridEntries {
+    uvec2 entries[];
+} grid_entries;
+
+// Alive particle indices, grouped by bucket
+layout(std430, set = 0, binding = 10) buffer GridSorted {
+    uint indices[];
+} grid_sorted;
+
+ivec3 grid_cell(vec3 position) {
+    return ivec3(floor(position / params.cell_size));
+}
+
+uint grid_bucket(ivec3 cell) {
+    return hash(uint(cell.x) ^ hash(uint(cell.y) ^ hash(uint(cell.z)))) & (params.cell_count - 1u);
+}
+
+ivec3 grid_neighbor_offset(uint n) {
+    return ivec3(int(n % 3u), int((n / 3u) % 3u), int(n / 9u)) - 1;
+}
+
+// Sorted index range of the n-th cell around `cell`. Empty when an earlier
+// neighbor cell hashed into the same bucket, so no particle is visited twice.
+uvec2 grid_neighbor_range(ivec3 cell, uint n) {
+    uint bucket = grid_bucket(cell + grid_neighbor_offset(n));
+    for (uint m = 0u; m < n; m++) {
+        if (grid_bucket(cell + grid_neighbor_offset(m)) == bucket) {
+            return uvec2(0u);
+        }
+    }
+    return uvec2(grid_starts.starts[bucket], grid_starts.starts[bucket + 1u]);
+}
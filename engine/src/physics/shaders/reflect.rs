@@ -0,0 +1,590 @@
+// Minimal SPIR-V reflection, just enough to read back the memory layout of
+// push constant blocks, storage buffers and specialization constant defaults
+// so they can be checked against the `#[repr(C)]` structs on the CPU side.
+
+use std::collections::HashMap;
+use std::fmt;
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+const HEADER_WORDS: usize = 5;
+
+// Opcodes
+const OP_NAME: u32 = 5;
+const OP_MEMBER_NAME: u32 = 6;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_SPEC_CONSTANT_TRUE: u32 = 48;
+const OP_SPEC_CONSTANT_FALSE: u32 = 49;
+const OP_SPEC_CONSTANT: u32 = 50;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+// Decorations
+const DECORATION_SPEC_ID: u32 = 1;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+// Storage classes. SPIR-V before 1.3 puts storage buffers in `Uniform`.
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflectError(String);
+
+impl fmt::Display for ReflectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SPIR-V reflection failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for ReflectError {}
+
+// Byte layout of a struct as seen by the shader
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockLayout {
+    pub name: String,
+    pub size: u32,
+    pub members: Vec<MemberLayout>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemberLayout {
+    pub name: String,
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl BlockLayout {
+    pub fn new(name: &str, size: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            size: size as u32,
+            members: Vec::new(),
+        }
+    }
+
+    pub fn with_member(mut self, name: &str, offset: usize, size: usize) -> Self {
+        self.members.push(MemberLayout {
+            name: name.to_string(),
+            offset: offset as u32,
+            size: size as u32,
+        });
+        self
+    }
+
+    // Differences between the layout the shader uses (`self`) and the one
+    // the CPU expects, one line per mismatch. Names are informational only
+    // since stripped SPIR-V has none, so members are matched by position.
+    pub fn mismatches(&self, expected: &BlockLayout) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        if self.size != expected.size {
+            mismatches.push(format!(
+                "{}: shader size {} bytes, expected {}",
+                expected.name, self.size, expected.size
+            ));
+        }
+        if self.members.len() != expected.members.len() {
+            mismatches.push(format!(
+                "{}: shader has {} members, expected {}",
+                expected.name,
+                self.members.len(),
+                expected.members.len()
+            ));
+        }
+        for (actual, wanted) in self.members.iter().zip(&expected.members) {
+            if actual.offset != wanted.offset || actual.size != wanted.size {
+                mismatches.push(format!(
+                    "{}.{}: shader member `{}` at offset {} with size {}, expected offset {} with size {}",
+                    expected.name,
+                    wanted.name,
+                    actual.name,
+                    actual.offset,
+                    actual.size,
+                    wanted.offset,
+                    wanted.size
+                ));
+            }
+        }
+        mismatches
+    }
+}
+
+#[derive(Debug, Clone)]
+enum SpirvType {
+    Scalar { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Array { length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Variable {
+    type_id: u32,
+    storage_class: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct ShaderReflection {
+    names: HashMap<u32, String>,
+    member_names: HashMap<(u32, u32), String>,
+    decorations: HashMap<(u32, u32), u32>,
+    member_offsets: HashMap<(u32, u32), u32>,
+    matrix_strides: HashMap<(u32, u32), u32>,
+    types: HashMap<u32, SpirvType>,
+    constants: HashMap<u32, u32>,
+    spec_ids: HashMap<u32, u32>,
+    spec_defaults: HashMap<u32, u32>,
+    variables: HashMap<u32, Variable>,
+}
+
+impl ShaderReflection {
+    pub fn parse(words: &[u32]) -> Result<Self, ReflectError> {
+        if words.len() < HEADER_WORDS || words[0] != SPIRV_MAGIC {
+            return Err(ReflectError("not a SPIR-V module".to_string()));
+        }
+
+        let mut reflection = Self::default();
+        let mut cursor = HEADER_WORDS;
+        while cursor < words.len() {
+            let word_count = (words[cursor] >> 16) as usize;
+            let opcode = words[cursor] & 0xffff;
+            if word_count == 0 || cursor + word_count > words.len() {
+                return Err(ReflectError(format!(
+                    "truncated instruction at word {}",
+                    cursor
+                )));
+            }
+            reflection.record(opcode, &words[cursor + 1..cursor + word_count]);
+            cursor += word_count;
+        }
+        Ok(reflection)
+    }
+
+    fn record(&mut self, opcode: u32, operands: &[u32]) {
+        let operand = |i: usize| operands.get(i).copied().unwrap_or(0);
+        match opcode {
+            OP_NAME if !operands.is_empty() => {
+                self.names
+                    .insert(operand(0), literal_string(&operands[1..]));
+            }
+            OP_MEMBER_NAME if operands.len() >= 2 => {
+                self.member_names
+                    .insert((operand(0), operand(1)), literal_string(&operands[2..]));
+            }
+            OP_TYPE_BOOL => {
+                self.types
+                    .insert(operand(0), SpirvType::Scalar { width: 32 });
+            }
+            OP_TYPE_INT | OP_TYPE_FLOAT => {
+                self.types
+                    .insert(operand(0), SpirvType::Scalar { width: operand(1) });
+            }
+            OP_TYPE_VECTOR => {
+                self.types.insert(
+                    operand(0),
+                    SpirvType::Vector {
+                        component: operand(1),
+                        count: operand(2),
+                    },
+                );
+            }
+            OP_TYPE_MATRIX => {
+                self.types.insert(
+                    operand(0),
+                    SpirvType::Matrix {
+                        column: operand(1),
+                        count: operand(2),
+                    },
+                );
+            }
+            OP_TYPE_ARRAY => {
+                // The length is an id, resolved once all constants are known
+                self.types
+                    .insert(operand(0), SpirvType::Array { length: operand(2) });
+            }
+            OP_TYPE_RUNTIME_ARRAY => {
+                self.types.insert(
+                    operand(0),
+                    SpirvType::RuntimeArray {
+                        element: operand(1),
+                    },
+                );
+            }
+            OP_TYPE_STRUCT => {
+                self.types.insert(
+                    operand(0),
+                    SpirvType::Struct {
+                        members: operands[1..].to_vec(),
+                    },
+                );
+            }
+            OP_TYPE_POINTER => {
+                self.types.insert(
+                    operand(0),
+                    SpirvType::Pointer {
+                        pointee: operand(2),
+                    },
+                );
+            }
+            OP_CONSTANT => {
+                self.constants.insert(operand(1), operand(2));
+            }
+            OP_SPEC_CONSTANT => {
+                self.spec_defaults.insert(operand(1), operand(2));
+            }
+            OP_SPEC_CONSTANT_TRUE | OP_SPEC_CONSTANT_FALSE => {
+                let value = (opcode == OP_SPEC_CONSTANT_TRUE) as u32;
+                self.spec_defaults.insert(operand(1), value);
+            }
+            OP_VARIABLE => {
+                self.variables.insert(
+                    operand(1),
+                    Variable {
+                        type_id: operand(0),
+                        storage_class: operand(2),
+                    },
+                );
+            }
+            OP_DECORATE if operands.len() >= 2 => {
+                let (target, decoration) = (operand(0), operand(1));
+                if decoration == DECORATION_SPEC_ID {
+                    self.spec_ids.insert(operand(2), target);
+                } else {
+                    self.decorations.insert((target, decoration), operand(2));
+                }
+            }
+            OP_MEMBER_DECORATE if operands.len() >= 3 => {
+                let key = (operand(0), operand(1));
+                match operand(2) {
+                    DECORATION_OFFSET => {
+                        self.member_offsets.insert(key, operand(3));
+                    }
+                    DECORATION_MATRIX_STRIDE => {
+                        self.matrix_strides.insert(key, operand(3));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Default value of the specialization constant with `constant_id`
+    pub fn spec_constant(&self, constant_id: u32) -> Option<u32> {
+        let id = self.spec_ids.get(&constant_id)?;
+        self.spec_defaults.get(id).copied()
+    }
+
+    pub fn push_constants(&self) -> Option<BlockLayout> {
+        self.variables
+            .values()
+            .find(|variable| variable.storage_class == STORAGE_CLASS_PUSH_CONSTANT)
+            .and_then(|variable| self.pointee(variable.type_id))
+            .and_then(|block| self.struct_layout(block))
+    }
+
+    // Layout of the block bound as a storage buffer at `set`/`binding`
+    pub fn storage_buffer_block(&self, set: u32, binding: u32) -> Option<BlockLayout> {
+        self.struct_layout(self.storage_buffer_type(set, binding)?)
+    }
+
+    // Layout of the elements of the runtime array that makes up the storage
+    // buffer at `set`/`binding`, e.g. `buffer Particles { Particle p[]; }`
+    pub fn storage_buffer_element(&self, set: u32, binding: u32) -> Option<(u32, BlockLayout)> {
+        let block = self.storage_buffer_type(set, binding)?;
+        let SpirvType::Struct { members } = self.types.get(&block)? else {
+            return None;
+        };
+        let array = *members.last()?;
+        let SpirvType::RuntimeArray { element } = self.types.get(&array)? else {
+            return None;
+        };
+        let stride = self.decoration(array, DECORATION_ARRAY_STRIDE)?;
+        Some((stride, self.struct_layout(*element)?))
+    }
+
+    fn storage_buffer_type(&self, set: u32, binding: u32) -> Option<u32> {
+        self.variables.iter().find_map(|(&id, variable)| {
+            let is_buffer = variable.storage_class == STORAGE_CLASS_STORAGE_BUFFER
+                || variable.storage_class == STORAGE_CLASS_UNIFORM;
+            let matches = self.decoration(id, DECORATION_DESCRIPTOR_SET).unwrap_or(0) == set
+                && self.decoration(id, DECORATION_BINDING) == Some(binding);
+            (is_buffer && matches)
+                .then(|| self.pointee(variable.type_id))
+                .flatten()
+        })
+    }
+
+    fn decoration(&self, id: u32, decoration: u32) -> Option<u32> {
+        self.decorations.get(&(id, decoration)).copied()
+    }
+
+    fn pointee(&self, pointer: u32) -> Option<u32> {
+        match self.types.get(&pointer)? {
+            SpirvType::Pointer { pointee } => Some(*pointee),
+            _ => None,
+        }
+    }
+
+    fn struct_layout(&self, id: u32) -> Option<BlockLayout> {
+        let SpirvType::Struct { members } = self.types.get(&id)? else {
+            return None;
+        };
+
+        let mut layout = BlockLayout {
+            name: self.names.get(&id).cloned().unwrap_or_default(),
+            size: 0,
+            members: Vec::with_capacity(members.len()),
+        };
+        for (index, &member) in members.iter().enumerate() {
+            let key = (id, index as u32);
+            let offset = *self.member_offsets.get(&key)?;
+            let size = self.type_size(member, self.matrix_strides.get(&key).copied())?;
+            layout.size = layout.size.max(offset + size);
+            layout.members.push(MemberLayout {
+                name: self.member_names.get(&key).cloned().unwrap_or_default(),
+                offset,
+                size,
+            });
+        }
+        Some(layout)
+    }
+
+    // Size in bytes of a type inside an explicitly laid out block. Runtime
+    // arrays have no size of their own.
+    fn type_size(&self, id: u32, matrix_stride: Option<u32>) -> Option<u32> {
+        match self.types.get(&id)? {
+            SpirvType::Scalar { width } => Some(width / 8),
+            SpirvType::Vector { component, count } => {
+                Some(self.type_size(*component, None)? * count)
+            }
+            SpirvType::Matrix { column, count } => {
+                let stride = match matrix_stride {
+                    Some(stride) => stride,
+                    None => self.type_size(*column, None)?,
+                };
+                Some(stride * count)
+            }
+            SpirvType::Array { length, .. } => {
+                let length = *self.constants.get(length)?;
+                let stride = self.decoration(id, DECORATION_ARRAY_STRIDE)?;
+                Some(stride * length)
+            }
+            SpirvType::RuntimeArray { .. } => Some(0),
+            SpirvType::Struct { .. } => Some(self.struct_layout(id)?.size),
+            SpirvType::Pointer { .. } => None,
+        }
+    }
+}
+
+fn literal_string(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .take_while(|&byte| byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Hand-assembled modules for tests that can't run the shader compiler
+#[cfg(test)]
+pub(crate) mod assembler {
+    use super::*;
+
+    pub const FLOAT: u32 = 1;
+    pub const UINT: u32 = 2;
+    pub const VEC2: u32 = 3;
+    pub const VEC3: u32 = 4;
+    pub const VEC4: u32 = 5;
+    pub const INT: u32 = 6;
+
+    pub struct SpirvAssembler {
+        words: Vec<u32>,
+        next_id: u32,
+    }
+
+    impl SpirvAssembler {
+        pub fn new() -> Self {
+            let mut assembler = Self {
+                words: vec![SPIRV_MAGIC, 0x0001_0000, 0, 0, 0],
+                next_id: 16,
+            };
+            assembler.op(OP_TYPE_FLOAT, &[FLOAT, 32]);
+            assembler.op(OP_TYPE_INT, &[UINT, 32, 0]);
+            assembler.op(OP_TYPE_VECTOR, &[VEC2, FLOAT, 2]);
+            assembler.op(OP_TYPE_VECTOR, &[VEC3, FLOAT, 3]);
+            assembler.op(OP_TYPE_VECTOR, &[VEC4, FLOAT, 4]);
+            assembler.op(OP_TYPE_INT, &[INT, 32, 1]);
+            assembler
+        }
+
+        fn op(&mut self, opcode: u32, operands: &[u32]) {
+            self.words
+                .push(((operands.len() as u32 + 1) << 16) | opcode);
+            self.words.extend_from_slice(operands);
+        }
+
+        fn id(&mut self) -> u32 {
+            self.next_id += 1;
+            self.next_id
+        }
+
+        fn name(&mut self, id: u32, member: Option<u32>, name: &str) {
+            let mut bytes = name.as_bytes().to_vec();
+            bytes.resize((bytes.len() / 4 + 1) * 4, 0);
+            let mut operands = vec![id];
+            operands.extend(member);
+            operands.extend(
+                bytes
+                    .chunks(4)
+                    .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])),
+            );
+            let opcode = if member.is_some() {
+                OP_MEMBER_NAME
+            } else {
+                OP_NAME
+            };
+            self.op(opcode, &operands);
+        }
+
+        // Declares a struct of (name, type, offset) members and returns its id
+        pub fn structure(&mut self, name: &str, members: &[(&str, u32, u32)]) -> u32 {
+            let id = self.id();
+            let mut operands = vec![id];
+            operands.extend(members.iter().map(|m| m.1));
+            self.op(OP_TYPE_STRUCT, &operands);
+            self.name(id, None, name);
+            for (index, (member, _, offset)) in members.iter().enumerate() {
+                self.name(id, Some(index as u32), member);
+                self.op(
+                    OP_MEMBER_DECORATE,
+                    &[id, index as u32, DECORATION_OFFSET, *offset],
+                );
+            }
+            id
+        }
+
+        pub fn push_constant_block(&mut self, block: u32) {
+            let (pointer, variable) = (self.id(), self.id());
+            self.op(
+                OP_TYPE_POINTER,
+                &[pointer, STORAGE_CLASS_PUSH_CONSTANT, block],
+            );
+            self.op(
+                OP_VARIABLE,
+                &[pointer, variable, STORAGE_CLASS_PUSH_CONSTANT],
+            );
+        }
+
+        pub fn runtime_array(&mut self, element: u32, stride: u32) -> u32 {
+            let array = self.id();
+            self.op(OP_TYPE_RUNTIME_ARRAY, &[array, element]);
+            self.op(OP_DECORATE, &[array, DECORATION_ARRAY_STRIDE, stride]);
+            array
+        }
+
+        // Storage buffer holding a runtime array of `element`
+        pub fn storage_buffer(&mut self, set: u32, binding: u32, element: u32, stride: u32) {
+            let array = self.runtime_array(element, stride);
+            let block = self.structure("Buffer", &[("items", array, 0)]);
+            self.storage_block(set, binding, block);
+        }
+
+        pub fn storage_block(&mut self, set: u32, binding: u32, block: u32) {
+            let (pointer, variable) = (self.id(), self.id());
+            self.op(
+                OP_TYPE_POINTER,
+                &[pointer, STORAGE_CLASS_STORAGE_BUFFER, block],
+            );
+            self.op(
+                OP_VARIABLE,
+                &[pointer, variable, STORAGE_CLASS_STORAGE_BUFFER],
+            );
+            self.op(OP_DECORATE, &[variable, DECORATION_DESCRIPTOR_SET, set]);
+            self.op(OP_DECORATE, &[variable, DECORATION_BINDING, binding]);
+        }
+
+        pub fn spec_constant(&mut self, constant_id: u32, default: u32) {
+            let id = self.id();
+            self.op(OP_SPEC_CONSTANT, &[UINT, id, default]);
+            self.op(OP_DECORATE, &[id, DECORATION_SPEC_ID, constant_id]);
+        }
+
+        pub fn finish(self) -> Vec<u32> {
+            self.words
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::assembler::*;
+    use super::*;
+
+    #[test]
+    fn test_reflects_blocks_and_spec_constants() {
+        let mut module = SpirvAssembler::new();
+        let constants = module.structure(
+            "Constants",
+            &[
+                ("gravity", VEC3, 0),
+                ("delta_time", FLOAT, 12),
+                ("bounds", VEC2, 16),
+            ],
+        );
+        module.push_constant_block(constants);
+        let item = module.structure("Item", &[("position", VEC4, 0), ("id", UINT, 16)]);
+        module.storage_buffer(0, 1, item, 32);
+        module.spec_constant(3, 7);
+        let reflection = ShaderReflection::parse(&module.finish()).unwrap();
+
+        let expected = BlockLayout::new("Constants", 24)
+            .with_member("gravity", 0, 12)
+            .with_member("delta_time", 12, 4)
+            .with_member("bounds", 16, 8);
+        let push_constants = reflection.push_constants().unwrap();
+        assert_eq!(push_constants, expected);
+        assert!(push_constants.mismatches(&expected).is_empty());
+
+        let (stride, element) = reflection.storage_buffer_element(0, 1).unwrap();
+        assert_eq!(stride, 32);
+        assert_eq!(element.size, 20);
+        assert_eq!(reflection.storage_buffer_block(0, 1).unwrap().size, 0);
+        assert!(reflection.storage_buffer_element(0, 0).is_none());
+
+        assert_eq!(reflection.spec_constant(3), Some(7));
+        assert_eq!(reflection.spec_constant(4), None);
+    }
+
+    #[test]
+    fn test_reports_mismatches() {
+        let shader = BlockLayout::new("Particle", 32)
+            .with_member("position", 0, 16)
+            .with_member("velocity", 16, 16);
+        let expected = BlockLayout::new("Particle", 48)
+            .with_member("position", 0, 12)
+            .with_member("mass", 12, 4)
+            .with_member("velocity", 16, 12);
+
+        let mismatches = shader.mismatches(&expected);
+        assert_eq!(mismatches.len(), 4);
+        assert!(mismatches[0].contains("size 32"));
+        assert!(ShaderReflection::parse(&[0, 1, 2]).is_err());
+    }
+}
@@ -0,0 +1,785 @@
+use glam::{Vec3, Vec4};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::graphics::resource::Vertex;
+use crate::physics::{
+    constraints::Constraint,
+    physics::{PhysicsObject, PhysicsWorld},
+};
+
+const DEFAULT_DENSITY: f32 = 1.0;
+const DEFAULT_TIMESTEP: f32 = 1.0 / 60.0;
+
+// XPBD compliance is the inverse of stiffness (m/N for distances). It is
+// divided by the squared substep length when solving, so the material
+// responds the same way regardless of the timestep; 0 is perfectly stiff.
+const DEFAULT_STRETCH_COMPLIANCE: f32 = 0.0;
+const DEFAULT_BEND_COMPLIANCE: f32 = 1e-3;
+const DEFAULT_VOLUME_COMPLIANCE: f32 = 0.0;
+
+#[derive(Debug, Clone, Copy)]
+struct DistanceElement {
+    a: usize,
+    b: usize,
+    rest_length: f32,
+    compliance: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct VolumeElement {
+    tet: [usize; 4],
+    rest_volume: f32,
+}
+
+#[derive(Debug, Clone)]
+struct XpbdState {
+    delta_time: f32,
+    // Lagrange multipliers accumulated over the iterations of a substep,
+    // distance elements first and volume elements after them
+    lambdas: Vec<f32>,
+}
+
+// Stretch, bend and volume constraints of one deformable body, solved with
+// XPBD. Pinned particles have infinite mass and are never moved.
+pub struct SoftBodyConstraint {
+    object_index: usize,
+    distances: Vec<DistanceElement>,
+    volumes: Vec<VolumeElement>,
+    volume_compliance: f32,
+    state: Mutex<XpbdState>,
+}
+
+impl SoftBodyConstraint {
+    fn new(
+        object_index: usize,
+        distances: Vec<DistanceElement>,
+        volumes: Vec<VolumeElement>,
+        volume_compliance: f32,
+    ) -> Self {
+        let lambdas = vec![0.0; distances.len() + volumes.len()];
+        Self {
+            object_index,
+            distances,
+            volumes,
+            volume_compliance,
+            state: Mutex::new(XpbdState {
+                delta_time: DEFAULT_TIMESTEP,
+                lambdas,
+            }),
+        }
+    }
+}
+
+impl Clone for SoftBodyConstraint {
+    fn clone(&self) -> Self {
+        SoftBodyConstraint {
+            object_index: self.object_index,
+            distances: self.distances.clone(),
+            volumes: self.volumes.clone(),
+            volume_compliance: self.volume_compliance,
+            state: Mutex::new(self.state.lock().unwrap().clone()),
+        }
+    }
+}
+
+impl Constraint for SoftBodyConstraint {
+    fn project(&self, objects: &mut [RefCell<PhysicsObject>]) {
+        let mut object = objects[self.object_index].borrow_mut();
+        let PhysicsObject::DeformableBody {
+            positions, masses, ..
+        } = &mut *object
+        else {
+            return;
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let dt2 = state.delta_time * state.delta_time;
+        let (distance_lambdas, volume_lambdas) = state.lambdas.split_at_mut(self.distances.len());
+
+        for (element, lambda) in self.distances.iter().zip(distance_lambdas) {
+            let w_a = inverse_mass(masses[element.a]);
+            let w_b = inverse_mass(masses[element.b]);
+            let w = w_a + w_b;
+            if w == 0.0 {
+                continue;
+            }
+
+            let delta = positions[element.a] - positions[element.b];
+            let length = delta.length();
+            if length < f32::EPSILON {
+                continue;
+            }
+            let normal = delta / length;
+
+            let alpha = element.compliance / dt2;
+            let c = length - element.rest_length;
+            let delta_lambda = (-c - alpha * *lambda) / (w + alpha);
+            *lambda += delta_lambda;
+
+            positions[element.a] += normal * (delta_lambda * w_a);
+            positions[element.b] -= normal * (delta_lambda * w_b);
+        }
+
+        let alpha = self.volume_compliance / dt2;
+        for (element, lambda) in self.volumes.iter().zip(volume_lambdas) {
+            let p = element.tet.map(|i| positions[i]);
+            let gradients = volume_gradients(&p);
+
+            let w: f32 = element
+                .tet
+                .iter()
+                .zip(&gradients)
+                .map(|(&i, gradient)| inverse_mass(masses[i]) * gradient.length_squared())
+                .sum();
+            if w == 0.0 {
+                continue;
+            }
+
+            let c = 6.0 * (tetrahedron_volume(&p) - element.rest_volume);
+            let delta_lambda = (-c - alpha * *lambda) / (w + alpha);
+            *lambda += delta_lambda;
+
+            for (&i, gradient) in element.tet.iter().zip(&gradients) {
+                positions[i] += *gradient * (delta_lambda * inverse_mass(masses[i]));
+            }
+        }
+    }
+
+    fn begin_substep(&self, delta_time: f32) {
+        let mut state = self.state.lock().unwrap();
+        state.delta_time = delta_time;
+        state.lambdas.fill(0.0);
+    }
+
+    fn bodies(&self) -> Vec<usize> {
+        vec![self.object_index]
+    }
+
+    fn clone_box(&self) -> Box<dyn Constraint> {
+        Box::new(self.clone())
+    }
+}
+
+// A soft body added to the world together with the surface it renders with.
+// Particle `i` maps to vertex `i` of the exported vertex buffer.
+#[derive(Debug, Clone)]
+pub struct SoftBodyHandle {
+    pub body: usize,
+    pub indices: Vec<u32>,
+    uvs: Vec<[f32; 2]>,
+}
+
+impl SoftBodyHandle {
+    pub fn export_vertices(&self, world: &PhysicsWorld) -> Vec<Vertex> {
+        let mut vertices = vec![
+            Vertex {
+                position: [0.0; 3],
+                normal: [0.0; 3],
+                uv: [0.0; 2],
+            };
+            self.uvs.len()
+        ];
+        self.write_vertices(world, &mut vertices);
+        vertices
+    }
+
+    // Writes current particle positions and smooth normals into a vertex
+    // buffer laid out like the one the body was built from
+    pub fn write_vertices(&self, world: &PhysicsWorld, vertices: &mut [Vertex]) {
+        let object = world.objects[self.body].borrow();
+        let PhysicsObject::DeformableBody { positions, .. } = &*object else {
+            return;
+        };
+
+        let normals = vertex_normals(positions, &self.indices);
+        for (i, vertex) in vertices.iter_mut().enumerate().take(positions.len()) {
+            vertex.position = positions[i].to_array();
+            vertex.normal = normals[i].to_array();
+            vertex.uv = self.uvs[i];
+        }
+    }
+}
+
+// Builds a cloth sheet: one particle per vertex, stretch constraints along
+// triangle edges and bend constraints across every edge shared by two
+// triangles (between the two opposite vertices)
+pub struct ClothBuilder {
+    positions: Vec<Vec3>,
+    uvs: Vec<[f32; 2]>,
+    indices: Vec<u32>,
+    density: f32,
+    stretch_compliance: f32,
+    bend_compliance: f32,
+    pinned: Vec<usize>,
+}
+
+impl ClothBuilder {
+    // Sheet spanned by `u` and `v` from `origin`, split into `columns` x `rows`
+    // quads. Particles are stored row by row, `(columns + 1) * (rows + 1)` total.
+    pub fn grid(origin: Vec3, u: Vec3, v: Vec3, columns: usize, rows: usize) -> Self {
+        let columns = columns.max(1);
+        let rows = rows.max(1);
+
+        let mut positions = Vec::with_capacity((columns + 1) * (rows + 1));
+        let mut uvs = Vec::with_capacity(positions.capacity());
+        for row in 0..=rows {
+            for column in 0..=columns {
+                let s = column as f32 / columns as f32;
+                let t = row as f32 / rows as f32;
+                positions.push(origin + u * s + v * t);
+                uvs.push([s, t]);
+            }
+        }
+
+        let stride = (columns + 1) as u32;
+        let mut indices = Vec::with_capacity(columns * rows * 6);
+        for row in 0..rows as u32 {
+            for column in 0..columns as u32 {
+                let i = row * stride + column;
+                indices.extend_from_slice(&[i, i + 1, i + stride]);
+                indices.extend_from_slice(&[i + 1, i + stride + 1, i + stride]);
+            }
+        }
+
+        Self::new(positions, uvs, indices)
+    }
+
+    // Cloth from a render mesh. Vertices are used as given, so vertices split
+    // at UV seams become separate particles and the cloth tears there.
+    pub fn from_vertices(vertices: &[Vertex], indices: &[u32]) -> Self {
+        Self::new(
+            vertices.iter().map(|v| Vec3::from(v.position)).collect(),
+            vertices.iter().map(|v| v.uv).collect(),
+            indices.to_vec(),
+        )
+    }
+
+    fn new(positions: Vec<Vec3>, uvs: Vec<[f32; 2]>, indices: Vec<u32>) -> Self {
+        Self {
+            positions,
+            uvs,
+            indices,
+            density: DEFAULT_DENSITY,
+            stretch_compliance: DEFAULT_STRETCH_COMPLIANCE,
+            bend_compliance: DEFAULT_BEND_COMPLIANCE,
+            pinned: Vec::new(),
+        }
+    }
+
+    // Mass per unit area, spread over the particles of each triangle
+    pub fn with_density(mut self, density: f32) -> Self {
+        self.density = density;
+        self
+    }
+
+    pub fn with_stretch_compliance(mut self, compliance: f32) -> Self {
+        self.stretch_compliance = compliance.max(0.0);
+        self
+    }
+
+    pub fn with_bend_compliance(mut self, compliance: f32) -> Self {
+        self.bend_compliance = compliance.max(0.0);
+        self
+    }
+
+    pub fn pin(mut self, particle: usize) -> Self {
+        self.pinned.push(particle);
+        self
+    }
+
+    pub fn pin_where<F: Fn(usize, Vec3) -> bool>(mut self, predicate: F) -> Self {
+        for (i, &position) in self.positions.iter().enumerate() {
+            if predicate(i, position) {
+                self.pinned.push(i);
+            }
+        }
+        self
+    }
+
+    pub fn build(self, world: &mut PhysicsWorld) -> SoftBodyHandle {
+        let triangles: Vec<[usize; 3]> = self
+            .indices
+            .chunks_exact(3)
+            .map(|t| [t[0] as usize, t[1] as usize, t[2] as usize])
+            .collect();
+
+        let mut masses = vec![0.0; self.positions.len()];
+        for tri in &triangles {
+            let [a, b, c] = tri.map(|i| self.positions[i]);
+            let area = 0.5 * (b - a).cross(c - a).length();
+            for &i in tri {
+                masses[i] += area * self.density / 3.0;
+            }
+        }
+
+        // Opposite vertices of the triangles sharing each edge
+        let mut edges: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new();
+        for &[a, b, c] in &triangles {
+            for (e0, e1, opposite) in [(a, b, c), (b, c, a), (c, a, b)] {
+                edges
+                    .entry((e0.min(e1), e0.max(e1)))
+                    .or_default()
+                    .push(opposite);
+            }
+        }
+
+        let mut distances = Vec::new();
+        for &(a, b) in edges.keys() {
+            distances.push(self.distance(a, b, self.stretch_compliance));
+        }
+        for opposite in edges.values() {
+            if let [c, d] = opposite[..] {
+                distances.push(self.distance(c, d, self.bend_compliance));
+            }
+        }
+
+        let body = add_deformable_body(
+            world,
+            self.positions,
+            masses,
+            &self.pinned,
+            Vec::new(),
+            distances,
+            0.0,
+        );
+
+        SoftBodyHandle {
+            body,
+            indices: self.indices,
+            uvs: self.uvs,
+        }
+    }
+
+    fn distance(&self, a: usize, b: usize, compliance: f32) -> DistanceElement {
+        DistanceElement {
+            a,
+            b,
+            rest_length: self.positions[a].distance(self.positions[b]),
+            compliance,
+        }
+    }
+}
+
+// Builds a volumetric soft body from a tetrahedral mesh, with a distance
+// constraint along every tetrahedron edge and a volume constraint per
+// tetrahedron
+pub struct SoftBodyBuilder {
+    positions: Vec<Vec3>,
+    tetrahedra: Vec<[usize; 4]>,
+    density: f32,
+    edge_compliance: f32,
+    volume_compliance: f32,
+    pinned: Vec<usize>,
+}
+
+impl SoftBodyBuilder {
+    pub fn from_tetrahedra(positions: Vec<Vec3>, tetrahedra: Vec<[usize; 4]>) -> Self {
+        // Orient every tetrahedron so that its signed volume is positive
+        let tetrahedra = tetrahedra
+            .into_iter()
+            .map(|mut tet| {
+                if tetrahedron_volume(&tet.map(|i| positions[i])) < 0.0 {
+                    tet.swap(2, 3);
+                }
+                tet
+            })
+            .collect();
+
+        Self {
+            positions,
+            tetrahedra,
+            density: DEFAULT_DENSITY,
+            edge_compliance: DEFAULT_STRETCH_COMPLIANCE,
+            volume_compliance: DEFAULT_VOLUME_COMPLIANCE,
+            pinned: Vec::new(),
+        }
+    }
+
+    // Box between `min` and `max` split into `resolution` cells per axis, six
+    // tetrahedra per cell
+    pub fn block(min: Vec3, max: Vec3, resolution: [usize; 3]) -> Self {
+        let [nx, ny, nz] = resolution.map(|n| n.max(1));
+        let cell = (max - min) / Vec3::new(nx as f32, ny as f32, nz as f32);
+        let index = |x: usize, y: usize, z: usize| (z * (ny + 1) + y) * (nx + 1) + x;
+
+        let mut positions = Vec::with_capacity((nx + 1) * (ny + 1) * (nz + 1));
+        for z in 0..=nz {
+            for y in 0..=ny {
+                for x in 0..=nx {
+                    positions.push(min + cell * Vec3::new(x as f32, y as f32, z as f32));
+                }
+            }
+        }
+
+        // Every cell is cut along its main diagonal, one tetrahedron per path
+        // from corner 0 to corner 7 along the cell edges. Neighbouring cells
+        // then share their face diagonals.
+        const PATHS: [[usize; 2]; 6] = [[1, 3], [1, 5], [2, 3], [2, 6], [4, 5], [4, 6]];
+        let mut tetrahedra = Vec::with_capacity(nx * ny * nz * 6);
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    let corner = |bits: usize| {
+                        index(x + (bits & 1), y + ((bits >> 1) & 1), z + ((bits >> 2) & 1))
+                    };
+                    for [first, second] in PATHS {
+                        tetrahedra.push([corner(0), corner(first), corner(second), corner(7)]);
+                    }
+                }
+            }
+        }
+
+        Self::from_tetrahedra(positions, tetrahedra)
+    }
+
+    // Mass per unit volume, spread over the corners of each tetrahedron
+    pub fn with_density(mut self, density: f32) -> Self {
+        self.density = density;
+        self
+    }
+
+    pub fn with_edge_compliance(mut self, compliance: f32) -> Self {
+        self.edge_compliance = compliance.max(0.0);
+        self
+    }
+
+    pub fn with_volume_compliance(mut self, compliance: f32) -> Self {
+        self.volume_compliance = compliance.max(0.0);
+        self
+    }
+
+    pub fn pin(mut self, particle: usize) -> Self {
+        self.pinned.push(particle);
+        self
+    }
+
+    pub fn pin_where<F: Fn(usize, Vec3) -> bool>(mut self, predicate: F) -> Self {
+        for (i, &position) in self.positions.iter().enumerate() {
+            if predicate(i, position) {
+                self.pinned.push(i);
+            }
+        }
+        self
+    }
+
+    pub fn build(self, world: &mut PhysicsWorld) -> SoftBodyHandle {
+        let mut masses = vec![0.0; self.positions.len()];
+        let mut volumes = Vec::with_capacity(self.tetrahedra.len());
+        let mut edges = BTreeMap::new();
+        let mut faces: BTreeMap<[usize; 3], Option<[u32; 3]>> = BTreeMap::new();
+
+        for &tet in &self.tetrahedra {
+            let p = tet.map(|i| self.positions[i]);
+            let rest_volume = tetrahedron_volume(&p);
+            volumes.push(VolumeElement { tet, rest_volume });
+
+            for &i in &tet {
+                masses[i] += rest_volume * self.density / 4.0;
+            }
+
+            for (a, b) in [(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)] {
+                let (a, b) = (tet[a].min(tet[b]), tet[a].max(tet[b]));
+                edges.insert((a, b), self.positions[a].distance(self.positions[b]));
+            }
+
+            // Faces seen twice are interior, the rest form the surface
+            for face in tetrahedron_faces(tet) {
+                let mut key = face;
+                key.sort_unstable();
+                faces
+                    .entry(key)
+                    .and_modify(|f| *f = None)
+                    .or_insert(Some(face.map(|i| i as u32)));
+            }
+        }
+
+        let distances = edges
+            .into_iter()
+            .map(|((a, b), rest_length)| DistanceElement {
+                a,
+                b,
+                rest_length,
+                compliance: self.edge_compliance,
+            })
+            .collect();
+        let indices: Vec<u32> = faces.into_values().flatten().flatten().collect();
+        let uvs = vec![[0.0; 2]; self.positions.len()];
+
+        let body = add_deformable_body(
+            world,
+            self.positions,
+            masses,
+            &self.pinned,
+            volumes,
+            distances,
+            self.volume_compliance,
+        );
+
+        SoftBodyHandle { body, indices, uvs }
+    }
+}
+
+fn add_deformable_body(
+    world: &mut PhysicsWorld,
+    positions: Vec<Vec3>,
+    mut masses: Vec<f32>,
+    pinned: &[usize],
+    volumes: Vec<VolumeElement>,
+    distances: Vec<DistanceElement>,
+    volume_compliance: f32,
+) -> usize {
+    for mass in masses.iter_mut() {
+        // Particles not referenced by any element still need a finite mass
+        if *mass <= 0.0 {
+            *mass = DEFAULT_DENSITY;
+        }
+    }
+    for &i in pinned {
+        if let Some(mass) = masses.get_mut(i) {
+            *mass = f32::INFINITY;
+        }
+    }
+
+    let center = positions.iter().sum::<Vec3>() / positions.len().max(1) as f32;
+    let radius = positions
+        .iter()
+        .map(|p| p.distance(center))
+        .fold(0.0, f32::max);
+
+    let body = world.add_object(PhysicsObject::DeformableBody {
+        prev_positions: positions.clone(),
+        velocities: vec![Vec3::ZERO; positions.len()],
+        masses,
+        rest_volume: volumes.iter().map(|v| v.rest_volume).sum(),
+        volumes: volumes.iter().map(|v| v.rest_volume).collect(),
+        tetrahedra: volumes.iter().map(|v| v.tet).collect(),
+        bounding_box: Vec4::new(0.0, 0.0, 0.0, radius),
+        positions,
+    });
+
+    world.add_constraint(Box::new(SoftBodyConstraint::new(
+        body,
+        distances,
+        volumes,
+        volume_compliance,
+    )));
+    body
+}
+
+fn inverse_mass(mass: f32) -> f32 {
+    if mass.is_finite() && mass > 0.0 {
+        1.0 / mass
+    } else {
+        0.0
+    }
+}
+
+fn tetrahedron_volume(p: &[Vec3; 4]) -> f32 {
+    (p[1] - p[0]).cross(p[2] - p[0]).dot(p[3] - p[0]) / 6.0
+}
+
+// Gradients of 6 * volume with respect to each corner
+fn volume_gradients(p: &[Vec3; 4]) -> [Vec3; 4] {
+    [
+        (p[3] - p[1]).cross(p[2] - p[1]),
+        (p[2] - p[0]).cross(p[3] - p[0]),
+        (p[3] - p[0]).cross(p[1] - p[0]),
+        (p[1] - p[0]).cross(p[2] - p[0]),
+    ]
+}
+
+// Faces of a positively oriented tetrahedron, wound to face outwards
+fn tetrahedron_faces(t: [usize; 4]) -> [[usize; 3]; 4] {
+    [
+        [t[1], t[2], t[3]],
+        [t[0], t[3], t[2]],
+        [t[0], t[1], t[3]],
+        [t[0], t[2], t[1]],
+    ]
+}
+
+fn vertex_normals(positions: &[Vec3], indices: &[u32]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; positions.len()];
+    for tri in indices.chunks_exact(3) {
+        let [a, b, c] = [tri[0], tri[1], tri[2]].map(|i| i as usize);
+        // Unnormalized, so larger triangles weigh more
+        let normal = (positions[b] - positions[a]).cross(positions[c] - positions[a]);
+        normals[a] += normal;
+        normals[b] += normal;
+        normals[c] += normal;
+    }
+    normals.iter().map(|n| n.normalize_or_zero()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particles(world: &PhysicsWorld, body: usize) -> Vec<Vec3> {
+        match &*world.objects[body].borrow() {
+            PhysicsObject::DeformableBody { positions, .. } => positions.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_particles(world: &PhysicsWorld, body: usize, f: impl Fn(usize, Vec3) -> Vec3) {
+        if let PhysicsObject::DeformableBody { positions, .. } =
+            &mut *world.objects[body].borrow_mut()
+        {
+            for (i, p) in positions.iter_mut().enumerate() {
+                *p = f(i, *p);
+            }
+        }
+    }
+
+    fn solve(world: &mut PhysicsWorld, delta_time: f32, iterations: usize) {
+        let constraint = world.constraints.pop().unwrap();
+        constraint.begin_substep(delta_time);
+        for _ in 0..iterations {
+            constraint.project(&mut world.objects);
+        }
+        world.constraints.push(constraint);
+    }
+
+    #[test]
+    fn test_cloth_grid_topology() {
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        let cloth = ClothBuilder::grid(Vec3::ZERO, Vec3::X * 2.0, Vec3::NEG_Y * 2.0, 2, 2)
+            .pin(0)
+            .pin(2)
+            .build(&mut world);
+
+        assert_eq!(cloth.indices.len(), 2 * 2 * 6);
+        match &*world.objects[cloth.body].borrow() {
+            PhysicsObject::DeformableBody { masses, .. } => {
+                assert_eq!(masses.len(), 9);
+                assert!(masses[0].is_infinite() && masses[2].is_infinite());
+                assert!(masses[4].is_finite() && masses[4] > 0.0);
+            }
+            _ => unreachable!(),
+        }
+
+        let vertices = cloth.export_vertices(&world);
+        assert_eq!(vertices.len(), 9);
+        assert_eq!(vertices[8].position, [2.0, -2.0, 0.0]);
+        assert_eq!(vertices[8].uv, [1.0, 1.0]);
+        assert_eq!(vertices[4].normal, [0.0, 0.0, -1.0]);
+    }
+
+    #[test]
+    fn test_stiff_cloth_restores_edge_lengths() {
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        let cloth = ClothBuilder::grid(Vec3::ZERO, Vec3::X, Vec3::NEG_Y, 1, 1)
+            .pin(0)
+            .build(&mut world);
+
+        // Pull the free corner away, the pinned one must not move
+        set_particles(&world, cloth.body, |i, p| if i == 3 { p * 3.0 } else { p });
+        solve(&mut world, 1.0 / 60.0, 50);
+
+        let p = particles(&world, cloth.body);
+        assert_eq!(p[0], Vec3::ZERO);
+        assert!((p[0].distance(p[1]) - 1.0).abs() < 1e-3);
+        assert!((p[1].distance(p[3]) - 1.0).abs() < 1e-3);
+        assert!((p[0].distance(p[2]) - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_hanging_cloth_through_world_update() {
+        let mut world = PhysicsWorld::new(Vec3::new(0.0, -9.81, 0.0));
+        let cloth = ClothBuilder::grid(Vec3::ZERO, Vec3::X, Vec3::NEG_Y, 2, 2)
+            .pin_where(|_, p| p.y == 0.0)
+            .build(&mut world);
+
+        for _ in 0..120 {
+            world.update(1.0 / 60.0);
+        }
+
+        // Without the cloth constraints the free rows would have fallen ~20 units
+        let p = particles(&world, cloth.body);
+        assert_eq!(p[0], Vec3::ZERO);
+        assert_eq!(p[2], Vec3::X);
+        for (a, b) in [(0, 3), (3, 6), (4, 7), (6, 7)] {
+            let length = p[a].distance(p[b]);
+            assert!((length - 0.5).abs() < 0.05, "{} {}: {}", a, b, length);
+        }
+    }
+
+    #[test]
+    fn test_compliance_is_timestep_independent() {
+        // A compliant cloth corner sagging under gravity should come to rest at
+        // the same place whatever the substep length
+        let rest_position = |delta_time: f32| {
+            let mut world = PhysicsWorld::new(Vec3::ZERO);
+            let cloth = ClothBuilder::grid(Vec3::ZERO, Vec3::X, Vec3::NEG_Y, 1, 1)
+                .with_stretch_compliance(1e-3)
+                .pin_where(|i, _| i != 1)
+                .build(&mut world);
+
+            let gravity = Vec3::new(0.0, -9.81, 0.0);
+            for _ in 0..(2.0 / delta_time) as usize {
+                if let PhysicsObject::DeformableBody {
+                    positions,
+                    prev_positions,
+                    velocities,
+                    ..
+                } = &mut *world.objects[cloth.body].borrow_mut()
+                {
+                    prev_positions[1] = positions[1];
+                    velocities[1] =
+                        velocities[1] * (1.0 - 10.0 * delta_time) + gravity * delta_time;
+                    positions[1] += velocities[1] * delta_time;
+                }
+
+                // Enough iterations for the multipliers to converge
+                solve(&mut world, delta_time, 20);
+
+                if let PhysicsObject::DeformableBody {
+                    positions,
+                    prev_positions,
+                    velocities,
+                    ..
+                } = &mut *world.objects[cloth.body].borrow_mut()
+                {
+                    velocities[1] = (positions[1] - prev_positions[1]) / delta_time;
+                }
+            }
+            particles(&world, cloth.body)[1]
+        };
+
+        let coarse = rest_position(1.0 / 30.0);
+        let fine = rest_position(1.0 / 240.0);
+        assert!(coarse.y < -1e-3 && coarse.y > -0.1, "{:?}", coarse);
+        assert!(coarse.distance(fine) < 1e-4, "{:?} vs {:?}", coarse, fine);
+    }
+
+    #[test]
+    fn test_soft_block_keeps_volume() {
+        let mut world = PhysicsWorld::new(Vec3::ZERO);
+        let block = SoftBodyBuilder::block(Vec3::ZERO, Vec3::ONE, [2, 2, 2])
+            .with_edge_compliance(1.0)
+            .build(&mut world);
+
+        let total_volume = |world: &PhysicsWorld| match &*world.objects[block.body].borrow() {
+            PhysicsObject::DeformableBody {
+                positions,
+                tetrahedra,
+                ..
+            } => tetrahedra
+                .iter()
+                .map(|t| tetrahedron_volume(&t.map(|i| positions[i])))
+                .sum::<f32>(),
+            _ => unreachable!(),
+        };
+        assert!((total_volume(&world) - 1.0).abs() < 1e-4);
+        // 6 faces of 2x2 quads, two triangles each
+        assert_eq!(block.indices.len(), 6 * 4 * 2 * 3);
+
+        // Squash it flat along Y
+        set_particles(&world, block.body, |_, p| Vec3::new(p.x, p.y * 0.5, p.z));
+        solve(&mut world, 1.0 / 60.0, 30);
+        assert!((total_volume(&world) - 1.0).abs() < 1e-2);
+    }
+}
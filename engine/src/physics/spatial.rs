@@ -1,10 +1,67 @@
 use glam::Vec3;
 use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
+use std::hash::Hash;
+
+use crate::graphics::debug_draw::DebugDraw;
+use crate::physics::bvh::DynamicAabbTree;
+use crate::physics::debug_shapes::{CELL_COLOR, SHAPE_COLOR, SHARED_CELL_COLOR};
 
 const CELL_SIZE: f32 = 10.0;
 const LOAD_FACTOR_THRESHOLD: f32 = 0.75;
 
+// Common interface of the broad-phase structures. Objects are identified by
+// their body index; `update` receives the current AABB of every body.
+pub trait BroadPhase: Send + Sync {
+    fn update(&mut self, bounds: &[(Vec3, Vec3)]);
+
+    // Candidate pairs (i < j), possibly with false positives
+    fn potential_pairs(&self) -> Vec<(usize, usize)>;
+
+    // Objects whose AABB overlaps the given box, sorted by index
+    fn query_aabb(&self, min: Vec3, max: Vec3) -> Vec<usize>;
+
+    // Visits objects along a ray. The distance passed with each candidate is a
+    // lower bound for hits on it and never decreases between calls, so
+    // `visit` can return false once it exceeds the closest hit found.
+    fn traverse_ray(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+        visit: &mut dyn FnMut(usize, f32) -> bool,
+    );
+
+    fn bounds(&self, object_index: usize) -> Option<(Vec3, Vec3)>;
+    fn object_count(&self) -> usize;
+    fn clone_box(&self) -> Box<dyn BroadPhase>;
+
+    // Outlines the structure for debugging, by default the stored AABBs
+    fn debug_draw(&self, draw: &mut DebugDraw) {
+        for index in 0..self.object_count() {
+            if let Some((min, max)) = self.bounds(index) {
+                draw.aabb(min, max, SHAPE_COLOR);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadPhaseKind {
+    // Uniform grid, best when objects are of similar size
+    SpatialHash,
+    // Incremental AABB tree, handles mixed object sizes and sparse scenes
+    DynamicTree,
+}
+
+impl BroadPhaseKind {
+    pub fn create(self) -> Box<dyn BroadPhase> {
+        match self {
+            BroadPhaseKind::SpatialHash => Box::new(SpatialHash::new()),
+            BroadPhaseKind::DynamicTree => Box::new(DynamicAabbTree::new()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
 pub struct GridCell {
     x: i32,
@@ -13,10 +70,14 @@ pub struct GridCell {
 }
 
 // Spatial hash table with dynamic resizing
+#[derive(Debug, Clone)]
 pub struct SpatialHash {
     cell_size: f32,
     grid: HashMap<GridCell, Vec<usize>>,
     object_cells: Vec<Vec<GridCell>>, // Track which cells each object is in
+    object_bounds: Vec<Option<(Vec3, Vec3)>>, // AABB each object was inserted with
+    occupied_bounds: Option<(Vec3, Vec3)>, // Union of the inserted AABBs
+    largest_extent: f32,
     total_objects: usize,
     cells_used: usize,
 }
@@ -27,6 +88,9 @@ impl SpatialHash {
             cell_size: CELL_SIZE,
             grid: HashMap::new(),
             object_cells: Vec::new(),
+            object_bounds: Vec::new(),
+            occupied_bounds: None,
+            largest_extent: 0.0,
             total_objects: 0,
             cells_used: 0,
         }
@@ -35,6 +99,9 @@ impl SpatialHash {
     pub fn clear(&mut self) {
         self.grid.clear();
         self.object_cells.clear();
+        self.object_bounds.clear();
+        self.occupied_bounds = None;
+        self.largest_extent = 0.0;
         self.total_objects = 0;
         self.cells_used = 0;
     }
@@ -57,24 +124,23 @@ impl SpatialHash {
     fn resize(&mut self) {
         if self.calculate_load_factor() > LOAD_FACTOR_THRESHOLD {
             self.cell_size *= 2.0; // Double cell size to reduce number of cells
-        } else if self.calculate_load_factor() < LOAD_FACTOR_THRESHOLD / 4.0 {
+        } else if self.calculate_load_factor() < LOAD_FACTOR_THRESHOLD / 4.0
+            && self.cell_size >= self.largest_extent
+        {
             self.cell_size /= 2.0; // Halve cell size to increase spatial resolution
         } else {
             return;
         }
 
-        // Rebuild grid with new cell size
-        let old_grid = std::mem::take(&mut self.grid);
-        self.grid = HashMap::with_capacity(old_grid.capacity());
+        // Rebuild grid with new cell size. Cells have to be recomputed from the
+        // stored bounds, otherwise lookups by position use the wrong cell size.
+        self.grid.clear();
         self.cells_used = 0;
 
-        for (_, objects) in old_grid {
-            for &obj_idx in &objects {
-                if obj_idx < self.object_cells.len() {
-                    for cell in &self.object_cells[obj_idx] {
-                        self.insert_to_cell(*cell, obj_idx);
-                    }
-                }
+        for obj_idx in 0..self.object_bounds.len() {
+            if let Some((min, max)) = self.object_bounds[obj_idx] {
+                self.object_cells[obj_idx].clear();
+                self.file_into_cells(obj_idx, min, max);
             }
         }
     }
@@ -90,13 +156,27 @@ impl SpatialHash {
         // Ensure object_cells vector is large enough
         if object_index >= self.object_cells.len() {
             self.object_cells.resize_with(object_index + 1, Vec::new);
+            self.object_bounds.resize(object_index + 1, None);
         }
 
-        let min_cell = self.position_to_cell(min);
-        let max_cell = self.position_to_cell(max);
-
         // Clear previous cells for this object
         self.object_cells[object_index].clear();
+        self.object_bounds[object_index] = Some((min, max));
+        self.occupied_bounds = Some(match self.occupied_bounds {
+            Some((occupied_min, occupied_max)) => (occupied_min.min(min), occupied_max.max(max)),
+            None => (min, max),
+        });
+        self.largest_extent = self.largest_extent.max((max - min).max_element());
+
+        self.file_into_cells(object_index, min, max);
+
+        self.total_objects += 1;
+        self.resize();
+    }
+
+    fn file_into_cells(&mut self, object_index: usize, min: Vec3, max: Vec3) {
+        let min_cell = self.position_to_cell(min);
+        let max_cell = self.position_to_cell(max);
 
         // Insert into all overlapping cells
         for x in min_cell.x..=max_cell.x {
@@ -108,9 +188,19 @@ impl SpatialHash {
                 }
             }
         }
+    }
 
-        self.total_objects += 1;
-        self.resize();
+    pub fn bounds(&self, object_index: usize) -> Option<(Vec3, Vec3)> {
+        self.object_bounds.get(object_index).copied().flatten()
+    }
+
+    // Number of object slots indexed since the last `clear`
+    pub fn object_count(&self) -> usize {
+        self.object_bounds.len()
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
     }
 
     pub fn query_radius(&self, center: Vec3, radius: f32) -> Vec<usize> {
@@ -136,6 +226,143 @@ impl SpatialHash {
         result.into_iter().collect()
     }
 
+    // Objects whose stored AABB overlaps the given box, sorted by index
+    pub fn query_aabb(&self, min: Vec3, max: Vec3) -> Vec<usize> {
+        let min_cell = self.position_to_cell(min);
+        let max_cell = self.position_to_cell(max);
+        let mut result = std::collections::HashSet::new();
+
+        // Large query boxes are cheaper to answer by scanning the bounds directly
+        let cell_count = (max_cell.x - min_cell.x + 1) as i64
+            * (max_cell.y - min_cell.y + 1) as i64
+            * (max_cell.z - min_cell.z + 1) as i64;
+        if cell_count > self.grid.len() as i64 {
+            result.extend(0..self.object_bounds.len());
+        } else {
+            for x in min_cell.x..=max_cell.x {
+                for y in min_cell.y..=max_cell.y {
+                    for z in min_cell.z..=max_cell.z {
+                        if let Some(objects) = self.grid.get(&GridCell { x, y, z }) {
+                            result.extend(objects);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut result: Vec<usize> = result
+            .into_iter()
+            .filter(|&i| match self.bounds(i) {
+                Some((obj_min, obj_max)) => aabb_overlap(min, max, obj_min, obj_max),
+                None => false,
+            })
+            .collect();
+        result.sort_unstable();
+        result
+    }
+
+    // Walks the grid along a ray (3D-DDA) and returns candidate objects in the
+    // order their cells are first visited. `visit` receives each new candidate
+    // and the distance at which the ray left the previous cell; returning
+    // false stops the traversal.
+    pub fn traverse_ray<F>(&self, origin: Vec3, dir: Vec3, max_dist: f32, mut visit: F)
+    where
+        F: FnMut(usize, f32) -> bool,
+    {
+        let Some((occupied_min, occupied_max)) = self.occupied_bounds else {
+            return;
+        };
+
+        let dir = dir.normalize_or_zero();
+        if dir == Vec3::ZERO {
+            // Without a direction there is nothing to walk, fall back to
+            // reporting every object
+            for i in 0..self.object_bounds.len() {
+                if self.object_bounds[i].is_some() && !visit(i, 0.0) {
+                    return;
+                }
+            }
+            return;
+        }
+
+        // Only the part of the ray inside the occupied bounds can reach an
+        // object. Clipping to it keeps the walk short for unbounded rays and
+        // for rays starting far away from everything.
+        let Some((t_start, t_end)) =
+            ray_interval(origin, dir.recip(), occupied_min, occupied_max, max_dist)
+        else {
+            return;
+        };
+        let start = origin + dir * t_start;
+        let max_dist = t_end;
+
+        let mut cell = self.position_to_cell(start);
+        let end_cell = self.position_to_cell(origin + dir * max_dist);
+        let step = [
+            dir.x.signum() as i32,
+            dir.y.signum() as i32,
+            dir.z.signum() as i32,
+        ];
+
+        let mut t_max = Vec3::ZERO;
+        let mut t_delta = Vec3::ZERO;
+        let cell_coords = [cell.x, cell.y, cell.z];
+        for axis in 0..3 {
+            if dir[axis] == 0.0 {
+                t_max[axis] = f32::INFINITY;
+                t_delta[axis] = f32::INFINITY;
+            } else {
+                let boundary = if dir[axis] > 0.0 {
+                    (cell_coords[axis] + 1) as f32 * self.cell_size
+                } else {
+                    cell_coords[axis] as f32 * self.cell_size
+                };
+                t_max[axis] = t_start + (boundary - start[axis]) / dir[axis];
+                t_delta[axis] = self.cell_size / dir[axis].abs();
+            }
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        let mut t_entry = t_start;
+        loop {
+            if let Some(objects) = self.grid.get(&cell) {
+                for &obj in objects {
+                    if seen.insert(obj) && !visit(obj, t_entry) {
+                        return;
+                    }
+                }
+            }
+
+            if cell == end_cell {
+                return;
+            }
+
+            // Step into the neighbouring cell across the closest boundary
+            let axis = if t_max.x < t_max.y {
+                if t_max.x < t_max.z {
+                    0
+                } else {
+                    2
+                }
+            } else if t_max.y < t_max.z {
+                1
+            } else {
+                2
+            };
+
+            t_entry = t_max[axis];
+            if t_entry > max_dist {
+                return;
+            }
+            t_max[axis] += t_delta[axis];
+            match axis {
+                0 => cell.x += step[0],
+                1 => cell.y += step[1],
+                _ => cell.z += step[2],
+            }
+        }
+    }
+
     pub fn get_potential_pairs(&self) -> Vec<(usize, usize)> {
         let mut pairs = std::collections::HashSet::new();
 
@@ -157,50 +384,181 @@ impl SpatialHash {
     }
 }
 
+impl Default for SpatialHash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BroadPhase for SpatialHash {
+    fn update(&mut self, bounds: &[(Vec3, Vec3)]) {
+        self.clear();
+        for (i, (min, max)) in bounds.iter().enumerate() {
+            self.insert(i, *min, *max);
+        }
+    }
+
+    fn potential_pairs(&self) -> Vec<(usize, usize)> {
+        self.get_potential_pairs()
+    }
+
+    fn query_aabb(&self, min: Vec3, max: Vec3) -> Vec<usize> {
+        SpatialHash::query_aabb(self, min, max)
+    }
+
+    fn traverse_ray(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+        visit: &mut dyn FnMut(usize, f32) -> bool,
+    ) {
+        SpatialHash::traverse_ray(self, origin, dir, max_dist, visit)
+    }
+
+    fn bounds(&self, object_index: usize) -> Option<(Vec3, Vec3)> {
+        SpatialHash::bounds(self, object_index)
+    }
+
+    fn object_count(&self) -> usize {
+        SpatialHash::object_count(self)
+    }
+
+    fn clone_box(&self) -> Box<dyn BroadPhase> {
+        Box::new(self.clone())
+    }
+
+    // Outlines every occupied cell, shared cells are where pairs come from
+    fn debug_draw(&self, draw: &mut DebugDraw) {
+        for (cell, objects) in &self.grid {
+            if objects.is_empty() {
+                continue;
+            }
+            let min = Vec3::new(cell.x as f32, cell.y as f32, cell.z as f32) * self.cell_size;
+            let color = if objects.len() > 1 {
+                SHARED_CELL_COLOR
+            } else {
+                CELL_COLOR
+            };
+            draw.aabb(min, min + Vec3::splat(self.cell_size), color);
+        }
+    }
+}
+
 // Parallel collision detection using rayon
 pub struct ParallelBroadPhase {
-    spatial_hash: SpatialHash,
+    backend: Box<dyn BroadPhase>,
 }
 
 impl ParallelBroadPhase {
     pub fn new() -> Self {
-        Self {
-            spatial_hash: SpatialHash::new(),
-        }
+        Self::with_kind(BroadPhaseKind::SpatialHash)
     }
 
-    pub fn update(&mut self, positions: &[(Vec3, Vec3)]) -> Vec<(usize, usize)> {
-        use rayon::prelude::*;
+    pub fn with_kind(kind: BroadPhaseKind) -> Self {
+        Self::with_backend(kind.create())
+    }
 
-        self.spatial_hash.clear();
+    pub fn with_backend(backend: Box<dyn BroadPhase>) -> Self {
+        Self { backend }
+    }
+
+    // `filter` decides whether a pair of overlapping objects may collide at
+    // all (collision layers, ignored pairs, ...)
+    pub fn update<F>(&mut self, positions: &[(Vec3, Vec3)], filter: F) -> Vec<(usize, usize)>
+    where
+        F: Fn(usize, usize) -> bool + Sync,
+    {
+        use rayon::prelude::*;
 
-        // Insert objects in parallel
-        positions
-            .par_iter()
-            .enumerate()
-            .for_each(|(i, (min, max))| {
-                self.spatial_hash.insert(i, *min, *max);
-            });
+        self.backend.update(positions);
 
         // Get potential pairs
-        let pairs = self.spatial_hash.get_potential_pairs();
+        let pairs = self.backend.potential_pairs();
 
         // Filter pairs in parallel
         pairs
             .into_par_iter()
             .filter(|&(i, j)| {
-                // Additional filtering can be added here
-                // For example, checking if objects are in the same island
-                true
+                let (min_i, max_i) = positions[i];
+                let (min_j, max_j) = positions[j];
+                aabb_overlap(min_i, max_i, min_j, max_j) && filter(i, j)
             })
             .collect()
     }
 }
 
+impl ParallelBroadPhase {
+    // Scene queries reuse the structure built by the last `update`
+    pub fn backend(&self) -> &dyn BroadPhase {
+        self.backend.as_ref()
+    }
+
+    pub fn rebuild(&mut self, positions: &[(Vec3, Vec3)]) {
+        self.backend.update(positions);
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.backend.object_count()
+    }
+
+    pub fn query_aabb(&self, min: Vec3, max: Vec3) -> Vec<usize> {
+        self.backend.query_aabb(min, max)
+    }
+
+    pub fn traverse_ray<F>(&self, origin: Vec3, dir: Vec3, max_dist: f32, mut visit: F)
+    where
+        F: FnMut(usize, f32) -> bool,
+    {
+        self.backend.traverse_ray(origin, dir, max_dist, &mut visit)
+    }
+}
+
+impl Default for ParallelBroadPhase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for ParallelBroadPhase {
+    fn clone(&self) -> Self {
+        Self {
+            backend: self.backend.clone_box(),
+        }
+    }
+}
+
+pub fn aabb_overlap(min_a: Vec3, max_a: Vec3, min_b: Vec3, max_b: Vec3) -> bool {
+    min_a.cmple(max_b).all() && min_b.cmple(max_a).all()
+}
+
+// Distances along a ray at which it enters and leaves a box, clamped to
+// [0, max_dist]. None when the ray misses the box within that range.
+fn ray_interval(
+    origin: Vec3,
+    inv_dir: Vec3,
+    min: Vec3,
+    max: Vec3,
+    max_dist: f32,
+) -> Option<(f32, f32)> {
+    let t1 = (min - origin) * inv_dir;
+    let t2 = (max - origin) * inv_dir;
+    let t_enter = t1.min(t2).max_element().max(0.0);
+    let t_exit = t1.max(t2).min_element().min(max_dist);
+
+    if t_enter <= t_exit {
+        Some((t_enter, t_exit))
+    } else {
+        None
+    }
+}
+
 // Morton encoding for better cache coherency
+#[allow(dead_code)]
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 struct MortonCode(u64);
 
+#[allow(dead_code)]
 impl MortonCode {
     fn new(x: i32, y: i32, z: i32) -> Self {
         let x = Self::expand_bits(x as u64);
@@ -220,11 +578,13 @@ impl MortonCode {
 }
 
 // Cache-efficient spatial hash using Morton codes
+#[allow(dead_code)]
 pub struct CacheFriendlySpatialHash {
     cell_size: f32,
     grid: HashMap<MortonCode, Vec<usize>>,
 }
 
+#[allow(dead_code)]
 impl CacheFriendlySpatialHash {
     pub fn new(cell_size: f32) -> Self {
         Self {
@@ -233,21 +593,28 @@ impl CacheFriendlySpatialHash {
         }
     }
 
-    fn position_to_morton(&self, position: Vec3) -> MortonCode {
+    fn position_to_cell(&self, position: Vec3) -> (i32, i32, i32) {
         let x = (position.x / self.cell_size).floor() as i32;
         let y = (position.y / self.cell_size).floor() as i32;
         let z = (position.z / self.cell_size).floor() as i32;
-        MortonCode::new(x, y, z)
+        (x, y, z)
     }
 
     pub fn insert(&mut self, object_index: usize, min: Vec3, max: Vec3) {
-        let min_code = self.position_to_morton(min);
-        let max_code = self.position_to_morton(max);
-
-        // Insert into cells (simplified for example)
-        self.grid.entry(min_code).or_default().push(object_index);
-        if min_code != max_code {
-            self.grid.entry(max_code).or_default().push(object_index);
+        let (min_x, min_y, min_z) = self.position_to_cell(min);
+        let (max_x, max_y, max_z) = self.position_to_cell(max);
+
+        // Insert into every overlapping cell, not just the corner cells, so
+        // objects spanning several cells are found from all of them
+        for x in min_x..=max_x {
+            for y in min_y..=max_y {
+                for z in min_z..=max_z {
+                    self.grid
+                        .entry(MortonCode::new(x, y, z))
+                        .or_default()
+                        .push(object_index);
+                }
+            }
         }
     }
 }
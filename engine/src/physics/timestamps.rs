@@ -0,0 +1,176 @@
+// GPU timestamps around the compute passes of a step. Recording writes one
+// timestamp before the first pass and one after every pass, and `step` reads
+// them back once the step's fence signaled. The time between two timestamps
+// goes to the pass ending at the later one:
+//
+//   emit      spawning from the emitters, only on steps that spawn
+//   grid      counting, prefix sum and reorder of the neighbor grid
+//   <name>    every neighbor pass, all its sub-passes together
+//   update    integration
+//
+// Timing is off by default, see `GpuPhysicsSystem::set_gpu_timestamps`.
+
+use ash::vk;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::physics::gpu_physics::{vulkan_error, GpuPhysicsSystem, PhysicsError};
+
+// Timestamps per step, room for the fixed passes and plenty of neighbor
+// passes. Passes past it go untimed.
+const MAX_TIMESTAMPS: u32 = 32;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PassTiming {
+    pub name: String,
+    #[serde(rename = "time_ms", serialize_with = "super::debug::serialize_millis")]
+    pub time: Duration,
+}
+
+pub(crate) struct GpuTimestamps {
+    pool: vk::QueryPool,
+    // Nanoseconds per tick
+    period: f32,
+    // The pass ending at each timestamp after the first, filled in while
+    // recording
+    labels: RefCell<Vec<String>>,
+}
+
+impl GpuTimestamps {
+    fn new(device: &ash::Device, period: f32) -> Result<Self, PhysicsError> {
+        let info = vk::QueryPoolCreateInfo::builder()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(MAX_TIMESTAMPS);
+        let pool = unsafe { device.create_query_pool(&info, None) }
+            .map_err(|e| vulkan_error("Timestamps", "create timestamp query pool", e))?;
+        Ok(Self {
+            pool,
+            period,
+            labels: RefCell::new(Vec::new()),
+        })
+    }
+
+    unsafe fn begin(&self, device: &ash::Device, cmd: vk::CommandBuffer) {
+        self.labels.borrow_mut().clear();
+        device.cmd_reset_query_pool(cmd, self.pool, 0, MAX_TIMESTAMPS);
+        device.cmd_write_timestamp(cmd, vk::PipelineStageFlags::TOP_OF_PIPE, self.pool, 0);
+    }
+
+    unsafe fn mark(&self, device: &ash::Device, cmd: vk::CommandBuffer, label: &str) {
+        let mut labels = self.labels.borrow_mut();
+        let query = labels.len() as u32 + 1;
+        if query >= MAX_TIMESTAMPS {
+            return;
+        }
+        device.cmd_write_timestamp(
+            cmd,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            self.pool,
+            query,
+        );
+        labels.push(label.to_string());
+    }
+
+    // Only valid once the step that wrote the timestamps finished
+    fn resolve(&self, device: &ash::Device) -> Result<Vec<PassTiming>, PhysicsError> {
+        let labels = self.labels.borrow();
+        if labels.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut ticks = vec![0u64; labels.len() + 1];
+        unsafe {
+            device.get_query_pool_results(
+                self.pool,
+                0,
+                ticks.len() as u32,
+                &mut ticks,
+                vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+            )
+        }
+        .map_err(|e| vulkan_error("Timestamps", "read timestamps", e))?;
+        Ok(pass_timings(&labels, &ticks, self.period))
+    }
+}
+
+fn pass_timings(labels: &[String], ticks: &[u64], period: f32) -> Vec<PassTiming> {
+    labels
+        .iter()
+        .zip(ticks.windows(2))
+        .map(|(name, pair)| PassTiming {
+            name: name.clone(),
+            time: Duration::from_nanos(
+                (pair[1].saturating_sub(pair[0]) as f64 * period as f64) as u64,
+            ),
+        })
+        .collect()
+}
+
+impl GpuPhysicsSystem {
+    // Times every compute pass on the GPU, or stops timing with None.
+    // `timestamp_period` is the device's `limits.timestamp_period`, and the
+    // compute queue family has to support timestamps.
+    pub fn set_gpu_timestamps(&mut self, timestamp_period: Option<f32>) {
+        self.timestamp_period = timestamp_period;
+        self.destroy_timestamps();
+        self.pass_timings.clear();
+    }
+
+    // Pass timings of the last step, empty while timing is off
+    pub fn pass_timings(&self) -> &[PassTiming] {
+        &self.pass_timings
+    }
+
+    // The query pool is created on the first step after enabling timing or
+    // after a rebuild
+    pub(crate) fn ensure_timestamps(&mut self) -> Result<(), PhysicsError> {
+        if let (Some(period), None) = (self.timestamp_period, &self.timestamps) {
+            self.timestamps = Some(GpuTimestamps::new(&self.device, period)?);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn resolve_timestamps(&mut self) -> Result<(), PhysicsError> {
+        if let Some(timestamps) = &self.timestamps {
+            self.pass_timings = timestamps.resolve(&self.device)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn destroy_timestamps(&mut self) {
+        if let Some(timestamps) = self.timestamps.take() {
+            unsafe {
+                let _ = self.device.device_wait_idle();
+                self.device.destroy_query_pool(timestamps.pool, None);
+            }
+        }
+    }
+
+    pub(crate) unsafe fn begin_timestamps(&self, cmd: vk::CommandBuffer) {
+        if let Some(timestamps) = &self.timestamps {
+            timestamps.begin(&self.device, cmd);
+        }
+    }
+
+    // Marks the end of the pass recorded last
+    pub(crate) unsafe fn mark_pass(&self, cmd: vk::CommandBuffer, label: &str) {
+        if let Some(timestamps) = &self.timestamps {
+            timestamps.mark(&self.device, cmd, label);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pass_timings() {
+        let labels = vec!["emit".to_string(), "grid".to_string(), "update".to_string()];
+        // Two nanoseconds per tick, going backwards counts as no time
+        let timings = pass_timings(&labels, &[100, 150, 140, 640], 2.0);
+        let times: Vec<_> = timings.iter().map(|t| t.time.as_nanos()).collect();
+        assert_eq!(times, vec![100, 0, 1000]);
+        assert_eq!(timings[2].name, "update");
+    }
+}
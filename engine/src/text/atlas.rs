@@ -1,5 +1,5 @@
-use crate::context::Context;
 use crate::error::{Result, VulkanError};
+use crate::graphics::context::Context;
 use ash::vk;
 use std::collections::HashMap;
 use std::sync::Arc;
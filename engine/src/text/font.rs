@@ -10,6 +10,12 @@ pub struct FontManager {
     default_font: Option<Arc<Font>>,
 }
 
+impl Default for FontManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FontManager {
     pub fn new() -> Self {
         Self {
@@ -1,3 +1,4 @@
+use crate::memory::{self, FrameAllocator};
 use crate::text::atlas::{FontAtlas, GlyphInfo};
 use crate::text::vertex::TextVertex;
 use ash::vk;
@@ -25,26 +26,37 @@ pub struct TextElement {
     pub element_id: u32,
 }
 
+// Where `TextLayout::upload` put the vertices and indices, valid until the
+// frame allocator comes back to the same frame
+#[derive(Debug, Clone, Copy)]
+pub struct TextUpload {
+    pub vertex_buffer: vk::Buffer,
+    pub vertex_offset: vk::DeviceSize,
+    pub index_buffer: vk::Buffer,
+    pub index_offset: vk::DeviceSize,
+    pub index_count: u32,
+}
+
 pub struct TextLayout {
     vertices: Vec<TextVertex>,
     indices: Vec<u32>,
     bounding_boxes: Vec<BoundingBox>,
-    #[allow(dead_code)] // These fields will be used when implementing Vulkan buffer management
-    vertex_buffer: Option<vk::Buffer>,
-    #[allow(dead_code)]
-    index_buffer: Option<vk::Buffer>,
-    #[allow(dead_code)]
+    #[allow(dead_code)] // Will be used when implementing Vulkan buffer management
     bbox_buffer: Option<vk::Buffer>,
 }
 
+impl Default for TextLayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TextLayout {
     pub fn new() -> Self {
         Self {
             vertices: Vec::new(),
             indices: Vec::new(),
             bounding_boxes: Vec::new(),
-            vertex_buffer: None,
-            index_buffer: None,
             bbox_buffer: None,
         }
     }
@@ -155,6 +167,21 @@ impl TextLayout {
         ]);
     }
 
+    // Copies the vertices and indices into the current frame. Text is laid
+    // out again whenever it changes, so it lives in per-frame memory rather
+    // than in buffers of its own.
+    pub fn upload(&self, frames: &FrameAllocator) -> memory::Result<TextUpload> {
+        let (vertex_buffer, vertex_offset) = frames.upload(&self.vertices, 0)?;
+        let (index_buffer, index_offset) = frames.upload(&self.indices, 0)?;
+        Ok(TextUpload {
+            vertex_buffer,
+            vertex_offset,
+            index_buffer,
+            index_offset,
+            index_count: self.index_count(),
+        })
+    }
+
     pub fn vertices(&self) -> &[TextVertex] {
         &self.vertices
     }
@@ -6,7 +6,7 @@ pub mod vertex;
 
 pub use atlas::{FontAtlas, GlyphInfo, GlyphMetrics};
 pub use font::FontManager;
-pub use layout::{BoundingBox, Rect, TextElement, TextLayout};
+pub use layout::{BoundingBox, Rect, TextElement, TextLayout, TextUpload};
 pub use picking::TextPicker;
 pub use vertex::TextVertex;
 
@@ -105,7 +105,7 @@ impl TextPicker {
         let shader_stage = vk::PipelineShaderStageCreateInfo::builder()
             .stage(vk::ShaderStageFlags::COMPUTE)
             .module(vk::ShaderModule::null()) // TODO: Load actual shader
-            .name(std::ffi::CStr::from_bytes_with_nul(b"main\0").unwrap())
+            .name(c"main")
             .build();
 
         let compute_pipeline_info = vk::ComputePipelineCreateInfo::builder()
@@ -125,6 +125,7 @@ impl TextPicker {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn test_intersection(
         &self,
         command_buffer: vk::CommandBuffer,
@@ -206,7 +207,7 @@ impl TextPicker {
 
             // Dispatch compute shader
             let workgroup_size = 256;
-            let num_workgroups = (bbox_count + workgroup_size - 1) / workgroup_size;
+            let num_workgroups = bbox_count.div_ceil(workgroup_size);
             self.device
                 .cmd_dispatch(command_buffer, num_workgroups, 1, 1);
         }
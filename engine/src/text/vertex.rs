@@ -1,7 +1,8 @@
 use ash::vk;
+use bytemuck::{Pod, Zeroable};
 use memoffset::offset_of;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
 #[repr(C)]
 pub struct TextVertex {
     pub position: [f32; 2],